@@ -206,7 +206,7 @@ pub fn setup_wallet_transport_type(config: &GlobalConfig) -> TransportType {
                 control_server_auth: {
                     match auth {
                         TorControlAuthentication::None => tor::Authentication::None,
-                        TorControlAuthentication::Password(password) => tor::Authentication::HashedPassword(password),
+                        TorControlAuthentication::Password(password) => tor::Authentication::Password(password),
                     }
                 },
                 identity: identity.map(Box::new),
@@ -315,9 +315,7 @@ where B: BlockchainBackend + 'static
                     control_server_auth: {
                         match auth {
                             TorControlAuthentication::None => tor::Authentication::None,
-                            TorControlAuthentication::Password(password) => {
-                                tor::Authentication::HashedPassword(password)
-                            },
+                            TorControlAuthentication::Password(password) => tor::Authentication::Password(password),
                         }
                     },
                     identity: identity.map(Box::new),
@@ -24,6 +24,7 @@ use std::fmt;
 use tari_comms::{multiaddr::Multiaddr, socks, tor, transports::SocksConfig};
 
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum TransportType {
     /// Use a memory transport. This transport recognises /memory addresses primarily used for local testing.
     Memory { listener_address: Multiaddr },
@@ -0,0 +1,283 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+#[cfg(not(feature = "benches"))]
+mod benches {
+    pub fn main() {
+        println!("Enable the `benches` feature to run benches");
+    }
+}
+
+#[cfg(feature = "benches")]
+mod benches {
+    use criterion::{black_box, criterion_group, BatchSize, Criterion};
+    use futures::AsyncWriteExt;
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        net::SocketAddr,
+        num::NonZeroU16,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+    use tari_comms::{
+        memsocket::MemorySocket,
+        tor::{
+            commands::{self, AddOnion, AddOnionFlag, TorCommand},
+            parsers,
+            KeyBlob,
+            KeyType,
+            ResponseLine,
+            TorControlPortClient,
+        },
+    };
+    use tokio::{runtime::Runtime, sync::broadcast};
+
+    /// Counts heap allocations so that benches can report allocations per iteration alongside timings.
+    struct CountingAlloc;
+
+    static NUM_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            NUM_ALLOCS.fetch_add(1, Ordering::Relaxed);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingAlloc = CountingAlloc;
+
+    fn allocs_per_iter<F: FnMut()>(name: &str, mut f: F) {
+        const ITERATIONS: usize = 1000;
+        // Warm up so that any one-off allocations are not counted
+        f();
+        let start = NUM_ALLOCS.load(Ordering::Relaxed);
+        for _ in 0..ITERATIONS {
+            f();
+        }
+        let allocs = NUM_ALLOCS.load(Ordering::Relaxed) - start;
+        println!(
+            "{}: {:.2} allocations per iteration",
+            name,
+            allocs as f64 / ITERATIONS as f64
+        );
+    }
+
+    fn add_onion_command() -> AddOnion<'static> {
+        AddOnion::builder()
+            .with_key(KeyType::Ed25519V3, KeyBlob::String("this-is-a-key"))
+            .with_flags(vec![AddOnionFlag::Detach, AddOnionFlag::DiscardPK])
+            .max_streams(NonZeroU16::new(10).unwrap())
+            .add_port(9090)
+            .add_port((443u16, SocketAddr::from(([127, 0, 0, 1], 8443))))
+            .build()
+            .unwrap()
+    }
+
+    fn add_onion_to_command_string(c: &mut Criterion) {
+        let command = add_onion_command();
+        allocs_per_iter("ADD_ONION to_command_string", || {
+            command.to_command_string().unwrap();
+        });
+        c.bench_function("ADD_ONION to_command_string", move |b| {
+            b.iter(|| command.to_command_string().unwrap());
+        });
+    }
+
+    fn add_onion_write_command_reused_buf(c: &mut Criterion) {
+        let command = add_onion_command();
+        let mut buf = String::new();
+        allocs_per_iter("ADD_ONION write_command (reused buffer)", || {
+            buf.clear();
+            command.write_command(&mut buf).unwrap();
+        });
+        c.bench_function("ADD_ONION write_command (reused buffer)", move |b| {
+            b.iter(|| {
+                buf.clear();
+                command.write_command(&mut buf).unwrap();
+            });
+        });
+    }
+
+    fn add_onion_build_flags(c: &mut Criterion) {
+        let flags = [AddOnionFlag::Detach, AddOnionFlag::DiscardPK];
+        allocs_per_iter("AddOnion::new (flags from Vec)", || {
+            black_box(AddOnion::new(
+                KeyType::New,
+                KeyBlob::Best,
+                black_box(flags.to_vec()),
+                9090.into(),
+                None,
+            ));
+        });
+        allocs_per_iter("AddOnionBuilder (inline flags)", || {
+            AddOnion::builder()
+                .with_flags(flags.iter().copied())
+                .add_port(9090)
+                .build()
+                .unwrap();
+        });
+        c.bench_function("AddOnionBuilder (inline flags)", move |b| {
+            b.iter(|| {
+                AddOnion::builder()
+                    .with_flags(flags.iter().copied())
+                    .add_port(9090)
+                    .build()
+                    .unwrap()
+            });
+        });
+    }
+
+    /// Writes the given BW events, followed by the reply to a DEL_ONION command, and waits for the client to receive
+    /// the reply, which it can only do once every event before it has been processed.
+    async fn process_bw_events(events: &str, has_subscriber: bool) {
+        let (client_socket, mut server_socket) = MemorySocket::new_pair();
+        let (event_tx, _) = broadcast::channel(1);
+        let _event_rx = if has_subscriber {
+            Some(event_tx.subscribe())
+        } else {
+            None
+        };
+        let mut client = TorControlPortClient::new(client_socket, event_tx);
+        server_socket.write_all(events.as_bytes()).await.unwrap();
+        client.del_onion("dummy").await.unwrap();
+    }
+
+    fn tor_events(c: &mut Criterion) {
+        const NUM_EVENTS: usize = 1000;
+        let mut events = "650 BW 1024 2048\r\n".repeat(NUM_EVENTS);
+        events.push_str("250 OK\r\n");
+
+        let mut runtime = Runtime::new().unwrap();
+        let events_subscribed = events.clone();
+        c.bench_function("1000 BW events (subscribed)", move |b| {
+            b.iter(|| runtime.block_on(process_bw_events(&events_subscribed, true)))
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        c.bench_function("1000 BW events (no subscribers)", move |b| {
+            b.iter(|| runtime.block_on(process_bw_events(&events, false)))
+        });
+    }
+
+    const ALL_FLAGS: [AddOnionFlag; 5] = [
+        AddOnionFlag::DiscardPK,
+        AddOnionFlag::Detach,
+        AddOnionFlag::BasicAuth,
+        AddOnionFlag::NonAnonymous,
+        AddOnionFlag::MaxStreamsCloseCircuit,
+    ];
+
+    /// A reply to ADD_ONION NEW:ED25519-V3, as returned by tor
+    const ADD_ONION_REPLY: &[&str] = &[
+        "250-ServiceID=qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid",
+        "250-PrivateKey=ED25519-V3:\
+         Pg3GEyssauPRW3jP6mHwKOxvl_fMsF0QsZC3DvQ8jZ9AxmfRvSP35m9l0vOYyOxkOqWM6ufjdYuM8Ae6cR2UdreG6",
+        "250 OK",
+    ];
+
+    fn response_lines(lines: &[&str]) -> Vec<ResponseLine> {
+        lines.iter().map(|line| parsers::response_line(line).unwrap()).collect()
+    }
+
+    fn add_onion_to_command_string_sizes(c: &mut Criterion) {
+        c.bench_function_over_inputs(
+            "ADD_ONION to_command_string (flags, ports)",
+            |b, &&(num_flags, num_ports)| {
+                // BasicAuth is only accepted for v2 onion services
+                #[allow(deprecated)]
+                let command = AddOnion::builder()
+                    .with_key(KeyType::New, KeyBlob::Rsa1024)
+                    .with_flags(ALL_FLAGS.iter().copied().take(num_flags))
+                    .add_ports((0..num_ports).map(|i| 9000 + i as u16))
+                    .build()
+                    .unwrap();
+                b.iter(|| command.to_command_string().unwrap());
+            },
+            &[(0, 1), (2, 1), (5, 4)],
+        );
+    }
+
+    fn add_onion_parse_responses(c: &mut Criterion) {
+        let command = add_onion_command();
+        c.bench_function("ADD_ONION parse_responses", move |b| {
+            b.iter_batched(
+                || response_lines(ADD_ONION_REPLY),
+                |lines| command.parse_responses(lines).unwrap(),
+                BatchSize::SmallInput,
+            );
+        });
+    }
+
+    fn parse_response_lines(c: &mut Criterion) {
+        c.bench_function("parsers::response_line", |b| {
+            b.iter(|| {
+                for line in ADD_ONION_REPLY {
+                    black_box(parsers::response_line(line).unwrap());
+                }
+            });
+        });
+
+        c.bench_function("parsers::key_value", |b| {
+            let line = r#"net/listeners/socks="127.0.0.1:9050" "[::1]:9050" "unix:/run/tor/socks""#;
+            b.iter(|| parsers::key_value(line).unwrap());
+        });
+
+        c.bench_function("commands::parse_key_value_lines", |b| {
+            b.iter_batched(
+                || response_lines(ADD_ONION_REPLY),
+                |lines| commands::parse_key_value_lines(lines, false).unwrap(),
+                BatchSize::SmallInput,
+            );
+        });
+
+        c.bench_function("parsers::key_value_args", |b| {
+            let args = r#"METHODS=COOKIE,SAFECOOKIE,HASHEDPASSWORD COOKIEFILE="/home/user/.tor/control_auth_cookie""#;
+            b.iter(|| parsers::key_value_args(args).unwrap());
+        });
+
+        c.bench_function("parsers::quoted_string", |b| {
+            let quoted = r#""C:\Users\user\AppData\Roaming\tor\control_auth_cookie""#;
+            b.iter(|| parsers::quoted_string(quoted).unwrap());
+        });
+    }
+
+    criterion_group!(
+        name = tor;
+        config = Criterion::default().warm_up_time(Duration::from_millis(500));
+        targets = add_onion_to_command_string, add_onion_write_command_reused_buf, add_onion_build_flags, tor_events,
+            add_onion_to_command_string_sizes, add_onion_parse_responses, parse_response_lines
+    );
+
+    pub fn main() {
+        tor();
+        criterion::Criterion::default().configure_from_args().final_summary();
+    }
+}
+
+fn main() {
+    benches::main();
+}
@@ -0,0 +1,71 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tari_comms::tor::ResponseDecoder;
+use tokio_util::codec::Decoder;
+
+// Feeds the input to the decoder in chunks, as it would be read from the socket. The first byte of the input selects
+// the chunk size so that replies are split at arbitrary points.
+fuzz_target!(|data: &[u8]| {
+    let (chunk_size, data) = match data.split_first() {
+        Some((n, data)) => (*n as usize + 1, data),
+        None => return,
+    };
+
+    let mut decoder = ResponseDecoder::with_max_length(4096);
+    let mut buf = BytesMut::new();
+    let mut num_responses = 0;
+    let mut decoded_len = 0;
+    for chunk in data.chunks(chunk_size) {
+        buf.extend_from_slice(chunk);
+        loop {
+            match decoder.decode(&mut buf) {
+                Ok(Some(response)) => {
+                    num_responses += 1;
+                    decoded_len += response.value().len();
+                },
+                Ok(None) => break,
+                Err(_) => return,
+            }
+        }
+    }
+
+    loop {
+        match decoder.decode_eof(&mut buf) {
+            Ok(Some(response)) => {
+                num_responses += 1;
+                decoded_len += response.value().len();
+            },
+            Ok(None) => break,
+            Err(_) => return,
+        }
+    }
+
+    // Every reply consumes at least one line, and a value never contains more bytes than were read
+    let num_lines = data.iter().filter(|b| **b == b'\n').count() + 1;
+    assert!(num_responses <= num_lines);
+    assert!(decoded_len <= data.len());
+});
@@ -0,0 +1,356 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Types that describe circuits in tor control port replies and events, such as the `BUILD_FLAGS` and `PURPOSE` fields
+//! of `CIRC` events.
+
+use super::{
+    commands::InfoListIter,
+    parsers::{self, ParseError},
+};
+use std::{collections::HashMap, fmt, str::FromStr};
+
+tor_keyword_enum!(
+    /// A flag in the `BUILD_FLAGS` field of a circuit
+    BuildFlag {
+        /// The circuit is a one-hop circuit used to fetch directory information
+        OneHopTunnel => "ONEHOP_TUNNEL",
+        /// The circuit will not be used for client traffic that exits the tor network
+        IsInternal => "IS_INTERNAL",
+        /// The circuit only includes high-capacity relays
+        NeedCapacity => "NEED_CAPACITY",
+        /// The circuit only includes relays with a high uptime
+        NeedUptime => "NEED_UPTIME",
+    }
+);
+
+tor_keyword_enum!(
+    /// The `PURPOSE` field of a circuit
+    CircuitPurpose {
+        /// A circuit for general client traffic and fetching directory information
+        General => "GENERAL",
+        /// A client circuit to an onion service's introduction point
+        HsClientIntro => "HS_CLIENT_INTRO",
+        /// A client circuit to a rendezvous point
+        HsClientRend => "HS_CLIENT_REND",
+        /// A client circuit used to fetch an onion service descriptor
+        HsClientHsDir => "HS_CLIENT_HSDIR",
+        /// An onion service circuit to one of its introduction points
+        HsServiceIntro => "HS_SERVICE_INTRO",
+        /// An onion service circuit to a client's rendezvous point
+        HsServiceRend => "HS_SERVICE_REND",
+        /// An onion service circuit used to publish its descriptor
+        HsServiceHsDir => "HS_SERVICE_HSDIR",
+        /// A circuit used to test a relay's reachability or bandwidth
+        Testing => "TESTING",
+        /// A circuit built by a controller
+        Controller => "CONTROLLER",
+        /// A circuit used to measure the circuit build timeout
+        MeasureTimeout => "MEASURE_TIMEOUT",
+        /// A circuit built by the vanguards addon
+        HsVanguards => "HS_VANGUARDS",
+        /// A circuit used to test for path bias attacks
+        PathBiasTesting => "PATH_BIAS_TESTING",
+        /// A circuit kept open by circuit padding
+        CircuitPadding => "CIRCUIT_PADDING",
+    }
+);
+
+tor_keyword_enum!(
+    /// The `REASON` and `REMOTE_REASON` fields of a `CIRC` event for a circuit that failed or was closed
+    CircuitReason {
+        /// No reason was given
+        None => "NONE",
+        /// A relay violated the tor protocol
+        TorProtocol => "TORPROTOCOL",
+        /// An internal error occurred
+        Internal => "INTERNAL",
+        /// The circuit was closed at the request of the client or a controller
+        Requested => "REQUESTED",
+        /// A relay is hibernating
+        Hibernating => "HIBERNATING",
+        /// A relay ran out of memory, sockets or circuit IDs
+        ResourceLimit => "RESOURCELIMIT",
+        /// A connection to the next relay could not be made
+        ConnectFailed => "CONNECTFAILED",
+        /// The next relay did not have the expected identity
+        OrIdentity => "OR_IDENTITY",
+        /// The connection to a relay on the circuit was closed
+        OrConnClosed => "OR_CONN_CLOSED",
+        /// The circuit took too long to build
+        Timeout => "TIMEOUT",
+        /// The circuit expired after being used
+        Finished => "FINISHED",
+        /// The circuit was destroyed by a relay
+        Destroyed => "DESTROYED",
+        /// There are not enough relays to build a circuit
+        NoPath => "NOPATH",
+        /// The requested onion service does not exist
+        NoSuchService => "NOSUCHSERVICE",
+        /// A circuit used to measure the build timeout was closed after it was measured
+        MeasurementExpired => "MEASUREMENT_EXPIRED",
+        /// An introduction point circuit is no longer needed
+        IpNowRedundant => "IP_NOW_REDUNDANT",
+    }
+);
+
+/// The status of a circuit in a `CIRC` event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CircStatus {
+    Launched,
+    Built,
+    GuardWait,
+    Extended,
+    Failed {
+        reason: Option<CircuitReason>,
+        remote_reason: Option<CircuitReason>,
+    },
+    Closed {
+        reason: Option<CircuitReason>,
+        remote_reason: Option<CircuitReason>,
+    },
+    Other(String),
+}
+
+impl CircStatus {
+    /// Returns true if the circuit has failed or been closed, and so can no longer carry streams
+    pub fn is_closed(&self) -> bool {
+        matches!(self, CircStatus::Failed { .. } | CircStatus::Closed { .. })
+    }
+}
+
+/// A `CIRC` event, emitted when the status of a circuit changes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CircEvent {
+    pub circuit_id: String,
+    pub status: CircStatus,
+    /// The relays that the circuit has been extended to so far, as `$fingerprint~nickname` or `$fingerprint`
+    pub path: Vec<String>,
+    pub build_flags: BuildFlags,
+    pub purpose: Option<CircuitPurpose>,
+    /// The `KEY=VALUE` fields that are not parsed into the fields above e.g. `TIME_CREATED`, and those added by
+    /// later versions of tor
+    pub extra: HashMap<String, String>,
+}
+
+/// An entry of `GETINFO circuit-status`, which has the same format as the arguments of a `CIRC` event
+pub type CircuitStatus = CircEvent;
+
+impl CircEvent {
+    /// Parses the arguments of a `CIRC` event i.e. the event line without the leading `CIRC`, or a line of
+    /// `GETINFO circuit-status`
+    pub fn parse(args: &str) -> Result<Self, ParseError> {
+        let (positional, args) = parsers::event_args(args)?;
+        let mut positional = positional.into_iter();
+        let circuit_id = positional
+            .next()
+            .ok_or_else(|| ParseError("CIRC event has no circuit ID".to_string()))?;
+        let status = positional
+            .next()
+            .ok_or_else(|| ParseError("CIRC event has no status".to_string()))?;
+        let path = positional
+            .next()
+            .map(|path| path.split(',').map(ToString::to_string).collect())
+            .unwrap_or_default();
+
+        let mut build_flags = BuildFlags::default();
+        let mut purpose = None;
+        let mut reason = None;
+        let mut remote_reason = None;
+        let mut extra = HashMap::new();
+        for (key, value) in args {
+            match key {
+                "BUILD_FLAGS" => build_flags = value.parse()?,
+                "PURPOSE" => purpose = Some(value.parse()?),
+                "REASON" => reason = Some(value.parse()?),
+                "REMOTE_REASON" => remote_reason = Some(value.parse()?),
+                _ => {
+                    extra.insert(key.to_string(), value.into_owned());
+                },
+            }
+        }
+
+        let status = match status {
+            "LAUNCHED" => CircStatus::Launched,
+            "BUILT" => CircStatus::Built,
+            "GUARD_WAIT" => CircStatus::GuardWait,
+            "EXTENDED" => CircStatus::Extended,
+            "FAILED" => CircStatus::Failed { reason, remote_reason },
+            "CLOSED" => CircStatus::Closed { reason, remote_reason },
+            s => CircStatus::Other(s.to_string()),
+        };
+
+        Ok(Self {
+            circuit_id: circuit_id.to_string(),
+            status,
+            path,
+            build_flags,
+            purpose,
+            extra,
+        })
+    }
+}
+
+/// The entries of a `GETINFO circuit-status` reply, which are parsed one line at a time
+pub type CircuitStatusIter = InfoListIter<CircuitStatus>;
+
+/// The `BUILD_FLAGS` field of a circuit i.e. a comma-separated list of [BuildFlag]s
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildFlags(Vec<BuildFlag>);
+
+impl BuildFlags {
+    pub fn contains(&self, flag: &BuildFlag) -> bool {
+        self.0.contains(flag)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BuildFlag> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl FromStr for BuildFlags {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|flag| !flag.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(BuildFlags)
+    }
+}
+
+impl fmt::Display for BuildFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, flag) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", flag)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn build_flags_parse() {
+        let flags = "NEED_CAPACITY,IS_INTERNAL,SOME_FUTURE_FLAG"
+            .parse::<BuildFlags>()
+            .unwrap();
+        assert_eq!(flags.iter().collect::<Vec<_>>(), [
+            &BuildFlag::NeedCapacity,
+            &BuildFlag::IsInternal,
+            &BuildFlag::Other("SOME_FUTURE_FLAG".to_string())
+        ]);
+        assert!(flags.contains(&BuildFlag::IsInternal));
+        assert!(!flags.contains(&BuildFlag::OneHopTunnel));
+        assert_eq!(flags.to_string(), "NEED_CAPACITY,IS_INTERNAL,SOME_FUTURE_FLAG");
+
+        assert!("".parse::<BuildFlags>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn circuit_reason_parse() {
+        let cases = [
+            ("TIMEOUT", CircuitReason::Timeout),
+            ("DESTROYED", CircuitReason::Destroyed),
+            ("OR_CONN_CLOSED", CircuitReason::OrConnClosed),
+            ("FINISHED", CircuitReason::Finished),
+            ("NOSUCHSERVICE", CircuitReason::NoSuchService),
+            ("CHANNEL_CLOSED", CircuitReason::Other("CHANNEL_CLOSED".to_string())),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(s.parse::<CircuitReason>().unwrap(), *expected);
+            assert_eq!(expected.to_string(), *s);
+        }
+    }
+
+    #[test]
+    fn circ_event_parse() {
+        let event = CircEvent::parse(
+            "5 FAILED $AAAA~relay1,$BBBB~relay2 BUILD_FLAGS=NEED_CAPACITY PURPOSE=GENERAL \
+             TIME_CREATED=2020-08-13T10:21:03.123456 REASON=DESTROYED REMOTE_REASON=OR_IDENTITY",
+        )
+        .unwrap();
+        assert_eq!(event.circuit_id, "5");
+        assert_eq!(event.status, CircStatus::Failed {
+            reason: Some(CircuitReason::Destroyed),
+            remote_reason: Some(CircuitReason::OrIdentity),
+        });
+        assert_eq!(event.path, ["$AAAA~relay1", "$BBBB~relay2"]);
+        assert!(event.build_flags.contains(&BuildFlag::NeedCapacity));
+        assert_eq!(event.purpose, Some(CircuitPurpose::General));
+        assert_eq!(event.extra.len(), 1);
+        assert_eq!(event.extra["TIME_CREATED"], "2020-08-13T10:21:03.123456");
+
+        let event = CircEvent::parse("6 CLOSED REASON=TIMEOUT").unwrap();
+        assert_eq!(event.status, CircStatus::Closed {
+            reason: Some(CircuitReason::Timeout),
+            remote_reason: None,
+        });
+        assert!(event.path.is_empty());
+
+        let event = CircEvent::parse("7 LAUNCHED").unwrap();
+        assert_eq!(event.status, CircStatus::Launched);
+        assert_eq!(event.purpose, None);
+
+        assert!(CircEvent::parse("7").is_err());
+    }
+
+    #[test]
+    fn circ_event_extended_fields() {
+        let event = CircEvent::parse(
+            "8 BUILT $AAAA~relay1 BUILD_FLAGS=IS_INTERNAL,NEED_CAPACITY PURPOSE=HS_SERVICE_INTRO \
+             HS_STATE=HSSI_ESTABLISHED REND_QUERY=abcdef SOCKS_PASSWORD=\"pass word\" CONFLUX_ID=1234",
+        )
+        .unwrap();
+        assert_eq!(event.status, CircStatus::Built);
+        assert!(event.build_flags.contains(&BuildFlag::IsInternal));
+        assert_eq!(event.purpose, Some(CircuitPurpose::HsServiceIntro));
+        assert_eq!(event.extra.len(), 4);
+        assert_eq!(event.extra["HS_STATE"], "HSSI_ESTABLISHED");
+        assert_eq!(event.extra["REND_QUERY"], "abcdef");
+        assert_eq!(event.extra["SOCKS_PASSWORD"], "pass word");
+        assert_eq!(event.extra["CONFLUX_ID"], "1234");
+    }
+
+    #[test]
+    fn circuit_purpose_parse() {
+        assert_eq!("GENERAL".parse::<CircuitPurpose>().unwrap(), CircuitPurpose::General);
+        assert_eq!(
+            "HS_SERVICE_REND".parse::<CircuitPurpose>().unwrap(),
+            CircuitPurpose::HsServiceRend
+        );
+        let purpose = "CONFLUX_LINKED".parse::<CircuitPurpose>().unwrap();
+        assert_eq!(purpose, CircuitPurpose::Other("CONFLUX_LINKED".to_string()));
+        assert_eq!(purpose.to_string(), "CONFLUX_LINKED");
+        assert!("".parse::<CircuitPurpose>().is_err());
+    }
+}
@@ -21,23 +21,98 @@
 // USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
 use super::{
+    circuit::CircuitStatusIter,
+    clock::{Clock, SystemClock},
     commands,
-    commands::{AddOnionFlag, AddOnionResponse, TorCommand},
-    error::TorClientError,
+    commands::{
+        AddOnionFlag,
+        AddOnionResponse,
+        AuthChallengeResponse,
+        DescriptorKind,
+        ProtocolInfo,
+        ProtocolInfoResponse,
+        RelayDescriptor,
+        TorCommand,
+    },
+    connection_state::{self, ConnectionState, ConnectionStateTracker},
+    error::{ConnectionFailureKind, OnionAddressError, TorClientError},
+    parsers,
     response::ResponseLine,
-    types::{KeyBlob, KeyType, PortMapping},
+    stream::StreamStatusIter,
+    types::{
+        self,
+        AddressMapping,
+        AnyKeyType,
+        ClientName,
+        ExternalAddress,
+        KeyBlob,
+        KeyType,
+        ListenerAddress,
+        OnionAddress,
+        OnionInventory,
+        PortMapping,
+        PortSpec,
+        TorVersion,
+        TrafficCounters,
+        VersionStatus,
+    },
     PrivateKey,
     LOG_TARGET,
 };
 use crate::{
     multiaddr::Multiaddr,
-    tor::control_client::{event::TorControlEvent, monitor::spawn_monitor},
-    transports::{TcpTransport, Transport},
+    runtime::task,
+    tor::control_client::{
+        event::TorControlEvent,
+        monitor::{spawn_monitor, EnabledEvents},
+    },
+    transports::{TcpSocket, TcpTransport, Transport},
+};
+use data_encoding::HEXUPPER;
+use futures::{
+    channel::mpsc,
+    future::{self, Either},
+    pin_mut,
+    stream,
+    AsyncRead,
+    AsyncWrite,
+    Future,
+    SinkExt,
+    Stream,
+    StreamExt,
 };
-use futures::{channel::mpsc, AsyncRead, AsyncWrite, SinkExt, StreamExt};
 use log::*;
-use std::{borrow::Cow, fmt, fmt::Display, num::NonZeroU16};
-use tokio::sync::broadcast;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    fmt::Display,
+    fs,
+    io,
+    mem,
+    net::IpAddr,
+    num::NonZeroU16,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{broadcast, watch};
+
+/// How often `status/circuit-established` is polled while waiting for tor to be ready
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// The delay before a command that failed because of a transient failure is retried
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+/// The reply code tor uses when a command failed because it is temporarily out of a resource
+const RESOURCE_EXHAUSTED_CODE: u16 = 451;
+/// The reply code to `GETINFO address` if tor does not know its address
+const ADDRESS_UNKNOWN_CODE: u16 = 551;
+/// The reply code tor uses for an unrecognized GETINFO key (among other things)
+const UNRECOGNIZED_CODE: u16 = 552;
+/// The GETINFO key prefixes of the bytes an onion service has read and written, followed by its service id
+const SERVICE_TRAFFIC_READ_PREFIX: &str = "hs/service/traffic/read/id/";
+const SERVICE_TRAFFIC_WRITTEN_PREFIX: &str = "hs/service/traffic/written/id/";
+/// The reply code to DEL_ONION if tor does not know the service, or it is owned by another connection
+const UNKNOWN_SERVICE_CODE: u16 = 552;
 
 /// Client for the Tor control port.
 ///
@@ -46,30 +121,89 @@ pub struct TorControlPortClient {
     cmd_tx: mpsc::Sender<String>,
     output_stream: mpsc::Receiver<ResponseLine>,
     event_tx: broadcast::Sender<TorControlEvent>,
+    last_reply: Option<(u16, String)>,
+    recycled_buf_rx: mpsc::Receiver<String>,
+    response_buf: Vec<ResponseLine>,
+    protocol_info: Option<ProtocolInfoResponse>,
+    has_extended_events: bool,
+    newnym_min_interval: Option<Duration>,
+    last_newnym: Option<Instant>,
+    enabled_events: Vec<String>,
+    /// The events set by SETEVENTS, shared with the monitor so that it can skip events of any other kind
+    event_filter: EnabledEvents,
+    is_closed: bool,
+    /// True if the reply to a command was not read to the end, so the rest of it must be discarded before the next
+    /// reply is read
+    has_unfinished_reply: bool,
+    /// When the last command was handed to the monitor to be written
+    last_sent_at: Option<Instant>,
+    clock: Arc<dyn Clock>,
+    state: ConnectionStateTracker,
 }
 
 impl TorControlPortClient {
+    /// Returns a builder for a client with non-default options
+    pub fn builder() -> TorControlPortClientBuilder {
+        TorControlPortClientBuilder::new()
+    }
+
     /// Connect using TCP to the given address.
     pub async fn connect(
         addr: Multiaddr,
         event_tx: broadcast::Sender<TorControlEvent>,
     ) -> Result<Self, TorClientError>
     {
-        let mut tcp = TcpTransport::new();
-        tcp.set_nodelay(true);
-        let socket = tcp.dial(addr)?.await?;
-        Ok(Self::new(socket, event_tx))
+        Self::builder().connect(addr, event_tx).await
     }
 
     /// Create a new TorControlPortClient using the given socket
     pub fn new<TSocket>(socket: TSocket, event_tx: broadcast::Sender<TorControlEvent>) -> Self
     where TSocket: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+        Self::builder().build(socket, event_tx)
+    }
+
+    fn with_builder<TSocket>(
+        builder: TorControlPortClientBuilder,
+        socket: TSocket,
+        event_tx: broadcast::Sender<TorControlEvent>,
+    ) -> Self
+    where
+        TSocket: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         let (cmd_tx, cmd_rx) = mpsc::channel(10);
-        let output_stream = spawn_monitor(cmd_rx, socket, event_tx.clone());
+        let (recycled_buf_tx, recycled_buf_rx) = mpsc::channel(1);
+        let state = builder
+            .connection_state
+            .unwrap_or_else(|| ConnectionStateTracker::new(ConnectionState::Authenticating));
+        state.set_if(ConnectionState::Authenticating, connection_state::is_not_reconnecting);
+        let event_filter = EnabledEvents::default();
+        let output_stream = spawn_monitor(
+            cmd_rx,
+            socket,
+            event_tx.clone(),
+            recycled_buf_tx,
+            builder.read_buffer_capacity,
+            state.clone(),
+            event_filter.clone(),
+        );
         Self {
             cmd_tx,
             output_stream,
             event_tx,
+            last_reply: None,
+            recycled_buf_rx,
+            response_buf: Vec::new(),
+            protocol_info: None,
+            has_extended_events: false,
+            newnym_min_interval: None,
+            last_newnym: None,
+            enabled_events: Vec::new(),
+            event_filter,
+            is_closed: false,
+            has_unfinished_reply: false,
+            last_sent_at: None,
+            clock: builder.clock,
+            state,
         }
     }
 
@@ -85,66 +219,852 @@ impl TorControlPortClient {
         self.event_tx.subscribe()
     }
 
-    /// Authenticate with the tor control port
+    /// Returns the current state of the connection
+    pub fn connection_state(&self) -> ConnectionState {
+        self.state.get()
+    }
+
+    /// Returns a receiver that is notified when the state of the connection changes. The client is `Authenticating`
+    /// once connected, `Ready` once authentication succeeds and `Closed` once tor closes the connection or the client
+    /// is closed.
+    pub fn watch_connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    /// Closes the connection to the control port, first sending QUIT if `send_quit` is true. This waits for the
+    /// connection to be closed, discarding the replies to any commands that were abandoned before they completed, and
+    /// returns an error if tor does not reply to QUIT. Once closed, every command returns
+    /// `TorClientError::ClientClosed`, as does calling this again.
+    pub async fn close(&mut self, send_quit: bool) -> Result<(), TorClientError> {
+        if self.is_closed {
+            return Err(TorClientError::ClientClosed);
+        }
+        let result = if send_quit { self.quit().await } else { Ok(()) };
+        self.is_closed = true;
+        // The monitor exits, closing the socket, once the command channel is closed. The output stream ends when it
+        // has exited.
+        self.cmd_tx.close_channel();
+        while self.output_stream.next().await.is_some() {}
+        self.state.set(ConnectionState::Closed);
+        result
+    }
+
+    async fn quit(&mut self) -> Result<(), TorClientError> {
+        self.send_line("QUIT".to_string()).await?;
+        loop {
+            self.recv_next_responses().await?;
+            let last = self
+                .response_buf
+                .last()
+                .expect("recv_next_responses always receives a line");
+            if last.is_err() {
+                return Err(TorClientError::TorCommandFailed(last.value.clone()));
+            }
+            // Skip replies to abandoned commands
+            if last.value == "closing connection" {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Returns the code and message of the final reply line to the last command sent, or `None` if no command has
+    /// completed yet. This is cleared when a command is sent and set once the server has replied to it.
+    pub fn last_reply(&self) -> Option<(u16, String)> {
+        self.last_reply.clone()
+    }
+
+    /// Authenticate with the tor control port. Tor should not send events before authentication completes but, as for
+    /// any command, stray `650` lines are routed to the event stream and do not affect the reply.
     pub async fn authenticate(&mut self, authentication: &Authentication) -> Result<(), TorClientError> {
-        match authentication {
+        #[allow(deprecated)]
+        let secret = match authentication {
             Authentication::None => {
                 self.send_line("AUTHENTICATE".to_string()).await?;
+                ""
             },
-            Authentication::HashedPassword(passwd) => {
-                self.send_line(format!("AUTHENTICATE \"{}\"", passwd.replace("\"", "\\\"")))
+            Authentication::Password(passwd) | Authentication::HashedPassword(passwd) => {
+                if is_tor_password_hash(passwd) {
+                    warn!(
+                        target: LOG_TARGET,
+                        "The control port password looks like the output of `tor --hash-password`. The hashed form \
+                         belongs in the torrc (HashedControlPassword), AUTHENTICATE needs the original password."
+                    );
+                }
+                self.send_line(format!("AUTHENTICATE {}", parsers::quote_arg(passwd)))
                     .await?;
+                passwd.as_str()
             },
             Authentication::Cookie(cookie) => {
                 self.send_line(format!("AUTHENTICATE {}", cookie)).await?;
+                cookie.as_str()
+            },
+        };
+
+        // Tor does not echo the password or cookie in its reply, but the reply is kept and may be logged, so any
+        // secret in it is masked just in case
+        match self.recv_ok().await {
+            Err(TorClientError::TorCommandFailed(msg)) => {
+                if let Some((code, msg)) = self.last_reply.take() {
+                    self.last_reply = Some((code, types::redact_secret(msg, secret)));
+                }
+                Err(TorClientError::TorCommandFailed(types::redact_secret(msg, secret)))
+            },
+            Ok(()) => {
+                // The monitor may have seen the connection close after the reply
+                self.state
+                    .set_if(ConnectionState::Ready, |state| state == ConnectionState::Authenticating);
+                Ok(())
             },
+            Err(err) => Err(err),
         }
+    }
 
-        self.recv_ok().await?;
+    /// Authenticate using the contents of the control cookie file. The file is read from `cookie_path` if given,
+    /// otherwise from the path tor reports in PROTOCOLINFO. The reported path is the path as seen by the tor process,
+    /// which is not the path visible to this process if tor runs in a container with the file mounted elsewhere. In
+    /// that case the path should be resolved by the caller (e.g. from an environment variable or config option) and
+    /// passed here.
+    pub async fn authenticate_with_cookie(&mut self, cookie_path: Option<&Path>) -> Result<(), TorClientError> {
+        let path = match cookie_path {
+            Some(path) => path.to_path_buf(),
+            None => self
+                .protocol_info()
+                .await?
+                .cookie_file()
+                .map(PathBuf::from)
+                .ok_or_else(|| TorClientError::NoCookieFile)?,
+        };
+        let read_path = path.clone();
+        let cookie = task::spawn_blocking(move || fs::read(read_path))
+            .await
+            .unwrap_or_else(|err| Err(io::Error::new(io::ErrorKind::Other, err)))
+            .map_err(|source| TorClientError::CookieFileRead { path, source })?;
+        self.authenticate(&Authentication::Cookie(HEXUPPER.encode(&cookie)))
+            .await
+    }
 
-        Ok(())
+    /// The AUTHCHALLENGE command, which starts SAFECOOKIE authentication. `client_nonce` should be 32 random bytes.
+    pub async fn auth_challenge(&mut self, client_nonce: &[u8]) -> Result<AuthChallengeResponse, TorClientError> {
+        self.execute(commands::AuthChallenge::new(client_nonce)).await
     }
 
-    /// The GETCONF command. Returns configuration keys matching the `conf_name`.
+    /// The GETCONF command. Returns configuration keys matching the `conf_name`. An option that is not set has no
+    /// values, use [get_conf_entries](Self::get_conf_entries) to see which keywords are not set.
     #[allow(clippy::needless_lifetimes)]
     pub async fn get_conf<'a>(&mut self, conf_name: &'a str) -> Result<Vec<Cow<'a, str>>, TorClientError> {
         let command = commands::get_conf(conf_name);
-        self.request_response(command).await
+        self.execute(command).await
+    }
+
+    /// The GETCONF command for a single keyword, returning every entry of the reply with its keyword in the order tor
+    /// sent them (see [commands::GetConfEntries]). An option that is not set is returned without a value.
+    pub async fn get_conf_entries(&mut self, keyword: &str) -> Result<Vec<(String, Option<String>)>, TorClientError> {
+        self.execute(commands::GetConfEntries::new(keyword)).await
     }
 
     /// The GETINFO command. Returns configuration keys matching the `conf_name`.
     #[allow(clippy::needless_lifetimes)]
     pub async fn get_info<'a>(&mut self, key_name: &'a str) -> Result<Vec<Cow<'a, str>>, TorClientError> {
         let command = commands::get_info(key_name);
-        let response = self.request_response(command).await?;
+        let response = self.execute(command).await?;
         if response.is_empty() {
             return Err(TorClientError::ServerNoResponse);
         }
         Ok(response)
     }
 
-    /// The SETEVENTS command.
+    /// The PROTOCOLINFO command. The result is cached for the lifetime of this connection, so only the first call
+    /// queries the server.
+    pub async fn protocol_info(&mut self) -> Result<&ProtocolInfoResponse, TorClientError> {
+        if self.protocol_info.is_none() {
+            self.query_protocol_info().await?;
+        }
+        Ok(self.protocol_info.as_ref().expect("protocol_info was just set"))
+    }
+
+    /// Discards the cached PROTOCOLINFO result and queries the server again. This is only needed if the tor daemon may
+    /// have changed (e.g. been upgraded) without this connection being closed.
+    pub async fn refresh_protocol_info(&mut self) -> Result<&ProtocolInfoResponse, TorClientError> {
+        self.protocol_info = None;
+        self.protocol_info().await
+    }
+
+    /// Establishes the capabilities of this session. This queries (and caches) PROTOCOLINFO, so that commands are
+    /// checked against the version of the connected tor, and, if `use_extended_events` is true, enables the
+    /// `EXTENDED_EVENTS` and `VERBOSE_NAMES` features so that events are sent in their extended format. USEFEATURE
+    /// is only accepted once authenticated, so `use_extended_events` should only be set after calling `authenticate`.
+    ///
+    /// Negotiation is idempotent: commands that have already succeeded on this connection are not sent again.
+    pub async fn negotiate(&mut self, use_extended_events: bool) -> Result<&ProtocolInfoResponse, TorClientError> {
+        let _ = self.protocol_info().await?;
+        if use_extended_events && !self.has_extended_events {
+            let _ = self
+                .execute(commands::use_feature(&["EXTENDED_EVENTS", "VERBOSE_NAMES"]))
+                .await?;
+            self.has_extended_events = true;
+        }
+        Ok(self.protocol_info.as_ref().expect("protocol_info was set above"))
+    }
+
+    /// Returns true if extended events and verbose names have been enabled by [TorControlPortClient::negotiate].
+    pub fn has_extended_events(&self) -> bool {
+        self.has_extended_events
+    }
+
+    /// Returns the version of the connected tor daemon, as reported by PROTOCOLINFO.
+    pub async fn get_tor_version(&mut self) -> Result<TorVersion, TorClientError> {
+        Ok(self.protocol_info().await?.tor_version())
+    }
+
+    /// Returns the status of every circuit that tor has open (`GETINFO circuit-status`). The entries are parsed as they
+    /// are iterated over, but the whole reply is received first.
+    pub async fn get_circuit_status(&mut self) -> Result<CircuitStatusIter, TorClientError> {
+        self.execute(commands::get_circuit_status()).await
+    }
+
+    /// Returns the number of circuits that have not failed or been closed. The entries of `GETINFO circuit-status` are
+    /// parsed one at a time and discarded, so this is cheaper than collecting [get_circuit_status].
+    ///
+    /// [get_circuit_status]: TorControlPortClient::get_circuit_status
+    pub async fn active_circuit_count(&mut self) -> Result<usize, TorClientError> {
+        self.get_circuit_status()
+            .await?
+            .try_fold(0, |count, status| Ok(count + !status?.status.is_closed() as usize))
+    }
+
+    /// Returns the status of every stream that tor has open (`GETINFO stream-status`). The entries are parsed as they
+    /// are iterated over.
+    pub async fn get_stream_status(&mut self) -> Result<StreamStatusIter, TorClientError> {
+        self.execute(commands::get_stream_status()).await
+    }
+
+    /// Returns the number of streams that have not failed or been closed, in the same way as
+    /// [active_circuit_count](TorControlPortClient::active_circuit_count).
+    pub async fn active_stream_count(&mut self) -> Result<usize, TorClientError> {
+        self.get_stream_status()
+            .await?
+            .try_fold(0, |count, status| Ok(count + !status?.status.is_closed() as usize))
+    }
+
+    /// Returns the microdescriptor of the relay with the given fingerprint, or
+    /// `TorClientError::DescriptorNotFound` if tor does not have it.
+    pub async fn get_microdescriptor(&mut self, fingerprint: &str) -> Result<RelayDescriptor, TorClientError> {
+        self.execute(commands::GetDescriptor::new(DescriptorKind::Micro, fingerprint))
+            .await
+    }
+
+    /// Returns the server descriptor of the relay with the given fingerprint, or
+    /// `TorClientError::DescriptorNotFound` if tor does not have it.
+    pub async fn get_server_descriptor(&mut self, fingerprint: &str) -> Result<RelayDescriptor, TorClientError> {
+        self.execute(commands::GetDescriptor::new(DescriptorKind::Server, fingerprint))
+            .await
+    }
+
+    /// Returns the addresses that tor is accepting SOCKS connections on. This is useful to discover the port chosen by
+    /// tor when configured with `SocksPort auto`.
+    pub async fn get_socks_listeners(&mut self) -> Result<Vec<ListenerAddress>, TorClientError> {
+        self.get_listeners("net/listeners/socks").await
+    }
+
+    /// Returns the address to use as the SOCKS proxy. This is the first TCP listener (which has the port tor chose if
+    /// configured with `SocksPort auto`) or, if tor only listens on unix sockets, the first of those.
+    pub async fn socks_proxy_addr(&mut self) -> Result<ListenerAddress, TorClientError> {
+        let mut listeners = self.get_socks_listeners().await?;
+        match listeners
+            .iter()
+            .position(|addr| matches!(addr, ListenerAddress::Tcp(_)))
+        {
+            Some(pos) => Ok(listeners.swap_remove(pos)),
+            None if !listeners.is_empty() => Ok(listeners.swap_remove(0)),
+            None => Err(TorClientError::NoSocksListener),
+        }
+    }
+
+    /// Returns the addresses that tor is accepting control port connections on.
+    pub async fn get_control_listeners(&mut self) -> Result<Vec<ListenerAddress>, TorClientError> {
+        self.get_listeners("net/listeners/control").await
+    }
+
+    async fn get_listeners(&mut self, key_name: &str) -> Result<Vec<ListenerAddress>, TorClientError> {
+        let addrs = self.execute(commands::get_listeners(key_name)).await?;
+        addrs.map(|addr| addr.map_err(Into::into)).collect()
+    }
+
+    /// Returns the service ids of the onion services that tor already has. These are the detached services that
+    /// outlived a previous control connection (`onions/detached`) and the services owned by this connection
+    /// (`onions/current`). Adding any of these again fails, so callers should reconcile against this list on
+    /// startup instead.
+    pub async fn adopt_existing_services(&mut self) -> Result<Vec<String>, TorClientError> {
+        let mut service_ids = Vec::new();
+        for key_name in &["onions/detached", "onions/current"] {
+            for service_id in self.get_onion_service_ids(key_name).await? {
+                if !service_ids.contains(&service_id) {
+                    service_ids.push(service_id);
+                }
+            }
+        }
+        Ok(service_ids)
+    }
+
+    /// Returns the current and detached onion services, using a single GETINFO if tor has both kinds. Tor fails the
+    /// whole command if either list is empty, in which case each list is queried separately.
+    pub async fn get_onion_inventory(&mut self) -> Result<OnionInventory, TorClientError> {
+        const KEYS: [&str; 2] = ["onions/current", "onions/detached"];
+        let mut inventory = OnionInventory::default();
+        let result = {
+            let mut values = Box::pin(self.get_info_stream(&KEYS));
+            loop {
+                match values.next().await.transpose() {
+                    Ok(Some((key, value))) => inventory.add_services(&key, &value)?,
+                    Ok(None) => break Ok(()),
+                    Err(err) => break Err(err),
+                }
+            }
+        };
+
+        match result {
+            Ok(()) => Ok(inventory),
+            Err(TorClientError::TorCommandFailed(_)) if self.last_reply_starts_with("No onion services") => {
+                let mut inventory = OnionInventory::default();
+                for key in &KEYS {
+                    let service_ids = self.get_onion_service_ids(key).await?;
+                    inventory.add_services(key, &service_ids.join("\n"))?;
+                }
+                Ok(inventory)
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn get_onion_service_ids(&mut self, key_name: &str) -> Result<Vec<String>, TorClientError> {
+        match self.execute(commands::get_info(key_name)).await {
+            Ok(service_ids) => Ok(service_ids.into_iter().map(Cow::into_owned).collect()),
+            // Tor replies with an error rather than an empty list if there are no services
+            Err(TorClientError::TorCommandFailed(_)) if self.last_reply_starts_with("No onion services") => {
+                Ok(Vec::new())
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns true if tor is able to build circuits (`status/circuit-established`), which requires it to have
+    /// bootstrapped far enough to have a usable consensus.
+    pub async fn is_circuit_established(&mut self) -> Result<bool, TorClientError> {
+        self.get_info_bool("status/circuit-established").await
+    }
+
+    /// Waits until tor is able to build circuits, returning `TorClientError::NotReady` if it is not able to within
+    /// `timeout`.
+    pub async fn wait_until_ready(&mut self, timeout: Duration) -> Result<(), TorClientError> {
+        self.wait_until_ready_cancellable(timeout, future::pending::<()>())
+            .await
+    }
+
+    /// The same as [wait_until_ready](Self::wait_until_ready), but returns `TorClientError::Cancelled` as soon as
+    /// `cancel` completes e.g. when a `ShutdownSignal` is triggered.
+    pub async fn wait_until_ready_cancellable<F: Future>(
+        &mut self,
+        timeout: Duration,
+        cancel: F,
+    ) -> Result<(), TorClientError>
+    {
+        pin_mut!(cancel);
+        let deadline = self.clock.now() + timeout;
+        loop {
+            if self.is_circuit_established().await? {
+                return Ok(());
+            }
+            if self.clock.now() + READY_POLL_INTERVAL > deadline {
+                return Err(TorClientError::NotReady(timeout));
+            }
+            // Cancellation is checked first, as the delay of a mock clock is always ready
+            let delay = self.clock.delay_for(READY_POLL_INTERVAL);
+            if let Either::Left(_) = future::select(cancel.as_mut(), delay).await {
+                return Err(TorClientError::Cancelled);
+            }
+        }
+    }
+
+    /// Waits for tor to be able to build circuits, returning `TorClientError::NotReady` if it is not able to within
+    /// `timeout`. This is the same as [wait_until_ready](Self::wait_until_ready).
+    pub async fn wait_for_circuit_established(&mut self, timeout: Duration) -> Result<(), TorClientError> {
+        self.wait_until_ready(timeout).await
+    }
+
+    /// The same as [wait_until_ready_cancellable](Self::wait_until_ready_cancellable).
+    pub async fn wait_for_circuit_established_cancellable<F: Future>(
+        &mut self,
+        timeout: Duration,
+        cancel: F,
+    ) -> Result<(), TorClientError>
+    {
+        self.wait_until_ready_cancellable(timeout, cancel).await
+    }
+
+    /// Waits for tor to have enough directory information to build circuits, returning
+    /// `TorClientError::NotEnoughDirInfo` if it does not within `timeout`. This waits for the `STATUS_CLIENT
+    /// ENOUGH_DIR_INFO` event after checking `status/enough-dir-info` once. `STATUS_CLIENT` events are enabled for the
+    /// duration of the wait if they are not already.
+    pub async fn wait_for_enough_dir_info(&mut self, timeout: Duration) -> Result<(), TorClientError> {
+        self.wait_for_enough_dir_info_cancellable(timeout, future::pending::<()>())
+            .await
+    }
+
+    /// The same as [wait_for_enough_dir_info](Self::wait_for_enough_dir_info), but returns
+    /// `TorClientError::Cancelled` as soon as `cancel` completes. The event subscription is dropped when the wait
+    /// ends, whether or not it was cancelled.
+    pub async fn wait_for_enough_dir_info_cancellable<F: Future>(
+        &mut self,
+        timeout: Duration,
+        cancel: F,
+    ) -> Result<(), TorClientError>
+    {
+        self.wait_for_status(
+            "status/enough-dir-info",
+            TorControlEvent::EnoughDirInfo,
+            timeout,
+            cancel,
+        )
+        .await
+        .map_err(|err| err.unwrap_or(TorClientError::NotEnoughDirInfo(timeout)))
+    }
+
+    /// Waits for the GETINFO boolean `key_name` to be true or for the `event` that is emitted when it becomes true.
+    /// Returns `Err(None)` on timeout. Cancellation is only checked while waiting for events, so that a command is
+    /// never abandoned part way through its reply. `STATUS_CLIENT` is removed from the events set by SETEVENTS
+    /// afterwards if this enabled it.
+    async fn wait_for_status<F: Future>(
+        &mut self,
+        key_name: &str,
+        event: TorControlEvent,
+        timeout: Duration,
+        cancel: F,
+    ) -> Result<(), Option<TorClientError>>
+    {
+        const STATUS_CLIENT: &str = "STATUS_CLIENT";
+        pin_mut!(cancel);
+        let deadline = self.clock.now() + timeout;
+        // Subscribe before checking the status so that the event cannot be missed in between
+        let mut events = self.get_event_stream();
+        let was_enabled = self.enabled_events.iter().any(|e| e == STATUS_CLIENT);
+        self.enable_event(STATUS_CLIENT).await?;
+        let result = async {
+            let mut is_set = self.get_info_bool(key_name).await?;
+            while !is_set {
+                let remaining = deadline.saturating_duration_since(self.clock.now());
+                let timeout = self.clock.delay_for(remaining);
+                let recv = events.recv();
+                pin_mut!(recv);
+                // A received event takes precedence over cancellation, which takes precedence over the timeout
+                let result = match future::select(recv, future::select(cancel.as_mut(), timeout)).await {
+                    Either::Left((result, _)) => result,
+                    Either::Right((Either::Left(_), _)) => return Err(Some(TorClientError::Cancelled)),
+                    Either::Right((Either::Right(_), _)) => return Err(None),
+                };
+                match result {
+                    Ok(TorControlEvent::TorControlDisconnected) => return Err(Some(TorClientError::UnexpectedEof)),
+                    Ok(ev) => is_set = mem::discriminant(&ev) == mem::discriminant(&event),
+                    // Events were dropped, and the one we are waiting for may have been one of them
+                    Err(broadcast::RecvError::Lagged(_)) => is_set = self.get_info_bool(key_name).await?,
+                    Err(broadcast::RecvError::Closed) => return Err(Some(TorClientError::UnexpectedEof)),
+                }
+            }
+            Ok(())
+        }
+        .await;
+
+        if !was_enabled && self.is_connected() {
+            // An error restoring the events is only returned if the wait itself succeeded
+            if let Err(err) = self.disable_event(STATUS_CLIENT).await {
+                result?;
+                return Err(Some(err));
+            }
+        }
+        result
+    }
+
+    /// Returns tor's best guess at its external IP address (`GETINFO address`), or [ExternalAddress::Unknown] if it
+    /// has not determined it.
+    pub async fn get_external_address(&mut self) -> Result<ExternalAddress, TorClientError> {
+        match self.get_info("address").await {
+            Ok(values) => {
+                let value = values
+                    .first()
+                    .ok_or_else(|| parsers::ParseError("GETINFO address returned no value".to_string()))?;
+                let addr = value
+                    .parse::<IpAddr>()
+                    .map_err(|_| parsers::ParseError(format!("Invalid address '{}'", value)))?;
+                Ok(ExternalAddress::Known(addr))
+            },
+            Err(TorClientError::TorCommandFailed(_)) if matches!(self.last_reply, Some((ADDRESS_UNKNOWN_CODE, _))) => {
+                Ok(ExternalAddress::Unknown)
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns the process ID of the tor daemon (`GETINFO process/pid`). Returns
+    /// [TorClientError::GetInfoKeyUnsupported] if tor does not support the key.
+    pub async fn get_pid(&mut self) -> Result<u32, TorClientError> {
+        const KEY: &str = "process/pid";
+        match self.get_info(KEY).await {
+            Ok(values) => {
+                let value = values
+                    .first()
+                    .ok_or_else(|| parsers::ParseError(format!("GETINFO {} returned no value", KEY)))?;
+                value
+                    .parse()
+                    .map_err(|_| parsers::ParseError(format!("Invalid process ID '{}'", value)).into())
+            },
+            Err(TorClientError::TorCommandFailed(_))
+                if matches!(self.last_reply, Some((UNRECOGNIZED_CODE, _))) &&
+                    self.last_reply_starts_with("Unrecognized key") =>
+            {
+                Err(TorClientError::GetInfoKeyUnsupported(KEY.to_string()))
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns whether the running version of tor is recommended by the directory authorities (`GETINFO
+    /// status/version/current`). Returns [VersionStatus::Unknown] if tor does not support the key.
+    pub async fn get_version_status(&mut self) -> Result<VersionStatus, TorClientError> {
+        match self.get_info("status/version/current").await {
+            Ok(values) => {
+                let value = values.first().ok_or_else(|| {
+                    parsers::ParseError("GETINFO status/version/current returned no value".to_string())
+                })?;
+                Ok(value.parse()?)
+            },
+            Err(TorClientError::TorCommandFailed(_))
+                if matches!(self.last_reply, Some((UNRECOGNIZED_CODE, _))) &&
+                    self.last_reply_starts_with("Unrecognized key") =>
+            {
+                Ok(VersionStatus::Unknown)
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns the number of bytes each of the given onion services has read and written, keyed by service id
+    /// (`GETINFO hs/service/traffic/read/id/<ServiceID> hs/service/traffic/written/id/<ServiceID>`). Returns
+    /// [TorClientError::GetInfoKeyUnsupported] if tor does not count the traffic of onion services, which current
+    /// versions of tor do not.
+    pub async fn get_onion_service_traffic(
+        &mut self,
+        service_ids: &[&str],
+    ) -> Result<HashMap<String, TrafficCounters>, TorClientError>
+    {
+        if service_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let keys = service_ids
+            .iter()
+            .flat_map(|id| {
+                vec![
+                    format!("{}{}", SERVICE_TRAFFIC_READ_PREFIX, id),
+                    format!("{}{}", SERVICE_TRAFFIC_WRITTEN_PREFIX, id),
+                ]
+            })
+            .collect::<Vec<_>>();
+        let mut read = HashMap::new();
+        let mut written = HashMap::new();
+        let result = {
+            let keys = keys.iter().map(String::as_str).collect::<Vec<_>>();
+            let mut values = Box::pin(self.get_info_stream(&keys));
+            loop {
+                let (key, value) = match values.next().await.transpose() {
+                    Ok(Some(key_value)) => key_value,
+                    Ok(None) => break Ok(()),
+                    Err(err) => break Err(err),
+                };
+                let counter = parsers::number::<u64>(&key, &value)?;
+                if let Some(service_id) = key.strip_prefix(SERVICE_TRAFFIC_READ_PREFIX) {
+                    read.insert(service_id.to_string(), counter);
+                } else if let Some(service_id) = key.strip_prefix(SERVICE_TRAFFIC_WRITTEN_PREFIX) {
+                    written.insert(service_id.to_string(), counter);
+                }
+            }
+        };
+
+        match result {
+            Ok(()) => {},
+            Err(TorClientError::TorCommandFailed(_)) if matches!(self.last_reply, Some((UNRECOGNIZED_CODE, _))) => {
+                return Err(TorClientError::GetInfoKeyUnsupported(
+                    SERVICE_TRAFFIC_READ_PREFIX.to_string(),
+                ));
+            },
+            Err(err) => return Err(err),
+        }
+
+        service_ids
+            .iter()
+            .map(|id| {
+                let counter = |counters: &HashMap<String, u64>, prefix: &str| {
+                    counters
+                        .get(*id)
+                        .copied()
+                        .ok_or_else(|| parsers::ParseError(format!("Tor did not return {}{}", prefix, id)))
+                };
+                Ok((id.to_string(), TrafficCounters {
+                    read: counter(&read, SERVICE_TRAFFIC_READ_PREFIX)?,
+                    written: counter(&written, SERVICE_TRAFFIC_WRITTEN_PREFIX)?,
+                }))
+            })
+            .collect()
+    }
+
+    /// Returns all of tor's address mappings (`GETINFO address-mappings/all`)
+    pub async fn get_address_mappings(&mut self) -> Result<Vec<AddressMapping>, TorClientError> {
+        let mut mappings = Vec::new();
+        let mut values = Box::pin(self.get_info_stream(&["address-mappings/all"]));
+        while let Some((_, body)) = values.next().await.transpose()? {
+            mappings.extend(AddressMapping::parse_all(&body)?);
+        }
+        Ok(mappings)
+    }
+
+    /// Queries all of the given GETINFO keys in a single command, returning a stream of `(key, value)` pairs that
+    /// yields each value as soon as its reply line is received. Multi-line values (e.g. `config-text`) are yielded
+    /// whole, without the leading line break. Single-line quoted values are unquoted.
+    ///
+    /// The stream ends after the last value or the first error, including a reply line that cannot be parsed. If it
+    /// ends or is dropped before the end of the reply, the remainder of the reply is discarded before the next command
+    /// is sent. Returns [TorClientError::GetInfoNoKeys] if no keys are given.
+    pub fn get_info_stream<'a>(
+        &'a mut self,
+        keys: &[&str],
+    ) -> impl Stream<Item = Result<(String, String), TorClientError>> + 'a
+    {
+        enum State<'a> {
+            Send(&'a mut TorControlPortClient, Result<String, TorClientError>),
+            Receive(&'a mut TorControlPortClient),
+            Done,
+        }
+
+        let command = commands::KeyValueCommand::new("GETINFO", keys);
+        let command_line = command.validate().and_then(|_| command.to_command_string());
+        stream::unfold(State::Send(self, command_line), |state| async move {
+            let client = match state {
+                State::Send(client, command_line) => {
+                    if let Err(err) = async { client.send_line(command_line?).await }.await {
+                        return Some((Err(err), State::Done));
+                    }
+                    client.has_unfinished_reply = true;
+                    client
+                },
+                State::Receive(client) => client,
+                State::Done => return None,
+            };
+
+            let line = match client.receive_line().await {
+                Ok(line) => line,
+                Err(err) => return Some((Err(err), State::Done)),
+            };
+            if line.is_end_reply() {
+                client.has_unfinished_reply = false;
+            }
+            if line.is_err() {
+                // Every error line up to the end of the reply is included, as commands::key_value_lines does
+                let mut is_end_reply = line.is_end_reply();
+                let mut errors = line.value;
+                while !is_end_reply {
+                    match client.receive_line().await {
+                        Ok(line) if line.is_err() => {
+                            is_end_reply = line.is_end_reply();
+                            client.has_unfinished_reply = !is_end_reply;
+                            errors.push('\n');
+                            errors.push_str(&line.value);
+                        },
+                        Ok(line) => {
+                            client.has_unfinished_reply = !line.is_end_reply();
+                            break;
+                        },
+                        Err(err) => return Some((Err(err), State::Done)),
+                    }
+                }
+                return Some((Err(TorClientError::TorCommandFailed(errors)), State::Done));
+            }
+            if commands::is_trailing_ok(&line) {
+                return None;
+            }
+            let key_value = parse_info_line(&line);
+            let next = if line.is_end_reply() || key_value.is_err() {
+                State::Done
+            } else {
+                State::Receive(client)
+            };
+            Some((key_value, next))
+        })
+    }
+
+    async fn get_info_bool(&mut self, key_name: &str) -> Result<bool, TorClientError> {
+        let values = self.get_info(key_name).await?;
+        Ok(values.first().map(|v| v == "1").unwrap_or(false))
+    }
+
+    /// Adds the event (e.g. `GUARD`) to the events set by SETEVENTS on this connection, if it is not already set
+    pub async fn enable_event(&mut self, event: &str) -> Result<(), TorClientError> {
+        if self.enabled_events.iter().any(|e| e == event) {
+            return Ok(());
+        }
+        let mut events = self.enabled_events.clone();
+        events.push(event.to_string());
+        self.replace_events(events).await
+    }
+
+    /// Removes the event from the events set by SETEVENTS on this connection, if it is set. The other events are kept.
+    pub async fn disable_event(&mut self, event: &str) -> Result<(), TorClientError> {
+        if self.enabled_events.iter().all(|e| e != event) {
+            return Ok(());
+        }
+        let events = self
+            .enabled_events
+            .iter()
+            .filter(|e| *e != event)
+            .cloned()
+            .collect::<Vec<_>>();
+        self.replace_events(events).await
+    }
+
+    /// Sends SETEVENTS with the given events, replacing those previously set
+    async fn replace_events(&mut self, events: Vec<String>) -> Result<(), TorClientError> {
+        // Events of the new kinds may arrive before the reply, and of the old kinds until tor has processed the
+        // command, so both are let through until it completes
+        let previous = self.event_filter.get();
+        if let Some(previous) = previous.as_ref() {
+            let mut in_flight = previous.clone();
+            in_flight.extend(events.iter().cloned());
+            self.event_filter.set(Some(in_flight));
+        }
+        let event_names = events.iter().map(String::as_str).collect::<Vec<_>>();
+        match self.execute(commands::set_events(&event_names)).await {
+            Ok(_) => {
+                self.event_filter.set(Some(events.clone()));
+                self.enabled_events = events;
+                Ok(())
+            },
+            Err(err) => {
+                self.event_filter.set(previous);
+                Err(err)
+            },
+        }
+    }
+
+    /// Sends the command once tor is able to build circuits (see [TorControlPortClient::wait_until_ready]). Commands
+    /// such as ADD_ONION may fail if they are sent while tor is still bootstrapping.
+    pub async fn execute_when_ready<T: TorCommand + Display>(
+        &mut self,
+        command: T,
+        timeout: Duration,
+    ) -> Result<T::Output, TorClientError>
+    where
+        T::Error: Into<TorClientError>,
+    {
+        command.validate().map_err(Into::into)?;
+        self.wait_until_ready(timeout).await?;
+        self.execute(command).await
+    }
+
+    /// The SETCONF command. Each option is set to the given value, or reset to its default if the value is `None`.
+    /// Values are quoted by this client.
+    pub async fn set_conf(&mut self, options: &[(&str, Option<&str>)]) -> Result<(), TorClientError> {
+        self.execute(commands::SetConf::new(options)).await
+    }
+
+    /// Sets `SocksPort` to the given ports, replacing the current ones. An empty list resets it to the default.
+    pub async fn set_socks_ports(&mut self, ports: &[PortSpec]) -> Result<(), TorClientError> {
+        self.set_ports("SocksPort", ports).await
+    }
+
+    /// Sets `ControlPort` to the given ports, replacing the current ones. An empty list resets it to the default. Tor
+    /// keeps this connection open even if it is to a port that is no longer configured.
+    pub async fn set_control_ports(&mut self, ports: &[PortSpec]) -> Result<(), TorClientError> {
+        self.set_ports("ControlPort", ports).await
+    }
+
+    async fn set_ports(&mut self, option: &str, ports: &[PortSpec]) -> Result<(), TorClientError> {
+        let values = ports.iter().map(ToString::to_string).collect::<Vec<_>>();
+        let mut options = values.iter().map(|v| (option, Some(v.as_str()))).collect::<Vec<_>>();
+        if options.is_empty() {
+            options.push((option, None));
+        }
+        self.set_conf(&options).await
+    }
+
+    /// The SAVECONF command, which writes the current configuration to the torrc so that it is kept when tor restarts.
+    pub async fn save_conf(&mut self) -> Result<(), TorClientError> {
+        self.execute(commands::SaveConf).await
+    }
+
+    /// The SETEVENTS command. This replaces the events that were previously set.
     pub async fn set_events(&mut self, events: &[&str]) -> Result<(), TorClientError> {
-        let command = commands::set_events(events);
-        let _ = self.request_response(command).await?;
+        self.replace_events(events.iter().map(ToString::to_string).collect())
+            .await
+    }
+
+    /// The ONION_CLIENT_AUTH_ADD command, which gives tor the base64-encoded x25519 `private_key` used to access the
+    /// v3 onion service at `address` with client authorization.
+    pub async fn onion_client_auth_add(
+        &mut self,
+        address: &OnionAddress,
+        private_key: &str,
+        client_name: Option<&ClientName>,
+    ) -> Result<(), TorClientError>
+    {
+        let mut command = commands::OnionClientAuthAdd::new(address, private_key);
+        if let Some(client_name) = client_name {
+            command = command.with_client_name(client_name);
+        }
+        self.execute(command).await
+    }
+
+    /// The SIGNAL command.
+    pub async fn signal(&mut self, signal: &str) -> Result<(), TorClientError> {
+        let _ = self.execute(commands::signal(signal)).await?;
+        Ok(())
+    }
+
+    /// Sets the minimum interval between NEWNYM signals sent by [signal_newnym](Self::signal_newnym), or `None` (the
+    /// default) to send every signal. Tor ignores NEWNYM signals sent less than 10 seconds apart without telling the
+    /// client, so setting this gives callers an error instead of a signal that has no effect.
+    pub fn set_newnym_min_interval(&mut self, interval: Option<Duration>) {
+        self.newnym_min_interval = interval;
+    }
+
+    /// Signals NEWNYM, which makes tor use new circuits for new connections. Returns
+    /// `TorClientError::NewnymRateLimited` without signalling if the previous NEWNYM was sent less than the minimum
+    /// interval ago.
+    pub async fn signal_newnym(&mut self) -> Result<(), TorClientError> {
+        if let (Some(min_interval), Some(last_newnym)) = (self.newnym_min_interval, self.last_newnym) {
+            let elapsed = self.clock.now().saturating_duration_since(last_newnym);
+            if elapsed < min_interval {
+                return Err(TorClientError::NewnymRateLimited {
+                    retry_after: min_interval - elapsed,
+                });
+            }
+        }
+        self.signal("NEWNYM").await?;
+        self.last_newnym = Some(self.clock.now());
         Ok(())
     }
 
     /// The ADD_ONION command, used to create onion hidden services.
-    pub async fn add_onion_custom<P: Into<PortMapping>>(
+    pub async fn add_onion_custom<'a, K: Into<AnyKeyType<'a>>, P: Into<PortMapping>>(
         &mut self,
-        key_type: KeyType,
-        key_blob: KeyBlob<'_>,
+        key_type: K,
+        key_blob: KeyBlob<'a>,
         flags: Vec<AddOnionFlag>,
         port: P,
         num_streams: Option<NonZeroU16>,
     ) -> Result<AddOnionResponse, TorClientError>
     {
         let command = commands::AddOnion::new(key_type, key_blob, flags, port.into(), num_streams);
-        self.request_response(command).await
+        self.execute(command).await
     }
 
     /// The ADD_ONION command using a v2 key
+    #[deprecated(note = "v2 onion services are not supported from tor 0.4.6, use add_onion_v3 instead")]
     pub async fn add_onion_v2<P: Into<PortMapping>>(
         &mut self,
         flags: Vec<AddOnionFlag>,
@@ -152,7 +1072,20 @@ impl TorControlPortClient {
         num_streams: Option<NonZeroU16>,
     ) -> Result<AddOnionResponse, TorClientError>
     {
-        self.add_onion_custom(KeyType::New, KeyBlob::Rsa1024, flags, port, num_streams)
+        self.add_onion_custom(KeyType::New, KeyBlob::NEW_V2, flags, port, num_streams)
+            .await
+    }
+
+    /// The ADD_ONION command using a new v3 (ED25519-V3) key. This should be preferred over the other `add_onion`
+    /// variants for new onion services.
+    pub async fn add_onion_v3<P: Into<PortMapping>>(
+        &mut self,
+        flags: Vec<AddOnionFlag>,
+        port: P,
+        num_streams: Option<NonZeroU16>,
+    ) -> Result<AddOnionResponse, TorClientError>
+    {
+        self.add_onion_custom(KeyType::New, KeyBlob::Ed25519V3, flags, port, num_streams)
             .await
     }
 
@@ -169,7 +1102,7 @@ impl TorControlPortClient {
             .await
     }
 
-    /// The ADD_ONION command using the given `PrivateKey`.
+    /// The ADD_ONION command using the given `PrivateKey`. Legacy v2 (RSA1024) keys are still passed to tor as is.
     pub async fn add_onion_from_private_key<P: Into<PortMapping>>(
         &mut self,
         private_key: &PrivateKey,
@@ -178,10 +1111,7 @@ impl TorControlPortClient {
         num_streams: Option<NonZeroU16>,
     ) -> Result<AddOnionResponse, TorClientError>
     {
-        let (key_type, key_blob) = match private_key {
-            PrivateKey::Rsa1024(key) => (KeyType::Rsa1024, KeyBlob::String(key)),
-            PrivateKey::Ed25519V3(key) => (KeyType::Ed25519V3, KeyBlob::String(key)),
-        };
+        let (key_type, key_blob) = private_key.as_key_type_and_blob();
         self.add_onion_custom(key_type, key_blob, flags, port, num_streams)
             .await
     }
@@ -189,70 +1119,418 @@ impl TorControlPortClient {
     /// The DEL_ONION command.
     pub async fn del_onion(&mut self, service_id: &str) -> Result<(), TorClientError> {
         let command = commands::DelOnion::new(service_id);
-        self.request_response(command).await
+        self.execute(command).await
     }
 
-    async fn request_response<T: TorCommand + Display>(&mut self, command: T) -> Result<T::Output, TorClientError>
-    where T::Error: Into<TorClientError> {
-        trace!(target: LOG_TARGET, "Sent command: {}", command);
-        let cmd_str = command.to_command_string().map_err(Into::into)?;
-        self.send_line(cmd_str).await?;
-        let responses = self.recv_next_responses().await?;
-        trace!(target: LOG_TARGET, "Response from tor: {:?}", responses);
-        if responses.is_empty() {
-            return Err(TorClientError::ServerNoResponse);
+    /// Removes the onion service, even if it was not created by this connection. Tor allows any connection to remove
+    /// a detached service, so DEL_ONION is tried first. If tor does not know the service and `private_key` (the key the
+    /// service was created with) is given, the service is adopted by re-adding it with the key and then removed. This
+    /// fails with [TorClientError::OnionServiceExists] if the service is owned by another (open) connection, as tor
+    /// does not allow it to be removed by anyone else. Without the key, the DEL_ONION error is returned.
+    ///
+    /// Re-adding the service only detects whether it is owned by another connection: if tor does not have the service
+    /// at all, it is briefly created with the key and removed again. An `ED25519-V3` key for a different service is
+    /// rejected with [TorClientError::PrivateKeyServiceMismatch] before anything is sent.
+    pub async fn force_remove_onion(
+        &mut self,
+        service_id: &str,
+        private_key: Option<&PrivateKey>,
+    ) -> Result<(), TorClientError>
+    {
+        // Only the address of an ED25519-V3 key can be derived without adding the service
+        match private_key.map(PrivateKey::onion_address) {
+            Some(Ok(address)) if address.service_id() != service_id => {
+                return Err(TorClientError::PrivateKeyServiceMismatch {
+                    service_id: service_id.to_string(),
+                    key_service_id: address.service_id().to_string(),
+                });
+            },
+            Some(Err(OnionAddressError::UnsupportedKeyType(_))) | Some(Ok(_)) | None => {},
+            Some(Err(err)) => return Err(err.into()),
         }
-        let response = command.parse_responses(responses).map_err(Into::into)?;
-        Ok(response)
+
+        let err = match self.del_onion(service_id).await {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        let private_key = match private_key {
+            Some(private_key) if matches!(self.last_reply, Some((UNKNOWN_SERVICE_CODE, _))) => private_key,
+            _ => return Err(err),
+        };
+
+        // The port is never used as the service is removed straight away, but ADD_ONION requires one
+        let response = self
+            .add_onion_from_private_key(private_key, vec![AddOnionFlag::DiscardPK], 1, None)
+            .await?;
+        self.del_onion(&response.service_id).await?;
+        if response.service_id != service_id {
+            return Err(TorClientError::PrivateKeyServiceMismatch {
+                service_id: service_id.to_string(),
+                key_service_id: response.service_id,
+            });
+        }
+        Ok(())
     }
 
-    async fn send_line(&mut self, line: String) -> Result<(), TorClientError> {
-        self.cmd_tx
-            .send(line)
-            .await
-            .map_err(|_| TorClientError::CommandSenderDisconnected)
+    /// Send the given command to the tor control port and parse the response(s) returned by the server.
+    ///
+    /// The reply lines are received before they are parsed, so the whole reply is held in memory. Replies that may be
+    /// large should be read with [get_info_stream](Self::get_info_stream), which yields each value as it is received.
+    pub async fn execute<T: TorCommand + Display>(&mut self, command: T) -> Result<T::Output, TorClientError>
+    where T::Error: Into<TorClientError> {
+        self.execute_ref(&command).await
     }
 
-    async fn recv_ok(&mut self) -> Result<(), TorClientError> {
-        let resp = self.receive_line().await?;
-        if resp.is_ok() {
-            Ok(())
-        } else {
-            Err(TorClientError::TorCommandFailed(resp.value))
-        }
+    /// The same as [execute](Self::execute), but also returns the round-trip time of the command. This is measured
+    /// from when the command is sent to when its reply has been parsed, so it excludes time spent discarding the rest
+    /// of an earlier reply. Errors are returned without a duration.
+    pub async fn execute_timed<T: TorCommand + Display>(
+        &mut self,
+        command: T,
+    ) -> Result<(T::Output, Duration), TorClientError>
+    where
+        T::Error: Into<TorClientError>,
+    {
+        let output = self.execute_ref(&command).await?;
+        let elapsed = self
+            .last_sent_at
+            .map(|at| self.clock.now().saturating_duration_since(at))
+            .unwrap_or_default();
+        Ok((output, elapsed))
     }
 
-    async fn recv_next_responses(&mut self) -> Result<Vec<ResponseLine>, TorClientError> {
-        let mut msgs = Vec::new();
+    /// Sends the command, retrying it if tor rejects it because of a transient failure (`451 Resource exhausted`) up to
+    /// `max_attempts` attempts in total. Commands that are not idempotent (see [TorCommand::is_idempotent]) are sent
+    /// once, so that a retry never creates state (e.g. a second onion service) twice.
+    pub async fn execute_with_retry<T: TorCommand + Display>(
+        &mut self,
+        command: T,
+        max_attempts: usize,
+    ) -> Result<T::Output, TorClientError>
+    where
+        T::Error: Into<TorClientError>,
+    {
+        let max_attempts = if command.is_idempotent() { max_attempts } else { 1 };
+        let mut attempt = 1;
         loop {
-            let msg = self.receive_line().await?;
-            let has_more = msg.has_more();
-            msgs.push(msg.into_owned());
-            if !has_more {
-                break;
+            match self.execute_ref(&command).await {
+                Err(err) if attempt < max_attempts && self.is_transient_failure() => {
+                    debug!(
+                        target: LOG_TARGET,
+                        "Retrying command '{}' after transient failure (attempt {}/{}): {}",
+                        command,
+                        attempt,
+                        max_attempts,
+                        err
+                    );
+                    attempt += 1;
+                    self.clock.delay_for(RETRY_DELAY).await;
+                },
+                result => return result,
             }
         }
+    }
 
-        Ok(msgs)
+    /// Validates and serializes the command exactly as [execute](Self::execute) would, returning the command line
+    /// (without the line terminator) instead of sending it. Nothing is written to the control port. The command is
+    /// also checked against the version of tor if it is known (see [protocol_info](Self::protocol_info)).
+    ///
+    /// The returned line is not redacted, so it may contain secrets such as private keys.
+    pub fn dry_run<T: TorCommand>(&self, command: &T) -> Result<String, TorClientError>
+    where T::Error: Into<TorClientError> {
+        let mut buf = String::new();
+        self.write_validated_command(command, &mut buf)?;
+        Ok(buf)
     }
 
-    async fn receive_line(&mut self) -> Result<ResponseLine, TorClientError> {
-        let line = self
-            .output_stream
-            .next()
-            .await
-            .ok_or_else(|| TorClientError::UnexpectedEof)?;
+    fn is_transient_failure(&self) -> bool {
+        matches!(self.last_reply, Some((RESOURCE_EXHAUSTED_CODE, _)))
+    }
+
+    fn write_validated_command<T: TorCommand>(&self, command: &T, buf: &mut String) -> Result<(), TorClientError>
+    where T::Error: Into<TorClientError> {
+        command.validate().map_err(Into::into)?;
+        if let Some(info) = self.protocol_info.as_ref() {
+            command.validate_for_version(info.tor_version()).map_err(Into::into)?;
+        }
+        command.write_command(buf).map_err(Into::into)
+    }
+
+    async fn execute_ref<T: TorCommand + Display>(&mut self, command: &T) -> Result<T::Output, TorClientError>
+    where T::Error: Into<TorClientError> {
+        if self.protocol_info.is_none() && command.requires_tor_version() {
+            self.query_protocol_info().await?;
+        }
+        self.send_command(command).await
+    }
+
+    async fn query_protocol_info(&mut self) -> Result<(), TorClientError> {
+        let info = self.send_command(&ProtocolInfo).await?;
+        self.protocol_info = Some(info);
+        Ok(())
+    }
+
+    /// Sends the validated command and parses its reply. Unlike [execute_ref](Self::execute_ref), the version of tor
+    /// is never queried first, so that this can be used to query it.
+    async fn send_command<T: TorCommand + Display>(&mut self, command: &T) -> Result<T::Output, TorClientError>
+    where T::Error: Into<TorClientError> {
+        // The span covers sending the command, receiving the reply and parsing it. Only the command verb is recorded,
+        // never any arguments as these may contain secrets.
+        #[cfg(feature = "tracing")]
+        let span = tracing::span!(
+            tracing::Level::DEBUG,
+            "tor_command",
+            verb = tracing::field::Empty,
+            code = tracing::field::Empty
+        );
+        let execute = async {
+            let mut buf = self.take_command_buf();
+            self.write_validated_command(command, &mut buf)?;
+            trace!(target: LOG_TARGET, "Sent command: {}", command);
+            #[cfg(feature = "tracing")]
+            span.record("verb", &command.verb());
+
+            self.send_and_receive(buf).await?;
+            #[cfg(feature = "tracing")]
+            {
+                if let Some((code, _)) = self.last_reply.as_ref() {
+                    span.record("code", code);
+                }
+            }
+
+            trace!(target: LOG_TARGET, "Response from tor: {:?}", self.response_buf);
+            if self.response_buf.is_empty() {
+                return Err(TorClientError::ServerNoResponse);
+            }
+            // Draining (rather than taking) the buffer keeps its allocation for the next command
+            command
+                .parse_responses(self.response_buf.drain(..))
+                .map_err(|err| err.into().with_command(command.verb()))
+        };
+        #[cfg(feature = "tracing")]
+        let execute = tracing::Instrument::instrument(execute, span.clone());
+        execute.await
+    }
+
+    /// Sends a command line as is and returns the reply lines, without interpreting them. Asynchronous events are
+    /// still routed to the event stream. This allows commands that are not modelled by this client to be used.
+    ///
+    /// The caller is responsible for the syntax of the command, including quoting any arguments (see
+    /// [parsers::quote_arg]). The command line must be a single line, without the line terminator.
+    pub async fn send_raw(&mut self, command_line: &str) -> Result<Vec<ResponseLine>, TorClientError> {
+        if command_line.contains(|ch| ch == '\r' || ch == '\n') {
+            return Err(TorClientError::RawCommandNotSingleLine);
+        }
+        let mut buf = self.take_command_buf();
+        buf.push_str(command_line);
+        self.send_and_receive(buf).await?;
+        Ok(self.response_buf.drain(..).collect())
+    }
+
+    async fn send_and_receive(&mut self, line: String) -> Result<(), TorClientError> {
+        self.send_line(line).await?;
+        self.recv_next_responses().await
+    }
+
+    /// Returns an empty buffer to write the next command into, reusing a buffer handed back by the monitor once it
+    /// has written a previous command to the socket.
+    fn take_command_buf(&mut self) -> String {
+        let mut buf = self.recycled_buf_rx.try_next().ok().flatten().unwrap_or_default();
+        buf.clear();
+        buf
+    }
+
+    async fn send_line(&mut self, line: String) -> Result<(), TorClientError> {
+        if self.is_closed {
+            return Err(TorClientError::ClientClosed);
+        }
+        while self.has_unfinished_reply {
+            let line = self.receive_line().await?;
+            self.has_unfinished_reply = !line.is_end_reply();
+        }
+        self.last_reply = None;
+        self.last_sent_at = Some(self.clock.now());
+        self.cmd_tx
+            .send(line)
+            .await
+            .map_err(|_| TorClientError::CommandSenderDisconnected)
+    }
+
+    /// Returns true if the message of the last reply starts with `prefix`. Unlike the message of a
+    /// [TorClientError::TorCommandFailed] error, this does not include the command verb.
+    fn last_reply_starts_with(&self, prefix: &str) -> bool {
+        self.last_reply
+            .as_ref()
+            .map_or(false, |(_, msg)| msg.starts_with(prefix))
+    }
+
+    async fn recv_ok(&mut self) -> Result<(), TorClientError> {
+        let resp = self.receive_line().await?;
+        if resp.is_ok() {
+            Ok(())
+        } else {
+            Err(TorClientError::TorCommandFailed(resp.value))
+        }
+    }
+
+    /// Receives the reply lines to the last command into `response_buf`.
+    async fn recv_next_responses(&mut self) -> Result<(), TorClientError> {
+        self.response_buf.clear();
+        loop {
+            let msg = self.receive_line().await?;
+            let is_end_reply = msg.is_end_reply();
+            self.response_buf.push(msg.into_owned());
+            if is_end_reply {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn receive_line(&mut self) -> Result<ResponseLine, TorClientError> {
+        let line = self
+            .output_stream
+            .next()
+            .await
+            .ok_or_else(|| TorClientError::UnexpectedEof)?;
+
+        if line.is_end_reply() {
+            self.last_reply = Some((line.code(), line.value().to_string()));
+        }
 
         Ok(line)
     }
 }
 
+/// Builder for a [TorControlPortClient] with non-default options
+#[derive(Debug, Clone)]
+pub struct TorControlPortClientBuilder {
+    read_buffer_capacity: usize,
+    clock: Arc<dyn Clock>,
+    connection_state: Option<ConnectionStateTracker>,
+}
+
+impl TorControlPortClientBuilder {
+    /// The initial capacity of the read buffer if none is set, which is the default of the codec framing
+    pub const DEFAULT_READ_BUFFER_CAPACITY: usize = 8 * 1024;
+
+    pub fn new() -> Self {
+        Self {
+            read_buffer_capacity: Self::DEFAULT_READ_BUFFER_CAPACITY,
+            clock: Arc::new(SystemClock),
+            connection_state: None,
+        }
+    }
+
+    /// Set the initial capacity of the buffer that replies are read into. The buffer grows to fit a reply line (or
+    /// data body) that is larger than this, so this only needs to be raised to reduce the number of reads for callers
+    /// that receive large replies, such as `GETINFO ns/all`. Capacities below
+    /// [DEFAULT_READ_BUFFER_CAPACITY](Self::DEFAULT_READ_BUFFER_CAPACITY) are raised to it.
+    pub fn read_buffer_capacity(mut self, capacity: usize) -> Self {
+        self.read_buffer_capacity = capacity;
+        self
+    }
+
+    /// Use `clock` for the time-dependent behaviour of the client: the NEWNYM rate limit, the timeouts and polling of
+    /// the waits, the delay between retries and the round-trip time of [execute_timed]. Defaults to [SystemClock].
+    ///
+    /// [execute_timed]: TorControlPortClient::execute_timed
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Report the state of the connection to `tracker`, so that the state can be watched across reconnections
+    pub(crate) fn with_connection_state(mut self, tracker: ConnectionStateTracker) -> Self {
+        self.connection_state = Some(tracker);
+        self
+    }
+
+    /// Connect using TCP to the given address.
+    pub async fn connect(
+        self,
+        addr: Multiaddr,
+        event_tx: broadcast::Sender<TorControlEvent>,
+    ) -> Result<TorControlPortClient, TorClientError>
+    {
+        if let Some(tracker) = &self.connection_state {
+            tracker.set_if(ConnectionState::Connecting, connection_state::is_not_reconnecting);
+        }
+        match self.dial(addr).await {
+            Ok(socket) => Ok(self.build(socket, event_tx)),
+            Err(err) => {
+                if let Some(tracker) = &self.connection_state {
+                    tracker.set_if(ConnectionState::Closed, connection_state::is_not_reconnecting);
+                }
+                Err(err)
+            },
+        }
+    }
+
+    async fn dial(&self, addr: Multiaddr) -> Result<TcpSocket, TorClientError> {
+        let mut tcp = TcpTransport::new();
+        tcp.set_nodelay(true);
+        let socket = match tcp.dial(addr.clone()) {
+            Ok(dial) => dial.await,
+            Err(err) => Err(err),
+        };
+        socket.map_err(|source| TorClientError::ConnectionFailed {
+            kind: ConnectionFailureKind::from_connect_error(&source),
+            addr,
+            source,
+        })
+    }
+
+    /// Create the client using the given socket
+    pub fn build<TSocket>(self, socket: TSocket, event_tx: broadcast::Sender<TorControlEvent>) -> TorControlPortClient
+    where TSocket: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+        TorControlPortClient::with_builder(self, socket, event_tx)
+    }
+}
+
+impl Default for TorControlPortClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parses a `key=value` GETINFO reply line. The value of a data line is the data that followed it.
+fn parse_info_line(line: &ResponseLine) -> Result<(String, String), TorClientError> {
+    let mut parts = line.value().splitn(2, '=');
+    let key = parts.next().unwrap_or_default();
+    let value = parts
+        .next()
+        .ok_or_else(|| parsers::ParseError(format!("GETINFO reply line '{}' has no value", key)))?;
+    let value = if line.is_data() {
+        value.strip_prefix('\n').unwrap_or(value).to_string()
+    } else if value.starts_with('"') {
+        parsers::quoted_string(value)?.0.into_owned()
+    } else {
+        value.to_string()
+    };
+    Ok((key.to_string(), value))
+}
+
+/// Returns true if `s` has the form of a `tor --hash-password` hash i.e. `16:` followed by the hex-encoded salt,
+/// iteration count and digest
+fn is_tor_password_hash(s: &str) -> bool {
+    s.strip_prefix("16:")
+        .map(|hex| hex.len() == 58 && hex.chars().all(|ch| ch.is_ascii_hexdigit()))
+        .unwrap_or(false)
+}
+
 /// Represents tor control port authentication mechanisms
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq)]
 pub enum Authentication {
     /// No control port authentication required
     None,
-    /// A hashed password will be sent to authenticate
+    /// The HASHEDPASSWORD method. This is the plaintext password, which is sent quoted and hashed by tor to compare
+    /// with its `HashedControlPassword` option. The `16:...` form from `tor --hash-password` goes in the torrc and is
+    /// not accepted here.
+    Password(String),
+    /// The same as `Password`: despite the name, this is the plaintext password and not its hash
+    #[deprecated(note = "this is the plaintext password, use Authentication::Password instead")]
     HashedPassword(String),
     /// Cookie authentication. The contents of the cookie file encoded as hex
     Cookie(String),
@@ -264,11 +1542,27 @@ impl Default for Authentication {
     }
 }
 
+/// The password or cookie is redacted
+impl fmt::Debug for Authentication {
+    #[allow(deprecated)]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Authentication::*;
+        match self {
+            None => write!(f, "None"),
+            Password(_) => write!(f, "Password({})", types::REDACTED),
+            HashedPassword(_) => write!(f, "HashedPassword({})", types::REDACTED),
+            Cookie(_) => write!(f, "Cookie({})", types::REDACTED),
+        }
+    }
+}
+
 impl fmt::Display for Authentication {
+    #[allow(deprecated)]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use Authentication::*;
         match self {
             None => write!(f, "None"),
+            Password(_) => write!(f, "Password"),
             HashedPassword(_) => write!(f, "HashedPassword"),
             Cookie(_) => write!(f, "Cookie"),
         }
@@ -280,11 +1574,11 @@ mod test {
     use super::*;
     use crate::{
         runtime,
-        tor::control_client::{test_server, test_server::canned_responses, types::PrivateKey},
+        tor::control_client::{test_server, test_server::canned_responses, types::PrivateKey, MockClock},
     };
-    use futures::future;
     use std::net::SocketAddr;
     use tari_test_utils::unpack_enum;
+    use tokio::{net::TcpListener, sync::oneshot, time};
 
     async fn setup_test() -> (TorControlPortClient, test_server::State) {
         let (_, mock_state, socket) = test_server::spawn().await;
@@ -293,6 +1587,56 @@ mod test {
         (tor, mock_state)
     }
 
+    async fn setup_test_with_mock_clock() -> (TorControlPortClient, test_server::State, MockClock) {
+        let (_, mock_state, socket) = test_server::spawn().await;
+        let (event_tx, _) = broadcast::channel(1);
+        let clock = MockClock::new();
+        let tor = TorControlPortClient::builder()
+            .with_clock(Arc::new(clock.clone()))
+            .build(socket, event_tx);
+        (tor, mock_state, clock)
+    }
+
+    #[runtime::test]
+    async fn read_buffer_capacity() {
+        // A body that is larger than the default and the configured buffer, and a reply whose lines straddle reads
+        let lines = (0..5000)
+            .map(|i| format!("SocksPort {}", 10000 + i))
+            .collect::<Vec<_>>();
+        let mut reply = vec!["250+config-text=".to_string()];
+        reply.extend(lines.iter().cloned());
+        reply.extend(vec![".".to_string(), "250 OK".to_string()]);
+        let reply = reply.iter().map(String::as_str).collect::<Vec<_>>();
+
+        for capacity in &[TorControlPortClientBuilder::DEFAULT_READ_BUFFER_CAPACITY, 64 * 1024, 16] {
+            let (_, mock_state, socket) = test_server::spawn().await;
+            let (event_tx, _) = broadcast::channel(1);
+            let mut tor = TorControlPortClient::builder()
+                .read_buffer_capacity(*capacity)
+                .build(socket, event_tx);
+
+            mock_state.push_response(&reply).await;
+            let values = tor
+                .get_info_stream(&["config-text"])
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(values.len(), 1);
+            assert_eq!(values[0].1, lines.join("\n"));
+
+            // The connection is still in sync afterwards
+            tor.set_events(&["BW"]).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn client_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<TorControlPortClient>();
+    }
+
     #[runtime::test]
     async fn connect() {
         let (mut listener, addr) = TcpTransport::default()
@@ -309,6 +1653,112 @@ mod test {
         result_in.unwrap().unwrap().0.await.unwrap();
     }
 
+    #[runtime::test]
+    async fn events_before_authentication() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        // No subscribers, so events are dropped by the decoder
+        mock_state
+            .set_canned_response(&["650 NETWORK_LIVENESS UP", "250 OK"])
+            .await;
+        tor.authenticate(&Authentication::None).await.unwrap();
+
+        let mut events = tor.get_event_stream();
+        mock_state
+            .set_canned_response(&["650 NETWORK_LIVENESS DOWN", "650 NETWORK_LIVENESS BOGUS", "250 OK"])
+            .await;
+        tor.authenticate(&Authentication::None).await.unwrap();
+        unpack_enum!(TorControlEvent::NetworkLivenessDown = events.recv().await.unwrap());
+
+        mock_state
+            .set_canned_response(&[
+                "250-PROTOCOLINFO 1",
+                "650 NETWORK_LIVENESS UP",
+                "250-AUTH METHODS=NULL",
+                "250-VERSION Tor=\"0.4.5.6\"",
+                "250 OK",
+            ])
+            .await;
+        let info = tor.protocol_info().await.unwrap();
+        assert_eq!(info.tor_version(), TorVersion::new(0, 4, 5, 6));
+        unpack_enum!(TorControlEvent::NetworkLivenessUp = events.recv().await.unwrap());
+    }
+
+    #[runtime::test]
+    async fn independent_clients() {
+        // Two clients, as if connected to two tor daemons. The client has no global state, so neither sees the other's
+        // replies or events
+        const NUM_COMMANDS: usize = 10;
+        async fn run_client(version: &'static str, event: &'static str) {
+            let (_, mock_state, socket) = test_server::spawn().await;
+            let (event_tx, mut events) = broadcast::channel(NUM_COMMANDS + 1);
+            let mut tor = TorControlPortClient::new(socket, event_tx);
+            let reply = format!("250-version={}", version);
+            for _ in 0..NUM_COMMANDS {
+                mock_state.push_response(&[event, &reply, "250 OK"]).await;
+            }
+
+            for _ in 0..NUM_COMMANDS {
+                assert_eq!(tor.get_info("version").await.unwrap(), [version]);
+                // Yield so that the commands of the two clients are interleaved
+                task::yield_now().await;
+            }
+            for _ in 0..NUM_COMMANDS {
+                let received = events.recv().await.unwrap();
+                match (event, received) {
+                    ("650 NETWORK_LIVENESS UP", TorControlEvent::NetworkLivenessUp) |
+                    ("650 NETWORK_LIVENESS DOWN", TorControlEvent::NetworkLivenessDown) => {},
+                    (_, received) => panic!("Unexpected event {:?}", received),
+                }
+            }
+            assert!(events.try_recv().is_err());
+            assert_eq!(mock_state.take_requests().await.len(), NUM_COMMANDS);
+        }
+
+        future::join(
+            run_client("0.4.5.6", "650 NETWORK_LIVENESS UP"),
+            run_client("0.4.6.1", "650 NETWORK_LIVENESS DOWN"),
+        )
+        .await;
+    }
+
+    #[runtime::test]
+    async fn connect_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let expected_addr = format!("/ip4/127.0.0.1/tcp/{}", port).parse::<Multiaddr>().unwrap();
+        let (event_tx, _) = broadcast::channel(1);
+        let err = match TorControlPortClient::connect(expected_addr.clone(), event_tx).await {
+            Ok(_) => panic!("connected to a closed port"),
+            Err(err) => err,
+        };
+        unpack_enum!(TorClientError::ConnectionFailed { addr, kind, source } = err);
+        assert_eq!(addr, expected_addr);
+        assert_eq!(kind, ConnectionFailureKind::Refused);
+        assert_eq!(source.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[runtime::test]
+    async fn connect_address_resolution_failed() {
+        // The resolver rejects a UDP address without a lookup, so this does not depend on the network
+        let expected_addr = "/ip4/127.0.0.1/udp/9051".parse::<Multiaddr>().unwrap();
+        let (event_tx, _) = broadcast::channel(1);
+        let err = match TorControlPortClient::connect(expected_addr.clone(), event_tx).await {
+            Ok(_) => panic!("connected to an address that cannot be resolved"),
+            Err(err) => err,
+        };
+        unpack_enum!(TorClientError::ConnectionFailed { addr, kind, source } = err);
+        assert_eq!(addr, expected_addr);
+        assert_eq!(kind, ConnectionFailureKind::AddressResolution);
+        assert_eq!(source.kind(), io::ErrorKind::Other);
+        assert_eq!(
+            source.to_string(),
+            "Address resolution failed: DNS Resolution: address '/ip4/127.0.0.1/udp/9051' is not supported"
+        );
+    }
+
     #[runtime::test]
     async fn authenticate() {
         let (mut tor, mock_state) = setup_test().await;
@@ -318,6 +1768,14 @@ mod test {
         assert_eq!(req.len(), 1);
         assert_eq!(req.remove(0), "AUTHENTICATE");
 
+        tor.authenticate(&Authentication::Password("ab\"cde".to_string()))
+            .await
+            .unwrap();
+        let mut req = mock_state.take_requests().await;
+        assert_eq!(req.len(), 1);
+        assert_eq!(req.remove(0), "AUTHENTICATE \"ab\\\"cde\"");
+
+        #[allow(deprecated)]
         tor.authenticate(&Authentication::HashedPassword("ab\"cde".to_string()))
             .await
             .unwrap();
@@ -333,6 +1791,82 @@ mod test {
         assert_eq!(req.remove(0), "AUTHENTICATE NOTACTUALLYHEXENCODED");
     }
 
+    #[runtime::test]
+    async fn authenticate_password_special_characters() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        let password = "p@ss \"wo\\rd\"\n\t;=";
+        tor.authenticate(&Authentication::Password(password.to_string()))
+            .await
+            .unwrap();
+        let request = mock_state.take_requests().await.pop().unwrap();
+        assert_eq!(request, r#"AUTHENTICATE "p@ss \"wo\\rd\"\n\t;=""#);
+        let (quoted, rest) = parsers::quoted_string(request.strip_prefix("AUTHENTICATE ").unwrap()).unwrap();
+        assert_eq!(quoted, password);
+        assert!(rest.is_empty());
+
+        let auth = Authentication::Password(password.to_string());
+        assert!(!format!("{:?}", auth).contains("p@ss"));
+        assert_eq!(auth.to_string(), "Password");
+    }
+
+    #[test]
+    fn tor_password_hash() {
+        assert!(is_tor_password_hash(
+            "16:872860B76453A77D60CA2BB8C1A7042072093276A3D701AD684053EC4C"
+        ));
+        assert!(!is_tor_password_hash("16:not-a-hash"));
+        assert!(!is_tor_password_hash("hunter2"));
+    }
+
+    #[runtime::test]
+    async fn authenticate_failure_redacts_password() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        // Tor does not currently echo the password, but a reply that did must not leak it
+        mock_state
+            .push_response(&[r#"515 Authentication failed: "hunter2" is not the password"#])
+            .await;
+        let auth = Authentication::Password("hunter2".to_string());
+        let err = tor.authenticate(&auth).await.unwrap_err();
+        assert!(!err.to_string().contains("hunter2"));
+        assert!(!format!("{:?}", err).contains("hunter2"));
+        unpack_enum!(TorClientError::TorCommandFailed(msg) = err);
+        assert_eq!(msg, r#"Authentication failed: "[redacted]" is not the password"#);
+        let (code, msg) = tor.last_reply().unwrap();
+        assert_eq!(code, 515);
+        assert!(!msg.contains("hunter2"));
+        assert!(!format!("{:?}", auth).contains("hunter2"));
+
+        let request = mock_state.take_requests().await.pop().unwrap();
+        assert_eq!(request, r#"AUTHENTICATE "hunter2""#);
+    }
+
+    #[runtime::test]
+    async fn authenticate_with_cookie() {
+        let (mut tor, mock_state) = setup_test().await;
+        let dir = tempfile::tempdir().unwrap();
+        let cookie_path = dir.path().join("control_auth_cookie");
+        fs::write(&cookie_path, &[0xab, 0x01, 0xff]).unwrap();
+
+        // The path reported by PROTOCOLINFO does not exist in this process, so the override is used instead
+        mock_state.push_response(canned_responses::PROTOCOLINFO_OK).await;
+        mock_state.set_canned_response(canned_responses::OK).await;
+        let info = tor.protocol_info().await.unwrap();
+        assert_eq!(info.cookie_file(), Some("/home/user/.tor/control_auth_cookie"));
+        tor.authenticate_with_cookie(Some(&cookie_path)).await.unwrap();
+        assert_eq!(mock_state.take_requests().await, [
+            "PROTOCOLINFO 1",
+            "AUTHENTICATE AB01FF"
+        ]);
+
+        let err = tor.authenticate_with_cookie(None).await.unwrap_err();
+        unpack_enum!(TorClientError::CookieFileRead { path, source } = err);
+        assert_eq!(path, Path::new("/home/user/.tor/control_auth_cookie"));
+        assert_eq!(source.kind(), io::ErrorKind::NotFound);
+        assert!(mock_state.take_requests().await.is_empty());
+    }
+
     #[runtime::test]
     async fn get_conf_ok() {
         let (mut tor, mock_state) = setup_test().await;
@@ -371,70 +1905,653 @@ mod test {
     }
 
     #[runtime::test]
-    async fn get_info_kv_multiline_value_ok() {
+    async fn get_info_stream() {
         let (mut tor, mock_state) = setup_test().await;
 
         mock_state
-            .set_canned_response(canned_responses::GET_INFO_ONIONS_DETACHED_OK)
+            .push_response(&[
+                "250-version=0.4.5.8",
+                "250+config-text=",
+                "SocksPort 9050",
+                "ControlPort 9051",
+                ".",
+                r#"250-config-file="/etc/tor/torrc""#,
+                "250 OK",
+            ])
             .await;
-
-        let values = tor.get_info("onions/detached").await.unwrap();
-        assert_eq!(values, [
-            "mochz2xppfziim5olr5f6q27poc4vfob2xxxxxxxxxxxxxxxxxxxxxxx",
-            "nhqdqym6j35rk7tdou4cdj4gjjqagimutxxxxxxxxxxxxxxxxxxxxxxx"
+        let values = tor
+            .get_info_stream(&["version", "config-text", "config-file"])
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(values, vec![
+            ("version".to_string(), "0.4.5.8".to_string()),
+            (
+                "config-text".to_string(),
+                "SocksPort 9050\nControlPort 9051".to_string()
+            ),
+            ("config-file".to_string(), "/etc/tor/torrc".to_string()),
         ]);
+        let request = mock_state.take_requests().await.pop().unwrap();
+        assert_eq!(request, "GETINFO version config-text config-file");
+
+        mock_state.push_response(&[r#"552 Unrecognized key "dummy""#]).await;
+        let mut stream = Box::pin(tor.get_info_stream(&["dummy"]));
+        let err = stream.next().await.unwrap().unwrap_err();
+        unpack_enum!(TorClientError::TorCommandFailed(_msg) = err);
+        assert!(stream.next().await.is_none());
+        drop(stream);
+
+        // Every line of a multi-line failure is kept
+        mock_state
+            .push_response(&["551-Unable to read descriptor", "551 Internal error"])
+            .await;
+        let mut stream = Box::pin(tor.get_info_stream(&["desc/name/foo"]));
+        let err = stream.next().await.unwrap().unwrap_err();
+        unpack_enum!(TorClientError::TorCommandFailed(msg) = err);
+        assert_eq!(msg, "Unable to read descriptor\nInternal error");
+        assert!(stream.next().await.is_none());
+        drop(stream);
+        assert!(matches!(tor.last_reply, Some((551, _))));
+
+        let mut stream = Box::pin(tor.get_info_stream(&[]));
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(err, TorClientError::GetInfoNoKeys));
+        assert!(stream.next().await.is_none());
+        drop(stream);
+        assert!(mock_state.take_requests().await.is_empty());
+
+        // The stream ends at a line that cannot be parsed, and the rest of the reply is discarded
+        mock_state
+            .push_response(&["250-version", "250-config-file=/etc/tor/torrc", "250 OK"])
+            .await;
+        let mut stream = Box::pin(tor.get_info_stream(&["version", "config-file"]));
+        let err = stream.next().await.unwrap().unwrap_err();
+        unpack_enum!(TorClientError::ParseError(_e) = err);
+        assert!(stream.next().await.is_none());
+        drop(stream);
+        mock_state
+            .push_response(&["250-net/listeners/socks=\"127.0.0.1:9050\"", "250 OK"])
+            .await;
+        let addr = tor.socks_proxy_addr().await.unwrap();
+        assert_eq!(addr, ListenerAddress::Tcp("127.0.0.1:9050".parse().unwrap()));
     }
 
     #[runtime::test]
-    async fn get_info_err() {
+    async fn get_info_stream_dropped() {
         let (mut tor, mock_state) = setup_test().await;
 
-        mock_state.set_canned_response(canned_responses::ERR_552).await;
+        mock_state
+            .push_response(&["250-version=0.4.5.8", "250-config-file=/etc/tor/torrc", "250 OK"])
+            .await;
+        {
+            let mut stream = Box::pin(tor.get_info_stream(&["version", "config-file"]));
+            let (key, _) = stream.next().await.unwrap().unwrap();
+            assert_eq!(key, "version");
+        }
 
-        let err = tor.get_info("net/listeners/socks").await.unwrap_err();
-        unpack_enum!(TorClientError::TorCommandFailed(_s) = err);
+        // The rest of the previous reply is not mistaken for the reply to the next command
+        mock_state
+            .push_response(&["250-net/listeners/socks=\"127.0.0.1:9050\"", "250 OK"])
+            .await;
+        let addr = tor.socks_proxy_addr().await.unwrap();
+        assert_eq!(addr, ListenerAddress::Tcp("127.0.0.1:9050".parse().unwrap()));
     }
 
     #[runtime::test]
-    async fn add_onion_from_private_key_ok() {
+    async fn get_external_address() {
         let (mut tor, mock_state) = setup_test().await;
 
-        mock_state
-            .set_canned_response(canned_responses::ADD_ONION_RSA1024_OK)
-            .await;
+        mock_state.push_response(&["250-address=198.51.100.7", "250 OK"]).await;
+        let addr = tor.get_external_address().await.unwrap();
+        assert_eq!(addr, ExternalAddress::Known("198.51.100.7".parse().unwrap()));
 
-        let private_key = PrivateKey::Rsa1024("dummy-key".into());
-        let response = tor
-            .add_onion_from_private_key(&private_key, vec![], 8080, None)
-            .await
-            .unwrap();
+        mock_state.push_response(&["551 Address unknown"]).await;
+        let addr = tor.get_external_address().await.unwrap();
+        assert_eq!(addr, ExternalAddress::Unknown);
 
-        assert_eq!(response.service_id, "62q4tswkxp74dtn7");
-        assert!(response.private_key.is_none());
+        mock_state.push_response(&["552 Unrecognized key \"address\""]).await;
+        let err = tor.get_external_address().await.unwrap_err();
+        unpack_enum!(TorClientError::TorCommandFailed(_msg) = err);
 
-        let request = mock_state.take_requests().await.pop().unwrap();
-        assert_eq!(request, "ADD_ONION RSA1024:dummy-key Port=8080,127.0.0.1:8080");
+        mock_state.push_response(&["250-address=", "250 OK"]).await;
+        tor.get_external_address().await.unwrap_err();
+        mock_state.push_response(&["250 OK"]).await;
+        tor.get_external_address().await.unwrap_err();
     }
 
     #[runtime::test]
-    async fn add_onion_ok() {
+    async fn get_pid() {
         let (mut tor, mock_state) = setup_test().await;
 
-        mock_state.set_canned_response(canned_responses::ADD_ONION_OK).await;
+        mock_state.push_response(&["250-process/pid=1234", "250 OK"]).await;
+        assert_eq!(tor.get_pid().await.unwrap(), 1234);
+        let request = mock_state.take_requests().await.pop().unwrap();
+        assert_eq!(request, "GETINFO process/pid");
 
-        let response = tor
-            .add_onion_custom(
-                KeyType::New,
-                KeyBlob::Best,
-                vec![],
-                8080,
-                Some(NonZeroU16::new(10u16).unwrap()),
-            )
-            .await
-            .unwrap();
+        mock_state
+            .push_response(&[r#"552 Unrecognized key "process/pid""#])
+            .await;
+        let err = tor.get_pid().await.unwrap_err();
+        unpack_enum!(TorClientError::GetInfoKeyUnsupported(key) = err);
+        assert_eq!(key, "process/pid");
+
+        mock_state.push_response(&["250-process/pid=", "250 OK"]).await;
+        let err = tor.get_pid().await.unwrap_err();
+        unpack_enum!(TorClientError::ServerNoResponse = err);
+    }
+
+    #[runtime::test]
+    async fn get_version_status() {
+        let (mut tor, mock_state) = setup_test().await;
 
+        let cases = [
+            ("recommended", VersionStatus::Recommended),
+            ("new", VersionStatus::New),
+            ("new in series", VersionStatus::NewInSeries),
+            ("old", VersionStatus::Old),
+            ("unrecommended", VersionStatus::Unrecommended),
+            ("obsolete", VersionStatus::Obsolete),
+            ("unknown", VersionStatus::Unknown),
+        ];
+        for (keyword, expected) in cases.iter() {
+            let line = format!("250-status/version/current={}", keyword);
+            mock_state.push_response(&[&line, "250 OK"]).await;
+            let status = tor.get_version_status().await.unwrap();
+            assert_eq!(status, *expected);
+            assert_eq!(status.to_string(), *keyword);
+        }
         assert_eq!(
-            response.service_id,
+            mock_state.take_requests().await.pop().unwrap(),
+            "GETINFO status/version/current"
+        );
+        assert!(VersionStatus::Obsolete.is_outdated());
+        assert!(!VersionStatus::New.is_outdated());
+
+        // Older versions of tor do not support the key
+        mock_state
+            .push_response(&[r#"552 Unrecognized key "status/version/current""#])
+            .await;
+        assert_eq!(tor.get_version_status().await.unwrap(), VersionStatus::Unknown);
+
+        mock_state.push_response(&["250 OK"]).await;
+        tor.get_version_status().await.unwrap_err();
+    }
+
+    #[runtime::test]
+    async fn get_onion_service_traffic() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        const SERVICE1: &str = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+        const SERVICE2: &str = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+        let read1 = format!("250-hs/service/traffic/read/id/{}=123456", SERVICE1);
+        let written1 = format!("250-hs/service/traffic/written/id/{}=7890", SERVICE1);
+        let read2 = format!("250-hs/service/traffic/read/id/{}=0", SERVICE2);
+        let written2 = format!("250-hs/service/traffic/written/id/{}=42", SERVICE2);
+        mock_state
+            .push_response(&[
+                read1.as_str(),
+                written1.as_str(),
+                read2.as_str(),
+                written2.as_str(),
+                "250 OK",
+            ])
+            .await;
+        let traffic = tor.get_onion_service_traffic(&[SERVICE1, SERVICE2]).await.unwrap();
+        assert_eq!(traffic.len(), 2);
+        assert_eq!(traffic[SERVICE1], TrafficCounters {
+            read: 123456,
+            written: 7890
+        });
+        assert_eq!(traffic[SERVICE2], TrafficCounters { read: 0, written: 42 });
+        assert_eq!(
+            mock_state.take_requests().await.pop().unwrap(),
+            format!(
+                "GETINFO hs/service/traffic/read/id/{0} hs/service/traffic/written/id/{0} \
+                 hs/service/traffic/read/id/{1} hs/service/traffic/written/id/{1}",
+                SERVICE1, SERVICE2
+            )
+        );
+
+        // Tor does not count the traffic of onion services
+        let unrecognized = format!(r#"552 Unrecognized key "hs/service/traffic/read/id/{}""#, SERVICE1);
+        mock_state.push_response(&[unrecognized.as_str()]).await;
+        let err = tor.get_onion_service_traffic(&[SERVICE1]).await.unwrap_err();
+        unpack_enum!(TorClientError::GetInfoKeyUnsupported(key) = err);
+        assert_eq!(key, "hs/service/traffic/read/id/");
+
+        // A missing counter is an error rather than 0
+        mock_state.push_response(&[read1.as_str(), "250 OK"]).await;
+        let err = tor.get_onion_service_traffic(&[SERVICE1]).await.unwrap_err();
+        unpack_enum!(TorClientError::ParseError(err) = err);
+        assert_eq!(
+            err.0,
+            format!("Tor did not return hs/service/traffic/written/id/{}", SERVICE1)
+        );
+
+        mock_state
+            .push_response(&[read1.replace("123456", "lots").as_str(), written1.as_str(), "250 OK"])
+            .await;
+        let err = tor.get_onion_service_traffic(&[SERVICE1]).await.unwrap_err();
+        unpack_enum!(TorClientError::ParseError(_e) = err);
+    }
+
+    #[runtime::test]
+    async fn get_address_mappings() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        mock_state
+            .push_response(&[
+                "250+address-mappings/all=",
+                r#"example.com 93.184.216.34 "2021-03-04 10:38:12""#,
+                "foo.test bar.onion NEVER",
+                ".",
+                "250 OK",
+            ])
+            .await;
+        let mappings = tor.get_address_mappings().await.unwrap();
+        assert_eq!(mappings.len(), 2);
+        assert_eq!(mappings[0].address, "example.com");
+        assert_eq!(mappings[0].expiry.as_deref(), Some("2021-03-04 10:38:12"));
+        assert_eq!(mappings[1].new_address, "bar.onion");
+        assert_eq!(mappings[1].expiry, None);
+
+        mock_state.push_response(&["250-address-mappings/all=", "250 OK"]).await;
+        assert!(tor.get_address_mappings().await.unwrap().is_empty());
+    }
+
+    #[runtime::test]
+    async fn set_ports() {
+        let (mut tor, mock_state) = setup_test().await;
+        mock_state.set_canned_response(&["250 OK"]).await;
+
+        tor.set_socks_ports(&[
+            PortSpec::Auto,
+            PortSpec::Tcp("127.0.0.1:9050".parse().unwrap()),
+            PortSpec::Unix("/run/tor/my socks".into()),
+        ])
+        .await
+        .unwrap();
+        tor.set_control_ports(&[PortSpec::Unix("/run/tor/control".into())])
+            .await
+            .unwrap();
+        tor.set_socks_ports(&[]).await.unwrap();
+
+        let requests = mock_state.take_requests().await;
+        assert_eq!(requests, vec![
+            r#"SETCONF SocksPort="auto" SocksPort="127.0.0.1:9050" SocksPort="unix:\"/run/tor/my socks\"""#,
+            r#"SETCONF ControlPort="unix:/run/tor/control""#,
+            "SETCONF SocksPort",
+        ]);
+    }
+
+    #[runtime::test]
+    async fn socks_proxy_addr() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        mock_state
+            .push_response(&[
+                r#"250-net/listeners/socks="unix:/run/tor/socks" "[::1]:9050" "127.0.0.1:37321""#,
+                "250 OK",
+            ])
+            .await;
+        let addr = tor.socks_proxy_addr().await.unwrap();
+        assert_eq!(addr, ListenerAddress::Tcp("[::1]:9050".parse().unwrap()));
+
+        mock_state
+            .push_response(&[r#"250-net/listeners/socks="unix:/run/tor/socks""#, "250 OK"])
+            .await;
+        let addr = tor.socks_proxy_addr().await.unwrap();
+        assert_eq!(addr, ListenerAddress::Unix("/run/tor/socks".into()));
+
+        mock_state.push_response(&["250-net/listeners/socks=", "250 OK"]).await;
+        let err = tor.socks_proxy_addr().await.unwrap_err();
+        unpack_enum!(TorClientError::NoSocksListener = err);
+    }
+
+    #[runtime::test]
+    async fn get_socks_listeners_ok() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        mock_state
+            .set_canned_response(canned_responses::GET_INFO_NET_LISTENERS_OK)
+            .await;
+
+        let listeners = tor.get_socks_listeners().await.unwrap();
+        assert_eq!(listeners, vec![
+            ListenerAddress::Tcp(([127, 0, 0, 1], 9050).into()),
+            ListenerAddress::Unix("/run/tor/socks".into()),
+        ]);
+
+        let mut req = mock_state.take_requests().await;
+        assert_eq!(req.len(), 1);
+        assert_eq!(req.remove(0), "GETINFO net/listeners/socks");
+    }
+
+    #[runtime::test]
+    async fn get_info_kv_multiline_value_ok() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        mock_state
+            .set_canned_response(canned_responses::GET_INFO_ONIONS_DETACHED_OK)
+            .await;
+
+        let values = tor.get_info("onions/detached").await.unwrap();
+        assert_eq!(values, [
+            "mochz2xppfziim5olr5f6q27poc4vfob2xxxxxxxxxxxxxxxxxxxxxxx",
+            "nhqdqym6j35rk7tdou4cdj4gjjqagimutxxxxxxxxxxxxxxxxxxxxxxx"
+        ]);
+    }
+
+    #[runtime::test]
+    async fn get_circuit_status() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        mock_state
+            .set_canned_response(&[
+                "250+circuit-status=",
+                "1 BUILT $AAAA~relay1,$BBBB~relay2 PURPOSE=GENERAL",
+                "2 FAILED REASON=TIMEOUT",
+                ".",
+                "250 OK",
+            ])
+            .await;
+        let statuses = tor
+            .get_circuit_status()
+            .await
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(statuses[0].path, ["$AAAA~relay1", "$BBBB~relay2"]);
+        assert_eq!(statuses[1].circuit_id, "2");
+        assert_eq!(mock_state.take_requests().await, ["GETINFO circuit-status"]);
+    }
+
+    #[runtime::test]
+    async fn active_circuit_and_stream_count() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        mock_state
+            .push_response(&[
+                "250+circuit-status=",
+                "1 BUILT $AAAA~relay1,$BBBB~relay2 PURPOSE=GENERAL",
+                "2 FAILED REASON=TIMEOUT",
+                "3 LAUNCHED",
+                "4 CLOSED REASON=FINISHED",
+                "5 GUARD_WAIT $AAAA~relay1",
+                ".",
+                "250 OK",
+            ])
+            .await;
+        mock_state
+            .push_response(&[
+                "250+stream-status=",
+                "1 SUCCEEDED 1 example.com:443",
+                "2 CLOSED 1 example.com:80 REASON=DONE",
+                "3 DETACHED 0 tari.com:443 REASON=TIMEOUT",
+                "4 FAILED 0 tari.com:80 REASON=CONNECTREFUSED",
+                ".",
+                "250 OK",
+            ])
+            .await;
+        mock_state.push_response(&["250-stream-status=", "250 OK"]).await;
+        mock_state
+            .push_response(&["250+circuit-status=", "1", ".", "250 OK"])
+            .await;
+
+        assert_eq!(tor.active_circuit_count().await.unwrap(), 3);
+        assert_eq!(tor.active_stream_count().await.unwrap(), 2);
+        assert_eq!(tor.active_stream_count().await.unwrap(), 0);
+        let err = tor.active_circuit_count().await.unwrap_err();
+        unpack_enum!(TorClientError::ParseError(_err) = err);
+        assert_eq!(mock_state.take_requests().await, [
+            "GETINFO circuit-status",
+            "GETINFO stream-status",
+            "GETINFO stream-status",
+            "GETINFO circuit-status",
+        ]);
+    }
+
+    #[runtime::test]
+    async fn get_microdescriptor() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        mock_state
+            .set_canned_response(canned_responses::GET_INFO_MICRODESCRIPTOR_OK)
+            .await;
+
+        let descriptor = tor
+            .get_microdescriptor("$9695DFC35FFEB861329B9F1AB04C46397020CE31")
+            .await
+            .unwrap();
+        assert_eq!(descriptor.fingerprint, "9695DFC35FFEB861329B9F1AB04C46397020CE31");
+        assert!(descriptor
+            .body
+            .starts_with("onion-key\n-----BEGIN RSA PUBLIC KEY-----\n"));
+        assert!(descriptor
+            .body
+            .ends_with("id ed25519 8RH34kO07AT9WF9Wz5bAkRufgg1AW9Dq9rEv3S1TMv4"));
+        assert_eq!(descriptor.exit_policy_summary.as_deref(), Some("accept 80,443"));
+        assert_eq!(descriptor.address, None);
+
+        let request = mock_state.take_requests().await.pop().unwrap();
+        assert_eq!(request, "GETINFO md/id/9695DFC35FFEB861329B9F1AB04C46397020CE31");
+
+        mock_state
+            .set_canned_response(&["552 Unrecognized key \"md/id/0000000000000000000000000000000000000000\""])
+            .await;
+        let err = tor
+            .get_microdescriptor("0000000000000000000000000000000000000000")
+            .await
+            .unwrap_err();
+        unpack_enum!(TorClientError::DescriptorNotFound { fingerprint } = err);
+        assert_eq!(fingerprint, "0000000000000000000000000000000000000000");
+    }
+
+    #[runtime::test]
+    async fn adopt_existing_services() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        mock_state
+            .push_response(canned_responses::GET_INFO_ONIONS_DETACHED_OK)
+            .await;
+        mock_state
+            .push_response(&["551 No onion services of the specified type."])
+            .await;
+        let service_ids = tor.adopt_existing_services().await.unwrap();
+        assert_eq!(service_ids, [
+            "mochz2xppfziim5olr5f6q27poc4vfob2xxxxxxxxxxxxxxxxxxxxxxx",
+            "nhqdqym6j35rk7tdou4cdj4gjjqagimutxxxxxxxxxxxxxxxxxxxxxxx"
+        ]);
+        assert_eq!(mock_state.take_requests().await, [
+            "GETINFO onions/detached",
+            "GETINFO onions/current"
+        ]);
+
+        mock_state.set_canned_response(canned_responses::ERR_552).await;
+        let err = tor.adopt_existing_services().await.unwrap_err();
+        unpack_enum!(TorClientError::TorCommandFailed(_s) = err);
+    }
+
+    #[runtime::test]
+    async fn get_onion_inventory() {
+        let (mut tor, mock_state) = setup_test().await;
+        let current = OnionAddress::from_public_key(&[1u8; 32]);
+        let detached = [
+            OnionAddress::from_public_key(&[2u8; 32]),
+            OnionAddress::from_public_key(&[3u8; 32]),
+        ];
+
+        let current_line = format!("250-onions/current={}", current.service_id());
+        let detached_lines = [detached[0].to_string(), detached[1].service_id().to_string()];
+        mock_state
+            .push_response(&[
+                &current_line,
+                "250+onions/detached=",
+                &detached_lines[0],
+                &detached_lines[1],
+                ".",
+                "250 OK",
+            ])
+            .await;
+        let inventory = tor.get_onion_inventory().await.unwrap();
+        assert_eq!(inventory.current, [current.clone()]);
+        assert_eq!(inventory.detached, detached);
+        assert!(inventory.is_detached(&detached[1]));
+        assert!(!inventory.is_detached(&current));
+        assert_eq!(mock_state.take_requests().await, [
+            "GETINFO onions/current onions/detached"
+        ]);
+
+        // No detached services, so tor fails the combined query
+        const NO_SERVICES: &[&str] = &["551 No onion services of the specified type."];
+        mock_state.push_response(NO_SERVICES).await;
+        mock_state.push_response(&[&current_line, "250 OK"]).await;
+        mock_state.push_response(NO_SERVICES).await;
+        let inventory = tor.get_onion_inventory().await.unwrap();
+        assert_eq!(inventory.current, [current]);
+        assert!(inventory.detached.is_empty());
+        assert_eq!(mock_state.take_requests().await, [
+            "GETINFO onions/current onions/detached",
+            "GETINFO onions/current",
+            "GETINFO onions/detached",
+        ]);
+    }
+
+    #[runtime::test]
+    async fn execute_when_ready() {
+        let (mut tor, mock_state, clock) = setup_test_with_mock_clock().await;
+
+        const NOT_ESTABLISHED: &[&str] = &["250-status/circuit-established=0", "250 OK"];
+        mock_state.push_response(NOT_ESTABLISHED).await;
+        mock_state.push_response(NOT_ESTABLISHED).await;
+        mock_state
+            .push_response(&["250-status/circuit-established=1", "250 OK"])
+            .await;
+        mock_state.push_response(canned_responses::OK).await;
+        tor.execute_when_ready(commands::DelOnion::new("dummy"), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(mock_state.take_requests().await, [
+            "GETINFO status/circuit-established",
+            "GETINFO status/circuit-established",
+            "GETINFO status/circuit-established",
+            "DEL_ONION dummy"
+        ]);
+
+        assert_eq!(clock.elapsed(), READY_POLL_INTERVAL * 2);
+
+        mock_state.set_canned_response(NOT_ESTABLISHED).await;
+        let err = tor
+            .execute_when_ready(commands::DelOnion::new("dummy"), Duration::from_secs(10))
+            .await
+            .unwrap_err();
+        unpack_enum!(TorClientError::NotReady(_d) = err);
+        let requests = mock_state.take_requests().await;
+        assert_eq!(requests.len(), 41);
+        assert!(requests.iter().all(|r| r == "GETINFO status/circuit-established"));
+        assert_eq!(clock.elapsed(), READY_POLL_INTERVAL * 2 + Duration::from_secs(10));
+    }
+
+    #[runtime::test]
+    async fn execute_with_retry() {
+        let (mut tor, mock_state, clock) = setup_test_with_mock_clock().await;
+
+        const RESOURCE_EXHAUSTED: &[&str] = &["451 Resource exhausted"];
+        mock_state.push_response(RESOURCE_EXHAUSTED).await;
+        mock_state.push_response(&["250-version=0.4.5.6", "250 OK"]).await;
+        let version = tor.execute_with_retry(commands::get_info("version"), 3).await.unwrap();
+        assert_eq!(version, ["0.4.5.6"]);
+        assert_eq!(mock_state.take_requests().await.len(), 2);
+        assert_eq!(clock.elapsed(), RETRY_DELAY);
+
+        // Creating a new onion service is not idempotent, so it is never retried
+        mock_state.set_canned_response(RESOURCE_EXHAUSTED).await;
+        let command = commands::AddOnion::builder().add_port(8080).build().unwrap();
+        assert!(!command.is_idempotent());
+        let err = tor.execute_with_retry(command, 3).await.unwrap_err();
+        unpack_enum!(TorClientError::AddOnionFailed { code, reason } = err);
+        assert_eq!(code, 451);
+        assert_eq!(reason, "Resource exhausted");
+        assert_eq!(mock_state.take_requests().await.len(), 1);
+
+        // Persistent failures are retried up to the maximum number of attempts
+        let err = tor
+            .execute_with_retry(commands::get_info("version"), 3)
+            .await
+            .unwrap_err();
+        unpack_enum!(TorClientError::TorCommandFailed(_msg) = err);
+        assert_eq!(mock_state.take_requests().await.len(), 3);
+        assert_eq!(clock.elapsed(), RETRY_DELAY * 3);
+
+        // Other failures are not retried
+        mock_state.set_canned_response(canned_responses::ERR_552).await;
+        tor.execute_with_retry(commands::get_info("version"), 3)
+            .await
+            .unwrap_err();
+        assert_eq!(mock_state.take_requests().await.len(), 1);
+    }
+
+    #[runtime::test]
+    async fn get_info_err() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        mock_state.set_canned_response(canned_responses::ERR_552).await;
+
+        let err = tor.get_info("net/listeners/socks").await.unwrap_err();
+        unpack_enum!(TorClientError::TorCommandFailed(msg) = err);
+        // The verb is added, but not the key which could be a secret for other commands
+        assert!(msg.starts_with("GETINFO: "));
+        assert!(!msg.contains("net/listeners/socks"));
+    }
+
+    #[runtime::test]
+    async fn add_onion_from_private_key_ok() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        // The version is queried before a v2 onion service is added
+        mock_state.set_canned_response(canned_responses::PROTOCOLINFO_OK).await;
+        tor.protocol_info().await.unwrap();
+
+        mock_state
+            .set_canned_response(canned_responses::ADD_ONION_RSA1024_OK)
+            .await;
+
+        let private_key = PrivateKey::Rsa1024("dummy-key".into());
+        let response = tor
+            .add_onion_from_private_key(&private_key, vec![], 8080, None)
+            .await
+            .unwrap();
+
+        assert_eq!(response.service_id, "62q4tswkxp74dtn7");
+        assert!(response.private_key.is_none());
+
+        let request = mock_state.take_requests().await.pop().unwrap();
+        assert_eq!(request, "ADD_ONION RSA1024:dummy-key Port=8080,127.0.0.1:8080");
+    }
+
+    #[runtime::test]
+    async fn add_onion_ok() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        mock_state.set_canned_response(canned_responses::ADD_ONION_OK).await;
+
+        let response = tor
+            .add_onion_custom(
+                KeyType::New,
+                KeyBlob::Best,
+                vec![],
+                8080,
+                Some(NonZeroU16::new(10u16).unwrap()),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            response.service_id,
             "qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid"
         );
         assert_eq!(
@@ -449,58 +2566,690 @@ mod test {
     }
 
     #[runtime::test]
-    async fn add_onion_discard_pk_ok() {
+    async fn force_remove_onion() {
+        let (mut tor, mock_state) = setup_test().await;
+        let private_key = PrivateKey::from_ed25519_secret(&[2u8; 64]);
+        let onion_address = private_key.onion_address().unwrap();
+        let service_id = onion_address.service_id();
+        let service_id_line = format!("250-ServiceID={}", service_id);
+        let add_onion = format!(
+            "ADD_ONION ED25519-V3:{} Flags=DiscardPK Port=1,127.0.0.1:1",
+            data_encoding::BASE64.encode(&[2u8; 64])
+        );
+
+        // A detached service is removed directly
+        mock_state.push_response(&["250 OK"]).await;
+        tor.force_remove_onion(service_id, Some(&private_key)).await.unwrap();
+        assert_eq!(mock_state.take_requests().await, vec![format!(
+            "DEL_ONION {}",
+            service_id
+        )]);
+
+        // Otherwise the service is adopted with its key and then removed
+        mock_state.push_response(&["552 Unknown Onion Service id"]).await;
+        mock_state.push_response(&[&service_id_line, "250 OK"]).await;
+        mock_state.push_response(&["250 OK"]).await;
+        tor.force_remove_onion(service_id, Some(&private_key)).await.unwrap();
+        assert_eq!(mock_state.take_requests().await, vec![
+            format!("DEL_ONION {}", service_id),
+            add_onion.clone(),
+            format!("DEL_ONION {}", service_id),
+        ]);
+
+        // A service owned by another connection cannot be adopted
+        mock_state.push_response(&["552 Unknown Onion Service id"]).await;
+        mock_state.push_response(&["550 Onion address collision"]).await;
+        let err = tor
+            .force_remove_onion(service_id, Some(&private_key))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TorClientError::OnionServiceExists { .. }));
+        assert_eq!(mock_state.take_requests().await.len(), 2);
+
+        // Without the key the service cannot be adopted
+        mock_state.push_response(&["552 Unknown Onion Service id"]).await;
+        let err = tor.force_remove_onion(service_id, None).await.unwrap_err();
+        unpack_enum!(TorClientError::TorCommandFailed(_msg) = err);
+        mock_state.take_requests().await;
+
+        // A key for a different service is rejected before anything is sent
+        let other_service_id = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd";
+        let err = tor
+            .force_remove_onion(other_service_id, Some(&private_key))
+            .await
+            .unwrap_err();
+        match err {
+            TorClientError::PrivateKeyServiceMismatch { key_service_id, .. } => assert_eq!(key_service_id, service_id),
+            err => panic!("Unexpected error {:?}", err),
+        }
+        assert!(mock_state.take_requests().await.is_empty());
+
+        // The address of a key of another type is checked once the service has been adopted, which is then removed
+        let other_key = PrivateKey::Other {
+            key_type: "X448".to_string(),
+            key: "this-is-a-key".to_string(),
+        };
+        mock_state.push_response(&["552 Unknown Onion Service id"]).await;
+        mock_state.push_response(&[&service_id_line, "250 OK"]).await;
+        mock_state.push_response(&["250 OK"]).await;
+        let err = tor
+            .force_remove_onion("62q4tswkxp74dtn7", Some(&other_key))
+            .await
+            .unwrap_err();
+        unpack_enum!(TorClientError::PrivateKeyServiceMismatch { key_service_id, .. } = err);
+        assert_eq!(key_service_id, service_id);
+        assert_eq!(
+            mock_state.take_requests().await.pop().unwrap(),
+            format!("DEL_ONION {}", service_id)
+        );
+    }
+
+    #[runtime::test]
+    async fn add_onion_err() {
         let (mut tor, mock_state) = setup_test().await;
 
+        mock_state.set_canned_response(canned_responses::ERR_552).await;
+
+        let err = tor
+            .add_onion_custom(KeyType::New, KeyBlob::Ed25519V3, vec![], 8080, None)
+            .await
+            .unwrap_err();
+
+        unpack_enum!(TorClientError::AddOnionFailed { code, reason } = err);
+        assert!(reason.starts_with("Unrecognised"));
+        assert_eq!(code, 552);
+
         mock_state
-            .set_canned_response(canned_responses::ADD_ONION_DISCARDPK_OK)
+            .push_response(&["551-Failed to add onion service", "551 Could not bind the port"])
             .await;
+        let err = tor
+            .add_onion_custom(KeyType::New, KeyBlob::Ed25519V3, vec![], 8080, None)
+            .await
+            .unwrap_err();
+        unpack_enum!(TorClientError::AddOnionFailed { code, reason } = err);
+        assert_eq!(code, 551);
+        assert_eq!(reason, "Failed to add onion service\nCould not bind the port");
+    }
 
-        let response = tor
+    #[runtime::test]
+    async fn parse_error_names_command() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        mock_state
+            .push_response(&[
+                "250-ServiceID=qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid",
+                "250-PrivateKey=ED25519-V3",
+                "250 OK",
+            ])
+            .await;
+        let err = tor
+            .add_onion_custom(KeyType::New, KeyBlob::Ed25519V3, vec![], 8080, None)
+            .await
+            .unwrap_err();
+        unpack_enum!(TorClientError::ParseError(err) = err);
+        assert_eq!(err.0, "while parsing ADD_ONION reply: Failed to parse private key");
+    }
+
+    #[runtime::test]
+    async fn add_onion_invalid_not_sent() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        let err = tor
             .add_onion_custom(
-                KeyType::Rsa1024,
-                KeyBlob::Rsa1024,
-                vec![
-                    AddOnionFlag::DiscardPK,
-                    AddOnionFlag::Detach,
-                    AddOnionFlag::BasicAuth,
-                    AddOnionFlag::MaxStreamsCloseCircuit,
-                    AddOnionFlag::NonAnonymous,
-                ],
-                PortMapping::new(8080, SocketAddr::from(([127u8, 0, 0, 1], 8081u16))),
+                KeyType::New,
+                KeyBlob::Ed25519V3,
+                vec![AddOnionFlag::BasicAuth],
+                8080,
                 None,
             )
+            .await
+            .unwrap_err();
+
+        unpack_enum!(TorClientError::AddOnionFlagNotSupported(_flag) = err);
+        assert!(mock_state.take_requests().await.is_empty());
+
+        let err = tor
+            .add_onion_custom(KeyType::Ed25519V3, KeyBlob::String(""), vec![], 8080, None)
+            .await
+            .unwrap_err();
+        unpack_enum!(TorClientError::AddOnionInvalidKey(_msg) = err);
+        assert!(mock_state.take_requests().await.is_empty());
+    }
+
+    #[runtime::test]
+    async fn last_reply() {
+        let (mut tor, mock_state) = setup_test().await;
+        assert!(tor.last_reply().is_none());
+
+        mock_state.set_canned_response(canned_responses::ADD_ONION_OK).await;
+        tor.add_onion_custom(KeyType::New, KeyBlob::Best, vec![], 8080, None)
+            .await
+            .unwrap();
+        assert_eq!(tor.last_reply().unwrap(), (250, "OK".to_string()));
+
+        mock_state.set_canned_response(canned_responses::ERR_552).await;
+        tor.del_onion("some-fake-id").await.unwrap_err();
+        assert_eq!(
+            tor.last_reply().unwrap(),
+            (552, "Unrecognised configuration key \"dummy\"".to_string())
+        );
+    }
+
+    #[runtime::test]
+    async fn command_buffer_reuse() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        mock_state.set_canned_response(canned_responses::ADD_ONION_OK).await;
+        tor.add_onion_custom(KeyType::New, KeyBlob::Best, vec![AddOnionFlag::Detach], 8080, None)
+            .await
+            .unwrap();
+        // The reply lines are drained from the same vec for every command, so it is only allocated once
+        let response_buf = (tor.response_buf.as_ptr(), tor.response_buf.capacity());
+        assert!(response_buf.1 >= canned_responses::ADD_ONION_OK.len());
+        mock_state.set_canned_response(canned_responses::OK).await;
+        tor.del_onion("abc").await.unwrap();
+        assert!(tor.response_buf.is_empty());
+        assert_eq!((tor.response_buf.as_ptr(), tor.response_buf.capacity()), response_buf);
+
+        let requests = mock_state.take_requests().await;
+        assert_eq!(requests, vec![
+            "ADD_ONION NEW:BEST Flags=Detach Port=8080,127.0.0.1:8080",
+            "DEL_ONION abc",
+        ]);
+
+        // The buffer for the next command comes back from the monitor empty, but with the capacity of its last use
+        let buf = tor.take_command_buf();
+        assert!(buf.is_empty());
+        assert!(buf.capacity() > 0);
+    }
+
+    #[runtime::test]
+    async fn events_not_enabled_skipped() {
+        let (mut tor, mock_state) = setup_test().await;
+        let mut events = tor.get_event_stream();
+
+        mock_state.set_canned_response(canned_responses::OK).await;
+        tor.set_events(&["NETWORK_LIVENESS"]).await.unwrap();
+
+        // BW was not enabled by this client, so it is skipped even though there is a subscriber
+        mock_state
+            .set_canned_response(&["650 BW 1024 2048", "650 NETWORK_LIVENESS UP", "250 OK"])
+            .await;
+        tor.del_onion("abc").await.unwrap();
+        unpack_enum!(TorControlEvent::NetworkLivenessUp = events.recv().await.unwrap());
+        assert!(events.try_recv().is_err());
+
+        // A failed SETEVENTS keeps the previous events
+        mock_state.set_canned_response(canned_responses::ERR_552).await;
+        tor.set_events(&["BW"]).await.unwrap_err();
+        mock_state
+            .set_canned_response(&["650 BW 1024 2048", "650 NETWORK_LIVENESS DOWN", "250 OK"])
+            .await;
+        tor.del_onion("abc").await.unwrap();
+        unpack_enum!(TorControlEvent::NetworkLivenessDown = events.recv().await.unwrap());
+        assert!(events.try_recv().is_err());
+    }
+
+    #[runtime::test]
+    async fn events_without_subscribers_skipped() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        mock_state
+            .set_canned_response(&[
+                "650 BW 1024 2048",
+                "650+ORCONN",
+                "multiline event body",
+                ".",
+                "650 OK",
+                "250 OK",
+            ])
+            .await;
+        tor.del_onion("abc").await.unwrap();
+        assert_eq!(tor.last_reply().unwrap(), (250, "OK".to_string()));
+
+        let mut events = tor.get_event_stream();
+        mock_state
+            .set_canned_response(&["650 NETWORK_LIVENESS UP", "250 OK"])
+            .await;
+        tor.del_onion("abc").await.unwrap();
+        let event = events.recv().await.unwrap();
+        unpack_enum!(TorControlEvent::NetworkLivenessUp = event);
+    }
+
+    #[runtime::test]
+    async fn protocol_info_cached() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        mock_state.set_canned_response(canned_responses::PROTOCOLINFO_OK).await;
+
+        let version = tor.get_tor_version().await.unwrap();
+        assert_eq!(version, TorVersion::new(0, 4, 5, 6));
+        assert_eq!(tor.get_tor_version().await.unwrap(), version);
+        let info = tor.protocol_info().await.unwrap();
+        assert_eq!(info.auth_methods(), &["COOKIE", "SAFECOOKIE"]);
+
+        let requests = mock_state.take_requests().await;
+        assert_eq!(requests, vec!["PROTOCOLINFO 1"]);
+
+        tor.refresh_protocol_info().await.unwrap();
+        let requests = mock_state.take_requests().await;
+        assert_eq!(requests, vec!["PROTOCOLINFO 1"]);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[runtime::test]
+    async fn execute_tracing_span() {
+        use std::{
+            fmt::Write,
+            sync::{Arc, Mutex},
+        };
+        use tracing::{field::Field, span, Event, Metadata, Subscriber};
+
+        /// Records each span as its name followed by its fields
+        #[derive(Clone, Default)]
+        struct SpanRecorder(Arc<Mutex<Vec<String>>>);
+
+        struct FieldWriter<'a>(&'a mut String);
+
+        impl tracing::field::Visit for FieldWriter<'_> {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                write!(self.0, " {}={:?}", field.name(), value).unwrap();
+            }
+        }
+
+        impl Subscriber for SpanRecorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+                let mut spans = self.0.lock().unwrap();
+                let mut s = span.metadata().name().to_string();
+                span.record(&mut FieldWriter(&mut s));
+                spans.push(s);
+                span::Id::from_u64(spans.len() as u64)
+            }
+
+            fn record(&self, id: &span::Id, values: &span::Record<'_>) {
+                let mut spans = self.0.lock().unwrap();
+                values.record(&mut FieldWriter(&mut spans[id.into_u64() as usize - 1]));
+            }
+
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+            fn event(&self, _: &Event<'_>) {}
+
+            fn enter(&self, _: &span::Id) {}
+
+            fn exit(&self, _: &span::Id) {}
+        }
+
+        let recorder = SpanRecorder::default();
+        let _guard = tracing::subscriber::set_default(recorder.clone());
+
+        let (mut tor, mock_state) = setup_test().await;
+        mock_state.set_canned_response(canned_responses::ADD_ONION_OK).await;
+        let private_key = PrivateKey::Ed25519V3("this-is-a-secret-key".to_string());
+        tor.add_onion_from_private_key(&private_key, vec![], 8080, None)
             .await
             .unwrap();
 
+        let spans = recorder.0.lock().unwrap();
+        let span = spans.iter().find(|s| s.starts_with("tor_command")).unwrap();
+        assert_eq!(span, "tor_command verb=\"ADD_ONION\" code=250");
+        assert!(spans.iter().all(|s| !s.contains("this-is-a-secret-key")));
+    }
+
+    #[runtime::test]
+    async fn add_onion_v3_ok() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        mock_state.set_canned_response(canned_responses::PROTOCOLINFO_OK).await;
+        tor.protocol_info().await.unwrap();
+        let _ = mock_state.take_requests().await;
+
+        mock_state.set_canned_response(canned_responses::ADD_ONION_OK).await;
+        let response = tor.add_onion_v3(vec![], 8080, None).await.unwrap();
         assert_eq!(
             response.service_id,
             "qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid"
         );
-        assert_eq!(response.private_key, None);
+        assert!(matches!(response.private_key, Some(PrivateKey::Ed25519V3(_))));
 
         let request = mock_state.take_requests().await.pop().unwrap();
-        assert_eq!(
-            request,
-            "ADD_ONION RSA1024:RSA1024 Flags=DiscardPK,Detach,BasicAuth,MaxStreamsCloseCircuit,NonAnonymous \
-             Port=8080,127.0.0.1:8081"
-        );
+        assert_eq!(request, "ADD_ONION NEW:ED25519-V3 Port=8080,127.0.0.1:8080");
     }
 
     #[runtime::test]
-    async fn add_onion_err() {
+    async fn negotiate() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        mock_state.push_response(canned_responses::PROTOCOLINFO_OK).await;
+        mock_state.push_response(canned_responses::OK).await;
+        let info = tor.negotiate(true).await.unwrap();
+        assert_eq!(info.tor_version(), TorVersion::new(0, 4, 5, 6));
+        assert!(tor.has_extended_events());
+
+        // Already negotiated
+        tor.negotiate(true).await.unwrap();
+        tor.negotiate(false).await.unwrap();
+
+        let requests = mock_state.take_requests().await;
+        assert_eq!(requests, vec![
+            "PROTOCOLINFO 1",
+            "USEFEATURE EXTENDED_EVENTS VERBOSE_NAMES"
+        ]);
+    }
+
+    #[runtime::test]
+    async fn negotiate_without_extended_events() {
         let (mut tor, mock_state) = setup_test().await;
 
+        mock_state.set_canned_response(canned_responses::PROTOCOLINFO_OK).await;
+        tor.negotiate(false).await.unwrap();
+        assert!(!tor.has_extended_events());
+        assert_eq!(mock_state.take_requests().await, vec!["PROTOCOLINFO 1"]);
+
         mock_state.set_canned_response(canned_responses::ERR_552).await;
+        let err = tor.negotiate(true).await.unwrap_err();
+        unpack_enum!(TorClientError::TorCommandFailed(_msg) = err);
+        assert!(!tor.has_extended_events());
+    }
+
+    #[runtime::test]
+    async fn send_raw() {
+        let (mut tor, mock_state) = setup_test().await;
 
+        let mut events = tor.get_event_stream();
+        mock_state
+            .set_canned_response(&[
+                "250+config/defaults=",
+                "SocksPort 9050",
+                ".",
+                "650 NETWORK_LIVENESS UP",
+                "250 OK",
+            ])
+            .await;
+        let lines = tor.send_raw("GETINFO config/defaults").await.unwrap();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].is_multiline);
+        assert_eq!(lines[0].value(), "config/defaults=\nSocksPort 9050");
+        assert_eq!(lines[1].code(), 250);
+        assert_eq!(lines[1].value(), "OK");
+        unpack_enum!(TorControlEvent::NetworkLivenessUp = events.recv().await.unwrap());
+
+        let err = tor.send_raw("GETINFO version\r\nSIGNAL HALT").await.unwrap_err();
+        unpack_enum!(TorClientError::RawCommandNotSingleLine = err);
+
+        let requests = mock_state.take_requests().await;
+        assert_eq!(requests, vec!["GETINFO config/defaults"]);
+    }
+
+    #[runtime::test]
+    async fn wait_for_circuit_established() {
+        let (mut tor, mock_state, clock) = setup_test_with_mock_clock().await;
+
+        // Polled in the same way as wait_until_ready, without enabling any events
+        mock_state
+            .push_response(&["250-status/circuit-established=0", "250 OK"])
+            .await;
+        mock_state
+            .push_response(&["250-status/circuit-established=1", "250 OK"])
+            .await;
+        tor.wait_for_circuit_established(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(mock_state.take_requests().await, [
+            "GETINFO status/circuit-established",
+            "GETINFO status/circuit-established"
+        ]);
+
+        assert_eq!(clock.elapsed(), READY_POLL_INTERVAL);
+
+        // Tor is polled until the next poll would be after the timeout
+        mock_state
+            .set_canned_response(&["250-status/circuit-established=0", "250 OK"])
+            .await;
         let err = tor
-            .add_onion_custom(KeyType::Ed25519V3, KeyBlob::Ed25519V3, vec![], 8080, None)
+            .wait_for_circuit_established(Duration::from_secs(10))
             .await
             .unwrap_err();
+        unpack_enum!(TorClientError::NotReady(_t) = err);
+        assert_eq!(mock_state.take_requests().await.len(), 41);
+        assert_eq!(clock.elapsed(), READY_POLL_INTERVAL + Duration::from_secs(10));
+        assert!(tor.enabled_events.is_empty());
+    }
 
+    #[runtime::test]
+    async fn execute_timed() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        const DELAY: Duration = Duration::from_millis(50);
+        mock_state.set_response_delay(DELAY).await;
+        mock_state.push_response(&["250-version=0.4.5.6", "250 OK"]).await;
+        let (values, elapsed) = tor.execute_timed(commands::get_info("version")).await.unwrap();
+        assert_eq!(values, ["0.4.5.6"]);
+        assert!(elapsed >= DELAY);
+        assert!(elapsed < Duration::from_secs(5));
+
+        mock_state.set_response_delay(Duration::from_secs(0)).await;
+        mock_state.push_response(canned_responses::ERR_552).await;
+        let err = tor.execute_timed(commands::get_info("version")).await.unwrap_err();
         unpack_enum!(TorClientError::TorCommandFailed(_s) = err);
     }
 
+    #[runtime::test]
+    async fn toggle_guard_events() {
+        let (mut tor, mock_state) = setup_test().await;
+        let mut events = tor.get_event_stream();
+
+        mock_state.push_response(canned_responses::OK).await;
+        mock_state.push_response(canned_responses::OK).await;
+        tor.set_events(&["NETWORK_LIVENESS"]).await.unwrap();
+        tor.enable_event("GUARD").await.unwrap();
+        // Already enabled
+        tor.enable_event("GUARD").await.unwrap();
+
+        mock_state
+            .push_response(&[
+                "650 GUARD ENTRY $7EA6EAD6FD83083C538F44038BBFA077587DD755~relay1 NEW",
+                "250 OK",
+            ])
+            .await;
+        tor.disable_event("GUARD").await.unwrap();
+        unpack_enum!(TorControlEvent::Guard(event) = events.recv().await.unwrap());
+        assert_eq!(event.relay.nickname.as_deref(), Some("relay1"));
+        // Not enabled
+        tor.disable_event("GUARD").await.unwrap();
+
+        assert_eq!(mock_state.take_requests().await, [
+            "SETEVENTS NETWORK_LIVENESS",
+            "SETEVENTS NETWORK_LIVENESS GUARD",
+            "SETEVENTS NETWORK_LIVENESS",
+        ]);
+    }
+
+    #[runtime::test]
+    async fn wait_cancelled() {
+        let (_, mock_state, socket) = test_server::spawn().await;
+        let (event_tx, _) = broadcast::channel(10);
+        let mut tor = TorControlPortClient::new(socket, event_tx.clone());
+
+        mock_state
+            .push_response(&["250-status/circuit-established=0", "250 OK"])
+            .await;
+        let (cancel_tx, cancel_rx) = oneshot::channel::<()>();
+        let cancel_after = task::spawn(async move {
+            time::delay_for(Duration::from_millis(50)).await;
+            cancel_tx.send(()).unwrap();
+        });
+        let start = Instant::now();
+        let err = tor
+            .wait_for_circuit_established_cancellable(Duration::from_secs(60), cancel_rx)
+            .await
+            .unwrap_err();
+        unpack_enum!(TorClientError::Cancelled = err);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        cancel_after.await.unwrap();
+
+        // Cancelling before starting abandons the wait once the status has been checked
+        mock_state.push_response(canned_responses::OK).await;
+        mock_state
+            .push_response(&["250-status/enough-dir-info=0", "250 OK"])
+            .await;
+        let err = tor
+            .wait_for_enough_dir_info_cancellable(Duration::from_secs(60), future::ready(()))
+            .await
+            .unwrap_err();
+        unpack_enum!(TorClientError::Cancelled = err);
+        // The subscription of the wait was dropped with it, and the events it enabled are disabled again
+        assert_eq!(event_tx.receiver_count(), 0);
+        assert!(tor.enabled_events.is_empty());
+
+        mock_state
+            .push_response(&["250-status/circuit-established=0", "250 OK"])
+            .await;
+        let err = tor
+            .wait_until_ready_cancellable(Duration::from_secs(60), future::ready(()))
+            .await
+            .unwrap_err();
+        unpack_enum!(TorClientError::Cancelled = err);
+        assert_eq!(mock_state.take_requests().await, [
+            "GETINFO status/circuit-established",
+            "SETEVENTS STATUS_CLIENT",
+            "GETINFO status/enough-dir-info",
+            "SETEVENTS ",
+            "GETINFO status/circuit-established",
+        ]);
+    }
+
+    #[runtime::test]
+    async fn wait_for_enough_dir_info() {
+        let (mut tor, mock_state, clock) = setup_test_with_mock_clock().await;
+
+        mock_state.push_response(canned_responses::OK).await;
+        mock_state.push_response(canned_responses::OK).await;
+        tor.set_events(&["NETWORK_LIVENESS"]).await.unwrap();
+        mock_state
+            .push_response(&[
+                "250-status/enough-dir-info=0",
+                "650 STATUS_CLIENT NOTICE ENOUGH_DIR_INFO",
+                "250 OK",
+            ])
+            .await;
+        tor.wait_for_enough_dir_info(Duration::from_secs(5)).await.unwrap();
+        // Previously set events are kept, and restored once the wait is over
+        assert_eq!(mock_state.take_requests().await, [
+            "SETEVENTS NETWORK_LIVENESS",
+            "SETEVENTS NETWORK_LIVENESS STATUS_CLIENT",
+            "GETINFO status/enough-dir-info",
+            "SETEVENTS NETWORK_LIVENESS",
+        ]);
+        assert_eq!(tor.enabled_events, ["NETWORK_LIVENESS"]);
+
+        mock_state.push_response(canned_responses::OK).await;
+        mock_state
+            .push_response(&["250-status/enough-dir-info=0", "250 OK"])
+            .await;
+        let err = tor.wait_for_enough_dir_info(Duration::from_secs(60)).await.unwrap_err();
+        unpack_enum!(TorClientError::NotEnoughDirInfo(_t) = err);
+        assert_eq!(clock.elapsed(), Duration::from_secs(60));
+        assert_eq!(mock_state.take_requests().await, [
+            "SETEVENTS NETWORK_LIVENESS STATUS_CLIENT",
+            "GETINFO status/enough-dir-info",
+            "SETEVENTS NETWORK_LIVENESS",
+        ]);
+        assert_eq!(tor.enabled_events, ["NETWORK_LIVENESS"]);
+
+        // Events that were already enabled are left as they are
+        mock_state.push_response(canned_responses::OK).await;
+        tor.enable_event("STATUS_CLIENT").await.unwrap();
+        mock_state
+            .push_response(&["250-status/enough-dir-info=1", "250 OK"])
+            .await;
+        tor.wait_for_enough_dir_info(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(mock_state.take_requests().await, [
+            "SETEVENTS NETWORK_LIVENESS STATUS_CLIENT",
+            "GETINFO status/enough-dir-info",
+        ]);
+        assert_eq!(tor.enabled_events, ["NETWORK_LIVENESS", "STATUS_CLIENT"]);
+    }
+
+    #[runtime::test]
+    async fn close() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        mock_state.push_response(canned_responses::OK).await;
+        mock_state.push_response(&["250 closing connection"]).await;
+        tor.authenticate(&Authentication::None).await.unwrap();
+        tor.close(true).await.unwrap();
+        assert!(!tor.is_connected());
+        assert_eq!(mock_state.take_requests().await, ["AUTHENTICATE", "QUIT"]);
+
+        let err = tor.get_info("version").await.unwrap_err();
+        unpack_enum!(TorClientError::ClientClosed = err);
+        let err = tor.close(true).await.unwrap_err();
+        unpack_enum!(TorClientError::ClientClosed = err);
+        assert!(mock_state.take_requests().await.is_empty());
+    }
+
+    #[runtime::test]
+    async fn close_without_quit() {
+        let (mut tor, mock_state) = setup_test().await;
+
+        tor.close(false).await.unwrap();
+        let err = tor.del_onion("dummy").await.unwrap_err();
+        unpack_enum!(TorClientError::ClientClosed = err);
+        assert!(mock_state.take_requests().await.is_empty());
+    }
+
+    #[runtime::test]
+    async fn signal_newnym_rate_limited() {
+        let (_, mock_state, socket) = test_server::spawn().await;
+        let (event_tx, _) = broadcast::channel(1);
+        let clock = MockClock::new();
+        let mut tor = TorControlPortClient::builder()
+            .with_clock(Arc::new(clock.clone()))
+            .build(socket, event_tx);
+        mock_state.set_canned_response(canned_responses::OK).await;
+
+        // Not rate limited by default
+        tor.signal_newnym().await.unwrap();
+        tor.signal_newnym().await.unwrap();
+        assert_eq!(mock_state.take_requests().await, ["SIGNAL NEWNYM", "SIGNAL NEWNYM"]);
+
+        tor.set_newnym_min_interval(Some(Duration::from_secs(10)));
+        let err = tor.signal_newnym().await.unwrap_err();
+        unpack_enum!(TorClientError::NewnymRateLimited { retry_after } = err);
+        assert_eq!(retry_after, Duration::from_secs(10));
+        assert!(mock_state.take_requests().await.is_empty());
+
+        tor.set_newnym_min_interval(Some(Duration::from_millis(1)));
+        clock.advance(Duration::from_millis(2));
+        tor.signal_newnym().await.unwrap();
+        assert_eq!(mock_state.take_requests().await, ["SIGNAL NEWNYM"]);
+    }
+
+    #[runtime::test]
+    async fn signal_newnym_mock_clock() {
+        let (_, mock_state, socket) = test_server::spawn().await;
+        let (event_tx, _) = broadcast::channel(1);
+        let clock = MockClock::new();
+        let mut tor = TorControlPortClient::builder()
+            .with_clock(Arc::new(clock.clone()))
+            .build(socket, event_tx);
+        mock_state.set_canned_response(canned_responses::OK).await;
+        tor.set_newnym_min_interval(Some(Duration::from_secs(10)));
+
+        tor.signal_newnym().await.unwrap();
+        clock.advance(Duration::from_secs(4));
+        let err = tor.signal_newnym().await.unwrap_err();
+        unpack_enum!(TorClientError::NewnymRateLimited { retry_after } = err);
+        assert_eq!(retry_after, Duration::from_secs(6));
+
+        clock.advance(Duration::from_secs(6));
+        tor.signal_newnym().await.unwrap();
+        assert_eq!(mock_state.take_requests().await, ["SIGNAL NEWNYM", "SIGNAL NEWNYM"]);
+    }
+
     #[runtime::test]
     async fn del_onion_ok() {
         let (mut tor, mock_state) = setup_test().await;
@@ -524,4 +3273,122 @@ mod test {
         let request = mock_state.take_requests().await.pop().unwrap();
         assert_eq!(request, "DEL_ONION some-fake-id");
     }
+
+    #[runtime::test]
+    async fn dry_run() {
+        let (tor, mock_state) = setup_test().await;
+
+        let command = commands::AddOnion::builder()
+            .with_detach()
+            .max_streams(NonZeroU16::new(4).unwrap())
+            .add_port(9090)
+            .build()
+            .unwrap();
+        assert_eq!(
+            tor.dry_run(&command).unwrap(),
+            "ADD_ONION NEW:ED25519-V3 Flags=Detach,MaxStreamsCloseCircuit NumStreams=4 Port=9090,127.0.0.1:9090"
+        );
+        assert_eq!(tor.dry_run(&commands::get_info("version")).unwrap(), "GETINFO version");
+
+        // The command is validated before it is serialized
+        let command = commands::AddOnion::new(
+            AnyKeyType::Other("not a keyword"),
+            KeyBlob::String("dummy-key"),
+            Vec::new(),
+            9090.into(),
+            None,
+        );
+        let err = tor.dry_run(&command).unwrap_err();
+        assert!(matches!(err, TorClientError::AddOnionInvalidKey(_)));
+
+        assert!(mock_state.take_requests().await.is_empty());
+    }
+
+    /// Tests of the deprecated v2 (RSA1024) paths, which are kept for legacy callers
+    #[allow(deprecated)]
+    mod v2 {
+        use super::*;
+
+        #[runtime::test]
+        async fn add_onion_discard_pk_ok() {
+            let (mut tor, mock_state) = setup_test().await;
+
+            mock_state.set_canned_response(canned_responses::PROTOCOLINFO_OK).await;
+            tor.protocol_info().await.unwrap();
+
+            mock_state
+                .set_canned_response(canned_responses::ADD_ONION_DISCARDPK_OK)
+                .await;
+
+            let response = tor
+                .add_onion_custom(
+                    KeyType::New,
+                    KeyBlob::Rsa1024,
+                    vec![
+                        AddOnionFlag::DiscardPK,
+                        AddOnionFlag::Detach,
+                        AddOnionFlag::BasicAuth,
+                        AddOnionFlag::MaxStreamsCloseCircuit,
+                        AddOnionFlag::NonAnonymous,
+                    ],
+                    PortMapping::new(8080, SocketAddr::from(([127u8, 0, 0, 1], 8081u16))),
+                    None,
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(
+                response.service_id,
+                "qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid"
+            );
+            assert_eq!(response.private_key, None);
+
+            let request = mock_state.take_requests().await.pop().unwrap();
+            assert_eq!(
+                request,
+                "ADD_ONION NEW:RSA1024 Flags=DiscardPK,Detach,BasicAuth,MaxStreamsCloseCircuit,NonAnonymous \
+                 Port=8080,127.0.0.1:8081"
+            );
+        }
+
+        #[runtime::test]
+        async fn add_onion_v2_unsupported() {
+            let (mut tor, mock_state) = setup_test().await;
+
+            mock_state
+                .set_canned_response(&[
+                    "250-PROTOCOLINFO 1",
+                    "250-AUTH METHODS=NULL",
+                    "250-VERSION Tor=\"0.4.6.1-alpha\"",
+                    "250 OK",
+                ])
+                .await;
+            tor.protocol_info().await.unwrap();
+            let _ = mock_state.take_requests().await;
+
+            let err = tor.add_onion_v2(vec![], 8080, None).await.unwrap_err();
+            unpack_enum!(TorClientError::V2OnionUnsupported(version) = err);
+            assert_eq!(version, TorVersion::new(0, 4, 6, 1));
+            assert!(mock_state.take_requests().await.is_empty());
+        }
+
+        #[runtime::test]
+        async fn add_onion_v2_unsupported_version_not_cached() {
+            let (mut tor, mock_state) = setup_test().await;
+
+            mock_state
+                .set_canned_response(&[
+                    "250-PROTOCOLINFO 1",
+                    "250-AUTH METHODS=NULL",
+                    "250-VERSION Tor=\"0.4.6.1-alpha\"",
+                    "250 OK",
+                ])
+                .await;
+            let err = tor.add_onion_v2(vec![], 8080, None).await.unwrap_err();
+            unpack_enum!(TorClientError::V2OnionUnsupported(version) = err);
+            assert_eq!(version, TorVersion::new(0, 4, 6, 1));
+            // Only the version was queried, the ADD_ONION command was not sent
+            assert_eq!(mock_state.take_requests().await, vec!["PROTOCOLINFO 1"]);
+        }
+    }
 }
@@ -0,0 +1,235 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use futures::future::{BoxFuture, FutureExt};
+use std::{
+    fmt::Debug,
+    time::{Duration, Instant},
+};
+use tokio::time;
+
+/// A source of the current time and of delays, so that time-dependent logic such as the NEWNYM rate limit and
+/// reconnection delays can be tested without waiting for real time to pass.
+pub trait Clock: Debug + Send + Sync {
+    /// Returns the current instant
+    fn now(&self) -> Instant;
+
+    /// Returns a future that resolves once `duration` has passed on this clock
+    fn delay_for(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The default clock, which uses real time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn delay_for(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        time::delay_for(duration).boxed()
+    }
+}
+
+#[cfg(test)]
+pub use mock::MockClock;
+
+#[cfg(test)]
+mod mock {
+    use super::*;
+    use futures::future;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::watch;
+
+    /// A clock that only moves when it is advanced. By default, delays resolve as soon as they are polled, advancing
+    /// the clock by their duration, so a delay that loses a race with another future does not move the clock. A
+    /// [manual](MockClock::manual) clock instead leaves delays pending until the test advances it past them.
+    /// Clones share the same time.
+    #[derive(Debug, Clone)]
+    pub struct MockClock {
+        start: Instant,
+        is_manual: bool,
+        state: Arc<Mutex<MockState>>,
+        changed_tx: Arc<Mutex<watch::Sender<()>>>,
+        changed_rx: watch::Receiver<()>,
+    }
+
+    #[derive(Debug, Default)]
+    struct MockState {
+        elapsed: Duration,
+        next_delay_id: u64,
+        /// The id and deadline of each pending delay of a manual clock
+        delays: Vec<(u64, Duration)>,
+    }
+
+    impl MockClock {
+        pub fn new() -> Self {
+            let (changed_tx, changed_rx) = watch::channel(());
+            Self {
+                start: Instant::now(),
+                is_manual: false,
+                state: Default::default(),
+                changed_tx: Arc::new(Mutex::new(changed_tx)),
+                changed_rx,
+            }
+        }
+
+        /// Returns a clock whose delays only resolve once it is advanced past them
+        pub fn manual() -> Self {
+            Self {
+                is_manual: true,
+                ..Self::new()
+            }
+        }
+
+        /// Moves the clock forward by `duration`
+        pub fn advance(&self, duration: Duration) {
+            self.update(|state| state.elapsed += duration);
+        }
+
+        /// Returns how far the clock has moved since it was created
+        pub fn elapsed(&self) -> Duration {
+            self.lock().elapsed
+        }
+
+        /// Waits until a delay of a manual clock is pending, i.e. until the code under test is waiting for the clock to
+        /// be advanced
+        pub async fn wait_for_delay(&self) {
+            let mut changed_rx = self.changed_rx.clone();
+            loop {
+                {
+                    let state = self.lock();
+                    if state.delays.iter().any(|(_, deadline)| *deadline > state.elapsed) {
+                        return;
+                    }
+                }
+                changed_rx.recv().await;
+            }
+        }
+
+        fn lock(&self) -> std::sync::MutexGuard<'_, MockState> {
+            self.state.lock().expect("MockClock lock poisoned")
+        }
+
+        fn update<F: FnOnce(&mut MockState)>(&self, f: F) {
+            f(&mut self.lock());
+            // The clock holds a receiver, so this cannot fail
+            let _ = self.changed_tx.lock().expect("MockClock lock poisoned").broadcast(());
+        }
+    }
+
+    impl Default for MockClock {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.start + self.elapsed()
+        }
+
+        fn delay_for(&self, duration: Duration) -> BoxFuture<'static, ()> {
+            let clock = self.clone();
+            if !self.is_manual {
+                return future::lazy(move |_| clock.advance(duration)).boxed();
+            }
+
+            async move {
+                let mut id = 0;
+                clock.update(|state| {
+                    id = state.next_delay_id;
+                    state.next_delay_id += 1;
+                    let deadline = state.elapsed + duration;
+                    state.delays.push((id, deadline));
+                });
+                // The delay is no longer pending once it is dropped, whether or not it resolved
+                let _guard = DelayGuard {
+                    clock: clock.clone(),
+                    id,
+                };
+                let mut changed_rx = clock.changed_rx.clone();
+                loop {
+                    {
+                        let state = clock.lock();
+                        let is_due = state
+                            .delays
+                            .iter()
+                            .any(|(delay_id, deadline)| *delay_id == id && *deadline <= state.elapsed);
+                        if is_due {
+                            return;
+                        }
+                    }
+                    changed_rx.recv().await;
+                }
+            }
+            .boxed()
+        }
+    }
+
+    struct DelayGuard {
+        clock: MockClock,
+        id: u64,
+    }
+
+    impl Drop for DelayGuard {
+        fn drop(&mut self) {
+            let id = self.id;
+            self.clock
+                .update(|state| state.delays.retain(|(delay_id, _)| *delay_id != id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::runtime;
+
+    #[test]
+    fn mock_clock() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(clock.now() - start, Duration::from_secs(10));
+
+        let cloned = clock.clone();
+        futures::executor::block_on(cloned.delay_for(Duration::from_secs(5)));
+        assert_eq!(clock.elapsed(), Duration::from_secs(15));
+        assert_eq!(clock.now() - start, Duration::from_secs(15));
+    }
+
+    #[runtime::test]
+    async fn mock_clock_manual() {
+        let clock = MockClock::manual();
+        let mut delay = clock.delay_for(Duration::from_secs(5));
+        assert!(futures::poll!(&mut delay).is_pending());
+        clock.wait_for_delay().await;
+
+        clock.advance(Duration::from_secs(4));
+        assert!(futures::poll!(&mut delay).is_pending());
+        clock.advance(Duration::from_secs(1));
+        delay.await;
+        assert_eq!(clock.elapsed(), Duration::from_secs(5));
+    }
+}
@@ -21,16 +21,41 @@
 // USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
 use crate::tor::control_client::{
-    commands::TorCommand,
+    commands::{self, TorCommand},
     error::TorClientError,
-    parsers,
     parsers::ParseError,
     response::ResponseLine,
-    types::{KeyBlob, KeyType, PortMapping, PrivateKey},
+    types::{
+        AnyKeyType,
+        ClientName,
+        KeyBlob,
+        KeyType,
+        OnionAddress,
+        OnionTarget,
+        PortMapping,
+        PrivateKey,
+        TorVersion,
+        REDACTED,
+    },
+    LOG_TARGET,
 };
-use std::{borrow::Cow, fmt, num::NonZeroU16};
+use data_encoding::{BASE32_NOPAD, BASE64_NOPAD};
+use log::*;
+use smallvec::SmallVec;
+use std::{fmt, net::SocketAddr, num::NonZeroU16, str::FromStr};
 
-#[derive(Debug, Copy, Clone)]
+const ADD_ONION_PREFIX: &str = "ADD_ONION ";
+/// The first version of tor that no longer supports v2 onion services
+const V2_ONION_REMOVED_VERSION: TorVersion = TorVersion::new(0, 4, 6, 0);
+
+const DISCARDED_NEW_KEY_WARNING: &str = "ADD_ONION generates a new key with the DiscardPK flag, so the onion address \
+                                         cannot be re-created. Use AddOnionBuilder::allow_discarded_new_key if this \
+                                         is intended.";
+
+/// The flags of an ADD_ONION command. Commands rarely use more than a couple of flags, so these are stored inline.
+pub type AddOnionFlags = SmallVec<[AddOnionFlag; 4]>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum AddOnionFlag {
     /// The server should not include the newly generated private key as part of the response.
     DiscardPK,
@@ -43,6 +68,65 @@ pub enum AddOnionFlag {
     NonAnonymous,
     /// Close the circuit is the maximum streams allowed is reached.
     MaxStreamsCloseCircuit,
+    /// Client authorization is required using the "v3" method. The authorized clients are given as `ClientAuthV3`
+    /// keys (v3 only).
+    V3Auth,
+}
+
+/// Tor replies `550 Onion address collision` if a service with the given key is already running, whether it is owned
+/// by this or another control connection. The reply does not currently name the service, but the service ID is taken
+/// from it if it does.
+fn is_service_exists_reply(code: Option<u16>, msg: &str) -> bool {
+    matches!(code, Some(550) | Some(551)) && (msg.contains("collision") || msg.contains("already exists"))
+}
+
+fn parse_private_key(value: &str) -> Result<PrivateKey, TorClientError> {
+    let mut split = value.splitn(2, ':');
+    let key = split
+        .next()
+        .ok_or_else(|| ParseError("PrivateKey field was empty".to_string()))?;
+
+    let value = split
+        .next()
+        .ok_or_else(|| ParseError("Failed to parse private key".to_string()))?;
+
+    match key {
+        "ED25519-V3" => Ok(PrivateKey::Ed25519V3(value.to_owned())),
+        "RSA1024" => Ok(PrivateKey::Rsa1024(value.to_owned())),
+        "" => Err(ParseError("PrivateKey type was empty".to_string()).into()),
+        key_type => Ok(PrivateKey::Other {
+            key_type: key_type.to_owned(),
+            key: value.to_owned(),
+        }),
+    }
+}
+
+/// Returns true if `key` is a base32-encoded x25519 public key, as used for v3 client authorization
+fn is_x25519_public_key(key: &str) -> bool {
+    BASE32_NOPAD
+        .decode(key.to_ascii_uppercase().as_bytes())
+        .map(|bytes| bytes.len() == 32)
+        .unwrap_or(false)
+}
+
+/// Returns true if `cookie` is a v2 descriptor cookie, i.e. 16 bytes encoded as 22 base64 characters without padding
+fn is_descriptor_cookie(cookie: &str) -> bool {
+    BASE64_NOPAD
+        .decode(cookie.as_bytes())
+        .map(|bytes| bytes.len() == 16)
+        .unwrap_or(false)
+}
+
+/// Removes repeated flags, keeping the first occurrence of each so that flags are written in the order they were given
+fn dedup_flags(flags: &mut AddOnionFlags) {
+    let mut i = 0;
+    while i < flags.len() {
+        if flags[..i].contains(&flags[i]) {
+            flags.remove(i);
+        } else {
+            i += 1;
+        }
+    }
 }
 
 impl fmt::Display for AddOnionFlag {
@@ -54,6 +138,32 @@ impl fmt::Display for AddOnionFlag {
             BasicAuth => write!(f, "BasicAuth"),
             NonAnonymous => write!(f, "NonAnonymous"),
             MaxStreamsCloseCircuit => write!(f, "MaxStreamsCloseCircuit"),
+            V3Auth => write!(f, "V3Auth"),
+        }
+    }
+}
+
+impl AddOnionFlag {
+    /// The flags used when none are specified. Tor's defaults apply i.e. the private key is returned and the service
+    /// is bound to the current control connection.
+    pub fn default_set() -> Vec<AddOnionFlag> {
+        Vec::new()
+    }
+}
+
+impl FromStr for AddOnionFlag {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use AddOnionFlag::*;
+        match s.trim() {
+            "DiscardPK" => Ok(DiscardPK),
+            "Detach" => Ok(Detach),
+            "BasicAuth" => Ok(BasicAuth),
+            "NonAnonymous" => Ok(NonAnonymous),
+            "MaxStreamsCloseCircuit" => Ok(MaxStreamsCloseCircuit),
+            "V3Auth" => Ok(V3Auth),
+            s => Err(ParseError(format!("Unrecognised ADD_ONION flag '{}'", s))),
         }
     }
 }
@@ -62,132 +172,553 @@ impl fmt::Display for AddOnionFlag {
 ///
 /// This command instructs Tor to create onion hidden services.
 pub struct AddOnion<'a> {
-    key_type: KeyType,
+    key_type: AnyKeyType<'a>,
     key_blob: KeyBlob<'a>,
-    flags: Vec<AddOnionFlag>,
-    port_mapping: PortMapping,
+    flags: AddOnionFlags,
+    port_mappings: Vec<PortMapping>,
     num_streams: Option<NonZeroU16>,
+    client_auth_v2: Vec<(&'a ClientName, Option<&'a str>)>,
+    client_auth_v3: Vec<&'a str>,
+    error_on_discarded_key: bool,
 }
 
 impl<'a> AddOnion<'a> {
-    pub fn new(
-        key_type: KeyType,
+    /// Returns a builder for an ADD_ONION command. By default, the Tor proxy will generate a new v3 (ED25519-V3) key.
+    pub fn builder() -> AddOnionBuilder<'a> {
+        AddOnionBuilder::new()
+    }
+
+    pub fn new<K: Into<AnyKeyType<'a>>, F: Into<AddOnionFlags>>(
+        key_type: K,
         key_blob: KeyBlob<'a>,
-        flags: Vec<AddOnionFlag>,
+        flags: F,
         port_mapping: PortMapping,
         num_streams: Option<NonZeroU16>,
     ) -> Self
     {
+        let mut flags = flags.into();
+        dedup_flags(&mut flags);
         Self {
-            key_type,
+            key_type: key_type.into(),
             key_blob,
             flags,
-            port_mapping,
+            port_mappings: vec![port_mapping],
             num_streams,
+            client_auth_v2: Vec::new(),
+            client_auth_v3: Vec::new(),
+            error_on_discarded_key: false,
         }
     }
+
+    /// Returns true if this command creates a v2 (RSA1024) onion service
+    pub fn is_v2(&self) -> bool {
+        self.key_type.is_v2() || (self.key_type.is_new() && self.key_blob.is_v2())
+    }
+
+    /// Returns true if this command generates a new key that tor will not return (the `DiscardPK` flag), which means
+    /// that the onion service can never be re-created at the same address
+    pub fn discards_new_key(&self) -> bool {
+        self.key_type.is_new() && self.flags.contains(&AddOnionFlag::DiscardPK)
+    }
+
+    /// Returns an ADD_ONION command that requests a new ephemeral v3 (ED25519-V3) onion service, forwarding
+    /// `virtual_port` on the onion address to `local_addr`. Use [AddOnion::new] for anything more involved.
+    pub fn new_ephemeral_v3(virtual_port: u16, local_addr: SocketAddr) -> Self {
+        Self::new(
+            KeyType::New,
+            KeyBlob::Ed25519V3,
+            Vec::new(),
+            PortMapping::new(virtual_port, local_addr),
+            None,
+        )
+    }
+}
+
+/// Builder for the [AddOnion] command.
+pub struct AddOnionBuilder<'a> {
+    key_type: AnyKeyType<'a>,
+    key_blob: KeyBlob<'a>,
+    flags: AddOnionFlags,
+    port_mappings: Vec<PortMapping>,
+    num_streams: Option<NonZeroU16>,
+    default_num_streams: Option<NonZeroU16>,
+    client_auth_v2: Vec<(&'a ClientName, Option<&'a str>)>,
+    client_auth_v3: Vec<&'a str>,
+    error_on_discarded_key: bool,
+    allow_discarded_new_key: bool,
+}
+
+impl<'a> AddOnionBuilder<'a> {
+    /// The stream limit used by [AddOnionBuilder::with_default_num_streams]
+    pub const DEFAULT_NUM_STREAMS: u16 = 10;
+
+    pub fn new() -> Self {
+        Self {
+            key_type: KeyType::New.into(),
+            key_blob: KeyBlob::Ed25519V3,
+            flags: AddOnionFlag::default_set().into(),
+            port_mappings: Vec::new(),
+            num_streams: None,
+            default_num_streams: None,
+            client_auth_v2: Vec::new(),
+            client_auth_v3: Vec::new(),
+            error_on_discarded_key: false,
+            allow_discarded_new_key: false,
+        }
+    }
+
+    /// Set the key type and key blob. Defaults to `NEW:ED25519-V3`.
+    pub fn with_key<K: Into<AnyKeyType<'a>>>(mut self, key_type: K, key_blob: KeyBlob<'a>) -> Self {
+        self.key_type = key_type.into();
+        self.key_blob = key_blob;
+        self
+    }
+
+    /// Use an existing private key, such as one returned by a previous ADD_ONION command. See
+    /// [PrivateKey::as_key_type_and_blob].
+    pub fn with_private_key(self, private_key: &'a PrivateKey) -> Self {
+        let (key_type, key_blob) = private_key.as_key_type_and_blob();
+        self.with_key(key_type, key_blob)
+    }
+
+    /// Add a flag to the command. Flags that have already been added are ignored.
+    pub fn with_flag(mut self, flag: AddOnionFlag) -> Self {
+        self.extend(Some(flag));
+        self
+    }
+
+    /// Add each of the given flags to the command. Flags that have already been added are ignored.
+    pub fn with_flags<I: IntoIterator<Item = AddOnionFlag>>(mut self, flags: I) -> Self {
+        self.extend(flags);
+        self
+    }
+
+    /// Do not associate the onion service with the control connection, so that it outlives it.
+    pub fn with_detach(self) -> Self {
+        self.with_flag(AddOnionFlag::Detach)
+    }
+
+    /// Do not return the private key of a newly generated onion service.
+    pub fn discard_private_key(self) -> Self {
+        self.with_flag(AddOnionFlag::DiscardPK)
+    }
+
+    /// Return an error instead of ignoring a private key that the server returns despite the `DiscardPK` flag. By
+    /// default the key is dropped and the response has no private key.
+    pub fn error_on_discarded_key(mut self) -> Self {
+        self.error_on_discarded_key = true;
+        self
+    }
+
+    /// Do not warn when a new key is generated with the `DiscardPK` flag. Without the key, the service can never be
+    /// re-created at the same address, which is only intended if the address is used once.
+    pub fn allow_discarded_new_key(mut self) -> Self {
+        self.allow_discarded_new_key = true;
+        self
+    }
+
+    /// Add a mapping between an onion port and a proxied address. This may be a `u16` (forwarded to the same port on
+    /// 127.0.0.1), a `SocketAddr` (forwarded from the same onion port) or a `(u16, SocketAddr)` tuple. At least one
+    /// port mapping is required.
+    pub fn add_port<P: Into<PortMapping>>(mut self, port_mapping: P) -> Self {
+        self.port_mappings.push(port_mapping.into());
+        self
+    }
+
+    /// Add each of the given port mappings. See [AddOnionBuilder::add_port].
+    pub fn add_ports<I, P>(mut self, port_mappings: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PortMapping>,
+    {
+        self.extend(port_mappings.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set the maximum number of streams that may be open on a rendezvous circuit.
+    pub fn with_num_streams(mut self, num_streams: NonZeroU16) -> Self {
+        self.num_streams = Some(num_streams);
+        self
+    }
+
+    /// Limit the number of streams on a rendezvous circuit to `num_streams` and close the circuit when the limit is
+    /// exceeded. This sets both `NumStreams` and the `MaxStreamsCloseCircuit` flag.
+    pub fn max_streams(self, num_streams: NonZeroU16) -> Self {
+        self.with_num_streams(num_streams)
+            .with_flag(AddOnionFlag::MaxStreamsCloseCircuit)
+    }
+
+    /// Authorize the named client to connect to the (v2) onion service using the "basic" method. If no
+    /// `descriptor_cookie` is given, tor generates one and returns it in the reply. This adds a `ClientAuth` argument
+    /// and the `BasicAuth` flag.
+    pub fn add_client_auth_v2(mut self, client_name: &'a ClientName, descriptor_cookie: Option<&'a str>) -> Self {
+        self.client_auth_v2.push((client_name, descriptor_cookie));
+        self.with_flag(AddOnionFlag::BasicAuth)
+    }
+
+    /// Authorize the client with the given base32-encoded x25519 public key to connect to the (v3) onion service. This
+    /// adds a `ClientAuthV3` key and the `V3Auth` flag.
+    pub fn add_client_auth_v3(mut self, public_key: &'a str) -> Self {
+        self.client_auth_v3.push(public_key);
+        self.with_flag(AddOnionFlag::V3Auth)
+    }
+
+    /// Use `num_streams` as the `NumStreams` limit if the `MaxStreamsCloseCircuit` flag is set without one. Tor accepts
+    /// the flag without a limit, but it then has no effect. If no flag is set, or a limit is set with
+    /// [AddOnionBuilder::with_num_streams], this does nothing.
+    pub fn default_num_streams(mut self, num_streams: NonZeroU16) -> Self {
+        self.default_num_streams = Some(num_streams);
+        self
+    }
+
+    /// Equivalent to [AddOnionBuilder::default_num_streams] with a limit of
+    /// [DEFAULT_NUM_STREAMS](AddOnionBuilder::DEFAULT_NUM_STREAMS).
+    pub fn with_default_num_streams(self) -> Self {
+        self.default_num_streams(NonZeroU16::new(Self::DEFAULT_NUM_STREAMS).expect("DEFAULT_NUM_STREAMS is non-zero"))
+    }
+
+    /// Returns the warning that [build](Self::build) logs for the command, if any
+    fn warning(&self) -> Option<&'static str> {
+        let discards_new_key = self.key_type.is_new() && self.flags.contains(&AddOnionFlag::DiscardPK);
+        Some(DISCARDED_NEW_KEY_WARNING).filter(|_| discards_new_key && !self.allow_discarded_new_key)
+    }
+
+    /// Build the [AddOnion] command. An error is returned if no port mapping was added or if the command fails
+    /// [validation](TorCommand::validate).
+    pub fn build(self) -> Result<AddOnion<'a>, TorClientError> {
+        if self.port_mappings.is_empty() {
+            return Err(TorClientError::AddOnionNoPorts);
+        }
+        let warning = self.warning();
+
+        let num_streams = if self.flags.contains(&AddOnionFlag::MaxStreamsCloseCircuit) {
+            self.num_streams.or(self.default_num_streams)
+        } else {
+            self.num_streams
+        };
+        let command = AddOnion {
+            key_type: self.key_type,
+            key_blob: self.key_blob,
+            flags: self.flags,
+            port_mappings: self.port_mappings,
+            num_streams,
+            client_auth_v2: self.client_auth_v2,
+            client_auth_v3: self.client_auth_v3,
+            error_on_discarded_key: self.error_on_discarded_key,
+        };
+        command.validate()?;
+        if let Some(warning) = warning {
+            warn!(target: LOG_TARGET, "{}", warning);
+        }
+        Ok(command)
+    }
+}
+
+impl Default for AddOnionBuilder<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Extend<AddOnionFlag> for AddOnionBuilder<'_> {
+    fn extend<T: IntoIterator<Item = AddOnionFlag>>(&mut self, iter: T) {
+        for flag in iter {
+            if !self.flags.contains(&flag) {
+                self.flags.push(flag);
+            }
+        }
+    }
+}
+
+impl Extend<PortMapping> for AddOnionBuilder<'_> {
+    fn extend<T: IntoIterator<Item = PortMapping>>(&mut self, iter: T) {
+        self.port_mappings.extend(iter);
+    }
 }
 
 impl TorCommand for AddOnion<'_> {
     type Error = TorClientError;
     type Output = AddOnionResponse;
 
-    fn to_command_string(&self) -> Result<String, Self::Error> {
-        let mut s = String::from("ADD_ONION ");
+    /// Adding a service with an existing key is idempotent (tor rejects it if the service already exists), but every
+    /// command with a `NEW` key type creates another service
+    fn is_idempotent(&self) -> bool {
+        !self.key_type.is_new()
+    }
 
-        s.push_str(self.key_type.as_tor_repr());
-        s.push(':');
-        s.push_str(self.key_blob.as_tor_repr());
+    /// Checks that the key type and key blob, flags, client authorization keys and port mappings are consistent with
+    /// each other, returning an error that names the first inconsistency found.
+    fn validate(&self) -> Result<(), Self::Error> {
+        if self.port_mappings.is_empty() {
+            return Err(TorClientError::AddOnionNoPorts);
+        }
 
-        if !self.flags.is_empty() {
-            let flags = self.flags.iter().map(|f| f.to_string()).collect::<Vec<_>>().join(",");
-            s.push_str(&format!(" Flags={}", flags));
+        if let AnyKeyType::Other(key_type) = self.key_type {
+            let is_keyword = !key_type.is_empty() &&
+                key_type
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_');
+            if !is_keyword {
+                return Err(TorClientError::AddOnionInvalidKey(format!(
+                    "invalid key type '{}'",
+                    key_type
+                )));
+            }
         }
 
-        if let Some(num_streams) = self.num_streams {
-            s.push_str(&format!(" NumStreams={}", num_streams));
+        // NEW requires one of `BEST`, `RSA1024` or `ED25519-V3` and any other key type requires a serialized private
+        // key
+        match (self.key_type, &self.key_blob) {
+            (key_type, KeyBlob::String(_)) if key_type.is_new() => {
+                return Err(TorClientError::AddOnionInvalidKey(
+                    "key type NEW cannot be used with a serialized private key".to_string(),
+                ));
+            },
+            (key_type, _) if key_type.is_new() => {},
+            (key_type, KeyBlob::String(blob)) if blob.is_empty() => {
+                return Err(TorClientError::AddOnionInvalidKey(format!(
+                    "key type {} requires a private key but the key blob is empty",
+                    key_type.as_tor_repr()
+                )));
+            },
+            (_, KeyBlob::String(_)) => {},
+            (key_type, key_blob) => {
+                return Err(TorClientError::AddOnionInvalidKey(format!(
+                    "key type {} requires a private key but got {}",
+                    key_type.as_tor_repr(),
+                    key_blob.as_tor_repr()
+                )));
+            },
         }
 
-        s.push_str(&format!(
-            " Port={},{}",
-            self.port_mapping.onion_port(),
-            self.port_mapping.proxied_address()
-        ));
+        let is_v3 = matches!(
+            (self.key_type, &self.key_blob),
+            (AnyKeyType::Known(KeyType::Ed25519V3), _) |
+                (AnyKeyType::Known(KeyType::New), KeyBlob::Ed25519V3) |
+                (AnyKeyType::Known(KeyType::New), KeyBlob::Best)
+        );
+        if is_v3 && self.flags.contains(&AddOnionFlag::BasicAuth) {
+            return Err(TorClientError::AddOnionFlagNotSupported(
+                AddOnionFlag::BasicAuth.to_string(),
+            ));
+        }
 
+        if !self.client_auth_v2.is_empty() && !self.is_v2() {
+            return Err(TorClientError::AddOnionClientAuthV2RequiresV2);
+        }
+        for (i, (name, cookie)) in self.client_auth_v2.iter().enumerate() {
+            if self.client_auth_v2[..i].iter().any(|(n, _)| n == name) {
+                return Err(TorClientError::AddOnionDuplicateClientName(name.to_string()));
+            }
+            if cookie.map(|c| !is_descriptor_cookie(c)).unwrap_or(false) {
+                return Err(TorClientError::AddOnionInvalidDescriptorCookie(name.to_string()));
+            }
+        }
+
+        let has_v3_auth = self.flags.contains(&AddOnionFlag::V3Auth);
+        if !is_v3 && (has_v3_auth || !self.client_auth_v3.is_empty()) {
+            return Err(TorClientError::AddOnionClientAuthV3RequiresV3);
+        }
+        if has_v3_auth && self.client_auth_v3.is_empty() {
+            return Err(TorClientError::AddOnionMissingClientAuthV3);
+        }
+        for (i, key) in self.client_auth_v3.iter().enumerate() {
+            if !is_x25519_public_key(key) {
+                return Err(TorClientError::AddOnionInvalidClientAuthV3Key(key.to_string()));
+            }
+            if self.client_auth_v3[..i].iter().any(|k| k.eq_ignore_ascii_case(key)) {
+                return Err(TorClientError::AddOnionDuplicateClientAuthV3Key(key.to_string()));
+            }
+        }
+
+        for port_mapping in &self.port_mappings {
+            let target_port = match port_mapping.target() {
+                OnionTarget::Socket(addr) => addr.port(),
+                OnionTarget::HostPort(_, port) => *port,
+            };
+            if port_mapping.onion_port() == 0 || target_port == 0 {
+                return Err(TorClientError::AddOnionInvalidPort(format!(
+                    "{},{}",
+                    port_mapping.onion_port(),
+                    port_mapping.target()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_for_version(&self, version: TorVersion) -> Result<(), Self::Error> {
+        if self.is_v2() && version >= V2_ONION_REMOVED_VERSION {
+            return Err(TorClientError::V2OnionUnsupported(version));
+        }
+        Ok(())
+    }
+
+    fn requires_tor_version(&self) -> bool {
+        self.is_v2()
+    }
+
+    fn verb(&self) -> &str {
+        ADD_ONION_PREFIX.trim_end()
+    }
+
+    fn to_command_string(&self) -> Result<String, Self::Error> {
+        let mut s = String::new();
+        self.write_command(&mut s)?;
         Ok(s)
     }
 
-    fn parse_responses(&self, mut responses: Vec<ResponseLine>) -> Result<Self::Output, Self::Error> {
-        let last_response = responses.pop().ok_or_else(|| TorClientError::UnexpectedEof)?;
-        if let Some(err) = last_response.err() {
-            if err.contains("Onion address collision") {
-                return Err(TorClientError::OnionAddressCollision);
+    fn write_command(&self, buf: &mut String) -> Result<(), Self::Error> {
+        let key_blob = self.key_blob.as_tor_repr();
+        buf.reserve(self.command_len_hint(key_blob));
+        self.write_to(buf, key_blob, false)
+            .expect("fmt::Write for String is infallible");
+        Ok(())
+    }
+
+    fn parse_responses<I>(&self, responses: I) -> Result<Self::Output, Self::Error>
+    where I: IntoIterator<Item = ResponseLine> {
+        // ServiceID and PrivateKey are taken from any line, including the terminal line, so that the reply does not
+        // depend on the order in which tor writes them
+        let mut terminal_code = None;
+        let mut error_code = None;
+        let responses = responses.into_iter().inspect(|resp| {
+            if resp.is_end_reply() {
+                terminal_code = Some(resp.code);
             }
-            return Err(TorClientError::TorCommandFailed(err.to_owned()));
-        }
-
-        let mut service_id = None;
-        let mut private_key = None;
-
-        for response in responses {
-            let (key, values) = parsers::key_value(&response.value)?;
-            let value = values
-                .into_iter()
-                .next()
-                .ok_or_else(|| TorClientError::KeyValueNoValue)?;
-            match &*key {
-                "ServiceID" => {
-                    service_id = Some(value.into_owned());
-                },
-                "PrivateKey" => {
-                    let mut split = value.split(':');
-                    let key = split
-                        .next()
-                        .ok_or_else(|| ParseError("PrivateKey field was empty".to_string()))?;
-
-                    let value = split
-                        .next()
-                        .map(|v| Cow::from(v.to_owned()))
-                        .ok_or_else(|| ParseError("Failed to parse private key".to_string()))?;
-
-                    private_key = match key {
-                        "ED25519-V3" => Some(PrivateKey::Ed25519V3(value.into_owned())),
-                        "RSA1024" => Some(PrivateKey::Rsa1024(value.into_owned())),
-                        k => {
-                            return Err(
-                                ParseError(format!("Server returned unrecognised private key type '{}'", k)).into(),
-                            )
-                        },
-                    };
-                },
-                _ => {
-                    // Ignore key's we don't understand
-                },
+            if resp.is_err() {
+                error_code = Some(resp.code);
             }
+        });
+        let result = commands::parse_key_value_lines(responses, false);
+        // Every error line up to the terminal line is consumed, so the code is that of the terminal line unless the
+        // reply was cut short
+        let error_code = terminal_code.or(error_code);
+        let mut values = result.map_err(|err| match err {
+            TorClientError::TorCommandFailed(msg) if is_service_exists_reply(error_code, &msg) => {
+                TorClientError::OnionServiceExists {
+                    service_id: msg
+                        .split(|ch: char| !ch.is_ascii_alphanumeric() && ch != '.')
+                        .find_map(|word| word.parse::<OnionAddress>().ok())
+                        .map(|addr| addr.service_id().to_string()),
+                }
+            },
+            TorClientError::TorCommandFailed(reason) => TorClientError::AddOnionFailed {
+                code: error_code.unwrap_or_default(),
+                reason,
+            },
+            err => err,
+        })?;
+        if terminal_code != Some(250) {
+            return Err(ParseError("ADD_ONION reply did not end with a 250 status line".to_string()).into());
         }
 
+        let private_key = values
+            .remove("PrivateKey")
+            .and_then(|v| v.into_iter().next())
+            .map(|value| parse_private_key(&value))
+            .transpose()?;
+        let private_key = match private_key {
+            // Never surface a key the caller asked to discard
+            Some(_) if self.flags.contains(&AddOnionFlag::DiscardPK) => {
+                if self.error_on_discarded_key {
+                    return Err(TorClientError::AddOnionUnexpectedPrivateKey);
+                }
+                None
+            },
+            None if self.key_type.is_new() && !self.flags.contains(&AddOnionFlag::DiscardPK) => {
+                return Err(TorClientError::AddOnionNoPrivateKey);
+            },
+            private_key => private_key,
+        };
+        let service_id = values.remove("ServiceID").and_then(|v| v.into_iter().next());
+
         let service_id = service_id.ok_or_else(|| TorClientError::AddOnionNoServiceId)?;
 
         Ok(AddOnionResponse {
             service_id,
             private_key,
-            onion_port: self.port_mapping.onion_port(),
+            onion_port: self
+                .port_mappings
+                .first()
+                .map(PortMapping::onion_port)
+                .unwrap_or_default(),
+            port_mappings: self.port_mappings.clone(),
+            num_streams: self.num_streams,
+            flags: self.flags.clone(),
         })
     }
 }
 
+impl AddOnion<'_> {
+    /// Writes the command line to `writer` using the given key blob representation. Descriptor cookies are masked if
+    /// `redact_cookies` is true.
+    fn write_to<W: fmt::Write>(&self, writer: &mut W, key_blob: &str, redact_cookies: bool) -> fmt::Result {
+        writer.write_str(ADD_ONION_PREFIX)?;
+        writer.write_str(self.key_type.as_tor_repr())?;
+        writer.write_char(':')?;
+        writer.write_str(key_blob)?;
+
+        // The Flags= prefix is written with the first flag, so no flags means no (empty) Flags segment
+        for (i, flag) in self.flags.iter().enumerate() {
+            writer.write_str(if i == 0 { " Flags=" } else { "," })?;
+            write!(writer, "{}", flag)?;
+        }
+
+        if let Some(num_streams) = self.num_streams {
+            write!(writer, " NumStreams={}", num_streams)?;
+        }
+
+        for port_mapping in &self.port_mappings {
+            write!(writer, " Port={},{}", port_mapping.onion_port(), port_mapping.target())?;
+        }
+
+        for (client_name, cookie) in &self.client_auth_v2 {
+            write!(writer, " ClientAuth={}", client_name)?;
+            if let Some(cookie) = cookie {
+                writer.write_char(':')?;
+                writer.write_str(if redact_cookies { REDACTED } else { cookie })?;
+            }
+        }
+
+        for key in &self.client_auth_v3 {
+            writer.write_str(" ClientAuthV3=")?;
+            writer.write_str(key)?;
+        }
+
+        Ok(())
+    }
+
+    /// A rough upper bound of the length of the command line, used to size the output buffer up front.
+    fn command_len_hint(&self, key_blob: &str) -> usize {
+        // ADD_ONION_PREFIX + "ED25519-V3" + ":"
+        const PREFIX_LEN: usize = ADD_ONION_PREFIX.len() + 11;
+        // " Flags=" + the longest flag name ("MaxStreamsCloseCircuit") + ","
+        const FLAG_LEN: usize = 30;
+        // " NumStreams=65535"
+        const NUM_STREAMS_LEN: usize = 17;
+        // " Port=65535," + an IPv6 socket address
+        const PORT_LEN: usize = 60;
+        // " ClientAuth=" + the longest client name + ":" + a base64-encoded descriptor cookie
+        const CLIENT_AUTH_V2_LEN: usize = 51;
+        // " ClientAuthV3=" + a base32-encoded x25519 key
+        const CLIENT_AUTH_V3_LEN: usize = 66;
+
+        PREFIX_LEN +
+            key_blob.len() +
+            self.flags.len() * FLAG_LEN +
+            NUM_STREAMS_LEN +
+            self.port_mappings.len() * PORT_LEN +
+            self.client_auth_v2.len() * CLIENT_AUTH_V2_LEN +
+            self.client_auth_v3.len() * CLIENT_AUTH_V3_LEN
+    }
+}
+
+/// Renders the command line as it is sent to the tor control port, with the key blob and descriptor cookies masked.
 impl fmt::Display for AddOnion<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "ADD_ONION (KeyType={} KeyBlob={} Flags={} PortMapping={})",
-            self.key_type.as_tor_repr(),
-            self.key_blob,
-            self.flags
-                .iter()
-                .fold(String::new(), |acc, f| format!("{}, {}", acc, f)),
-            self.port_mapping
-        )
+        self.write_to(f, self.key_blob.as_redacted_tor_repr(), true)
     }
 }
 
@@ -196,25 +727,851 @@ pub struct AddOnionResponse {
     pub(crate) service_id: String,
     pub(crate) private_key: Option<PrivateKey>,
     pub(crate) onion_port: u16,
+    /// The port mappings, stream limit and flags the service was created with, so that a record of the service can be
+    /// kept from the response alone
+    pub(crate) port_mappings: Vec<PortMapping>,
+    pub(crate) num_streams: Option<NonZeroU16>,
+    pub(crate) flags: AddOnionFlags,
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::tor::control_client::{commands::test_utils::response_lines, types::Hostname};
+    use std::net::Ipv6Addr;
+    use tari_test_utils::unpack_enum;
+
+    #[test]
+    fn new_ephemeral_v3() {
+        let command = AddOnion::new_ephemeral_v3(80, ([127, 0, 0, 1], 8080).into());
+        assert_eq!(
+            command.to_command_string().unwrap(),
+            "ADD_ONION NEW:ED25519-V3 Port=80,127.0.0.1:8080"
+        );
+    }
+
+    #[test]
+    fn discards_new_key() {
+        // The builder warns but still builds the command, unless the warning is silenced
+        let builder = AddOnion::builder().discard_private_key().add_port(9090);
+        assert_eq!(builder.warning(), Some(DISCARDED_NEW_KEY_WARNING));
+        assert!(builder.build().unwrap().discards_new_key());
+        let builder = AddOnion::builder()
+            .discard_private_key()
+            .allow_discarded_new_key()
+            .add_port(9090);
+        assert_eq!(builder.warning(), None);
+        assert!(builder.build().unwrap().discards_new_key());
+
+        let builder = AddOnion::builder().add_port(9090);
+        assert_eq!(builder.warning(), None);
+        assert!(!builder.build().unwrap().discards_new_key());
+        let builder = AddOnion::builder()
+            .with_key(KeyType::Ed25519V3, KeyBlob::String("dummy-key"))
+            .discard_private_key()
+            .add_port(9090);
+        assert_eq!(builder.warning(), None);
+        assert!(!builder.build().unwrap().discards_new_key());
+    }
+
+    #[test]
+    fn builder_flag_helpers() {
+        let command = AddOnion::builder()
+            .with_detach()
+            .discard_private_key()
+            .add_port(9090)
+            .build()
+            .unwrap();
+        assert_eq!(
+            command.to_command_string().unwrap(),
+            "ADD_ONION NEW:ED25519-V3 Flags=Detach,DiscardPK Port=9090,127.0.0.1:9090"
+        );
+
+        let command = AddOnion::builder().add_port(9090).build().unwrap();
+        assert_eq!(
+            command.to_command_string().unwrap(),
+            "ADD_ONION NEW:ED25519-V3 Port=9090,127.0.0.1:9090"
+        );
+    }
+
+    #[test]
+    fn builder_port_conversions() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let to_command_string = |port: PortMapping| {
+            AddOnion::builder()
+                .add_port(port)
+                .build()
+                .unwrap()
+                .to_command_string()
+                .unwrap()
+        };
+
+        assert_eq!(
+            to_command_string(9090.into()),
+            "ADD_ONION NEW:ED25519-V3 Port=9090,127.0.0.1:9090"
+        );
+        assert_eq!(
+            to_command_string(addr.into()),
+            "ADD_ONION NEW:ED25519-V3 Port=8080,127.0.0.1:8080"
+        );
+        assert_eq!(
+            to_command_string((80u16, addr).into()),
+            "ADD_ONION NEW:ED25519-V3 Port=80,127.0.0.1:8080"
+        );
+
+        let command = AddOnion::builder()
+            .add_port(80)
+            .add_port((443u16, addr))
+            .build()
+            .unwrap();
+        assert_eq!(
+            command.to_command_string().unwrap(),
+            "ADD_ONION NEW:ED25519-V3 Port=80,127.0.0.1:80 Port=443,127.0.0.1:8080"
+        );
+    }
+
+    #[test]
+    fn ipv6_port_mapping() {
+        let addr = SocketAddr::from((Ipv6Addr::LOCALHOST, 8080));
+        let command = AddOnion::new_ephemeral_v3(80, addr);
+        assert_eq!(
+            command.to_command_string().unwrap(),
+            "ADD_ONION NEW:ED25519-V3 Port=80,[::1]:8080"
+        );
+
+        let addr = "[2001:db8::1]:65535".parse::<SocketAddr>().unwrap();
+        let command = AddOnion::builder().add_port(addr).build().unwrap();
+        assert_eq!(
+            command.to_command_string().unwrap(),
+            "ADD_ONION NEW:ED25519-V3 Port=65535,[2001:db8::1]:65535"
+        );
+    }
+
+    #[test]
+    fn hostname_port_mapping() {
+        let port_mapping = PortMapping::new_host(80, "backend.internal", 8080).unwrap();
+        assert_eq!(port_mapping.target().socket_addr(), None);
+        assert_eq!(
+            *port_mapping.target(),
+            OnionTarget::HostPort(Hostname::new("backend.internal").unwrap(), 8080)
+        );
+        assert_eq!(port_mapping.target().to_string(), "backend.internal:8080");
+        let socket_mapping = PortMapping::from_port(80);
+        assert_eq!(
+            *socket_mapping.proxied_address(),
+            SocketAddr::from(([127, 0, 0, 1], 80))
+        );
+
+        let command = AddOnion::builder().add_port(port_mapping).build().unwrap();
+        command.validate().unwrap();
+        assert_eq!(
+            command.to_command_string().unwrap(),
+            "ADD_ONION NEW:ED25519-V3 Port=80,backend.internal:8080"
+        );
+
+        for host in &[
+            "",
+            "backend internal",
+            "-backend.internal",
+            "backend..internal",
+            "backend:8080",
+        ] {
+            let err = PortMapping::new_host(80, host, 8080).unwrap_err();
+            unpack_enum!(TorClientError::AddOnionInvalidTarget(invalid) = err);
+            assert_eq!(invalid, *host);
+        }
+
+        let err = AddOnion::builder()
+            .add_port(PortMapping::new_host(80, "backend.internal", 0).unwrap())
+            .build()
+            .err()
+            .unwrap();
+        unpack_enum!(TorClientError::AddOnionInvalidPort(mapping) = err);
+        assert_eq!(mapping, "80,backend.internal:0");
+    }
 
     #[test]
-    fn to_command_string() {
-        let key = "this-is-a-key".to_string();
+    fn builder_extend_dedupes_flags() {
+        let is_daemon = true;
+        let mut builder = AddOnion::builder()
+            .with_detach()
+            .with_flags(vec![AddOnionFlag::DiscardPK, AddOnionFlag::Detach])
+            .add_ports(vec![80u16, 443]);
+        builder.extend(Some(AddOnionFlag::Detach).filter(|_| is_daemon));
+        builder.extend(vec![PortMapping::from(8080)]);
+
+        let command = builder.build().unwrap();
+        assert_eq!(
+            command.to_command_string().unwrap(),
+            "ADD_ONION NEW:ED25519-V3 Flags=Detach,DiscardPK Port=80,127.0.0.1:80 Port=443,127.0.0.1:443 \
+             Port=8080,127.0.0.1:8080"
+        );
+    }
+
+    #[test]
+    fn builder_max_streams() {
+        let command = AddOnion::builder()
+            .with_flag(AddOnionFlag::MaxStreamsCloseCircuit)
+            .max_streams(NonZeroU16::new(5).unwrap())
+            .add_port(9090)
+            .build()
+            .unwrap();
+        assert_eq!(
+            command.to_command_string().unwrap(),
+            "ADD_ONION NEW:ED25519-V3 Flags=MaxStreamsCloseCircuit NumStreams=5 Port=9090,127.0.0.1:9090"
+        );
+    }
+
+    #[test]
+    fn builder_default_num_streams() {
+        // Without a default, the flag is sent without a limit
+        let command = AddOnion::builder()
+            .with_flag(AddOnionFlag::MaxStreamsCloseCircuit)
+            .add_port(9090)
+            .build()
+            .unwrap();
+        assert_eq!(command.num_streams, None);
+        assert_eq!(
+            command.to_command_string().unwrap(),
+            "ADD_ONION NEW:ED25519-V3 Flags=MaxStreamsCloseCircuit Port=9090,127.0.0.1:9090"
+        );
+
+        let command = AddOnion::builder()
+            .with_flag(AddOnionFlag::MaxStreamsCloseCircuit)
+            .with_default_num_streams()
+            .add_port(9090)
+            .build()
+            .unwrap();
+        command.validate().unwrap();
+        assert_eq!(
+            command.to_command_string().unwrap(),
+            "ADD_ONION NEW:ED25519-V3 Flags=MaxStreamsCloseCircuit NumStreams=10 Port=9090,127.0.0.1:9090"
+        );
+
+        // An explicit limit takes precedence over the default
+        let command = AddOnion::builder()
+            .default_num_streams(NonZeroU16::new(20).unwrap())
+            .max_streams(NonZeroU16::new(5).unwrap())
+            .add_port(9090)
+            .build()
+            .unwrap();
+        assert_eq!(command.num_streams, NonZeroU16::new(5));
+
+        // The default is only applied if the flag is set
+        let command = AddOnion::builder()
+            .with_default_num_streams()
+            .add_port(9090)
+            .build()
+            .unwrap();
+        assert_eq!(command.num_streams, None);
+
         let command = AddOnion::new(
             KeyType::New,
+            KeyBlob::Ed25519V3,
+            vec![AddOnionFlag::MaxStreamsCloseCircuit],
+            9090.into(),
+            None,
+        );
+        command.validate().unwrap();
+        assert_eq!(command.num_streams, None);
+    }
+
+    #[test]
+    fn builder_key_mismatch() {
+        let err = AddOnion::builder()
+            .with_key(KeyType::New, KeyBlob::String("dummy-key"))
+            .add_port(9090)
+            .build()
+            .err()
+            .unwrap();
+        unpack_enum!(TorClientError::AddOnionInvalidKey(msg) = err);
+        assert!(msg.contains("NEW"));
+
+        let err = AddOnion::builder()
+            .with_key(KeyType::Ed25519V3, KeyBlob::String(""))
+            .add_port(9090)
+            .build()
+            .err()
+            .unwrap();
+        unpack_enum!(TorClientError::AddOnionInvalidKey(msg) = err);
+        assert!(msg.contains("ED25519-V3"));
+
+        let err = AddOnion::builder()
+            .with_key(KeyType::Ed25519V3, KeyBlob::Best)
+            .add_port(9090)
+            .build()
+            .err()
+            .unwrap();
+        unpack_enum!(TorClientError::AddOnionInvalidKey(msg) = err);
+        assert!(msg.contains("ED25519-V3"));
+
+        AddOnion::builder()
+            .with_key(KeyType::Ed25519V3, KeyBlob::String("dummy-key"))
+            .add_port(9090)
+            .build()
+            .unwrap();
+
+        // Commands that are not built with the builder are checked when they are validated, before they are sent
+        let command = AddOnion::new(KeyType::Ed25519V3, KeyBlob::String(""), Vec::new(), 9090.into(), None);
+        unpack_enum!(TorClientError::AddOnionInvalidKey(_msg) = command.validate().unwrap_err());
+        let command = AddOnion::new(
+            KeyType::New,
+            KeyBlob::String("dummy-key"),
+            Vec::new(),
+            9090.into(),
+            None,
+        );
+        unpack_enum!(TorClientError::AddOnionInvalidKey(_msg) = command.validate().unwrap_err());
+    }
+
+    #[test]
+    fn validate() {
+        let command = AddOnion::new_ephemeral_v3(9090, ([127, 0, 0, 1], 8080).into());
+        command.validate().unwrap();
+
+        let err = AddOnion::builder()
+            .with_flag(AddOnionFlag::BasicAuth)
+            .add_port(9090)
+            .build()
+            .err()
+            .unwrap();
+        unpack_enum!(TorClientError::AddOnionFlagNotSupported(flag) = err);
+        assert_eq!(flag, "BasicAuth");
+    }
+
+    const CLIENT_KEY_1: &str = "FPMANSL7BYAK6GQ7YMZI7J3DVETJOI6I3OH2YT4TV5Y5WGDNN2IA";
+    const CLIENT_KEY_2: &str = "QG3DPWH42LDNUY2Z42LDCE5BC4G6PFPEW4S3QTI6BNGP3HWFRTUQ";
+
+    #[test]
+    fn validate_client_auth_and_max_streams() {
+        // Keys are case-insensitive
+        let lowercase_key = CLIENT_KEY_2.to_lowercase();
+        let command = AddOnion::builder()
+            .with_detach()
+            .max_streams(NonZeroU16::new(3).unwrap())
+            .add_client_auth_v3(CLIENT_KEY_1)
+            .add_client_auth_v3(&lowercase_key)
+            .add_ports(vec![80u16, 443])
+            .build()
+            .unwrap();
+        assert_eq!(command.flags.as_slice(), &[
+            AddOnionFlag::Detach,
+            AddOnionFlag::MaxStreamsCloseCircuit,
+            AddOnionFlag::V3Auth
+        ]);
+
+        // A stream limit that does not close the circuit, with client auth and an existing v3 key
+        AddOnion::builder()
+            .with_key(KeyType::Ed25519V3, KeyBlob::String("this-is-a-key"))
+            .with_num_streams(NonZeroU16::new(3).unwrap())
+            .add_client_auth_v3(CLIENT_KEY_1)
+            .add_port(80)
+            .build()
+            .unwrap();
+
+        let err = AddOnion::builder()
+            .with_flag(AddOnionFlag::V3Auth)
+            .max_streams(NonZeroU16::new(3).unwrap())
+            .add_port(80)
+            .build()
+            .err()
+            .unwrap();
+        unpack_enum!(TorClientError::AddOnionMissingClientAuthV3 = err);
+
+        for key in &["", "not-base32!", "AAAA", &CLIENT_KEY_1[..50]] {
+            let err = AddOnion::builder()
+                .add_client_auth_v3(key)
+                .add_port(80)
+                .build()
+                .err()
+                .unwrap();
+            unpack_enum!(TorClientError::AddOnionInvalidClientAuthV3Key(invalid) = err);
+            assert_eq!(invalid, *key);
+        }
+
+        let err = AddOnion::builder()
+            .add_client_auth_v3(CLIENT_KEY_2)
+            .add_client_auth_v3(CLIENT_KEY_1)
+            .add_client_auth_v3(&lowercase_key)
+            .add_port(80)
+            .build()
+            .err()
+            .unwrap();
+        unpack_enum!(TorClientError::AddOnionDuplicateClientAuthV3Key(duplicate) = err);
+        assert_eq!(duplicate, lowercase_key);
+
+        let err = AddOnion::builder()
+            .add_client_auth_v3(CLIENT_KEY_1)
+            .add_port(80)
+            .add_port((443u16, SocketAddr::from(([127, 0, 0, 1], 0))))
+            .build()
+            .err()
+            .unwrap();
+        unpack_enum!(TorClientError::AddOnionInvalidPort(mapping) = err);
+        assert_eq!(mapping, "443,127.0.0.1:0");
+
+        let err = AddOnion::builder().add_port(0).build().err().unwrap();
+        unpack_enum!(TorClientError::AddOnionInvalidPort(mapping) = err);
+        assert_eq!(mapping, "0,127.0.0.1:0");
+    }
+
+    #[test]
+    fn defaults_to_v3() {
+        let command = AddOnion::builder().add_port(9090).build().unwrap();
+        assert!(!command.is_v2());
+        assert!(!AddOnion::new_ephemeral_v3(9090, ([127, 0, 0, 1], 8080).into()).is_v2());
+    }
+
+    #[test]
+    fn builder_requires_port_mapping() {
+        let err = AddOnion::builder().with_detach().build().err().unwrap();
+        unpack_enum!(TorClientError::AddOnionNoPorts = err);
+
+        let err = AddOnion::builder()
+            .add_ports(Vec::<PortMapping>::new())
+            .build()
+            .err()
+            .unwrap();
+        unpack_enum!(TorClientError::AddOnionNoPorts = err);
+
+        let mut builder = AddOnion::builder().with_key(KeyType::Ed25519V3, KeyBlob::String("this-is-a-key"));
+        builder.extend(Vec::<PortMapping>::new());
+        let err = builder.build().err().unwrap();
+        unpack_enum!(TorClientError::AddOnionNoPorts = err);
+    }
+
+    #[test]
+    fn flag_string_round_trip() {
+        use AddOnionFlag::*;
+        for flag in &[
+            DiscardPK,
+            Detach,
+            BasicAuth,
+            V3Auth,
+            NonAnonymous,
+            MaxStreamsCloseCircuit,
+        ] {
+            assert_eq!(flag.to_string().parse::<AddOnionFlag>().unwrap(), *flag);
+        }
+        assert!("Bogus".parse::<AddOnionFlag>().is_err());
+    }
+
+    #[test]
+    fn display_redacts_key_blob() {
+        let key = "this-is-a-secret-key".to_string();
+        let command = AddOnion::new(
+            KeyType::Ed25519V3,
             KeyBlob::String(&key),
-            vec![],
+            vec![AddOnionFlag::Detach, AddOnionFlag::DiscardPK],
             PortMapping::from_port(9090),
             None,
         );
+        let displayed = command.to_string();
+        assert_eq!(
+            displayed,
+            "ADD_ONION ED25519-V3:[redacted] Flags=Detach,DiscardPK Port=9090,127.0.0.1:9090"
+        );
+        assert!(!displayed.contains(&key));
+        assert!(command.to_command_string().unwrap().contains(&key));
+    }
+
+    const REPLY_WITH_KEY: &[&str] = &[
+        "250-ServiceID=qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid",
+        "250-PrivateKey=ED25519-V3:this-is-a-key",
+        "250 OK",
+    ];
+    const REPLY_WITHOUT_KEY: &[&str] = &[
+        "250-ServiceID=qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid",
+        "250 OK",
+    ];
+
+    #[test]
+    fn discard_pk_drops_returned_key() {
+        let command = AddOnion::builder()
+            .discard_private_key()
+            .add_port(9090)
+            .build()
+            .unwrap();
+        let resp = command.parse_responses(response_lines(REPLY_WITH_KEY)).unwrap();
+        assert!(resp.private_key.is_none());
+        let resp = command.parse_responses(response_lines(REPLY_WITHOUT_KEY)).unwrap();
+        assert!(resp.private_key.is_none());
+
+        let command = AddOnion::builder()
+            .discard_private_key()
+            .error_on_discarded_key()
+            .add_port(9090)
+            .build()
+            .unwrap();
+        let err = command.parse_responses(response_lines(REPLY_WITH_KEY)).unwrap_err();
+        unpack_enum!(TorClientError::AddOnionUnexpectedPrivateKey = err);
+    }
+
+    #[test]
+    fn new_key_requires_private_key() {
+        let command = AddOnion::builder().add_port(9090).build().unwrap();
+        let err = command.parse_responses(response_lines(REPLY_WITHOUT_KEY)).unwrap_err();
+        unpack_enum!(TorClientError::AddOnionNoPrivateKey = err);
+        let resp = command.parse_responses(response_lines(REPLY_WITH_KEY)).unwrap();
+        assert!(matches!(resp.private_key, Some(PrivateKey::Ed25519V3(_))));
+
+        // The key is not returned when an existing key is given
+        let command = AddOnion::builder()
+            .with_key(KeyType::Ed25519V3, KeyBlob::String("this-is-a-key"))
+            .add_port(9090)
+            .build()
+            .unwrap();
+        let resp = command.parse_responses(response_lines(REPLY_WITHOUT_KEY)).unwrap();
+        assert!(resp.private_key.is_none());
+    }
+
+    #[test]
+    fn service_id_on_terminal_line() {
+        let command = AddOnion::builder()
+            .discard_private_key()
+            .add_port(9090)
+            .build()
+            .unwrap();
+        let resp = command
+            .parse_responses(response_lines(&[
+                "250-PrivateKey=ED25519-V3:this-is-a-key",
+                "250 ServiceID=qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid",
+            ]))
+            .unwrap();
+        assert_eq!(
+            resp.service_id,
+            "qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid"
+        );
+
+        let command = AddOnion::builder().add_port(9090).build().unwrap();
+        let resp = command
+            .parse_responses(response_lines(&[
+                "250-ServiceID=qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid",
+                "250 PrivateKey=ED25519-V3:this-is-a-key",
+            ]))
+            .unwrap();
+        assert!(resp.private_key.is_some());
+
+        // The terminal status line is required
+        let err = command
+            .parse_responses(response_lines(&REPLY_WITH_KEY[..2]))
+            .unwrap_err();
+        unpack_enum!(TorClientError::ParseError(_err) = err);
+    }
+
+    #[test]
+    fn service_exists() {
+        let command = AddOnion::builder()
+            .with_key(KeyType::Ed25519V3, KeyBlob::String("this-is-a-key"))
+            .add_port(9090)
+            .build()
+            .unwrap();
+        let err = command
+            .parse_responses(response_lines(&["551 Onion service \
+                                               qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid.\
+                                               onion already exists"]))
+            .unwrap_err();
+        unpack_enum!(TorClientError::OnionServiceExists { service_id } = err);
+        assert_eq!(
+            service_id.as_deref(),
+            Some("qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid")
+        );
+
+        // Other 55x errors are not mistaken for an existing service
+        let err = command
+            .parse_responses(response_lines(&["551 Failed to generate onion address"]))
+            .unwrap_err();
+        unpack_enum!(TorClientError::AddOnionFailed { code, reason } = err);
+        assert_eq!(code, 551);
+        assert_eq!(reason, "Failed to generate onion address");
+
+        // Every line of a multi-line failure is kept, with the code of the terminal line
+        let err = command
+            .parse_responses(response_lines(&[
+                "551-Failed to add onion service",
+                "551 Could not bind the port",
+            ]))
+            .unwrap_err();
+        unpack_enum!(TorClientError::AddOnionFailed { code, reason } = err);
+        assert_eq!(code, 551);
+        assert_eq!(reason, "Failed to add onion service\nCould not bind the port");
+    }
+
+    #[test]
+    fn failed_and_no_service_id_are_distinct() {
+        let command = AddOnion::builder().add_port(9090).build().unwrap();
+        let err = command
+            .parse_responses(response_lines(&["512 Bad arguments to ADD_ONION"]))
+            .unwrap_err();
+        unpack_enum!(TorClientError::AddOnionFailed { code, reason } = err);
+        assert_eq!(code, 512);
+        assert_eq!(reason, "Bad arguments to ADD_ONION");
+
+        let err = command
+            .parse_responses(response_lines(&["550 Onion address collision"]))
+            .unwrap_err();
+        unpack_enum!(TorClientError::OnionServiceExists { service_id } = err);
+        assert_eq!(service_id, None);
+
+        let err = command
+            .parse_responses(response_lines(&["250-PrivateKey=ED25519-V3:this-is-a-key", "250 OK"]))
+            .unwrap_err();
+        unpack_enum!(TorClientError::AddOnionNoServiceId = err);
+    }
+
+    #[test]
+    fn flags_segment() {
+        let command = AddOnion::new(KeyType::New, KeyBlob::Ed25519V3, vec![], 9090.into(), None);
+        assert_eq!(
+            command.to_command_string().unwrap(),
+            "ADD_ONION NEW:ED25519-V3 Port=9090,127.0.0.1:9090"
+        );
+
+        // Flags are written in the order given, without repeats
+        let command = AddOnion::new(
+            KeyType::New,
+            KeyBlob::Ed25519V3,
+            vec![
+                AddOnionFlag::NonAnonymous,
+                AddOnionFlag::Detach,
+                AddOnionFlag::NonAnonymous,
+                AddOnionFlag::DiscardPK,
+            ],
+            9090.into(),
+            None,
+        );
         assert_eq!(
             command.to_command_string().unwrap(),
-            format!("ADD_ONION NEW:{} Port=9090,127.0.0.1:9090", key)
+            "ADD_ONION NEW:ED25519-V3 Flags=NonAnonymous,Detach,DiscardPK Port=9090,127.0.0.1:9090"
         );
     }
+
+    #[test]
+    fn response_echoes_requested_config() {
+        let command = AddOnion::builder()
+            .with_detach()
+            .discard_private_key()
+            .max_streams(NonZeroU16::new(5).unwrap())
+            .add_port(80)
+            .add_port((443u16, SocketAddr::from(([127, 0, 0, 1], 8443))))
+            .build()
+            .unwrap();
+        let resp = command.parse_responses(response_lines(REPLY_WITHOUT_KEY)).unwrap();
+        assert_eq!(resp.onion_port, 80);
+        let ports = resp
+            .port_mappings
+            .iter()
+            .map(|p| (p.onion_port(), *p.target()))
+            .collect::<Vec<_>>();
+        assert_eq!(ports, [
+            (80, SocketAddr::from(([127, 0, 0, 1], 80)).into()),
+            (443, SocketAddr::from(([127, 0, 0, 1], 8443)).into())
+        ]);
+        assert_eq!(resp.num_streams, NonZeroU16::new(5));
+        assert_eq!(resp.flags.as_slice(), [
+            AddOnionFlag::Detach,
+            AddOnionFlag::DiscardPK,
+            AddOnionFlag::MaxStreamsCloseCircuit
+        ]);
+    }
+
+    #[test]
+    fn other_key_type_round_trip() {
+        let command = AddOnion::builder()
+            .with_key(AnyKeyType::Other("X25519-V9"), KeyBlob::String("future-key"))
+            .add_port(9090)
+            .build()
+            .unwrap();
+        command.validate().unwrap();
+        assert_eq!(
+            command.to_command_string().unwrap(),
+            "ADD_ONION X25519-V9:future-key Port=9090,127.0.0.1:9090"
+        );
+
+        let resp = AddOnion::builder()
+            .add_port(9090)
+            .build()
+            .unwrap()
+            .parse_responses(response_lines(&[
+                "250-ServiceID=qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid",
+                "250-PrivateKey=X25519-V9:future-key",
+                "250 OK",
+            ]))
+            .unwrap();
+        let private_key = resp.private_key.unwrap();
+        assert!(
+            matches!(&private_key, PrivateKey::Other { key_type, key } if key_type == "X25519-V9" && key == "future-key")
+        );
+        let command = AddOnion::builder()
+            .with_private_key(&private_key)
+            .add_port(9090)
+            .build()
+            .unwrap();
+        assert_eq!(
+            command.to_command_string().unwrap(),
+            "ADD_ONION X25519-V9:future-key Port=9090,127.0.0.1:9090"
+        );
+
+        let command = AddOnion::new(
+            AnyKeyType::Other("BAD TYPE"),
+            KeyBlob::String("key"),
+            vec![],
+            9090.into(),
+            None,
+        );
+        let err = command.validate().unwrap_err();
+        unpack_enum!(TorClientError::AddOnionInvalidKey(_msg) = err);
+    }
+
+    /// Tests of the deprecated v2 (RSA1024) paths, which are kept for legacy callers
+    #[allow(deprecated)]
+    mod v2 {
+        use super::*;
+
+        const DESCRIPTOR_COOKIE: &str = "J7wHzmSx2BR5v0Ch/RTvhA";
+
+        #[test]
+        fn builds_legacy_commands() {
+            let command = AddOnion::builder()
+                .with_key(KeyType::New, KeyBlob::Rsa1024)
+                .add_port(9090)
+                .build()
+                .unwrap();
+            assert!(command.is_v2());
+            assert_eq!(
+                command.to_command_string().unwrap(),
+                "ADD_ONION NEW:RSA1024 Port=9090,127.0.0.1:9090"
+            );
+        }
+
+        #[test]
+        fn basic_auth() {
+            let command = AddOnion::builder()
+                .with_key(KeyType::New, KeyBlob::Rsa1024)
+                .with_flag(AddOnionFlag::BasicAuth)
+                .add_port(9090)
+                .build()
+                .unwrap();
+            command.validate().unwrap();
+        }
+
+        #[test]
+        fn client_auth_v3_requires_v3() {
+            let err = AddOnion::builder()
+                .with_key(KeyType::New, KeyBlob::Rsa1024)
+                .add_client_auth_v3(CLIENT_KEY_1)
+                .add_port(80)
+                .build()
+                .err()
+                .unwrap();
+            unpack_enum!(TorClientError::AddOnionClientAuthV3RequiresV3 = err);
+        }
+
+        #[test]
+        fn client_auth_v2() {
+            let alice = ClientName::new("alice").unwrap();
+            let bob = ClientName::new("bob").unwrap();
+            let command = AddOnion::builder()
+                .with_key(KeyType::New, KeyBlob::Rsa1024)
+                .add_client_auth_v2(&alice, Some(DESCRIPTOR_COOKIE))
+                .add_client_auth_v2(&bob, None)
+                .add_port(9090)
+                .build()
+                .unwrap();
+            assert_eq!(
+                command.to_command_string().unwrap(),
+                "ADD_ONION NEW:RSA1024 Flags=BasicAuth Port=9090,127.0.0.1:9090 \
+                 ClientAuth=alice:J7wHzmSx2BR5v0Ch/RTvhA ClientAuth=bob"
+            );
+            let displayed = command.to_string();
+            assert!(displayed.contains(" ClientAuth=alice:[redacted] ClientAuth=bob"));
+            assert!(!displayed.contains(DESCRIPTOR_COOKIE));
+
+            let err = AddOnion::builder()
+                .add_client_auth_v2(&alice, None)
+                .add_port(9090)
+                .build()
+                .err()
+                .unwrap();
+            assert!(matches!(err, TorClientError::AddOnionFlagNotSupported(_)));
+            let err = AddOnion::builder()
+                .with_key(AnyKeyType::Other("X25519"), KeyBlob::String("dummy-key"))
+                .add_client_auth_v2(&alice, None)
+                .add_port(9090)
+                .build()
+                .err()
+                .unwrap();
+            assert!(matches!(err, TorClientError::AddOnionClientAuthV2RequiresV2));
+
+            let err = AddOnion::builder()
+                .with_key(KeyType::Rsa1024, KeyBlob::String("dummy-key"))
+                .add_client_auth_v2(&alice, None)
+                .add_client_auth_v2(&alice, Some(DESCRIPTOR_COOKIE))
+                .add_port(9090)
+                .build()
+                .err()
+                .unwrap();
+            unpack_enum!(TorClientError::AddOnionDuplicateClientName(name) = err);
+            assert_eq!(name, "alice");
+
+            for cookie in &[
+                "",
+                "J7wHzmSx2BR5v0Ch/RTvhA==",
+                "J7wHzmSx2BR5v0Ch/RTv",
+                "not a cookie!!!!!!!!!!",
+            ] {
+                let err = AddOnion::builder()
+                    .with_key(KeyType::New, KeyBlob::Rsa1024)
+                    .add_client_auth_v2(&bob, Some(cookie))
+                    .add_port(9090)
+                    .build()
+                    .err()
+                    .unwrap();
+                unpack_enum!(TorClientError::AddOnionInvalidDescriptorCookie(name) = err);
+                assert_eq!(name, "bob");
+            }
+        }
+
+        #[test]
+        fn validate_for_version() {
+            let v2 = AddOnion::new(KeyType::New, KeyBlob::Rsa1024, vec![], 9090.into(), None);
+            v2.validate_for_version(TorVersion::new(0, 4, 5, 9)).unwrap();
+            let err = v2.validate_for_version(TorVersion::new(0, 4, 6, 1)).unwrap_err();
+            unpack_enum!(TorClientError::V2OnionUnsupported(version) = err);
+            assert_eq!(version, TorVersion::new(0, 4, 6, 1));
+
+            let v3 = AddOnion::new_ephemeral_v3(9090, ([127, 0, 0, 1], 8080).into());
+            v3.validate_for_version(TorVersion::new(0, 4, 6, 1)).unwrap();
+        }
+
+        #[test]
+        fn rsa1024_private_key_round_trip() {
+            const RSA1024_KEY: &str = "MIICXAIBAAKBgQC7/LJzdealNG0B90NzuAc9Qd64me8bGSpDJ2Fh+qKe1OhQx/tLx4";
+            let command = AddOnion::builder()
+                .with_key(KeyType::New, KeyBlob::Rsa1024)
+                .add_port(9090)
+                .build()
+                .unwrap();
+            let resp = command
+                .parse_responses(response_lines(&[
+                    "250-ServiceID=62q4tswkxp74dtn7",
+                    &format!("250-PrivateKey=RSA1024:{}", RSA1024_KEY),
+                    "250 OK",
+                ]))
+                .unwrap();
+            let private_key = resp.private_key.unwrap();
+            assert!(matches!(&private_key, PrivateKey::Rsa1024(key) if key == RSA1024_KEY));
+
+            let command = AddOnion::builder()
+                .with_private_key(&private_key)
+                .add_port(9090)
+                .build()
+                .unwrap();
+            assert!(command.is_v2());
+            assert_eq!(
+                command.to_command_string().unwrap(),
+                format!("ADD_ONION RSA1024:{} Port=9090,127.0.0.1:9090", RSA1024_KEY)
+            );
+        }
+    }
 }
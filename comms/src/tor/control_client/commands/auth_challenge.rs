@@ -0,0 +1,211 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::tor::control_client::{
+    commands::{self, TorCommand},
+    error::TorClientError,
+    parsers,
+    parsers::ParseError,
+    response::ResponseLine,
+};
+use data_encoding::{HEXUPPER, HEXUPPER_PERMISSIVE};
+use std::fmt;
+
+const AUTHCHALLENGE_PREFIX: &str = "AUTHCHALLENGE SAFECOOKIE ";
+
+/// The AUTHCHALLENGE command.
+///
+/// This command starts SAFECOOKIE authentication. The client sends a random nonce and tor replies with a hash proving
+/// that it knows the authentication cookie, along with its own nonce.
+pub struct AuthChallenge<'a> {
+    client_nonce: &'a [u8],
+}
+
+impl<'a> AuthChallenge<'a> {
+    pub fn new(client_nonce: &'a [u8]) -> Self {
+        Self { client_nonce }
+    }
+}
+
+impl TorCommand for AuthChallenge<'_> {
+    type Error = TorClientError;
+    type Output = AuthChallengeResponse;
+
+    fn verb(&self) -> &str {
+        "AUTHCHALLENGE"
+    }
+
+    fn to_command_string(&self) -> Result<String, Self::Error> {
+        Ok(format!(
+            "{}{}",
+            AUTHCHALLENGE_PREFIX,
+            HEXUPPER.encode(self.client_nonce)
+        ))
+    }
+
+    fn parse_responses<I>(&self, responses: I) -> Result<Self::Output, Self::Error>
+    where I: IntoIterator<Item = ResponseLine> {
+        let response = commands::last_response(responses)?;
+        if let Some(err) = response.err() {
+            return Err(TorClientError::TorCommandFailed(err.to_owned()));
+        }
+
+        let args = response
+            .value
+            .strip_prefix("AUTHCHALLENGE ")
+            .ok_or_else(|| ParseError(format!("Unexpected AUTHCHALLENGE reply '{}'", response.value)))?;
+
+        let mut server_hash = None;
+        let mut server_nonce = None;
+        for (key, value) in parsers::key_value_args(args)? {
+            match key {
+                "SERVERHASH" => server_hash = Some(parse_hex_32(key, &value)?),
+                "SERVERNONCE" => server_nonce = Some(parse_hex_32(key, &value)?),
+                _ => {},
+            }
+        }
+
+        Ok(AuthChallengeResponse {
+            server_hash: server_hash
+                .ok_or_else(|| ParseError("AUTHCHALLENGE reply did not include SERVERHASH".to_string()))?,
+            server_nonce: server_nonce
+                .ok_or_else(|| ParseError("AUTHCHALLENGE reply did not include SERVERNONCE".to_string()))?,
+        })
+    }
+}
+
+impl fmt::Display for AuthChallenge<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", AUTHCHALLENGE_PREFIX, HEXUPPER.encode(self.client_nonce))
+    }
+}
+
+/// Decodes a hex encoded 32-byte value
+fn parse_hex_32(key: &str, value: &str) -> Result<[u8; 32], ParseError> {
+    let mut bytes = [0u8; 32];
+    let len = HEXUPPER_PERMISSIVE
+        .decode_len(value.len())
+        .map_err(|err| ParseError(format!("Invalid {} '{}': {}", key, value, err)))?;
+    if len != bytes.len() {
+        return Err(ParseError(format!(
+            "Invalid {} '{}': expected {} bytes but got {}",
+            key,
+            value,
+            bytes.len(),
+            len
+        )));
+    }
+    HEXUPPER_PERMISSIVE
+        .decode_mut(value.as_bytes(), &mut bytes)
+        .map_err(|err| ParseError(format!("Invalid {} '{}': {}", key, value, err.error)))?;
+    Ok(bytes)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthChallengeResponse {
+    server_hash: [u8; 32],
+    server_nonce: [u8; 32],
+}
+
+impl AuthChallengeResponse {
+    /// The HMAC-SHA256 of the cookie and both nonces, computed by tor using the server-to-controller key.
+    pub fn server_hash(&self) -> &[u8; 32] {
+        &self.server_hash
+    }
+
+    pub fn server_nonce(&self) -> &[u8; 32] {
+        &self.server_nonce
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tari_test_utils::unpack_enum;
+
+    const SERVER_HASH: &str = "1AE7CB3D79DE6E55B509142AE4F7F3D2C0B4F1B2463A69B37D8FCF3C0472EAF2";
+    const SERVER_NONCE: &str = "0b6b81cdb3e3e6cad7c1a4ad0e9d2c4e0ff03b7e6bc8d9d7f4a2b3c1d0e9f8a7";
+
+    fn parse(line: &str) -> Result<AuthChallengeResponse, TorClientError> {
+        AuthChallenge::new(&[0; 32]).parse_responses(vec![parsers::response_line(line).unwrap()])
+    }
+
+    #[test]
+    fn to_command_string() {
+        let command = AuthChallenge::new(&[0xab, 0x01, 0xff]);
+        assert_eq!(command.to_command_string().unwrap(), "AUTHCHALLENGE SAFECOOKIE AB01FF");
+    }
+
+    #[test]
+    fn parse_responses() {
+        let response = parse(&format!(
+            "250 AUTHCHALLENGE SERVERHASH={} SERVERNONCE={}",
+            SERVER_HASH, SERVER_NONCE
+        ))
+        .unwrap();
+        assert_eq!(
+            response.server_hash()[..],
+            HEXUPPER.decode(SERVER_HASH.as_bytes()).unwrap()[..]
+        );
+        assert_eq!(response.server_nonce()[0], 0x0b);
+        assert_eq!(response.server_nonce()[31], 0xa7);
+    }
+
+    #[test]
+    fn parse_responses_malformed() {
+        let cases = [
+            // Not hex
+            format!(
+                "250 AUTHCHALLENGE SERVERHASH={} SERVERNONCE={}",
+                "Z".repeat(64),
+                SERVER_NONCE
+            ),
+            // Too short
+            format!(
+                "250 AUTHCHALLENGE SERVERHASH={} SERVERNONCE={}",
+                SERVER_HASH,
+                &SERVER_NONCE[..62]
+            ),
+            // Too long
+            format!(
+                "250 AUTHCHALLENGE SERVERHASH={}00 SERVERNONCE={}",
+                SERVER_HASH, SERVER_NONCE
+            ),
+            // Odd length
+            format!(
+                "250 AUTHCHALLENGE SERVERHASH={} SERVERNONCE={}",
+                &SERVER_HASH[..63],
+                SERVER_NONCE
+            ),
+            // Missing field
+            format!("250 AUTHCHALLENGE SERVERHASH={}", SERVER_HASH),
+            format!("250 SERVERHASH={} SERVERNONCE={}", SERVER_HASH, SERVER_NONCE),
+        ];
+        for case in cases.iter() {
+            let err = parse(case).unwrap_err();
+            unpack_enum!(TorClientError::ParseError(_err) = err);
+        }
+
+        let err = parse("515 Authentication failed: Wrong length for client nonce").unwrap_err();
+        unpack_enum!(TorClientError::TorCommandFailed(_msg) = err);
+    }
+}
@@ -20,9 +20,15 @@
 // WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
 // USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use crate::tor::control_client::{commands::TorCommand, error::TorClientError, response::ResponseLine};
+use crate::tor::control_client::{
+    commands::{self, TorCommand},
+    error::TorClientError,
+    response::ResponseLine,
+};
 use std::fmt;
 
+const DEL_ONION_PREFIX: &str = "DEL_ONION ";
+
 /// The DEL_ONION command.
 ///
 /// This instructs Tor to delete a hidden service.
@@ -40,12 +46,24 @@ impl<'a> TorCommand for DelOnion<'a> {
     type Error = TorClientError;
     type Output = ();
 
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn verb(&self) -> &str {
+        DEL_ONION_PREFIX.trim_end()
+    }
+
     fn to_command_string(&self) -> Result<String, Self::Error> {
-        Ok(format!("DEL_ONION {}", self.service_id))
+        let mut s = String::with_capacity(DEL_ONION_PREFIX.len() + self.service_id.len());
+        s.push_str(DEL_ONION_PREFIX);
+        s.push_str(self.service_id);
+        Ok(s)
     }
 
-    fn parse_responses(&self, mut responses: Vec<ResponseLine>) -> Result<Self::Output, Self::Error> {
-        let last_response = responses.pop().ok_or_else(|| TorClientError::UnexpectedEof)?;
+    fn parse_responses<I>(&self, responses: I) -> Result<Self::Output, Self::Error>
+    where I: IntoIterator<Item = ResponseLine> {
+        let last_response = commands::last_response(responses)?;
         if let Some(err) = last_response.err() {
             return Err(TorClientError::TorCommandFailed(err.to_owned()));
         }
@@ -56,7 +74,7 @@ impl<'a> TorCommand for DelOnion<'a> {
 
 impl fmt::Display for DelOnion<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "DEL_ONION (ServiceId = {})", self.service_id)
+        write!(f, "{}{}", DEL_ONION_PREFIX, self.service_id)
     }
 }
 
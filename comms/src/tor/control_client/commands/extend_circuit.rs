@@ -0,0 +1,339 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::tor::control_client::{
+    circuit::CircuitPurpose,
+    commands::TorCommand,
+    error::TorClientError,
+    guard::RelaySpec,
+    parsers::ParseError,
+    response::ResponseLine,
+};
+use data_encoding::HEXUPPER;
+use std::fmt;
+
+const EXTENDCIRCUIT: &str = "EXTENDCIRCUIT";
+/// The circuit ID that asks tor to build a new circuit
+const NEW_CIRCUIT_ID: &str = "0";
+/// The longest relay nickname tor accepts
+const NICKNAME_MAX_LEN: usize = 19;
+
+/// A relay in the path of an [ExtendCircuit] command
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PathRelay<'a> {
+    Spec(RelaySpec),
+    /// Nicknames are not unique, so tor may choose any relay with the nickname
+    Nickname(&'a str),
+}
+
+impl fmt::Display for PathRelay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathRelay::Spec(relay) => write!(f, "{}", relay),
+            PathRelay::Nickname(nickname) => f.write_str(nickname),
+        }
+    }
+}
+
+/// The EXTENDCIRCUIT command.
+///
+/// This command instructs tor to build a new circuit, or to extend an existing circuit, through the given relays. The
+/// output is the ID of the circuit.
+pub struct ExtendCircuit<'a> {
+    circuit_id: Option<&'a str>,
+    path: Vec<PathRelay<'a>>,
+    purpose: Option<CircuitPurpose>,
+}
+
+impl<'a> ExtendCircuit<'a> {
+    /// Returns a builder for an EXTENDCIRCUIT command. By default, the command builds a new circuit.
+    pub fn builder() -> ExtendCircuitBuilder<'a> {
+        ExtendCircuitBuilder::new()
+    }
+
+    /// Returns true if this command builds a new circuit rather than extending an existing one
+    pub fn is_new_circuit(&self) -> bool {
+        self.circuit_id.is_none()
+    }
+
+    fn write_to<W: fmt::Write>(&self, writer: &mut W) -> fmt::Result {
+        write!(
+            writer,
+            "{} {}",
+            EXTENDCIRCUIT,
+            self.circuit_id.unwrap_or(NEW_CIRCUIT_ID)
+        )?;
+        for (i, relay) in self.path.iter().enumerate() {
+            writer.write_str(if i == 0 { " " } else { "," })?;
+            write!(writer, "{}", relay)?;
+        }
+        if let Some(purpose) = &self.purpose {
+            // Unlike in replies and events, tor expects the purpose in lower case
+            write!(writer, " purpose={}", purpose.to_string().to_ascii_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+/// Builder for the [ExtendCircuit] command.
+pub struct ExtendCircuitBuilder<'a> {
+    circuit_id: Option<&'a str>,
+    path: Vec<PathRelay<'a>>,
+    purpose: Option<CircuitPurpose>,
+}
+
+impl<'a> ExtendCircuitBuilder<'a> {
+    pub fn new() -> Self {
+        Self {
+            circuit_id: None,
+            path: Vec::new(),
+            purpose: None,
+        }
+    }
+
+    /// Extend the existing circuit with the given ID instead of building a new circuit
+    pub fn extend(mut self, circuit_id: &'a str) -> Self {
+        self.circuit_id = Some(circuit_id);
+        self
+    }
+
+    /// Add a relay to the end of the path
+    pub fn add_relay(mut self, relay: RelaySpec) -> Self {
+        self.path.push(PathRelay::Spec(relay));
+        self
+    }
+
+    /// Add the relay with the given identity fingerprint to the end of the path
+    pub fn add_fingerprint(self, fingerprint: [u8; 20]) -> Self {
+        self.add_relay(RelaySpec {
+            fingerprint: HEXUPPER.encode(&fingerprint),
+            nickname: None,
+        })
+    }
+
+    /// Add the relay with the given nickname to the end of the path. Nicknames are not unique, so tor may choose any
+    /// relay with the nickname.
+    pub fn add_nickname(mut self, nickname: &'a str) -> Self {
+        self.path.push(PathRelay::Nickname(nickname));
+        self
+    }
+
+    /// Set the purpose of a new circuit, which must be [CircuitPurpose::General] (the default) or
+    /// [CircuitPurpose::Controller]
+    pub fn with_purpose(mut self, purpose: CircuitPurpose) -> Self {
+        self.purpose = Some(purpose);
+        self
+    }
+
+    /// Build the [ExtendCircuit] command. An error is returned if the command fails
+    /// [validation](TorCommand::validate).
+    pub fn build(self) -> Result<ExtendCircuit<'a>, TorClientError> {
+        let command = ExtendCircuit {
+            circuit_id: self.circuit_id,
+            path: self.path,
+            purpose: self.purpose,
+        };
+        command.validate()?;
+        Ok(command)
+    }
+}
+
+impl Default for ExtendCircuitBuilder<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TorCommand for ExtendCircuit<'_> {
+    type Error = TorClientError;
+    type Output = String;
+
+    /// Checks that an existing circuit is given by a (non-zero) numeric ID and is extended by at least one relay
+    /// without a purpose, that relay nicknames are valid and that the purpose is one that tor accepts.
+    fn validate(&self) -> Result<(), Self::Error> {
+        if let Some(circuit_id) = self.circuit_id {
+            let is_numeric = !circuit_id.is_empty() && circuit_id.chars().all(|ch| ch.is_ascii_digit());
+            if !is_numeric || circuit_id.chars().all(|ch| ch == '0') {
+                return Err(TorClientError::ExtendCircuitInvalidId(circuit_id.to_string()));
+            }
+            if self.path.is_empty() {
+                return Err(TorClientError::ExtendCircuitEmptyPath);
+            }
+            if let Some(purpose) = &self.purpose {
+                return Err(TorClientError::ExtendCircuitInvalidPurpose(purpose.to_string()));
+            }
+        }
+
+        for relay in &self.path {
+            if let PathRelay::Nickname(nickname) = relay {
+                let is_valid = !nickname.is_empty() &&
+                    nickname.len() <= NICKNAME_MAX_LEN &&
+                    nickname.chars().all(|ch| ch.is_ascii_alphanumeric());
+                if !is_valid {
+                    return Err(TorClientError::ExtendCircuitInvalidNickname(nickname.to_string()));
+                }
+            }
+        }
+
+        match &self.purpose {
+            None | Some(CircuitPurpose::General) | Some(CircuitPurpose::Controller) => Ok(()),
+            Some(purpose) => Err(TorClientError::ExtendCircuitInvalidPurpose(purpose.to_string())),
+        }
+    }
+
+    fn verb(&self) -> &str {
+        EXTENDCIRCUIT
+    }
+
+    fn to_command_string(&self) -> Result<String, Self::Error> {
+        let mut s = String::new();
+        self.write_command(&mut s)?;
+        Ok(s)
+    }
+
+    fn write_command(&self, buf: &mut String) -> Result<(), Self::Error> {
+        self.write_to(buf).expect("fmt::Write for String is infallible");
+        Ok(())
+    }
+
+    fn parse_responses<I>(&self, responses: I) -> Result<Self::Output, Self::Error>
+    where I: IntoIterator<Item = ResponseLine> {
+        let response = responses
+            .into_iter()
+            .find(|resp| resp.is_end_reply())
+            .ok_or_else(|| TorClientError::ServerNoResponse)?;
+        if response.is_err() {
+            return Err(TorClientError::TorCommandFailed(response.value));
+        }
+
+        let mut parts = response.value.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some("EXTENDED"), Some(circuit_id)) if !circuit_id.is_empty() => Ok(circuit_id.trim().to_string()),
+            _ => Err(ParseError(format!("Unexpected EXTENDCIRCUIT reply '{}'", response.value)).into()),
+        }
+    }
+}
+
+impl fmt::Display for ExtendCircuit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_to(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tor::control_client::commands::test_utils::response_lines;
+    use tari_test_utils::unpack_enum;
+
+    const FINGERPRINT_1: [u8; 20] = [0xAA; 20];
+    const FINGERPRINT_2: [u8; 20] = [0x0B; 20];
+
+    #[test]
+    fn to_command_string() {
+        let command = ExtendCircuit::builder()
+            .add_fingerprint(FINGERPRINT_1)
+            .add_fingerprint(FINGERPRINT_2)
+            .with_purpose(CircuitPurpose::Controller)
+            .build()
+            .unwrap();
+        assert!(command.is_new_circuit());
+        assert_eq!(
+            command.to_command_string().unwrap(),
+            "EXTENDCIRCUIT 0 $AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA,$0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B \
+             purpose=controller"
+        );
+        assert_eq!(command.to_string(), command.to_command_string().unwrap());
+
+        // Tor chooses the path if none is given
+        let command = ExtendCircuit::builder().build().unwrap();
+        assert_eq!(command.to_command_string().unwrap(), "EXTENDCIRCUIT 0");
+
+        let command = ExtendCircuit::builder()
+            .extend("12")
+            .add_nickname("relay1")
+            .add_relay("$0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b~relay2".parse().unwrap())
+            .build()
+            .unwrap();
+        assert!(!command.is_new_circuit());
+        assert_eq!(
+            command.to_command_string().unwrap(),
+            "EXTENDCIRCUIT 12 relay1,$0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B~relay2"
+        );
+    }
+
+    #[test]
+    fn validate() {
+        let err = ExtendCircuit::builder().extend("12").build().err().unwrap();
+        unpack_enum!(TorClientError::ExtendCircuitEmptyPath = err);
+
+        for circuit_id in &["0", "", "abc"] {
+            let err = ExtendCircuit::builder()
+                .extend(circuit_id)
+                .add_fingerprint(FINGERPRINT_1)
+                .build()
+                .err()
+                .unwrap();
+            unpack_enum!(TorClientError::ExtendCircuitInvalidId(id) = err);
+            assert_eq!(id, *circuit_id);
+        }
+
+        let err = ExtendCircuit::builder()
+            .extend("12")
+            .add_fingerprint(FINGERPRINT_1)
+            .with_purpose(CircuitPurpose::General)
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(err, TorClientError::ExtendCircuitInvalidPurpose(_)));
+
+        let err = ExtendCircuit::builder()
+            .with_purpose(CircuitPurpose::HsClientIntro)
+            .build()
+            .err()
+            .unwrap();
+        unpack_enum!(TorClientError::ExtendCircuitInvalidPurpose(purpose) = err);
+        assert_eq!(purpose, "HS_CLIENT_INTRO");
+
+        for nickname in &["", "relay one", "relay,two", "averyveryverylongname"] {
+            let err = ExtendCircuit::builder().add_nickname(nickname).build().err().unwrap();
+            unpack_enum!(TorClientError::ExtendCircuitInvalidNickname(name) = err);
+            assert_eq!(name, *nickname);
+        }
+    }
+
+    #[test]
+    fn parse_responses() {
+        let command = ExtendCircuit::builder().build().unwrap();
+        let circuit_id = command.parse_responses(response_lines(&["250 EXTENDED 1234"])).unwrap();
+        assert_eq!(circuit_id, "1234");
+
+        let err = command
+            .parse_responses(response_lines(&["552 No such router \"relay1\""]))
+            .unwrap_err();
+        unpack_enum!(TorClientError::TorCommandFailed(msg) = err);
+        assert_eq!(msg, "No such router \"relay1\"");
+
+        let err = command.parse_responses(response_lines(&["250 OK"])).unwrap_err();
+        assert!(matches!(err, TorClientError::ParseError(_)));
+    }
+}
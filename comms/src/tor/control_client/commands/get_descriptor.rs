@@ -0,0 +1,230 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::tor::control_client::{
+    commands::{self, TorCommand},
+    error::TorClientError,
+    response::ResponseLine,
+};
+use std::{fmt, net::IpAddr};
+
+const NOT_FOUND_CODE: u16 = 552;
+
+/// The kind of descriptor to fetch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorKind {
+    /// The relay's microdescriptor (`md/id/<fingerprint>`), which is what clients use to build circuits
+    Micro,
+    /// The relay's full server descriptor (`desc/id/<fingerprint>`). Tor only has these if it is configured to fetch
+    /// them e.g. with `FetchUselessDescriptors 1`.
+    Server,
+}
+
+impl DescriptorKind {
+    fn key_prefix(self) -> &'static str {
+        match self {
+            DescriptorKind::Micro => "md/id/",
+            DescriptorKind::Server => "desc/id/",
+        }
+    }
+}
+
+/// GETINFO for the descriptor of a relay, given its fingerprint.
+pub struct GetDescriptor<'a> {
+    kind: DescriptorKind,
+    fingerprint: &'a str,
+}
+
+impl<'a> GetDescriptor<'a> {
+    /// Creates the command for the relay with the given hex-encoded identity fingerprint, with or without the leading
+    /// `$`.
+    pub fn new(kind: DescriptorKind, fingerprint: &'a str) -> Self {
+        Self {
+            kind,
+            fingerprint: fingerprint.trim_start_matches('$'),
+        }
+    }
+}
+
+impl TorCommand for GetDescriptor<'_> {
+    type Error = TorClientError;
+    type Output = RelayDescriptor;
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        if !commands::is_hex_fingerprint(self.fingerprint) {
+            return Err(TorClientError::InvalidFingerprint(self.fingerprint.to_string()));
+        }
+        Ok(())
+    }
+
+    fn verb(&self) -> &str {
+        "GETINFO"
+    }
+
+    fn to_command_string(&self) -> Result<String, Self::Error> {
+        Ok(self.to_string())
+    }
+
+    fn parse_responses<I>(&self, responses: I) -> Result<Self::Output, Self::Error>
+    where I: IntoIterator<Item = ResponseLine> {
+        let mut body = None;
+        let mut responses = responses.into_iter();
+        while let Some(response) = responses.next() {
+            if response.is_err() {
+                if response.code == NOT_FOUND_CODE {
+                    return Err(TorClientError::DescriptorNotFound {
+                        fingerprint: self.fingerprint.to_string(),
+                    });
+                }
+                return Err(TorClientError::TorCommandFailed(commands::trailing_errors(
+                    response,
+                    &mut responses,
+                )));
+            }
+            if commands::is_trailing_ok(&response) {
+                continue;
+            }
+            // The descriptor is returned as the data body of a multi-line `key=` reply
+            if let Some(pos) = response.value.find('=') {
+                body = Some(response.value[pos + 1..].trim_start_matches('\n').to_string());
+            }
+        }
+
+        let body = body.ok_or_else(|| TorClientError::ServerNoResponse)?;
+        Ok(RelayDescriptor::parse(self.fingerprint, body))
+    }
+}
+
+impl fmt::Display for GetDescriptor<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "GETINFO {}{}", self.kind.key_prefix(), self.fingerprint)
+    }
+}
+
+/// A relay descriptor. The full descriptor is kept in `body`, and a few commonly used fields are parsed from it. Which
+/// fields are present depends on the kind of descriptor: microdescriptors do not include the relay's address, and
+/// server descriptors do not include the exit policy summary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayDescriptor {
+    pub fingerprint: String,
+    pub body: String,
+    /// From the `router` line of a server descriptor
+    pub nickname: Option<String>,
+    /// From the `router` line of a server descriptor
+    pub address: Option<IpAddr>,
+    /// From the `router` line of a server descriptor
+    pub or_port: Option<u16>,
+    /// From the `router` line of a server descriptor, if the relay is a directory cache
+    pub dir_port: Option<u16>,
+    /// From the `p` line of a microdescriptor e.g. `accept 80,443`
+    pub exit_policy_summary: Option<String>,
+}
+
+impl RelayDescriptor {
+    fn parse(fingerprint: &str, body: String) -> Self {
+        let mut descriptor = Self {
+            fingerprint: fingerprint.to_string(),
+            body: String::new(),
+            nickname: None,
+            address: None,
+            or_port: None,
+            dir_port: None,
+            exit_policy_summary: None,
+        };
+
+        for line in body.lines() {
+            let mut parts = line.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some("router"), Some(args)) => {
+                    // router <nickname> <address> <ORPort> <SOCKSPort> <DirPort>
+                    let args = args.split(' ').collect::<Vec<_>>();
+                    descriptor.nickname = args.get(0).map(ToString::to_string);
+                    descriptor.address = args.get(1).and_then(|addr| addr.parse().ok());
+                    descriptor.or_port = args.get(2).and_then(|port| port.parse().ok());
+                    descriptor.dir_port = args.get(4).and_then(|port| port.parse().ok()).filter(|port| *port != 0);
+                },
+                (Some("p"), Some(policy)) => {
+                    descriptor.exit_policy_summary = Some(policy.to_string());
+                },
+                _ => {},
+            }
+        }
+
+        descriptor.body = body;
+        descriptor
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tor::control_client::parsers;
+    use tari_test_utils::unpack_enum;
+
+    const FINGERPRINT: &str = "9695DFC35FFEB861329B9F1AB04C46397020CE31";
+
+    #[test]
+    fn validate() {
+        GetDescriptor::new(DescriptorKind::Micro, FINGERPRINT)
+            .validate()
+            .unwrap();
+        GetDescriptor::new(DescriptorKind::Micro, &format!("${}", FINGERPRINT))
+            .validate()
+            .unwrap();
+        let err = GetDescriptor::new(DescriptorKind::Server, "9695DFC35FFEB861329B9F1AB04C46397020CE31 x")
+            .validate()
+            .unwrap_err();
+        unpack_enum!(TorClientError::InvalidFingerprint(fingerprint) = err);
+        assert_eq!(fingerprint, "9695DFC35FFEB861329B9F1AB04C46397020CE31 x");
+    }
+
+    #[test]
+    fn parse_not_found() {
+        let command = GetDescriptor::new(DescriptorKind::Micro, FINGERPRINT);
+        let err = command
+            .parse_responses(vec![parsers::response_line(&format!(
+                "552 Unrecognized key \"md/id/{}\"",
+                FINGERPRINT
+            ))
+            .unwrap()])
+            .unwrap_err();
+        unpack_enum!(TorClientError::DescriptorNotFound { fingerprint } = err);
+        assert_eq!(fingerprint, FINGERPRINT);
+    }
+
+    #[test]
+    fn parse_server_descriptor() {
+        let descriptor = RelayDescriptor::parse(
+            FINGERPRINT,
+            "router moria1 128.31.0.34 9101 0 9131\nplatform Tor 0.4.5.6 on Linux\n".to_string(),
+        );
+        assert_eq!(descriptor.nickname.as_deref(), Some("moria1"));
+        assert_eq!(descriptor.address, Some([128, 31, 0, 34].into()));
+        assert_eq!(descriptor.or_port, Some(9101));
+        assert_eq!(descriptor.dir_port, Some(9131));
+        assert_eq!(descriptor.exit_policy_summary, None);
+    }
+}
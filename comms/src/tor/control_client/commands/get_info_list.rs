@@ -0,0 +1,308 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::tor::control_client::{
+    circuit::CircuitStatus,
+    commands::{self, TorCommand},
+    error::TorClientError,
+    parsers::{self, ParseError},
+    response::ResponseLine,
+    stream::StreamStatusEntry,
+    types::ListenerAddress,
+};
+use std::{fmt, vec};
+
+/// Parses the entry at the start of its argument, returning it along with the rest of the input
+pub type ParseEntry<T> = fn(&str) -> Result<(T, &str), ParseError>;
+
+/// GETINFO for a single key whose value is a list of entries, such as `circuit-status` (an entry per line) or
+/// `net/listeners/socks` (quoted entries separated by spaces).
+///
+/// The reply is kept as received and parsed one entry at a time by the returned [InfoListIter], rather than being split
+/// into entries up front.
+pub struct GetInfoList<'a, T> {
+    key: &'a str,
+    parse_entry: ParseEntry<T>,
+}
+
+impl<'a, T> GetInfoList<'a, T> {
+    pub fn new(key: &'a str, parse_entry: ParseEntry<T>) -> Self {
+        Self { key, parse_entry }
+    }
+}
+
+/// GETINFO for `circuit-status`
+pub fn get_circuit_status() -> GetInfoList<'static, CircuitStatus> {
+    GetInfoList::new("circuit-status", |s| {
+        let (line, rest) = split_line(s);
+        Ok((CircuitStatus::parse(line)?, rest))
+    })
+}
+
+/// GETINFO for `stream-status`
+pub fn get_stream_status() -> GetInfoList<'static, StreamStatusEntry> {
+    GetInfoList::new("stream-status", |s| {
+        let (line, rest) = split_line(s);
+        Ok((StreamStatusEntry::parse(line)?, rest))
+    })
+}
+
+/// GETINFO for one of the `net/listeners/*` keys e.g. `net/listeners/socks`
+pub fn get_listeners(key: &str) -> GetInfoList<'_, ListenerAddress> {
+    GetInfoList::new(key, |s| {
+        let (addr, rest) = parsers::quoted_string(s)?;
+        Ok((addr.parse()?, rest))
+    })
+}
+
+/// Splits `s` into its first line, without trailing whitespace, and the rest
+fn split_line(s: &str) -> (&str, &str) {
+    let end = s.find('\n').unwrap_or_else(|| s.len());
+    (s[..end].trim_end(), &s[end..])
+}
+
+impl<T> TorCommand for GetInfoList<'_, T> {
+    type Error = TorClientError;
+    type Output = InfoListIter<T>;
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn verb(&self) -> &str {
+        "GETINFO"
+    }
+
+    fn to_command_string(&self) -> Result<String, Self::Error> {
+        Ok(self.to_string())
+    }
+
+    fn parse_responses<I>(&self, responses: I) -> Result<Self::Output, Self::Error>
+    where I: IntoIterator<Item = ResponseLine> {
+        let mut values = Vec::new();
+        let mut responses = responses.into_iter();
+        while let Some(response) = responses.next() {
+            if response.is_err() {
+                return Err(TorClientError::TorCommandFailed(commands::trailing_errors(
+                    response,
+                    &mut responses,
+                )));
+            }
+            if commands::is_trailing_ok(&response) {
+                continue;
+            }
+            let mut value = response.value;
+            if let Some(pos) = value.find('=') {
+                // Reuse the allocation of the reply, which for a data body is the largest part of it
+                value.replace_range(..=pos, "");
+                values.push(value);
+            }
+        }
+        if values.is_empty() {
+            return Err(TorClientError::ServerNoResponse);
+        }
+
+        Ok(InfoListIter {
+            values: values.into_iter(),
+            value: String::new(),
+            pos: 0,
+            parse_entry: self.parse_entry,
+        })
+    }
+}
+
+impl<T> fmt::Display for GetInfoList<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "GETINFO {}", self.key)
+    }
+}
+
+/// Parses the entries of a [GetInfoList] reply one at a time, so that the parsed entries of e.g. a busy relay are not
+/// collected into a `Vec`. The reply itself is still received whole before it is parsed, so this does not bound the
+/// memory used for it. An entry that cannot be parsed is returned as an error and the rest of its line is skipped.
+#[derive(Clone)]
+pub struct InfoListIter<T> {
+    values: vec::IntoIter<String>,
+    value: String,
+    pos: usize,
+    parse_entry: ParseEntry<T>,
+}
+
+impl<T> fmt::Debug for InfoListIter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InfoListIter")
+            .field("values", &self.values)
+            .field("value", &self.value)
+            .field("pos", &self.pos)
+            .finish()
+    }
+}
+
+impl<T> Iterator for InfoListIter<T> {
+    type Item = Result<T, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let rest = self.value[self.pos..].trim_start();
+            if rest.is_empty() {
+                self.value = self.values.next()?;
+                self.pos = 0;
+                continue;
+            }
+            let start = self.value.len() - rest.len();
+            match (self.parse_entry)(rest) {
+                Ok((entry, remaining)) => {
+                    self.pos = self.value.len() - remaining.len();
+                    return Some(Ok(entry));
+                },
+                Err(err) => {
+                    self.pos = rest
+                        .find('\n')
+                        .map(|end| start + end + 1)
+                        .unwrap_or_else(|| self.value.len());
+                    return Some(Err(err));
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tor::control_client::{circuit::CircStatus, stream::StreamStatus};
+    use tari_test_utils::unpack_enum;
+
+    fn data_reply(key: &str, body: &str) -> Vec<ResponseLine> {
+        let mut line = parsers::response_line(&format!("250+{}=", key)).unwrap();
+        line.value.push('\n');
+        line.value.push_str(body);
+        vec![line, parsers::response_line("250 OK").unwrap()]
+    }
+
+    #[test]
+    fn circuit_status() {
+        let lines = vec![
+            parsers::response_line("250+circuit-status=").unwrap(),
+            parsers::response_line("250 OK").unwrap(),
+        ];
+        assert_eq!(get_circuit_status().parse_responses(lines).unwrap().count(), 0);
+
+        let lines = data_reply("circuit-status", "1 BUILT $AAAA~relay1 PURPOSE=GENERAL\n2 LAUNCHED");
+        let statuses = get_circuit_status()
+            .parse_responses(lines)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(statuses[0].status, CircStatus::Built);
+        assert_eq!(statuses[1].circuit_id, "2");
+
+        // A single circuit may be sent on the reply line
+        let lines = vec![parsers::response_line("250-circuit-status=3 EXTENDED").unwrap()];
+        assert_eq!(get_circuit_status().parse_responses(lines).unwrap().count(), 1);
+
+        let lines = vec![parsers::response_line("552 Unrecognized key \"circuit-status\"").unwrap()];
+        let err = get_circuit_status().parse_responses(lines).unwrap_err();
+        unpack_enum!(TorClientError::TorCommandFailed(_msg) = err);
+    }
+
+    #[test]
+    fn circuit_status_large() {
+        const NUM_CIRCUITS: usize = 100_000;
+        let body = (0..NUM_CIRCUITS)
+            .map(|i| format!("{} BUILT $AAAA~relay1,$BBBB~relay2 PURPOSE=GENERAL\n", i))
+            .collect::<String>();
+        let iter = get_circuit_status()
+            .parse_responses(data_reply("circuit-status", &body))
+            .unwrap();
+        let (count, last) = iter.fold((0, None), |(count, _), status| (count + 1, Some(status.unwrap())));
+        assert_eq!(count, NUM_CIRCUITS);
+        let last = last.unwrap();
+        assert_eq!(last.circuit_id, (NUM_CIRCUITS - 1).to_string());
+        assert_eq!(last.status, CircStatus::Built);
+    }
+
+    #[test]
+    fn circuit_status_skips_invalid_lines() {
+        let lines = data_reply("circuit-status", "\n1 LAUNCHED\n\n2\n3 EXTENDED $AAAA~relay1");
+        let mut iter = get_circuit_status().parse_responses(lines).unwrap();
+        assert_eq!(iter.next().unwrap().unwrap().status, CircStatus::Launched);
+        assert!(iter.next().unwrap().is_err());
+        assert_eq!(iter.next().unwrap().unwrap().status, CircStatus::Extended);
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn stream_status() {
+        let lines = data_reply(
+            "stream-status",
+            "1 SUCCEEDED 3 example.com:443\n2 CLOSED 3 example.com:80 REASON=DONE",
+        );
+        let statuses = get_stream_status()
+            .parse_responses(lines)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(statuses[0].status, StreamStatus::Succeeded);
+        assert_eq!(statuses[1].stream_id, "2");
+        assert!(statuses[1].status.is_closed());
+
+        let lines = vec![parsers::response_line("250-stream-status=").unwrap()];
+        assert_eq!(get_stream_status().parse_responses(lines).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn listeners() {
+        let command = get_listeners("net/listeners/socks");
+        assert_eq!(command.to_command_string().unwrap(), "GETINFO net/listeners/socks");
+
+        let lines = vec![
+            parsers::response_line(r#"250-net/listeners/socks="127.0.0.1:9050" "unix:/run/tor/my socks""#).unwrap(),
+            parsers::response_line("250 OK").unwrap(),
+        ];
+        let addrs = command
+            .parse_responses(lines)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(addrs, vec![
+            ListenerAddress::Tcp(([127, 0, 0, 1], 9050).into()),
+            ListenerAddress::Unix("/run/tor/my socks".into()),
+        ]);
+
+        let lines = vec![
+            parsers::response_line("250-net/listeners/socks=").unwrap(),
+            parsers::response_line("250 OK").unwrap(),
+        ];
+        assert_eq!(command.parse_responses(lines).unwrap().count(), 0);
+
+        let lines = vec![
+            parsers::response_line(r#"250-net/listeners/socks="not an address" "127.0.0.1:9050""#).unwrap(),
+            parsers::response_line("250 OK").unwrap(),
+        ];
+        let mut iter = command.parse_responses(lines).unwrap();
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+}
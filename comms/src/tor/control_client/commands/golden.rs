@@ -0,0 +1,368 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Golden tests for the command line serialization of every command. Each command has its own module, so a failing
+//! test names the command whose serialization changed. Changes to these strings change what is sent to tor.
+
+use super::*;
+use crate::tor::control_client::types::{KeyBlob, KeyType, PortMapping};
+use std::{
+    fmt::Debug,
+    net::{Ipv6Addr, SocketAddr},
+    num::NonZeroU16,
+};
+
+/// Asserts that each command serializes to the expected line, using both `to_command_string` and `write_command`
+fn assert_golden<T>(cases: Vec<(&str, T, &str)>)
+where
+    T: TorCommand,
+    T::Error: Debug,
+{
+    for (name, command, expected) in cases {
+        assert_eq!(command.to_command_string().unwrap(), expected, "case '{}'", name);
+        let mut buf = String::new();
+        command.write_command(&mut buf).unwrap();
+        assert_eq!(buf, expected, "case '{}' (write_command)", name);
+    }
+}
+
+mod add_onion {
+    use super::*;
+
+    #[test]
+    fn golden() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        assert_golden(vec![
+            (
+                "serialized key",
+                AddOnion::new(
+                    KeyType::New,
+                    KeyBlob::String("this-is-a-key"),
+                    vec![],
+                    PortMapping::from_port(9090),
+                    None,
+                ),
+                "ADD_ONION NEW:this-is-a-key Port=9090,127.0.0.1:9090",
+            ),
+            (
+                "ephemeral v3",
+                AddOnion::new_ephemeral_v3(80, addr),
+                "ADD_ONION NEW:ED25519-V3 Port=80,127.0.0.1:8080",
+            ),
+            (
+                "best key",
+                AddOnion::new(KeyType::New, KeyBlob::Best, vec![], 9090.into(), None),
+                "ADD_ONION NEW:BEST Port=9090,127.0.0.1:9090",
+            ),
+            (
+                "empty flags",
+                AddOnion::builder().with_flags(vec![]).add_port(9090).build().unwrap(),
+                "ADD_ONION NEW:ED25519-V3 Port=9090,127.0.0.1:9090",
+            ),
+            (
+                "all flags",
+                AddOnion::builder()
+                    .with_key(KeyType::Ed25519V3, KeyBlob::String("this-is-a-key"))
+                    .with_flags(vec![
+                        AddOnionFlag::DiscardPK,
+                        AddOnionFlag::Detach,
+                        AddOnionFlag::NonAnonymous,
+                        AddOnionFlag::MaxStreamsCloseCircuit,
+                    ])
+                    .add_port(9090)
+                    .build()
+                    .unwrap(),
+                "ADD_ONION ED25519-V3:this-is-a-key Flags=DiscardPK,Detach,NonAnonymous,MaxStreamsCloseCircuit \
+                 Port=9090,127.0.0.1:9090",
+            ),
+            (
+                "num streams",
+                AddOnion::builder()
+                    .max_streams(NonZeroU16::new(10).unwrap())
+                    .add_port(9090)
+                    .build()
+                    .unwrap(),
+                "ADD_ONION NEW:ED25519-V3 Flags=MaxStreamsCloseCircuit NumStreams=10 Port=9090,127.0.0.1:9090",
+            ),
+            (
+                "multiple ports",
+                AddOnion::builder()
+                    .add_ports(vec![80u16, 443])
+                    .add_port((8080u16, addr))
+                    .build()
+                    .unwrap(),
+                "ADD_ONION NEW:ED25519-V3 Port=80,127.0.0.1:80 Port=443,127.0.0.1:443 Port=8080,127.0.0.1:8080",
+            ),
+            (
+                "ipv6 target",
+                AddOnion::new_ephemeral_v3(80, (Ipv6Addr::LOCALHOST, 8080).into()),
+                "ADD_ONION NEW:ED25519-V3 Port=80,[::1]:8080",
+            ),
+            (
+                "hostname target",
+                AddOnion::builder()
+                    .add_port(PortMapping::new_host(80, "backend.internal", 8080).unwrap())
+                    .build()
+                    .unwrap(),
+                "ADD_ONION NEW:ED25519-V3 Port=80,backend.internal:8080",
+            ),
+            (
+                "v3 client auth",
+                AddOnion::builder()
+                    .max_streams(NonZeroU16::new(4).unwrap())
+                    .add_client_auth_v3("FPMANSL7BYAK6GQ7YMZI7J3DVETJOI6I3OH2YT4TV5Y5WGDNN2IA")
+                    .add_client_auth_v3("QG3DPWH42LDNUY2Z42LDCE5BC4G6PFPEW4S3QTI6BNGP3HWFRTUQ")
+                    .add_port(9090)
+                    .build()
+                    .unwrap(),
+                "ADD_ONION NEW:ED25519-V3 Flags=MaxStreamsCloseCircuit,V3Auth NumStreams=4 Port=9090,127.0.0.1:9090 \
+                 ClientAuthV3=FPMANSL7BYAK6GQ7YMZI7J3DVETJOI6I3OH2YT4TV5Y5WGDNN2IA \
+                 ClientAuthV3=QG3DPWH42LDNUY2Z42LDCE5BC4G6PFPEW4S3QTI6BNGP3HWFRTUQ",
+            ),
+        ]);
+    }
+}
+
+mod auth_challenge {
+    use super::*;
+
+    #[test]
+    fn golden() {
+        assert_golden(vec![(
+            "client nonce",
+            AuthChallenge::new(&[0x01, 0x23, 0xab, 0xcd]),
+            "AUTHCHALLENGE SAFECOOKIE 0123ABCD",
+        )]);
+    }
+}
+
+mod get_info_list {
+    use super::*;
+
+    #[test]
+    fn golden() {
+        assert_golden(vec![("circuit-status", get_circuit_status(), "GETINFO circuit-status")]);
+        assert_golden(vec![("stream-status", get_stream_status(), "GETINFO stream-status")]);
+        assert_golden(vec![(
+            "net/listeners",
+            get_listeners("net/listeners/socks"),
+            "GETINFO net/listeners/socks",
+        )]);
+    }
+}
+
+mod del_onion {
+    use super::*;
+
+    #[test]
+    fn golden() {
+        assert_golden(vec![(
+            "service id",
+            DelOnion::new("qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid"),
+            "DEL_ONION qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid",
+        )]);
+    }
+}
+
+mod extend_circuit {
+    use super::*;
+    use crate::tor::control_client::circuit::CircuitPurpose;
+
+    #[test]
+    fn golden() {
+        assert_golden(vec![
+            (
+                "new circuit",
+                ExtendCircuit::builder()
+                    .add_fingerprint([0xAA; 20])
+                    .add_fingerprint([0x0B; 20])
+                    .with_purpose(CircuitPurpose::Controller)
+                    .build()
+                    .unwrap(),
+                "EXTENDCIRCUIT 0 $AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA,$0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B0B \
+                 purpose=controller",
+            ),
+            (
+                "extend existing",
+                ExtendCircuit::builder()
+                    .extend("7")
+                    .add_nickname("relay1")
+                    .build()
+                    .unwrap(),
+                "EXTENDCIRCUIT 7 relay1",
+            ),
+        ]);
+    }
+}
+
+mod get_descriptor {
+    use super::*;
+
+    #[test]
+    fn golden() {
+        const FINGERPRINT: &str = "9695DFC35FFEB861329B9F1AB04C46397020CE31";
+        assert_golden(vec![
+            (
+                "microdescriptor",
+                GetDescriptor::new(DescriptorKind::Micro, FINGERPRINT),
+                "GETINFO md/id/9695DFC35FFEB861329B9F1AB04C46397020CE31",
+            ),
+            (
+                "server descriptor with $",
+                GetDescriptor::new(DescriptorKind::Server, "$9695DFC35FFEB861329B9F1AB04C46397020CE31"),
+                "GETINFO desc/id/9695DFC35FFEB861329B9F1AB04C46397020CE31",
+            ),
+        ]);
+    }
+}
+
+mod hs_post {
+    use super::*;
+    use crate::tor::control_client::types::OnionAddress;
+
+    #[test]
+    fn golden() {
+        let address = "qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid"
+            .parse::<OnionAddress>()
+            .unwrap();
+        let descriptor = "hs-descriptor 3\ndescriptor-lifetime 180\n.dot-line\nsignature abc";
+        assert_golden(vec![
+            (
+                "descriptor only",
+                HsPost::new(descriptor),
+                "+HSPOST\nhs-descriptor 3\ndescriptor-lifetime 180\n..dot-line\nsignature abc\n.",
+            ),
+            (
+                "servers and address",
+                HsPost::new(descriptor)
+                    .with_server("9695DFC35FFEB861329B9F1AB04C46397020CE31")
+                    .with_server("$847B1F850344D7876491A54892F904934E4EB85D")
+                    .with_address(&address)
+                    .unwrap(),
+                "+HSPOST SERVER=$9695DFC35FFEB861329B9F1AB04C46397020CE31 \
+                 SERVER=$847B1F850344D7876491A54892F904934E4EB85D \
+                 HSADDRESS=qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid\nhs-descriptor \
+                 3\ndescriptor-lifetime 180\n..dot-line\nsignature abc\n.",
+            ),
+        ]);
+    }
+}
+
+mod key_value {
+    use super::*;
+
+    #[test]
+    fn golden() {
+        assert_golden(vec![
+            ("GETCONF", get_conf("HiddenServicePort"), "GETCONF HiddenServicePort"),
+            (
+                "GETINFO",
+                get_info("net/listeners/socks"),
+                "GETINFO net/listeners/socks",
+            ),
+            ("SETEVENTS", set_events(&["CIRC", "BW"]), "SETEVENTS CIRC BW"),
+            // An empty SETEVENTS disables all events
+            ("SETEVENTS (no events)", set_events(&[]), "SETEVENTS "),
+            (
+                "USEFEATURE",
+                use_feature(&["EXTENDED_EVENTS", "VERBOSE_NAMES"]),
+                "USEFEATURE EXTENDED_EVENTS VERBOSE_NAMES",
+            ),
+        ]);
+    }
+}
+
+mod onion_client_auth_add {
+    use super::*;
+    use crate::tor::control_client::types::{ClientName, OnionAddress};
+
+    #[test]
+    fn golden() {
+        let address = "qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid"
+            .parse::<OnionAddress>()
+            .unwrap();
+        let client_name = ClientName::new("alice").unwrap();
+        const KEY: &str = "yPGUxgKaC5ACyEzsdANHJEJzt5DIqDRBlAFaAWWQn0o=";
+        assert_golden(vec![
+            (
+                "key only",
+                OnionClientAuthAdd::new(&address, KEY),
+                "ONION_CLIENT_AUTH_ADD qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid \
+                 x25519:yPGUxgKaC5ACyEzsdANHJEJzt5DIqDRBlAFaAWWQn0o=",
+            ),
+            (
+                "client name and permanent",
+                OnionClientAuthAdd::new(&address, KEY)
+                    .with_client_name(&client_name)
+                    .permanent(),
+                "ONION_CLIENT_AUTH_ADD qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid \
+                 x25519:yPGUxgKaC5ACyEzsdANHJEJzt5DIqDRBlAFaAWWQn0o= ClientName=alice Flags=Permanent",
+            ),
+        ]);
+    }
+}
+
+mod protocol_info {
+    use super::*;
+
+    #[test]
+    fn golden() {
+        assert_golden(vec![("version 1", ProtocolInfo, "PROTOCOLINFO 1")]);
+    }
+}
+
+mod save_conf {
+    use super::*;
+
+    #[test]
+    fn golden() {
+        assert_golden(vec![("no args", SaveConf, "SAVECONF")]);
+    }
+}
+
+mod set_conf {
+    use super::*;
+
+    #[test]
+    fn golden() {
+        assert_golden(vec![
+            (
+                "hidden service",
+                SetConf::new(&[
+                    ("HiddenServiceDir", Some("/var/lib/tor/my service")),
+                    ("HiddenServicePort", Some("80 127.0.0.1:8080")),
+                ]),
+                r#"SETCONF HiddenServiceDir="/var/lib/tor/my service" HiddenServicePort="80 127.0.0.1:8080""#,
+            ),
+            (
+                "reset to default",
+                SetConf::new(&[("HiddenServiceVersion", None)]),
+                "SETCONF HiddenServiceVersion",
+            ),
+            (
+                "escaped value",
+                SetConf::new(&[("ContactInfo", Some(r#"a "quoted" \ value"#))]),
+                r#"SETCONF ContactInfo="a \"quoted\" \\ value""#,
+            ),
+        ]);
+    }
+}
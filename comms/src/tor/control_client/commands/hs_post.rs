@@ -0,0 +1,160 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::tor::control_client::{
+    commands::{self, TorCommand},
+    error::TorClientError,
+    response::ResponseLine,
+    types::OnionAddress,
+};
+use std::fmt;
+
+const HSPOST: &str = "+HSPOST";
+
+/// The HSPOST command.
+///
+/// This command uploads an onion service descriptor to the HSDirs responsible for it, or to the given `SERVER`s.
+pub struct HsPost<'a> {
+    descriptor: &'a str,
+    servers: Vec<&'a str>,
+    address: Option<&'a OnionAddress>,
+}
+
+impl<'a> HsPost<'a> {
+    pub fn new(descriptor: &'a str) -> Self {
+        Self {
+            descriptor,
+            servers: Vec::new(),
+            address: None,
+        }
+    }
+
+    /// Upload the descriptor to the relay with the given fingerprint (with or without the leading `$`) instead of the
+    /// responsible HSDirs. May be given more than once.
+    pub fn with_server(mut self, fingerprint: &'a str) -> Self {
+        self.servers.push(fingerprint.trim_start_matches('$'));
+        self
+    }
+
+    /// The onion address that the descriptor is for, which tor uses to choose the HSDirs to upload to. Tor only
+    /// accepts v3 addresses, so a v2 address is rejected here rather than by tor.
+    pub fn with_address(mut self, address: &'a OnionAddress) -> Result<Self, TorClientError> {
+        if address.is_v2() {
+            return Err(TorClientError::HsPostV2Address(address.service_id().to_string()));
+        }
+        self.address = Some(address);
+        Ok(self)
+    }
+}
+
+impl TorCommand for HsPost<'_> {
+    type Error = TorClientError;
+    type Output = ();
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        if let Some(server) = self.servers.iter().find(|server| !commands::is_hex_fingerprint(server)) {
+            return Err(TorClientError::InvalidFingerprint(server.to_string()));
+        }
+        Ok(())
+    }
+
+    fn verb(&self) -> &str {
+        &HSPOST[1..]
+    }
+
+    fn to_command_string(&self) -> Result<String, Self::Error> {
+        Ok(self.to_string())
+    }
+
+    fn parse_responses<I>(&self, responses: I) -> Result<Self::Output, Self::Error>
+    where I: IntoIterator<Item = ResponseLine> {
+        let last_response = commands::last_response(responses)?;
+        if let Some(err) = last_response.err() {
+            return Err(TorClientError::TorCommandFailed(err.to_owned()));
+        }
+        Ok(())
+    }
+}
+
+/// The command and the descriptor as a data body, without the final line ending which is added when the command is
+/// sent. Lines of the descriptor that start with `.` are escaped.
+impl fmt::Display for HsPost<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(HSPOST)?;
+        for server in &self.servers {
+            write!(f, " SERVER=${}", server)?;
+        }
+        if let Some(address) = self.address {
+            write!(f, " HSADDRESS={}", address.service_id())?;
+        }
+        for line in self.descriptor.lines() {
+            f.write_str("\n")?;
+            if line.starts_with('.') {
+                f.write_str(".")?;
+            }
+            f.write_str(line)?;
+        }
+        f.write_str("\n.")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tari_test_utils::unpack_enum;
+
+    #[test]
+    fn with_address() {
+        let v3 = "qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid"
+            .parse::<OnionAddress>()
+            .unwrap();
+        let command = HsPost::new("hs-descriptor 3").with_address(&v3).unwrap();
+        assert_eq!(
+            command.to_command_string().unwrap(),
+            "+HSPOST HSADDRESS=qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid\nhs-descriptor 3\n."
+        );
+
+        let v2 = "62q4tswkxp74dtn7".parse::<OnionAddress>().unwrap();
+        let err = HsPost::new("hs-descriptor 3").with_address(&v2).err().unwrap();
+        unpack_enum!(TorClientError::HsPostV2Address(address) = err);
+        assert_eq!(address, "62q4tswkxp74dtn7");
+    }
+
+    #[test]
+    fn validate_servers() {
+        HsPost::new("hs-descriptor 3")
+            .with_server("$9695DFC35FFEB861329B9F1AB04C46397020CE31")
+            .validate()
+            .unwrap();
+        let err = HsPost::new("hs-descriptor 3")
+            .with_server("9695DFC35FFEB861329B9F1AB04C46397020CE31")
+            .with_server("moria1")
+            .validate()
+            .unwrap_err();
+        unpack_enum!(TorClientError::InvalidFingerprint(server) = err);
+        assert_eq!(server, "moria1");
+    }
+}
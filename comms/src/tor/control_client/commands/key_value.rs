@@ -20,34 +20,57 @@
 // WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
 // USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use crate::tor::control_client::{commands::TorCommand, error::TorClientError, parsers, response::ResponseLine};
-use std::{borrow::Cow, fmt, marker::PhantomData};
+use crate::tor::control_client::{
+    commands::{self, TorCommand},
+    error::TorClientError,
+    response::ResponseLine,
+};
+use std::{borrow::Cow, fmt};
+
+const GETCONF: &str = "GETCONF";
+const GETINFO: &str = "GETINFO";
+const SETEVENTS: &str = "SETEVENTS";
+const SIGNAL: &str = "SIGNAL";
+const USEFEATURE: &str = "USEFEATURE";
 
 /// The GETCONF command.
 ///
 /// This command is used to query the Tor proxy configuration file.
 pub fn get_conf(query: &str) -> KeyValueCommand<'_, '_> {
-    KeyValueCommand::new("GETCONF", &[query])
+    KeyValueCommand::new(GETCONF, &[query])
 }
 
 /// The GETINFO command.
 ///
 /// This command is used to retrieve Tor proxy configuration keys.
 pub fn get_info(key_name: &str) -> KeyValueCommand<'_, '_> {
-    KeyValueCommand::new("GETINFO", &[key_name])
+    KeyValueCommand::new(GETINFO, &[key_name])
 }
 
 /// The SETEVENTS command.
 ///
 /// This command is used to set the events that tor will emit
 pub fn set_events<'b>(event_types: &[&'b str]) -> KeyValueCommand<'static, 'b> {
-    KeyValueCommand::new("SETEVENTS", event_types)
+    KeyValueCommand::new(SETEVENTS, event_types)
+}
+
+/// The SIGNAL command.
+///
+/// This command is used to send a signal such as `NEWNYM` or `RELOAD` to tor
+pub fn signal(signal: &str) -> KeyValueCommand<'static, '_> {
+    KeyValueCommand::new(SIGNAL, &[signal])
+}
+
+/// The USEFEATURE command.
+///
+/// This command is used to enable optional features of the control protocol for the rest of the session
+pub fn use_feature<'b>(features: &[&'b str]) -> KeyValueCommand<'static, 'b> {
+    KeyValueCommand::new(USEFEATURE, features)
 }
 
 pub struct KeyValueCommand<'a, 'b> {
     command: &'a str,
     args: Vec<&'b str>,
-    _lifetime: PhantomData<&'b ()>,
 }
 
 impl<'a, 'b> KeyValueCommand<'a, 'b> {
@@ -55,7 +78,6 @@ impl<'a, 'b> KeyValueCommand<'a, 'b> {
         Self {
             command,
             args: args.to_vec(),
-            _lifetime: PhantomData,
         }
     }
 }
@@ -64,55 +86,137 @@ impl<'a, 'b> TorCommand for KeyValueCommand<'a, 'b> {
     type Error = TorClientError;
     type Output = Vec<Cow<'b, str>>;
 
-    fn to_command_string(&self) -> Result<String, Self::Error> {
-        Ok(format!("{} {}", self.command, self.args.join(" ")))
+    /// Queries are idempotent, as is setting the events or features to the same values again
+    fn is_idempotent(&self) -> bool {
+        matches!(self.command, GETCONF | GETINFO | SETEVENTS | USEFEATURE)
     }
 
-    fn parse_responses(&self, mut responses: Vec<ResponseLine>) -> Result<Self::Output, Self::Error> {
-        if let Some(resp) = responses.iter().find(|v| v.is_err()) {
-            return Err(TorClientError::TorCommandFailed(resp.value.to_string()));
+    /// GETINFO requires at least one key, whereas e.g. SETEVENTS without arguments clears the events
+    fn validate(&self) -> Result<(), Self::Error> {
+        if self.command == GETINFO && self.args.iter().all(|arg| arg.is_empty()) {
+            return Err(TorClientError::GetInfoNoKeys);
         }
+        Ok(())
+    }
+
+    fn verb(&self) -> &str {
+        self.command
+    }
 
-        if let Some(last_line) = responses.last() {
-            // Drop the last line if it's '250 OK' - some commands return it (GETINFO), some don't (GETCONF)
-            if last_line.value == "OK" {
-                let _ = responses.pop();
+    fn to_command_string(&self) -> Result<String, Self::Error> {
+        let args_len = self.args.iter().map(|arg| arg.len() + 1).sum::<usize>();
+        let mut s = String::with_capacity(self.command.len() + args_len.max(1));
+        s.push_str(self.command);
+        s.push(' ');
+        for (i, arg) in self.args.iter().enumerate() {
+            if i > 0 {
+                s.push(' ');
             }
+            s.push_str(arg);
         }
+        Ok(s)
+    }
 
-        let responses = responses
-            .iter()
-            .map(|resp| parsers::key_value(&resp.value))
-            .collect::<Vec<_>>();
-
-        // Return the first parse error if any
-        if let Some(Err(err)) = responses.iter().find(|v| v.is_err()) {
-            return Err(err.clone().into());
+    fn parse_responses<I>(&self, responses: I) -> Result<Self::Output, Self::Error>
+    where I: IntoIterator<Item = ResponseLine> {
+        let mut values = Vec::new();
+        // GETINFO replies may include informational lines, whereas a GETCONF line without a value is an option that is
+        // not set, which has no values
+        for entry in commands::key_value_lines(responses, self.command == GETINFO) {
+            let (_, line_values) = entry?;
+            values.extend(line_values.into_iter().map(Cow::from));
         }
 
-        Ok(responses
-            .iter()
-            .filter_map(|r| r.as_ref().ok())
-            .map(|(_, values)| values.iter().map(|value| Cow::from(value.clone().into_owned())))
-            .flatten()
-            .collect())
+        Ok(values)
     }
 }
 
 impl fmt::Display for KeyValueCommand<'_, '_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{} (Args = {})",
-            self.command,
-            self.args.iter().fold(String::new(), |acc, a| format!("{}, {}", acc, a))
-        )
+        write!(f, "{}", self.command)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::tor::control_client::{commands::test_utils::response_lines, parsers};
+    use tari_test_utils::unpack_enum;
+
+    #[test]
+    fn parse_responses_skips_informational_lines() {
+        let lines = ["250-OK", "250-version=0.4.5.6", "250-Informational", "250 OK"]
+            .iter()
+            .map(|line| parsers::response_line(line).unwrap())
+            .collect::<Vec<_>>();
+        let values = get_info("version").parse_responses(lines).unwrap();
+        assert_eq!(values, ["0.4.5.6"]);
+
+        let lines = vec![parsers::response_line("552 Unrecognized key \"version\"").unwrap()];
+        let err = get_info("version").parse_responses(lines).unwrap_err();
+        assert!(matches!(err, TorClientError::TorCommandFailed(_)));
+    }
+
+    #[test]
+    fn parse_responses_unset_option() {
+        let lines = vec![parsers::response_line("250 HiddenServicePort").unwrap()];
+        let values = get_conf("HiddenServicePort").parse_responses(lines).unwrap();
+        assert!(values.is_empty());
+
+        // Only GETINFO replies are expected to include informational lines
+        let lines = || response_lines(&["250-events/names=CIRC STREAM", "250-OK", "250 OK"]);
+        assert_eq!(
+            commands::key_value_lines(lines(), false)
+                .map(|entry| entry.unwrap().0)
+                .collect::<Vec<_>>(),
+            ["events/names", "OK"]
+        );
+        assert_eq!(commands::key_value_lines(lines(), true).count(), 1);
+    }
+
+    #[test]
+    fn parse_responses_in_order() {
+        let lines = [
+            "250-version=0.4.5.6",
+            "250-net/listeners/socks=\"127.0.0.1:9050\"",
+            "250 OK",
+        ]
+        .iter()
+        .map(|line| parsers::response_line(line).unwrap())
+        .collect::<Vec<_>>();
+        let values = get_info("version net/listeners/socks").parse_responses(lines).unwrap();
+        assert_eq!(values, ["0.4.5.6", "127.0.0.1:9050"]);
+
+        let lines = ["250-version=0.4.5.6", "552 Unrecognized key \"foo\""]
+            .iter()
+            .map(|line| parsers::response_line(line).unwrap())
+            .collect::<Vec<_>>();
+        let err = get_info("version foo").parse_responses(lines).unwrap_err();
+        assert!(matches!(err, TorClientError::TorCommandFailed(_)));
+
+        let lines = response_lines(&[
+            "250-version=0.4.5.6",
+            "551-Unable to read descriptor",
+            "551 Internal error",
+        ]);
+        let err = get_info("version desc/name/foo").parse_responses(lines).unwrap_err();
+        unpack_enum!(TorClientError::TorCommandFailed(msg) = err);
+        assert_eq!(msg, "Unable to read descriptor\nInternal error");
+    }
+
+    #[test]
+    fn validate() {
+        let err = KeyValueCommand::new(GETINFO, &[]).validate().unwrap_err();
+        assert!(matches!(err, TorClientError::GetInfoNoKeys));
+        let err = get_info("").validate().unwrap_err();
+        assert!(matches!(err, TorClientError::GetInfoNoKeys));
+        get_info("version").validate().unwrap();
+        set_events(&[]).validate().unwrap();
+    }
 
     #[test]
     fn to_command_string() {
@@ -121,5 +225,11 @@ mod test {
 
         let command = KeyValueCommand::new("GETINFO", &["net/listeners/socks"]);
         assert_eq!(command.to_command_string().unwrap(), "GETINFO net/listeners/socks");
+
+        let command = set_events(&["CIRC", "BW"]);
+        assert_eq!(command.to_command_string().unwrap(), "SETEVENTS CIRC BW");
+
+        let command = set_events(&[]);
+        assert_eq!(command.to_command_string().unwrap(), "SETEVENTS ");
     }
 }
@@ -20,21 +20,389 @@
 // WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
 // USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use crate::tor::control_client::response::ResponseLine;
+use crate::tor::control_client::{error::TorClientError, parsers, response::ResponseLine, types::TorVersion};
+use std::{collections::HashMap, iter};
 
 mod add_onion;
+mod auth_challenge;
 mod del_onion;
+mod extend_circuit;
+mod get_descriptor;
+mod get_info_list;
+mod hs_post;
 mod key_value;
+mod onion_client_auth;
+mod protocol_info;
+mod set_conf;
 
-pub use add_onion::{AddOnion, AddOnionFlag, AddOnionResponse};
+#[cfg(test)]
+mod golden;
+#[cfg(test)]
+mod test_utils;
+
+pub use add_onion::{AddOnion, AddOnionBuilder, AddOnionFlag, AddOnionFlags, AddOnionResponse};
+pub use auth_challenge::{AuthChallenge, AuthChallengeResponse};
 pub use del_onion::DelOnion;
-pub use key_value::{get_conf, get_info, set_events, KeyValueCommand};
+pub use extend_circuit::{ExtendCircuit, ExtendCircuitBuilder};
+pub use get_descriptor::{DescriptorKind, GetDescriptor, RelayDescriptor};
+pub use get_info_list::{get_circuit_status, get_listeners, get_stream_status, GetInfoList, InfoListIter, ParseEntry};
+pub use hs_post::HsPost;
+pub use key_value::{get_conf, get_info, set_events, signal, use_feature, KeyValueCommand};
+pub use onion_client_auth::OnionClientAuthAdd;
+pub use protocol_info::{ProtocolInfo, ProtocolInfoResponse};
+pub use set_conf::{GetConfEntries, SaveConf, SetConf};
 
 pub trait TorCommand {
     type Output;
     type Error;
 
+    /// Check that the command is well-formed before it is sent to the server. The default implementation accepts
+    /// every command.
+    fn validate(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Check that the command is supported by the given version of tor. This is called before the command is sent if
+    /// the version of the connected tor is known (see [TorControlPortClient::protocol_info]) or if
+    /// [requires_tor_version](Self::requires_tor_version) returns true, in which case the version is queried first.
+    /// The default implementation accepts every version.
+    ///
+    /// [TorControlPortClient::protocol_info]: crate::tor::TorControlPortClient::protocol_info
+    fn validate_for_version(&self, _version: TorVersion) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Returns true if this command may be rejected by [validate_for_version](Self::validate_for_version), so that
+    /// the version of tor must be known before it is sent. The default implementation returns false.
+    fn requires_tor_version(&self) -> bool {
+        false
+    }
+
+    /// Returns true if sending this command more than once has the same effect as sending it once, so that it is safe
+    /// to retry automatically. The default implementation returns false.
+    fn is_idempotent(&self) -> bool {
+        false
+    }
+
+    /// The command keyword e.g. `GETINFO`. This is used in traces and error messages in place of the command line,
+    /// which may contain secrets.
+    fn verb(&self) -> &str;
+
     fn to_command_string(&self) -> Result<String, Self::Error>;
 
-    fn parse_responses(&self, responses: Vec<ResponseLine>) -> Result<Self::Output, Self::Error>;
+    /// Appends the command line to `buf`. The client reuses the same buffer between commands, so implementations that
+    /// can write directly into it should override this. The default implementation appends `to_command_string`.
+    fn write_command(&self, buf: &mut String) -> Result<(), Self::Error> {
+        buf.push_str(&self.to_command_string()?);
+        Ok(())
+    }
+
+    /// Parses the reply lines to this command. The lines are consumed one at a time, so implementations should avoid
+    /// collecting them if they can be processed incrementally. Note that [TorControlPortClient::execute] receives the
+    /// whole reply before it is parsed, so this only bounds the memory used by callers that parse replies from another
+    /// source.
+    ///
+    /// [TorControlPortClient::execute]: crate::tor::TorControlPortClient::execute
+    fn parse_responses<I>(&self, responses: I) -> Result<Self::Output, Self::Error>
+    where I: IntoIterator<Item = ResponseLine>;
+}
+
+/// Collects the `key=value` reply lines of a command into a map of key to values, returning an error if any of the
+/// lines is an error reply. A trailing `250 OK` line is ignored. A key without a value, such as GETCONF's `250
+/// Keyword` for an option that is not set, has no values, unless `skip_informational` is set for a command whose reply
+/// may include informational lines e.g. `250-OK`, in which case lines without a `key=` are skipped. Values for repeated
+/// keys are appended in the order they were received.
+pub fn parse_key_value_lines<I>(
+    responses: I,
+    skip_informational: bool,
+) -> Result<HashMap<String, Vec<String>>, TorClientError>
+where
+    I: IntoIterator<Item = ResponseLine>,
+{
+    let mut map = HashMap::<_, Vec<_>>::new();
+    for entry in key_value_lines(responses, skip_informational) {
+        let (key, values) = entry?;
+        map.entry(key).or_default().extend(values);
+    }
+
+    Ok(map)
+}
+
+/// Parses the `key=value` reply lines of a command as they are consumed, in the order they were received. Iteration
+/// stops after the first error, which for an error reply includes the text of every error line up to the end of the
+/// reply, one per line, as [last_response] does. A trailing `250 OK` line is skipped. A line without a `key=` is
+/// returned as a key without values, unless `skip_informational` is set for a command whose reply may include
+/// informational lines e.g. `250-OK`, in which case it is skipped.
+pub(super) fn key_value_lines<I>(
+    responses: I,
+    skip_informational: bool,
+) -> impl Iterator<Item = Result<(String, Vec<String>), TorClientError>>
+where
+    I: IntoIterator<Item = ResponseLine>,
+{
+    let mut responses = responses.into_iter();
+    let mut is_done = false;
+    iter::from_fn(move || {
+        while !is_done {
+            let response = responses.next()?;
+            if response.is_err() {
+                is_done = true;
+                return Some(Err(TorClientError::TorCommandFailed(trailing_errors(
+                    response,
+                    &mut responses,
+                ))));
+            }
+            if is_trailing_ok(&response) {
+                continue;
+            }
+            if !response.value.contains('=') {
+                if skip_informational {
+                    continue;
+                }
+                return Some(Ok((response.value, Vec::new())));
+            }
+
+            let entry = parsers::key_value(&response.value)
+                .map(|(key, values)| (key.into_owned(), values.into_iter().map(|v| v.into_owned()).collect()))
+                .map_err(Into::into);
+            return Some(entry);
+        }
+        None
+    })
+}
+
+/// Returns the text of the error line `first` and of every error line that follows it up to the end of the reply, one
+/// per line. The lines are taken from `responses`, so the rest of the reply is consumed.
+pub(super) fn trailing_errors<I>(first: ResponseLine, responses: &mut I) -> String
+where I: Iterator<Item = ResponseLine> {
+    let mut is_end_reply = first.is_end_reply();
+    let mut errors = first.value;
+    while !is_end_reply {
+        match responses.next() {
+            Some(response) if response.is_err() => {
+                is_end_reply = response.is_end_reply();
+                errors.push('\n');
+                errors.push_str(&response.value);
+            },
+            _ => break,
+        }
+    }
+    errors
+}
+
+/// Returns the last line of a reply. If the reply failed, the value of the returned line is the text of every error
+/// line at the end of the reply, one per line, as tor lists some failures (e.g. each problem with a configuration) on
+/// separate lines.
+pub(super) fn last_response<I>(responses: I) -> Result<ResponseLine, TorClientError>
+where I: IntoIterator<Item = ResponseLine> {
+    let mut last = None;
+    let mut errors = String::new();
+    for response in responses {
+        if response.is_err() {
+            if !errors.is_empty() {
+                errors.push('\n');
+            }
+            errors.push_str(&response.value);
+        } else {
+            errors.clear();
+        }
+        last = Some(response);
+    }
+
+    let mut last = last.ok_or_else(|| TorClientError::UnexpectedEof)?;
+    if last.is_err() {
+        last.value = errors;
+    }
+    Ok(last)
+}
+
+/// Returns true if `s` is a hex-encoded relay identity fingerprint (without the leading `$`)
+pub(super) fn is_hex_fingerprint(s: &str) -> bool {
+    s.len() == 40 && s.chars().all(|ch| ch.is_ascii_hexdigit())
+}
+
+/// Returns true if the line is the `250 OK` that terminates some (but not all) replies
+pub(super) fn is_trailing_ok(response: &ResponseLine) -> bool {
+    response.is_end_reply() && response.value == "OK"
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tor::control_client::circuit::CircuitStatus;
+    use tari_test_utils::unpack_enum;
+
+    fn response(code: u16, has_more: bool, value: &str) -> ResponseLine {
+        ResponseLine {
+            code,
+            has_more,
+            is_multiline: false,
+            value: value.to_string(),
+        }
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn commands_are_send_sync() {
+        assert_send_sync::<AddOnion<'_>>();
+        assert_send_sync::<AddOnionBuilder<'_>>();
+        assert_send_sync::<AddOnionResponse>();
+        assert_send_sync::<AuthChallenge<'_>>();
+        assert_send_sync::<AuthChallengeResponse>();
+        assert_send_sync::<DelOnion<'_>>();
+        assert_send_sync::<ExtendCircuit<'_>>();
+        assert_send_sync::<ExtendCircuitBuilder<'_>>();
+        assert_send_sync::<GetDescriptor<'_>>();
+        assert_send_sync::<RelayDescriptor>();
+        assert_send_sync::<GetInfoList<'_, CircuitStatus>>();
+        assert_send_sync::<InfoListIter<CircuitStatus>>();
+        assert_send_sync::<HsPost<'_>>();
+        assert_send_sync::<KeyValueCommand<'_, '_>>();
+        assert_send_sync::<OnionClientAuthAdd<'_>>();
+        assert_send_sync::<ProtocolInfo>();
+        assert_send_sync::<ProtocolInfoResponse>();
+        assert_send_sync::<SaveConf>();
+        assert_send_sync::<SetConf<'_>>();
+    }
+
+    #[test]
+    fn idempotent_commands() {
+        use crate::tor::control_client::types::{KeyBlob, KeyType};
+        assert!(get_info("version").is_idempotent());
+        assert!(get_conf("SocksPort").is_idempotent());
+        assert!(set_events(&["BW"]).is_idempotent());
+        assert!(DelOnion::new("dummy").is_idempotent());
+        assert!(SetConf::new(&[("SocksPort", Some("9050"))]).is_idempotent());
+        assert!(SaveConf.is_idempotent());
+        assert!(!signal("NEWNYM").is_idempotent());
+        assert!(!ProtocolInfo.is_idempotent());
+        assert!(!AuthChallenge::new(&[0u8; 32]).is_idempotent());
+
+        let add_new = AddOnion::builder().add_port(9090).build().unwrap();
+        assert!(!add_new.is_idempotent());
+        let add_existing = AddOnion::builder()
+            .with_key(KeyType::Ed25519V3, KeyBlob::String("this-is-a-key"))
+            .add_port(9090)
+            .build()
+            .unwrap();
+        assert!(add_existing.is_idempotent());
+    }
+
+    #[test]
+    fn parse_key_value_lines_ok() {
+        let map = parse_key_value_lines(
+            vec![
+                response(250, true, "ServiceID=abcdef"),
+                response(250, true, "net/listeners/socks=\"127.0.0.1:9050\" \"unix:/tor/socks\""),
+                response(250, true, "ServiceID=ghijkl"),
+                response(250, false, "OK"),
+            ],
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(map.len(), 2);
+        assert_eq!(map["ServiceID"], &["abcdef", "ghijkl"]);
+        assert_eq!(map["net/listeners/socks"], &["127.0.0.1:9050", "unix:/tor/socks"]);
+    }
+
+    #[test]
+    fn parse_responses_incrementally() {
+        struct CountLines;
+
+        impl TorCommand for CountLines {
+            type Error = TorClientError;
+            type Output = usize;
+
+            fn verb(&self) -> &str {
+                "GETINFO"
+            }
+
+            fn to_command_string(&self) -> Result<String, Self::Error> {
+                Ok("GETINFO dummy".to_string())
+            }
+
+            fn parse_responses<I>(&self, responses: I) -> Result<Self::Output, Self::Error>
+            where I: IntoIterator<Item = ResponseLine> {
+                Ok(responses.into_iter().filter(|resp| !is_trailing_ok(resp)).count())
+            }
+        }
+
+        // The reply is generated lazily, so the lines are never all held in memory at once
+        const NUM_LINES: usize = 1_000_000;
+        let lines = (0..NUM_LINES)
+            .map(|i| response(250, true, &format!("dummy={}", i)))
+            .chain(Some(response(250, false, "OK")));
+        assert_eq!(CountLines.parse_responses(lines).unwrap(), NUM_LINES);
+    }
+
+    #[test]
+    fn parse_key_value_lines_without_value() {
+        // GETCONF replies with just the keyword for an option that is not set
+        let map = parse_key_value_lines(
+            vec![
+                response(250, true, "SocksPort=9050"),
+                response(250, false, "HiddenServicePort"),
+            ],
+            false,
+        )
+        .unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map["SocksPort"], &["9050"]);
+        assert!(map["HiddenServicePort"].is_empty());
+    }
+
+    #[test]
+    fn parse_key_value_lines_informational() {
+        let lines = || {
+            vec![
+                response(250, true, "OK"),
+                response(250, true, "version=0.4.5.6"),
+                response(250, false, "OK"),
+            ]
+        };
+        let map = parse_key_value_lines(lines(), true).unwrap();
+        assert_eq!(map.len(), 1);
+        assert_eq!(map["version"], &["0.4.5.6"]);
+
+        let map = parse_key_value_lines(lines(), false).unwrap();
+        assert_eq!(map.len(), 2);
+        assert!(map["OK"].is_empty());
+    }
+
+    #[test]
+    fn last_response_multi_line_error() {
+        let last = last_response(vec![
+            response(551, true, "Failed to parse/validate config: Unknown option 'Foo'"),
+            response(551, true, "Unknown option 'Bar'"),
+            response(551, false, "Configuration was not loaded"),
+        ])
+        .unwrap();
+        assert_eq!(last.code, 551);
+        assert_eq!(
+            last.value,
+            "Failed to parse/validate config: Unknown option 'Foo'\nUnknown option 'Bar'\nConfiguration was not loaded"
+        );
+
+        // Only the error lines at the end of the reply are collected
+        let last = last_response(vec![
+            response(250, true, "version=0.4.5.6"),
+            response(552, false, "Failed"),
+        ])
+        .unwrap();
+        assert_eq!(last.value, "Failed");
+        let last = last_response(vec![response(250, true, "version=0.4.5.6"), response(250, false, "OK")]).unwrap();
+        assert_eq!(last.value, "OK");
+
+        let err = last_response(Vec::new()).unwrap_err();
+        unpack_enum!(TorClientError::UnexpectedEof = err);
+    }
+
+    #[test]
+    fn parse_key_value_lines_err() {
+        let err = parse_key_value_lines(vec![response(552, false, "Unrecognized key")], false).unwrap_err();
+        unpack_enum!(TorClientError::TorCommandFailed(msg) = err);
+        assert_eq!(msg, "Unrecognized key");
+    }
 }
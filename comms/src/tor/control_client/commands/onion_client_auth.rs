@@ -0,0 +1,154 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::tor::control_client::{
+    commands::{self, TorCommand},
+    error::TorClientError,
+    response::ResponseLine,
+    types::{ClientName, OnionAddress, REDACTED},
+};
+use std::fmt;
+
+const ONION_CLIENT_AUTH_ADD: &str = "ONION_CLIENT_AUTH_ADD";
+
+/// The ONION_CLIENT_AUTH_ADD command.
+///
+/// This command gives tor the x25519 private key used to access a v3 onion service with client authorization.
+pub struct OnionClientAuthAdd<'a> {
+    address: &'a OnionAddress,
+    private_key: &'a str,
+    client_name: Option<&'a ClientName>,
+    is_permanent: bool,
+}
+
+impl<'a> OnionClientAuthAdd<'a> {
+    /// `private_key` is the base64-encoded x25519 private key of the client
+    pub fn new(address: &'a OnionAddress, private_key: &'a str) -> Self {
+        Self {
+            address,
+            private_key,
+            client_name: None,
+            is_permanent: false,
+        }
+    }
+
+    pub fn with_client_name(mut self, client_name: &'a ClientName) -> Self {
+        self.client_name = Some(client_name);
+        self
+    }
+
+    /// Tor will store the key in its `ClientOnionAuthDir`, so that it is kept when tor is restarted
+    pub fn permanent(mut self) -> Self {
+        self.is_permanent = true;
+        self
+    }
+
+    fn write_to<W: fmt::Write>(&self, w: &mut W, private_key: &str) -> fmt::Result {
+        write!(
+            w,
+            "{} {} x25519:{}",
+            ONION_CLIENT_AUTH_ADD,
+            self.address.service_id(),
+            private_key
+        )?;
+        if let Some(client_name) = self.client_name {
+            write!(w, " ClientName={}", client_name)?;
+        }
+        if self.is_permanent {
+            w.write_str(" Flags=Permanent")?;
+        }
+        Ok(())
+    }
+}
+
+impl TorCommand for OnionClientAuthAdd<'_> {
+    type Error = TorClientError;
+    type Output = ();
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        if self.address.is_v2() {
+            return Err(TorClientError::InvalidServiceId);
+        }
+        Ok(())
+    }
+
+    fn verb(&self) -> &str {
+        ONION_CLIENT_AUTH_ADD
+    }
+
+    fn to_command_string(&self) -> Result<String, Self::Error> {
+        let mut s = String::new();
+        self.write_command(&mut s)?;
+        Ok(s)
+    }
+
+    fn write_command(&self, buf: &mut String) -> Result<(), Self::Error> {
+        self.write_to(buf, self.private_key)
+            .expect("writing to a String cannot fail");
+        Ok(())
+    }
+
+    fn parse_responses<I>(&self, responses: I) -> Result<Self::Output, Self::Error>
+    where I: IntoIterator<Item = ResponseLine> {
+        // 251 is returned if the key replaced an existing one, which is not an error
+        let last_response = commands::last_response(responses)?;
+        if let Some(err) = last_response.err() {
+            return Err(TorClientError::TorCommandFailed(err.to_owned()));
+        }
+        Ok(())
+    }
+}
+
+/// The private key is redacted
+impl fmt::Display for OnionClientAuthAdd<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_to(f, REDACTED)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display_redacts_key() {
+        let address = "qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid"
+            .parse()
+            .unwrap();
+        let command = OnionClientAuthAdd::new(&address, "secret-key");
+        assert_eq!(
+            command.to_string(),
+            "ONION_CLIENT_AUTH_ADD qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid x25519:[redacted]"
+        );
+    }
+
+    #[test]
+    fn validate() {
+        let address = "62q4tswkxp74dtn7".parse().unwrap();
+        let err = OnionClientAuthAdd::new(&address, "key").validate().unwrap_err();
+        assert!(matches!(err, TorClientError::InvalidServiceId));
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::tor::control_client::{
+    commands::TorCommand,
+    error::TorClientError,
+    parsers,
+    parsers::ParseError,
+    response::ResponseLine,
+    types::TorVersion,
+};
+use std::fmt;
+
+const PROTOCOLINFO: &str = "PROTOCOLINFO 1";
+
+/// The PROTOCOLINFO command.
+///
+/// This command returns the authentication methods supported by the tor control port and the version of tor. It may
+/// be sent before authenticating.
+pub struct ProtocolInfo;
+
+impl TorCommand for ProtocolInfo {
+    type Error = TorClientError;
+    type Output = ProtocolInfoResponse;
+
+    /// Tor closes the connection if PROTOCOLINFO is sent a second time before authenticating, so it is not retried
+    fn is_idempotent(&self) -> bool {
+        false
+    }
+
+    fn verb(&self) -> &str {
+        "PROTOCOLINFO"
+    }
+
+    fn to_command_string(&self) -> Result<String, Self::Error> {
+        Ok(PROTOCOLINFO.to_string())
+    }
+
+    fn parse_responses<I>(&self, responses: I) -> Result<Self::Output, Self::Error>
+    where I: IntoIterator<Item = ResponseLine> {
+        let mut auth_methods = Vec::new();
+        let mut cookie_file = None;
+        let mut tor_version = None;
+
+        for response in responses {
+            if let Some(err) = response.err() {
+                return Err(TorClientError::TorCommandFailed(err.to_owned()));
+            }
+
+            let mut parts = response.value.splitn(2, ' ');
+            let keyword = parts.next().unwrap_or_default();
+            let args = parts.next().unwrap_or_default();
+            match keyword {
+                "AUTH" => {
+                    for (key, value) in parsers::key_value_args(args)? {
+                        match key {
+                            "METHODS" => auth_methods = value.split(',').map(ToString::to_string).collect(),
+                            "COOKIEFILE" => cookie_file = Some(value.into_owned()),
+                            _ => {},
+                        }
+                    }
+                },
+                "VERSION" => {
+                    for (key, value) in parsers::key_value_args(args)? {
+                        if key == "Tor" {
+                            tor_version = Some(value.parse()?);
+                        }
+                    }
+                },
+                _ => {
+                    // Ignore the PROTOCOLINFO version line, the trailing OK and any lines added in future versions
+                },
+            }
+        }
+
+        let tor_version =
+            tor_version.ok_or_else(|| ParseError("PROTOCOLINFO reply did not include the tor version".to_string()))?;
+
+        Ok(ProtocolInfoResponse {
+            auth_methods,
+            cookie_file,
+            tor_version,
+        })
+    }
+}
+
+impl fmt::Display for ProtocolInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(PROTOCOLINFO)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProtocolInfoResponse {
+    auth_methods: Vec<String>,
+    cookie_file: Option<String>,
+    tor_version: TorVersion,
+}
+
+impl ProtocolInfoResponse {
+    /// The authentication methods accepted by the control port e.g. `NULL`, `HASHEDPASSWORD`, `COOKIE` or `SAFECOOKIE`
+    pub fn auth_methods(&self) -> &[String] {
+        &self.auth_methods
+    }
+
+    /// The path to the authentication cookie file, if cookie authentication is supported
+    pub fn cookie_file(&self) -> Option<&str> {
+        self.cookie_file.as_deref()
+    }
+
+    pub fn tor_version(&self) -> TorVersion {
+        self.tor_version
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tor::control_client::parsers;
+
+    #[test]
+    fn parse_responses() {
+        let lines = [
+            "250-PROTOCOLINFO 1",
+            r#"250-AUTH METHODS=COOKIE,SAFECOOKIE,HASHEDPASSWORD COOKIEFILE="/var/lib/tor/control_auth_cookie""#,
+            r#"250-VERSION Tor="0.4.5.6""#,
+            "250 OK",
+        ];
+        let responses = lines.iter().map(|line| parsers::response_line(line).unwrap());
+        let info = ProtocolInfo.parse_responses(responses).unwrap();
+        assert_eq!(info.auth_methods(), &["COOKIE", "SAFECOOKIE", "HASHEDPASSWORD"]);
+        assert_eq!(info.cookie_file(), Some("/var/lib/tor/control_auth_cookie"));
+        assert_eq!(info.tor_version(), TorVersion::new(0, 4, 5, 6));
+    }
+}
@@ -0,0 +1,341 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::tor::control_client::{
+    commands::{self, TorCommand},
+    error::TorClientError,
+    parsers,
+    response::ResponseLine,
+};
+use std::fmt;
+
+const GETCONF: &str = "GETCONF";
+const SETCONF: &str = "SETCONF";
+const SAVECONF: &str = "SAVECONF";
+
+/// The SETCONF command.
+///
+/// This command sets configuration options of the running tor proxy. An option without a value is reset to its
+/// default. Setting any option replaces all of its current values, so options such as `HiddenServicePort` that may be
+/// given more than once must be given together.
+pub struct SetConf<'a> {
+    options: Vec<(&'a str, Option<&'a str>)>,
+}
+
+impl<'a> SetConf<'a> {
+    pub fn new(options: &[(&'a str, Option<&'a str>)]) -> Self {
+        Self {
+            options: options.to_vec(),
+        }
+    }
+}
+
+impl TorCommand for SetConf<'_> {
+    type Error = TorClientError;
+    type Output = ();
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        if self.options.is_empty() {
+            return Err(TorClientError::SetConfNoOptions);
+        }
+        Ok(())
+    }
+
+    fn verb(&self) -> &str {
+        SETCONF
+    }
+
+    fn to_command_string(&self) -> Result<String, Self::Error> {
+        Ok(self.to_string())
+    }
+
+    fn parse_responses<I>(&self, responses: I) -> Result<Self::Output, Self::Error>
+    where I: IntoIterator<Item = ResponseLine> {
+        let last_response = commands::last_response(responses)?;
+        if last_response.is_err() {
+            return Err(TorClientError::SetConfRejected {
+                code: last_response.code,
+                option: self.find_rejected_option(&last_response.value).map(ToString::to_string),
+                reason: last_response.value,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl SetConf<'_> {
+    /// Tor names the offending option in the error e.g. `552 Unrecognized option: Unknown option 'Foo'.  Failing.`, so
+    /// the first option of this command that is mentioned in the message is taken to be the one that was rejected.
+    /// Option names are case-insensitive.
+    fn find_rejected_option(&self, msg: &str) -> Option<&str> {
+        msg.split(|ch: char| !ch.is_ascii_alphanumeric() && ch != '_')
+            .filter(|word| !word.is_empty())
+            .find_map(|word| {
+                self.options
+                    .iter()
+                    .map(|(key, _)| *key)
+                    .find(|key| word.eq_ignore_ascii_case(key))
+            })
+    }
+}
+
+impl fmt::Display for SetConf<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(SETCONF)?;
+        for (key, value) in &self.options {
+            write!(f, " {}", key)?;
+            if let Some(value) = value {
+                write!(f, "={}", parsers::quote_arg(value))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The GETCONF command for a single keyword, returning every entry of the reply with its keyword, in order.
+///
+/// Unlike [get_conf](super::get_conf), the keyword of each value is kept. This is needed for keywords that stand for a
+/// group of options, such as `HiddenServiceOptions`, which tor replies to with every `HiddenServiceDir`,
+/// `HiddenServicePort` etc. option in the order they are configured. An option that is not set is returned as a
+/// single entry without a value.
+pub struct GetConfEntries<'a> {
+    keyword: &'a str,
+}
+
+impl<'a> GetConfEntries<'a> {
+    pub fn new(keyword: &'a str) -> Self {
+        Self { keyword }
+    }
+}
+
+impl TorCommand for GetConfEntries<'_> {
+    type Error = TorClientError;
+    type Output = Vec<(String, Option<String>)>;
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn verb(&self) -> &str {
+        GETCONF
+    }
+
+    fn to_command_string(&self) -> Result<String, Self::Error> {
+        Ok(self.to_string())
+    }
+
+    fn parse_responses<I>(&self, responses: I) -> Result<Self::Output, Self::Error>
+    where I: IntoIterator<Item = ResponseLine> {
+        let mut entries = Vec::new();
+        let mut responses = responses.into_iter();
+        while let Some(response) = responses.next() {
+            if response.is_err() {
+                return Err(TorClientError::TorCommandFailed(commands::trailing_errors(
+                    response,
+                    &mut responses,
+                )));
+            }
+            let entry = match response.value.find('=') {
+                Some(pos) => {
+                    let value = &response.value[pos + 1..];
+                    let value = if value.starts_with('"') {
+                        parsers::quoted_string(value)?.0.into_owned()
+                    } else {
+                        value.to_string()
+                    };
+                    (response.value[..pos].to_string(), Some(value))
+                },
+                None => (response.value, None),
+            };
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+}
+
+impl fmt::Display for GetConfEntries<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", GETCONF, self.keyword)
+    }
+}
+
+/// The SAVECONF command.
+///
+/// This command writes the current configuration of the tor proxy to its torrc file, so that it is kept when tor is
+/// restarted.
+pub struct SaveConf;
+
+impl TorCommand for SaveConf {
+    type Error = TorClientError;
+    type Output = ();
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn verb(&self) -> &str {
+        SAVECONF
+    }
+
+    fn to_command_string(&self) -> Result<String, Self::Error> {
+        Ok(SAVECONF.to_string())
+    }
+
+    fn parse_responses<I>(&self, responses: I) -> Result<Self::Output, Self::Error>
+    where I: IntoIterator<Item = ResponseLine> {
+        parse_ok(responses)
+    }
+}
+
+impl fmt::Display for SaveConf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(SAVECONF)
+    }
+}
+
+fn parse_ok<I>(responses: I) -> Result<(), TorClientError>
+where I: IntoIterator<Item = ResponseLine> {
+    let last_response = commands::last_response(responses)?;
+    if let Some(err) = last_response.err() {
+        return Err(TorClientError::TorCommandFailed(err.to_owned()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tari_test_utils::unpack_enum;
+
+    #[test]
+    fn validate() {
+        let err = SetConf::new(&[]).validate().unwrap_err();
+        unpack_enum!(TorClientError::SetConfNoOptions = err);
+    }
+
+    #[test]
+    fn rejected_multi_line() {
+        let command = SetConf::new(&[("SocksPort", Some("nope")), ("ControlPort", Some("nope"))]);
+        let lines = [
+            "551-Failed to parse/validate config: Invalid SocksPort configuration",
+            "551 Invalid ControlPort configuration",
+        ];
+        let err = command
+            .parse_responses(lines.iter().map(|line| parsers::response_line(line).unwrap()))
+            .unwrap_err();
+        unpack_enum!(TorClientError::SetConfRejected { code, option, reason } = err);
+        assert_eq!(code, 551);
+        assert_eq!(option.as_deref(), Some("SocksPort"));
+        assert_eq!(
+            reason,
+            "Failed to parse/validate config: Invalid SocksPort configuration\nInvalid ControlPort configuration"
+        );
+    }
+
+    #[test]
+    fn rejected_option() {
+        let command = SetConf::new(&[
+            ("HiddenServiceDir", Some("/var/lib/tor/hs")),
+            ("HiddenServicePort", Some("80 127.0.0.1:8080")),
+            ("SocksPort", Some("0")),
+        ]);
+        let err = command
+            .parse_responses(vec![parsers::response_line(
+                "513 Unacceptable option value: Failed to configure rendezvous options. See logs for details. \
+                 HiddenServicePort with no preceding HiddenServiceDir directive",
+            )
+            .unwrap()])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "SETCONF failed with code 513: HiddenServicePort rejected: Unacceptable option value: Failed to configure \
+             rendezvous options. See logs for details. HiddenServicePort with no preceding HiddenServiceDir directive"
+        );
+        unpack_enum!(TorClientError::SetConfRejected { code, option, reason } = err);
+        assert_eq!(code, 513);
+        assert_eq!(option.as_deref(), Some("HiddenServicePort"));
+        assert!(reason.starts_with("Unacceptable option value"));
+
+        let command = SetConf::new(&[("SocksPort", Some("0")), ("HiddenServiceDirr", Some("/tmp"))]);
+        let err = command
+            .parse_responses(vec![parsers::response_line(
+                "552 Unrecognized option: Unknown option 'hiddenservicedirr'.  Failing.",
+            )
+            .unwrap()])
+            .unwrap_err();
+        unpack_enum!(TorClientError::SetConfRejected { code, option, reason } = err);
+        assert_eq!(code, 552);
+        assert_eq!(option.as_deref(), Some("HiddenServiceDirr"));
+        assert_eq!(
+            reason,
+            "Unrecognized option: Unknown option 'hiddenservicedirr'.  Failing."
+        );
+
+        let err = command
+            .parse_responses(vec![parsers::response_line("553 Transition not allowed").unwrap()])
+            .unwrap_err();
+        assert!(matches!(err, TorClientError::SetConfRejected { option: None, .. }));
+    }
+
+    #[test]
+    fn get_conf_entries() {
+        let command = GetConfEntries::new("HiddenServiceOptions");
+        assert_eq!(command.to_command_string().unwrap(), "GETCONF HiddenServiceOptions");
+
+        let lines = [
+            "250-HiddenServiceDir=/var/lib/tor/other",
+            "250-HiddenServicePort=22 127.0.0.1:22",
+            "250-HiddenServiceDir=\"/var/lib/tor/with space\"",
+            "250 HiddenServicePort=80 127.0.0.1:8080",
+        ];
+        let entries = command
+            .parse_responses(lines.iter().map(|line| parsers::response_line(line).unwrap()))
+            .unwrap();
+        assert_eq!(entries, vec![
+            ("HiddenServiceDir".to_string(), Some("/var/lib/tor/other".to_string())),
+            ("HiddenServicePort".to_string(), Some("22 127.0.0.1:22".to_string())),
+            (
+                "HiddenServiceDir".to_string(),
+                Some("/var/lib/tor/with space".to_string())
+            ),
+            ("HiddenServicePort".to_string(), Some("80 127.0.0.1:8080".to_string())),
+        ]);
+
+        // An option that is not set
+        let entries = command
+            .parse_responses(vec![parsers::response_line("250 HiddenServiceOptions").unwrap()])
+            .unwrap();
+        assert_eq!(entries, vec![("HiddenServiceOptions".to_string(), None)]);
+
+        let err = command
+            .parse_responses(vec![parsers::response_line(
+                "552 Unrecognized configuration key \"Foo\"",
+            )
+            .unwrap()])
+            .unwrap_err();
+        unpack_enum!(TorClientError::TorCommandFailed(_msg) = err);
+    }
+}
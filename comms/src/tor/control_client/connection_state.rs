@@ -0,0 +1,106 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// The state of the connection to the tor control port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Connecting to the control port
+    Connecting,
+    /// Connected, waiting for authentication to succeed
+    Authenticating,
+    /// Authenticated, commands can be sent
+    Ready,
+    /// The connection was lost and is being re-established
+    Reconnecting,
+    /// The connection is closed, either by tor or by the client
+    Closed,
+}
+
+/// While reconnecting, the state stays `Reconnecting` until the owner of the connection has re-established it, even if
+/// the connection of a failed attempt is closed
+pub fn is_not_reconnecting(state: ConnectionState) -> bool {
+    state != ConnectionState::Reconnecting
+}
+
+/// Holds the current [ConnectionState] and notifies watchers when it changes. Clones share the same state, so the
+/// state of a connection can be updated by the client and by the monitor task that reads from its socket.
+#[derive(Debug, Clone)]
+pub struct ConnectionStateTracker {
+    tx: Arc<Mutex<watch::Sender<ConnectionState>>>,
+    rx: watch::Receiver<ConnectionState>,
+}
+
+impl ConnectionStateTracker {
+    pub fn new(initial: ConnectionState) -> Self {
+        let (tx, rx) = watch::channel(initial);
+        Self {
+            tx: Arc::new(Mutex::new(tx)),
+            rx,
+        }
+    }
+
+    pub fn get(&self) -> ConnectionState {
+        *self.rx.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<ConnectionState> {
+        self.rx.clone()
+    }
+
+    pub fn set(&self, state: ConnectionState) {
+        self.set_if(state, |_| true);
+    }
+
+    /// Moves to `state` if `condition` holds for the current state. Watchers are only notified if the state changed.
+    pub fn set_if<F>(&self, state: ConnectionState, condition: F)
+    where F: FnOnce(ConnectionState) -> bool {
+        // The lock is held so that the state cannot change between checking the condition and setting it
+        let tx = self.tx.lock().expect("ConnectionStateTracker lock poisoned");
+        let current = self.get();
+        if current != state && condition(current) {
+            // The tracker holds a receiver, so this cannot fail
+            let _ = tx.broadcast(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn set_if() {
+        let tracker = ConnectionStateTracker::new(ConnectionState::Authenticating);
+        let cloned = tracker.clone();
+        cloned.set(ConnectionState::Closed);
+        assert_eq!(tracker.get(), ConnectionState::Closed);
+
+        tracker.set_if(ConnectionState::Ready, |state| state == ConnectionState::Authenticating);
+        assert_eq!(tracker.get(), ConnectionState::Closed);
+        tracker.set_if(ConnectionState::Connecting, |state| state == ConnectionState::Closed);
+        assert_eq!(tracker.get(), ConnectionState::Connecting);
+        assert_eq!(*tracker.subscribe().borrow(), ConnectionState::Connecting);
+    }
+}
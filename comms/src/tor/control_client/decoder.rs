@@ -0,0 +1,222 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use super::{parsers, response::ResponseLine, LOG_TARGET};
+use bytes::BytesMut;
+use log::*;
+use tokio_util::codec::{Decoder, LinesCodec, LinesCodecError};
+
+/// The line that terminates the data body of a multi-line (`xxx+`) reply
+const END_OF_DATA: &str = ".";
+
+/// Decodes the replies sent by the tor control server into [ResponseLine]s.
+///
+/// The data body of a multi-line reply is appended to the value of the line that introduced it, separated by newlines.
+/// Lines that cannot be parsed are logged and skipped, so the only errors returned are framing errors (e.g. a line
+/// exceeding the maximum length).
+pub struct ResponseDecoder {
+    lines: LinesCodec,
+    state: State,
+    skip_filter: Option<Box<dyn Fn(&str) -> bool + Send + Sync>>,
+}
+
+enum State {
+    Line,
+    Data(ResponseLine),
+    SkipData,
+}
+
+impl ResponseDecoder {
+    pub fn new() -> Self {
+        Self::with_lines_codec(LinesCodec::new())
+    }
+
+    /// Returns a decoder that errors if a line is longer than `max_length` bytes. Data bodies may contain any number of
+    /// such lines.
+    pub fn with_max_length(max_length: usize) -> Self {
+        Self::with_lines_codec(LinesCodec::new_with_max_length(max_length))
+    }
+
+    fn with_lines_codec(lines: LinesCodec) -> Self {
+        Self {
+            lines,
+            state: State::Line,
+            skip_filter: None,
+        }
+    }
+
+    /// Replies for which `filter` returns true, along with their data body, are discarded without being parsed. The
+    /// filter is given the raw reply line.
+    pub fn with_skip_filter<F>(mut self, filter: F) -> Self
+    where F: Fn(&str) -> bool + Send + Sync + 'static {
+        self.skip_filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Processes a single line, returning a reply once it is complete
+    fn process_line(&mut self, line: String) -> Option<ResponseLine> {
+        match std::mem::replace(&mut self.state, State::Line) {
+            State::Line => {
+                if self.skip_filter.as_ref().map(|skip| skip(&line)).unwrap_or(false) {
+                    if is_multiline(&line) {
+                        self.state = State::SkipData;
+                    }
+                    return None;
+                }
+
+                match parsers::response_line(&line) {
+                    Ok(response) if response.is_data() => {
+                        self.state = State::Data(response);
+                        None
+                    },
+                    Ok(response) => Some(response),
+                    Err(err) => {
+                        error!(
+                            target: LOG_TARGET,
+                            "Error processing response from tor control server: '{:?}'", err
+                        );
+                        None
+                    },
+                }
+            },
+            State::Data(mut response) => {
+                if line.trim() == END_OF_DATA {
+                    return Some(response);
+                }
+                response.value.push('\n');
+                response.value.push_str(&line);
+                self.state = State::Data(response);
+                None
+            },
+            State::SkipData => {
+                if line.trim() != END_OF_DATA {
+                    self.state = State::SkipData;
+                }
+                None
+            },
+        }
+    }
+}
+
+impl Default for ResponseDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for ResponseDecoder {
+    type Error = LinesCodecError;
+    type Item = ResponseLine;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        while let Some(line) = self.lines.decode(buf)? {
+            if let Some(response) = self.process_line(line) {
+                return Ok(Some(response));
+            }
+        }
+        Ok(None)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        while let Some(line) = self.lines.decode_eof(buf)? {
+            if let Some(response) = self.process_line(line) {
+                return Ok(Some(response));
+            }
+        }
+        // A data body that is cut off by the connection closing is returned as is
+        match std::mem::replace(&mut self.state, State::Line) {
+            State::Data(response) => Ok(Some(response)),
+            State::Line | State::SkipData => Ok(None),
+        }
+    }
+}
+
+fn is_multiline(line: &str) -> bool {
+    line.len() > 3 && line.as_bytes()[3] == b'+'
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode_all(decoder: &mut ResponseDecoder, input: &str) -> Vec<ResponseLine> {
+        let mut buf = BytesMut::from(input);
+        let mut responses = Vec::new();
+        while let Some(response) = decoder.decode_eof(&mut buf).unwrap() {
+            responses.push(response);
+        }
+        responses
+    }
+
+    #[test]
+    fn decode() {
+        let mut decoder = ResponseDecoder::new();
+        let responses = decode_all(
+            &mut decoder,
+            "250-ServiceID=abc\r\n250+onions/detached=\r\nabc\r\ndef\r\n.\r\ninvalid\r\n250 OK\r\n",
+        );
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0].value, "ServiceID=abc");
+        assert!(responses[1].is_multiline);
+        assert_eq!(responses[1].value, "onions/detached=\nabc\ndef");
+        assert_eq!(responses[2].value, "OK");
+        assert!(!responses[2].has_more);
+    }
+
+    #[test]
+    fn decode_partial() {
+        let mut decoder = ResponseDecoder::new();
+        let mut buf = BytesMut::from("250+key=\r\nval");
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"ue\r\n.\r\n250 O");
+        assert_eq!(decoder.decode(&mut buf).unwrap().unwrap().value, "key=\nvalue");
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"K\r\n");
+        assert_eq!(decoder.decode(&mut buf).unwrap().unwrap().value, "OK");
+    }
+
+    #[test]
+    fn skip_filter() {
+        let mut decoder = ResponseDecoder::new().with_skip_filter(|line| line.starts_with("650"));
+        let responses = decode_all(
+            &mut decoder,
+            "650 BW 1 2\r\n650+ORCONN\r\n250 in the body\r\n.\r\n250 OK\r\n",
+        );
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].value, "OK");
+    }
+
+    #[test]
+    fn truncated_data_body() {
+        let mut decoder = ResponseDecoder::new();
+        let responses = decode_all(&mut decoder, "250+key=\r\nvalue");
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].value, "key=\nvalue");
+    }
+
+    #[test]
+    fn max_length() {
+        let mut decoder = ResponseDecoder::with_max_length(8);
+        let mut buf = BytesMut::from("250 this line is too long\r\n");
+        decoder.decode(&mut buf).unwrap_err();
+    }
+}
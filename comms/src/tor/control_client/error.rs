@@ -19,35 +19,204 @@
 // SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
 // WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
 // USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
-use super::parsers::ParseError;
-use std::io;
+use super::{parsers::ParseError, types::TorVersion};
+use crate::{multiaddr::Multiaddr, transports::AddressResolutionError};
+use std::{fmt, io, path::PathBuf, time::Duration};
 use thiserror::Error;
 use tokio_util::codec::LinesCodecError;
 
+/// The reason a connection to the tor control port failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionFailureKind {
+    /// Nothing is listening on the address e.g. tor is not running or the port is wrong
+    Refused,
+    /// The connection attempt timed out
+    TimedOut,
+    /// The address could not be resolved
+    AddressResolution,
+    Other,
+}
+
+impl ConnectionFailureKind {
+    pub(super) fn from_connect_error(err: &io::Error) -> Self {
+        if err
+            .get_ref()
+            .map_or(false, |inner| inner.is::<AddressResolutionError>())
+        {
+            return ConnectionFailureKind::AddressResolution;
+        }
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => ConnectionFailureKind::Refused,
+            io::ErrorKind::TimedOut => ConnectionFailureKind::TimedOut,
+            _ => ConnectionFailureKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionFailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionFailureKind::Refused => write!(f, "connection refused"),
+            ConnectionFailureKind::TimedOut => write!(f, "timed out"),
+            ConnectionFailureKind::AddressResolution => write!(f, "address resolution failed"),
+            ConnectionFailureKind::Other => write!(f, "connection error"),
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum TorClientError {
     #[error("Failed to read/write line to socket. The maximum line length was exceeded.")]
     MaxLineLengthExceeded,
     #[error("IO Error: {0}")]
     Io(#[from] io::Error),
+    #[error("Failed to connect to the tor control port at '{addr}' ({kind}): {source}")]
+    ConnectionFailed {
+        addr: Multiaddr,
+        kind: ConnectionFailureKind,
+        source: io::Error,
+    },
     #[error("Command failed: {0}")]
     TorCommandFailed(String),
     #[error("Tor control port connection unexpectedly closed")]
     UnexpectedEof,
     #[error("Parse error: {0}")]
     ParseError(#[from] ParseError),
+    #[error("Invalid onion address: {0}")]
+    OnionAddressError(#[from] OnionAddressError),
     #[error("The server returned no response")]
     ServerNoResponse,
-    #[error("Server did not return a ServiceID for ADD_ONION command")]
+    #[error("ADD_ONION requires at least one port mapping")]
+    AddOnionNoPorts,
+    #[error("ADD_ONION flag '{0}' is not supported for ED25519-V3 onion services")]
+    AddOnionFlagNotSupported(String),
+    #[error("Invalid ADD_ONION key: {0}")]
+    AddOnionInvalidKey(String),
+    #[error("ADD_ONION client authorization with ClientAuthV3 and the V3Auth flag requires an ED25519-V3 key")]
+    AddOnionClientAuthV3RequiresV3,
+    #[error("The ADD_ONION flag V3Auth requires at least one ClientAuthV3 key")]
+    AddOnionMissingClientAuthV3,
+    #[error("Invalid ADD_ONION ClientAuthV3 key '{0}': expected a base32-encoded x25519 public key")]
+    AddOnionInvalidClientAuthV3Key(String),
+    #[error("ADD_ONION ClientAuthV3 key '{0}' was given more than once")]
+    AddOnionDuplicateClientAuthV3Key(String),
+    #[error("ADD_ONION client authorization with ClientAuth requires an RSA1024 key")]
+    AddOnionClientAuthV2RequiresV2,
+    #[error("ADD_ONION ClientAuth client '{0}' was given more than once")]
+    AddOnionDuplicateClientName(String),
+    #[error("Invalid ADD_ONION descriptor cookie for client '{0}': expected 16 bytes encoded as 22 base64 characters")]
+    AddOnionInvalidDescriptorCookie(String),
+    #[error("Invalid ADD_ONION port mapping '{0}': ports must be non-zero")]
+    AddOnionInvalidPort(String),
+    #[error("Invalid ADD_ONION port target host '{0}'")]
+    AddOnionInvalidTarget(String),
+    #[error("Tor {0} does not support v2 onion services")]
+    V2OnionUnsupported(TorVersion),
+    #[error("Tor rejected the ADD_ONION command with code {code}: {reason}")]
+    AddOnionFailed { code: u16, reason: String },
+    #[error("Tor accepted the ADD_ONION command but the reply did not include a ServiceID")]
     AddOnionNoServiceId,
+    #[error("Server did not return a PrivateKey for a new onion service created without the DiscardPK flag")]
+    AddOnionNoPrivateKey,
+    #[error("Server returned a PrivateKey for an onion service created with the DiscardPK flag")]
+    AddOnionUnexpectedPrivateKey,
     #[error("The given service id was invalid")]
     InvalidServiceId,
-    #[error("Onion address is exists")]
-    OnionAddressCollision,
+    #[error("The onion service already exists (service id: {service_id:?})")]
+    OnionServiceExists { service_id: Option<String> },
+    #[error("The private key is for onion service {key_service_id}, not {service_id}")]
+    PrivateKeyServiceMismatch { service_id: String, key_service_id: String },
+    #[error("Tor does not support the GETINFO key '{0}'")]
+    GetInfoKeyUnsupported(String),
+    #[error("GETINFO requires at least one key")]
+    GetInfoNoKeys,
     #[error("Response returned an no value for key")]
     KeyValueNoValue,
     #[error("The command sender disconnected")]
     CommandSenderDisconnected,
+    #[error("The client has been closed")]
+    ClientClosed,
+    #[error("Tor was not able to build circuits within {0:?}")]
+    NotReady(Duration),
+    #[error("Tor did not have enough directory information to build circuits within {0:?}")]
+    NotEnoughDirInfo(Duration),
+    #[error("The wait was cancelled")]
+    Cancelled,
+    #[error("SETCONF requires at least one option")]
+    SetConfNoOptions,
+    #[error("SETCONF failed with code {code}: {}{reason}", rejected_option_prefix(option))]
+    SetConfRejected {
+        code: u16,
+        /// The option that tor objected to, if it could be identified from the reply. No option is set if any option
+        /// is rejected.
+        option: Option<String>,
+        reason: String,
+    },
+    #[error("A raw command must be a single line")]
+    RawCommandNotSingleLine,
+    #[error("Tor is not accepting SOCKS connections")]
+    NoSocksListener,
+    #[error("Tor did not report a cookie file in PROTOCOLINFO and no cookie path was given")]
+    NoCookieFile,
+    #[error("Failed to read the tor control cookie file '{}': {source}", path.display())]
+    CookieFileRead { path: PathBuf, source: io::Error },
+    #[error("HSPOST HSADDRESS must be a v3 onion address, got '{0}'")]
+    HsPostV2Address(String),
+    #[error("Invalid client name '{name}': {reason}")]
+    InvalidClientName { name: String, reason: &'static str },
+    #[error("'{0}' is not a hex-encoded relay fingerprint")]
+    InvalidFingerprint(String),
+    #[error("EXTENDCIRCUIT circuit ID '{0}' is not the numeric ID of an existing circuit")]
+    ExtendCircuitInvalidId(String),
+    #[error("EXTENDCIRCUIT requires at least one relay to extend an existing circuit")]
+    ExtendCircuitEmptyPath,
+    #[error("Invalid EXTENDCIRCUIT relay nickname '{0}': expected 1 to 19 alphanumeric characters")]
+    ExtendCircuitInvalidNickname(String),
+    #[error("EXTENDCIRCUIT purpose '{0}' is not supported: only a new GENERAL or CONTROLLER circuit can be requested")]
+    ExtendCircuitInvalidPurpose(String),
+    #[error("Tor does not have a descriptor for relay {fingerprint}")]
+    DescriptorNotFound { fingerprint: String },
+    #[error("NEWNYM was signalled too recently, try again in {retry_after:?}")]
+    NewnymRateLimited { retry_after: Duration },
+}
+
+impl TorClientError {
+    /// Adds the verb of the command whose reply caused this error to the errors that do not otherwise identify the
+    /// command, e.g. `GETINFO: Unrecognized key "foo"`.
+    pub(super) fn with_command(self, verb: &str) -> Self {
+        match self {
+            TorClientError::ParseError(err) => TorClientError::ParseError(err.with_command(verb)),
+            TorClientError::TorCommandFailed(reason) => {
+                TorClientError::TorCommandFailed(format!("{}: {}", verb, reason))
+            },
+            err => err,
+        }
+    }
+}
+
+fn rejected_option_prefix(option: &Option<String>) -> String {
+    option
+        .as_ref()
+        .map(|option| format!("{} rejected: ", option))
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum OnionAddressError {
+    #[error("Onion address must be 16 (v2) or 56 (v3) characters long, got {0}")]
+    InvalidLength(usize),
+    #[error("Onion address is not valid base32")]
+    InvalidEncoding,
+    #[error("Unsupported onion address version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Onion address checksum is invalid")]
+    InvalidChecksum,
+    #[error("v2 onion addresses do not contain a public key")]
+    V2NoPublicKey,
+    #[error("Private key is not an ED25519-V3 key blob")]
+    InvalidPrivateKey,
+    #[error("The onion address of a {0} key cannot be derived without adding the service")]
+    UnsupportedKeyType(String),
 }
 
 impl From<LinesCodecError> for TorClientError {
@@ -20,7 +20,16 @@
 // WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
 // USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use super::response::ResponseLine;
+use super::{
+    circuit::CircEvent,
+    guard::GuardEvent,
+    heartbeat::Heartbeat,
+    parsers::ParseError,
+    response::ResponseLine,
+    stream::StreamEvent,
+    LOG_TARGET,
+};
+use log::*;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -36,12 +45,29 @@ pub enum TorControlEvent {
     NetworkLivenessUp,
     NetworkLivenessDown,
     TorControlDisconnected,
+    /// Sent by the hidden service controller once it has reconnected to the control port after a
+    /// `TorControlDisconnected`, authenticated, subscribed to events and added the hidden service again. This is sent
+    /// once per reconnection.
+    TorControlReconnected,
+    /// `STATUS_CLIENT CIRCUIT_ESTABLISHED`: tor is able to build circuits
+    CircuitEstablished,
+    /// `STATUS_CLIENT CIRCUIT_NOT_ESTABLISHED`: tor is no longer able to build circuits
+    CircuitNotEstablished,
+    /// `STATUS_CLIENT ENOUGH_DIR_INFO`: tor has enough directory information to build circuits
+    EnoughDirInfo,
+    /// `STATUS_CLIENT NOT_ENOUGH_DIR_INFO`: tor no longer has enough directory information to build circuits
+    NotEnoughDirInfo,
+    Circ(Box<CircEvent>),
+    Stream(Box<StreamEvent>),
+    Guard(Box<GuardEvent>),
+    /// A `NOTICE` log event with the heartbeat that tor logs periodically. Other `NOTICE` events are `Unsupported`.
+    Heartbeat(Box<Heartbeat>),
     Unsupported(String),
 }
 
 impl TorControlEvent {
     pub fn try_from_response(resp: ResponseLine) -> Result<Self, ControlEventError> {
-        debug_assert!(resp.is_event());
+        debug_assert!(resp.is_async());
 
         let mut parts = resp.value.splitn(2, ' ');
         let event_type = parts.next().ok_or_else(|| ControlEventError::EmptyResponse)?;
@@ -56,7 +82,119 @@ impl TorControlEvent {
                     _ => Err(ControlEventError::InvalidEventData),
                 }
             },
+            "STATUS_CLIENT" => {
+                // STATUS_CLIENT <severity> <action> [arguments]
+                let mut args = parts.next().unwrap_or("").split(' ').skip(1);
+                match args.next().ok_or_else(|| ControlEventError::InvalidEventData)? {
+                    "CIRCUIT_ESTABLISHED" => Ok(TorControlEvent::CircuitEstablished),
+                    "CIRCUIT_NOT_ESTABLISHED" => Ok(TorControlEvent::CircuitNotEstablished),
+                    "ENOUGH_DIR_INFO" => Ok(TorControlEvent::EnoughDirInfo),
+                    "NOT_ENOUGH_DIR_INFO" => Ok(TorControlEvent::NotEnoughDirInfo),
+                    _ => Ok(TorControlEvent::Unsupported(event_type.to_owned())),
+                }
+            },
+            "CIRC" => CircEvent::parse(parts.next().unwrap_or(""))
+                .map(|event| TorControlEvent::Circ(Box::new(event)))
+                .map_err(|err| invalid_event_data("CIRC", err)),
+            "STREAM" => StreamEvent::parse(parts.next().unwrap_or(""))
+                .map(|event| TorControlEvent::Stream(Box::new(event)))
+                .map_err(|err| invalid_event_data("STREAM", err)),
+            "GUARD" => GuardEvent::parse(parts.next().unwrap_or(""))
+                .map(|event| TorControlEvent::Guard(Box::new(event)))
+                .map_err(|err| invalid_event_data("GUARD", err)),
+            "NOTICE" => match Heartbeat::parse(parts.next().unwrap_or("")) {
+                Some(heartbeat) => Ok(TorControlEvent::Heartbeat(Box::new(heartbeat))),
+                None => Ok(TorControlEvent::Unsupported(event_type.to_owned())),
+            },
             s => Ok(TorControlEvent::Unsupported(s.to_owned())),
         }
     }
 }
+
+fn invalid_event_data(event_type: &str, err: ParseError) -> ControlEventError {
+    debug!(target: LOG_TARGET, "Invalid {} event: {}", event_type, err);
+    ControlEventError::InvalidEventData
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tor::control_client::{
+        circuit::CircuitReason,
+        parsers,
+        stream::StreamReason,
+        CircStatus,
+        GuardStatus,
+        StreamStatus,
+    };
+    use tari_test_utils::unpack_enum;
+
+    fn parse_event(line: &str) -> Result<TorControlEvent, ControlEventError> {
+        TorControlEvent::try_from_response(parsers::response_line(line).unwrap())
+    }
+
+    #[test]
+    fn circ_and_stream_events() {
+        let event = parse_event("650 CIRC 5 FAILED $AAAA~relay1 PURPOSE=GENERAL REASON=TIMEOUT").unwrap();
+        unpack_enum!(TorControlEvent::Circ(event) = event);
+        assert_eq!(event.status, CircStatus::Failed {
+            reason: Some(CircuitReason::Timeout),
+            remote_reason: None,
+        });
+
+        let event = parse_event("650 STREAM 9 CLOSED 5 example.com:80 REASON=END REMOTE_REASON=CONNRESET").unwrap();
+        unpack_enum!(TorControlEvent::Stream(event) = event);
+        assert_eq!(event.status, StreamStatus::Closed {
+            reason: Some(StreamReason::End),
+            remote_reason: Some(StreamReason::ConnReset),
+        });
+
+        assert!(matches!(
+            parse_event("650 STREAM 9"),
+            Err(ControlEventError::InvalidEventData)
+        ));
+        assert!(matches!(
+            parse_event("650 CIRC"),
+            Err(ControlEventError::InvalidEventData)
+        ));
+    }
+
+    #[test]
+    fn guard_events() {
+        let event = parse_event("650 GUARD ENTRY $7EA6EAD6FD83083C538F44038BBFA077587DD755~relay1 UP").unwrap();
+        unpack_enum!(TorControlEvent::Guard(event) = event);
+        assert_eq!(event.status, GuardStatus::Up);
+        assert_eq!(event.relay.nickname.as_deref(), Some("relay1"));
+
+        let event = parse_event("650 GUARD ENTRY $7EA6EAD6FD83083C538F44038BBFA077587DD755 DOWN").unwrap();
+        unpack_enum!(TorControlEvent::Guard(event) = event);
+        assert_eq!(event.status, GuardStatus::Down);
+        assert_eq!(event.relay.fingerprint, "7EA6EAD6FD83083C538F44038BBFA077587DD755");
+
+        assert!(matches!(
+            parse_event("650 GUARD ENTRY"),
+            Err(ControlEventError::InvalidEventData)
+        ));
+    }
+
+    #[test]
+    fn heartbeat_event() {
+        let event = parse_event(
+            "650 NOTICE Heartbeat: Tor's uptime is 2 days 0:00 hours, with 3 circuits open. I've sent 10.00 kB and \
+             received 20.00 kB.",
+        )
+        .unwrap();
+        unpack_enum!(TorControlEvent::Heartbeat(heartbeat) = event);
+        assert_eq!(heartbeat.circuits_open, Some(3));
+        assert_eq!(heartbeat.bytes_received, Some(20 * 1024));
+
+        for line in &[
+            "650 NOTICE Bootstrapped 100% (done): Done",
+            "650 NOTICE Heartbeat: Circuit handshake stats since last time: 0/0 TAP, 5/5 NTor.",
+        ] {
+            let event = parse_event(line).unwrap();
+            unpack_enum!(TorControlEvent::Unsupported(event_type) = event);
+            assert_eq!(event_type, "NOTICE");
+        }
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Types that describe entry guards in `GUARD` events
+
+use super::{
+    commands,
+    error::TorClientError,
+    parsers::{self, ParseError},
+};
+use data_encoding::HEXUPPER;
+use std::{fmt, str::FromStr};
+
+tor_keyword_enum!(
+    /// The type of guard in a `GUARD` event. Tor only reports entry guards.
+    GuardType {
+        Entry => "ENTRY",
+    }
+);
+
+tor_keyword_enum!(
+    /// The status of a guard in a `GUARD` event
+    GuardStatus {
+        /// The guard was added to the guard list, which may mean that the previous guards are no longer usable
+        New => "NEW",
+        /// The guard was dropped from the guard list
+        Dropped => "DROPPED",
+        /// A connection to the guard succeeded
+        Up => "UP",
+        /// A connection to the guard failed
+        Down => "DOWN",
+        /// The guard is no longer listed as a guard in the consensus
+        Bad => "BAD",
+        /// The guard is listed as a guard in the consensus again
+        Good => "GOOD",
+    }
+);
+
+/// A relay as given in events, as `$fingerprint~nickname`, `$fingerprint=nickname` or `$fingerprint`. This is also
+/// how relays are given in the path of an [ExtendCircuit](commands::ExtendCircuit) command.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RelaySpec {
+    /// The hex-encoded identity fingerprint, without the leading `$`
+    pub fingerprint: String,
+    pub nickname: Option<String>,
+}
+
+impl RelaySpec {
+    /// Returns the relay with the given identity fingerprint, which must be 20 bytes
+    pub fn from_fingerprint(fingerprint: &[u8]) -> Result<Self, TorClientError> {
+        if fingerprint.len() != 20 {
+            return Err(TorClientError::InvalidFingerprint(HEXUPPER.encode(fingerprint)));
+        }
+        Ok(Self {
+            fingerprint: HEXUPPER.encode(fingerprint),
+            nickname: None,
+        })
+    }
+}
+
+impl FromStr for RelaySpec {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let spec = s
+            .strip_prefix('$')
+            .ok_or_else(|| ParseError(format!("Relay '{}' has no fingerprint", s)))?;
+        let mut parts = spec.splitn(2, |ch| ch == '~' || ch == '=');
+        let fingerprint = parts.next().unwrap_or_default();
+        if !commands::is_hex_fingerprint(fingerprint) {
+            return Err(ParseError(format!("Relay '{}' has an invalid fingerprint", s)));
+        }
+        let nickname = parts
+            .next()
+            .filter(|nickname| !nickname.is_empty())
+            .map(ToString::to_string);
+        Ok(Self {
+            fingerprint: fingerprint.to_ascii_uppercase(),
+            nickname,
+        })
+    }
+}
+
+impl fmt::Display for RelaySpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "${}", self.fingerprint)?;
+        if let Some(nickname) = &self.nickname {
+            write!(f, "~{}", nickname)?;
+        }
+        Ok(())
+    }
+}
+
+/// A `GUARD` event, emitted when the status of an entry guard changes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuardEvent {
+    pub guard_type: GuardType,
+    pub relay: RelaySpec,
+    pub status: GuardStatus,
+}
+
+impl GuardEvent {
+    /// Parses the arguments of a `GUARD` event i.e. the event line without the leading `GUARD`
+    pub fn parse(args: &str) -> Result<Self, ParseError> {
+        let (positional, _) = parsers::event_args(args)?;
+        match positional.as_slice() {
+            [guard_type, relay, status, ..] => Ok(Self {
+                guard_type: guard_type.parse()?,
+                relay: relay.parse()?,
+                status: status.parse()?,
+            }),
+            _ => Err(ParseError(format!(
+                "GUARD event has {} of 3 required arguments",
+                positional.len()
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tari_test_utils::unpack_enum;
+
+    const FINGERPRINT: &str = "7EA6EAD6FD83083C538F44038BBFA077587DD755";
+
+    #[test]
+    fn relay_spec_parse() {
+        let relay = format!("${}~relay1", FINGERPRINT).parse::<RelaySpec>().unwrap();
+        assert_eq!(relay.fingerprint, FINGERPRINT);
+        assert_eq!(relay.nickname.as_deref(), Some("relay1"));
+        assert_eq!(relay.to_string(), format!("${}~relay1", FINGERPRINT));
+
+        let relay = format!("${}=relay1", FINGERPRINT.to_ascii_lowercase())
+            .parse::<RelaySpec>()
+            .unwrap();
+        assert_eq!(relay.fingerprint, FINGERPRINT);
+        assert_eq!(relay.nickname.as_deref(), Some("relay1"));
+
+        let relay = format!("${}", FINGERPRINT).parse::<RelaySpec>().unwrap();
+        assert_eq!(relay.nickname, None);
+        assert_eq!(relay.to_string(), format!("${}", FINGERPRINT));
+
+        assert!("relay1".parse::<RelaySpec>().is_err());
+        assert!("$ABCD~relay1".parse::<RelaySpec>().is_err());
+    }
+
+    #[test]
+    fn relay_spec_from_fingerprint() {
+        let relay = RelaySpec::from_fingerprint(&[0x7E; 20]).unwrap();
+        assert_eq!(relay.fingerprint, "7E".repeat(20));
+        assert_eq!(relay.nickname, None);
+
+        let err = RelaySpec::from_fingerprint(&[0xAA; 19]).unwrap_err();
+        unpack_enum!(TorClientError::InvalidFingerprint(fingerprint) = err);
+        assert_eq!(fingerprint.len(), 38);
+    }
+
+    #[test]
+    fn guard_event_parse() {
+        let event = GuardEvent::parse(&format!("ENTRY ${}~relay1 UP", FINGERPRINT)).unwrap();
+        assert_eq!(event.guard_type, GuardType::Entry);
+        assert_eq!(event.relay.nickname.as_deref(), Some("relay1"));
+        assert_eq!(event.status, GuardStatus::Up);
+
+        let event = GuardEvent::parse(&format!("ENTRY ${} DOWN", FINGERPRINT)).unwrap();
+        assert_eq!(event.relay.fingerprint, FINGERPRINT);
+        assert_eq!(event.status, GuardStatus::Down);
+
+        let event = GuardEvent::parse(&format!("ENTRY ${} SOME_FUTURE_STATUS", FINGERPRINT)).unwrap();
+        assert_eq!(event.status, GuardStatus::Other("SOME_FUTURE_STATUS".to_string()));
+
+        assert!(GuardEvent::parse("ENTRY").is_err());
+        assert!(GuardEvent::parse("ENTRY relay1 UP").is_err());
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Parses the heartbeat that tor logs periodically at notice level, which is received as a `NOTICE` event
+
+use std::time::Duration;
+
+const HEARTBEAT_PREFIX: &str = "Heartbeat: ";
+
+/// The stats in a heartbeat message, which tor logs every `HeartbeatPeriod` (6 hours by default) e.g.
+/// `Heartbeat: Tor's uptime is 1 day 6:00 hours, with 12 circuits open. I've sent 3.53 MB and received 12.34 MB.`
+///
+/// The message is meant to be read by people and its wording differs between versions of tor and between clients and
+/// relays, so each field is `None` if it could not be found. Byte counts are only as precise as the message, which
+/// rounds to two decimal places of the unit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub uptime: Option<Duration>,
+    pub circuits_open: Option<u32>,
+    pub bytes_sent: Option<u64>,
+    pub bytes_received: Option<u64>,
+}
+
+impl Heartbeat {
+    /// Parses a log message, returning `None` if it is not a heartbeat. Tor logs some other messages starting with
+    /// `Heartbeat:` (such as circuit handshake stats), which are not heartbeats either as none of the stats are found.
+    pub fn parse(message: &str) -> Option<Self> {
+        let message = message.trim().strip_prefix(HEARTBEAT_PREFIX)?;
+        let heartbeat = Self {
+            uptime: text_after(message, "uptime is ").and_then(parse_uptime),
+            circuits_open: text_after(message, "with ")
+                .filter(|rest| rest.split(' ').nth(1) == Some("circuits"))
+                .and_then(|rest| rest.split(' ').next())
+                .and_then(|count| count.parse().ok()),
+            bytes_sent: text_after(message, "sent ").and_then(parse_bytes),
+            bytes_received: text_after(message, "and received ").and_then(parse_bytes),
+        };
+        Some(heartbeat).filter(|heartbeat| *heartbeat != Self::default())
+    }
+}
+
+fn text_after<'a>(s: &'a str, pat: &str) -> Option<&'a str> {
+    s.find(pat).map(|pos| &s[pos + pat.len()..])
+}
+
+/// Parses `H:MM hours`, `1 day H:MM hours` or `N days H:MM hours`, ignoring the rest of the message
+fn parse_uptime(s: &str) -> Option<Duration> {
+    let mut parts = s.split(|ch| ch == ' ' || ch == ',');
+    let mut first = parts.next()?;
+    let mut days = 0;
+    if matches!(parts.clone().next(), Some("day") | Some("days")) {
+        days = first.parse::<u64>().ok()?;
+        parts.next();
+        first = parts.next()?;
+    }
+
+    let mut hours_minutes = first.splitn(2, ':');
+    let hours = hours_minutes.next()?.parse::<u64>().ok()?;
+    let minutes = hours_minutes.next()?.parse::<u64>().ok()?;
+    Some(Duration::from_secs(((days * 24 + hours) * 60 + minutes) * 60))
+}
+
+/// Parses a byte count such as `512 kB` or `3.53 MB`, ignoring the rest of the message
+fn parse_bytes(s: &str) -> Option<u64> {
+    let mut parts = s.split(' ');
+    let value = parts.next()?.parse::<f64>().ok()?;
+    let unit = match parts.next()?.trim_end_matches(|ch| ch == '.' || ch == ',') {
+        "B" => 1u64,
+        "kB" => 1 << 10,
+        "MB" => 1 << 20,
+        "GB" => 1 << 30,
+        "TB" => 1 << 40,
+        _ => return None,
+    };
+    Some((value * unit as f64).round() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_heartbeat() {
+        let heartbeat = Heartbeat::parse(
+            "Heartbeat: Tor's uptime is 1 day 6:05 hours, with 12 circuits open. I've sent 3.50 MB and received 512 \
+             kB. I've received 0 connections on IPv4 and 0 on IPv6. I've made 21 connections with IPv4 and 0 with \
+             IPv6.",
+        )
+        .unwrap();
+        assert_eq!(heartbeat, Heartbeat {
+            uptime: Some(Duration::from_secs(30 * 3600 + 5 * 60)),
+            circuits_open: Some(12),
+            bytes_sent: Some(3_670_016),
+            bytes_received: Some(512 * 1024),
+        });
+
+        let heartbeat = Heartbeat::parse(
+            "Heartbeat: Tor's uptime is 0:30 hours, with 0 circuits open. I've sent 1.00 GB and received 2.25 GB.",
+        )
+        .unwrap();
+        assert_eq!(heartbeat.uptime, Some(Duration::from_secs(30 * 60)));
+        assert_eq!(heartbeat.circuits_open, Some(0));
+        assert_eq!(heartbeat.bytes_sent, Some(1 << 30));
+        assert_eq!(heartbeat.bytes_received, Some(9 << 28));
+
+        assert!(Heartbeat::parse("Heartbeat: Circuit handshake stats since last time: 0/0 TAP, 5/5 NTor.").is_none());
+
+        assert!(Heartbeat::parse("Bootstrapped 100% (done): Done").is_none());
+    }
+}
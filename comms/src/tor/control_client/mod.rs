@@ -20,25 +20,129 @@
 // WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
 // USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+/// Defines an enum of the keywords tor uses for a field, with an `Other` variant that preserves keywords that are not
+/// recognised (e.g. added in a later version of tor), and the `FromStr`/`Display` impls that map to and from them.
+/// Defined before the module declarations below so that they can use it.
+macro_rules! tor_keyword_enum {
+    ($(#[$meta:meta])* $name:ident { $($(#[$var_meta:meta])* $variant:ident => $keyword:literal,)+ }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $($(#[$var_meta])* $variant,)+
+            /// A keyword that is not recognised by this client
+            Other(String),
+        }
+
+        impl ::std::str::FromStr for $name {
+            type Err = $crate::tor::control_client::parsers::ParseError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                use $crate::tor::control_client::parsers::ParseError;
+                match s.trim() {
+                    "" => Err(ParseError(format!("Empty {}", stringify!($name)))),
+                    $($keyword => Ok($name::$variant),)+
+                    s => Ok($name::Other(s.to_string())),
+                }
+            }
+        }
+
+        impl ::std::fmt::Display for $name {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                match self {
+                    $($name::$variant => f.write_str($keyword),)+
+                    $name::Other(s) => f.write_str(s),
+                }
+            }
+        }
+    };
+}
+
+mod circuit;
+pub use circuit::{
+    BuildFlag,
+    BuildFlags,
+    CircEvent,
+    CircStatus,
+    CircuitPurpose,
+    CircuitReason,
+    CircuitStatus,
+    CircuitStatusIter,
+};
+
+mod guard;
+pub use guard::{GuardEvent, GuardStatus, GuardType, RelaySpec};
+
+mod heartbeat;
+pub use heartbeat::Heartbeat;
+
+mod stream;
+pub use stream::{StreamEvent, StreamReason, StreamStatus, StreamStatusEntry, StreamStatusIter};
+
+mod clock;
+#[cfg(test)]
+pub(crate) use clock::MockClock;
+pub use clock::{Clock, SystemClock};
+
+mod connection_state;
+pub use connection_state::ConnectionState;
+pub(crate) use connection_state::ConnectionStateTracker;
+
 mod client;
-pub use client::{Authentication, TorControlPortClient};
+pub use client::{Authentication, TorControlPortClient, TorControlPortClientBuilder};
 
 mod error;
-pub use error::TorClientError;
+pub use error::{ConnectionFailureKind, OnionAddressError, TorClientError};
 
 pub mod commands;
 
+mod decoder;
+pub use decoder::ResponseDecoder;
+
 mod event;
 pub use event::TorControlEvent;
 
 mod monitor;
+
+mod onion_listener;
+pub use onion_listener::OnionListener;
+
 mod parsers;
+/// The response parsers, exposed for the benches. This is not part of the public API.
+#[cfg(feature = "benches")]
+#[doc(hidden)]
+pub mod bench_parsers {
+    pub use super::parsers::{key_value, key_value_args, quoted_string, response_line};
+}
+
 mod response;
+pub use response::{ResponseLine, ResponseLineKind};
 
 mod types;
-pub use types::{KeyBlob, KeyType, PortMapping, PrivateKey};
+pub use types::{
+    encode_onion_v3_address,
+    onion_v3_checksum,
+    AddressMapping,
+    AnyKeyType,
+    ClientName,
+    ExternalAddress,
+    Hostname,
+    KeyBlob,
+    KeyType,
+    ListenerAddress,
+    OnionAddress,
+    OnionInventory,
+    OnionTarget,
+    PortMapping,
+    PortSpec,
+    PrivateKey,
+    TorVersion,
+    TrafficCounters,
+    VersionStatus,
+};
 
 #[cfg(test)]
-mod test_server;
+mod replay;
+#[cfg(test)]
+pub(super) mod test_server;
 
 const LOG_TARGET: &str = "comms::tor::control_client";
@@ -20,18 +20,35 @@
 // WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
 // USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use super::{event::TorControlEvent, parsers, response::ResponseLine, LOG_TARGET};
+use super::{
+    connection_state::{self, ConnectionState, ConnectionStateTracker},
+    decoder::ResponseDecoder,
+    event::TorControlEvent,
+    response::ResponseLine,
+    LOG_TARGET,
+};
 use crate::{compat::IoCompat, runtime::task};
-use futures::{channel::mpsc, future, future::Either, AsyncRead, AsyncWrite, SinkExt, Stream, StreamExt};
+use bytes::BytesMut;
+use futures::{channel::mpsc, future, future::Either, AsyncRead, AsyncWrite, SinkExt, StreamExt};
 use log::*;
-use std::fmt;
+use std::{
+    fmt,
+    sync::{Arc, RwLock},
+};
 use tokio::sync::broadcast;
-use tokio_util::codec::{Framed, LinesCodec};
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedParts, LinesCodecError};
+
+/// Command buffers with a larger capacity than this are dropped rather than handed back to the client.
+const MAX_RECYCLED_BUF_CAPACITY: usize = 1024;
 
 pub fn spawn_monitor<TSocket>(
     mut cmd_rx: mpsc::Receiver<String>,
     socket: TSocket,
     event_tx: broadcast::Sender<TorControlEvent>,
+    recycled_buf_tx: mpsc::Sender<String>,
+    read_buffer_capacity: usize,
+    state: ConnectionStateTracker,
+    enabled_events: EnabledEvents,
 ) -> mpsc::Receiver<ResponseLine>
 where
     TSocket: AsyncRead + AsyncWrite + Unpin + Send + 'static,
@@ -39,7 +56,10 @@ where
     let (mut responses_tx, responses_rx) = mpsc::channel(100);
 
     task::spawn(async move {
-        let framed = Framed::new(IoCompat::new(socket), LinesCodec::new());
+        let codec = ControlPortCodec::new(recycled_buf_tx, event_tx.clone(), enabled_events);
+        let mut parts = FramedParts::new(IoCompat::new(socket), codec);
+        parts.read_buf = BytesMut::with_capacity(read_buffer_capacity);
+        let framed = Framed::from_parts(parts);
         let (mut sink, mut stream) = framed.split();
         loop {
             let either = future::select(cmd_rx.next(), stream.next()).await;
@@ -52,6 +72,7 @@ where
                             target: LOG_TARGET,
                             "Error when sending to Tor control server: {:?}. Monitor is shutting down.", err
                         );
+                        state.set_if(ConnectionState::Closed, connection_state::is_not_reconnecting);
                         break;
                     }
                 },
@@ -61,36 +82,35 @@ where
                         target: LOG_TARGET,
                         "Tor control server command receiver closed. Monitor is exiting."
                     );
+                    // The client was closed, which sets the state itself, or dropped, in which case the state may be
+                    // shared with the client that replaced it and is left as is
                     break;
                 },
 
-                // Received a line from the control server
+                // Received a reply from the control server
                 Either::Right((Some(Ok(line)), _)) => {
-                    trace!(target: LOG_TARGET, "Read line of length '{}'", line.len());
-                    match parsers::response_line(&line) {
-                        Ok(mut line) => {
-                            if line.is_multiline {
-                                let lines = read_until(&mut stream, ".").await;
-                                line.value = format!("{}\n{}", line.value, lines.join("\n"));
-                            }
-
-                            if line.is_event() {
-                                match TorControlEvent::try_from_response(line) {
-                                    Ok(event) => {
+                    trace!(target: LOG_TARGET, "Read line of length '{}'", line.value.len());
+                    if line.is_async() {
+                        match TorControlEvent::try_from_response(line) {
+                            Ok(event) => {
+                                #[cfg(feature = "tracing")]
+                                tracing::span!(tracing::Level::DEBUG, "tor_event_dispatch", event = ?event).in_scope(
+                                    || {
                                         let _ = event_tx.send(event);
                                     },
-                                    Err(err) => {
-                                        log_server_response_error(err);
-                                    },
-                                }
-                            } else if let Err(err) = responses_tx.send(line).await {
-                                warn!(
-                                    target: LOG_TARGET,
-                                    "Failed to send response on internal channel: {:?}", err
                                 );
-                            }
-                        },
-                        Err(err) => log_server_response_error(err),
+                                #[cfg(not(feature = "tracing"))]
+                                let _ = event_tx.send(event);
+                            },
+                            Err(err) => {
+                                log_server_response_error(err);
+                            },
+                        }
+                    } else if let Err(err) = responses_tx.send(line).await {
+                        warn!(
+                            target: LOG_TARGET,
+                            "Failed to send response on internal channel: {:?}", err
+                        );
                     }
                 },
 
@@ -100,6 +120,7 @@ where
                         target: LOG_TARGET,
                         "Line framing error when reading from tor control server: '{:?}'. Monitor is exiting.", err
                     );
+                    state.set_if(ConnectionState::Closed, connection_state::is_not_reconnecting);
                     break;
                 },
                 // The control server disconnected
@@ -109,6 +130,7 @@ where
                         target: LOG_TARGET,
                         "Connection to tor control port closed. Monitor is exiting."
                     );
+                    state.set_if(ConnectionState::Closed, connection_state::is_not_reconnecting);
                     let _ = event_tx.send(TorControlEvent::TorControlDisconnected);
                     break;
                 },
@@ -119,30 +141,98 @@ where
     responses_rx
 }
 
-async fn read_until<E: fmt::Debug, S: Stream<Item = Result<String, E>> + Unpin>(
-    stream: &mut S,
-    pat: &str,
-) -> Vec<String>
-{
-    let mut items = Vec::new();
-    loop {
-        match stream.next().await {
-            Some(Ok(item)) => {
-                if item.trim() == pat {
-                    break items;
-                }
-                items.push(item);
-            },
-            Some(Err(err)) => {
-                error!(target: LOG_TARGET, "read_until: {:?}", err);
-            },
-            None => {
-                break items;
-            },
+/// Decodes replies using a [ResponseDecoder] that skips unobserved events, and writes command lines, handing each one
+/// back to the client so that its allocation can be reused for the next command.
+struct ControlPortCodec {
+    decoder: ResponseDecoder,
+    recycled_buf_tx: mpsc::Sender<String>,
+}
+
+impl ControlPortCodec {
+    fn new(
+        recycled_buf_tx: mpsc::Sender<String>,
+        event_tx: broadcast::Sender<TorControlEvent>,
+        enabled_events: EnabledEvents,
+    ) -> Self
+    {
+        Self {
+            decoder: ResponseDecoder::new()
+                .with_skip_filter(move |line| is_unobserved_event(line, &event_tx, &enabled_events)),
+            recycled_buf_tx,
+        }
+    }
+}
+
+impl Decoder for ControlPortCodec {
+    type Error = LinesCodecError;
+    type Item = ResponseLine;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decoder.decode(buf)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decoder.decode_eof(buf)
+    }
+}
+
+impl Encoder for ControlPortCodec {
+    type Error = LinesCodecError;
+    type Item = String;
+
+    fn encode(&mut self, mut line: String, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        buf.reserve(line.len() + 1);
+        buf.extend_from_slice(line.as_bytes());
+        buf.extend_from_slice(b"\n");
+
+        if line.capacity() <= MAX_RECYCLED_BUF_CAPACITY {
+            line.clear();
+            // If the client has not taken the previously recycled buffer, this one is simply dropped
+            let _ = self.recycled_buf_tx.try_send(line);
+        }
+        Ok(())
+    }
+}
+
+/// The kinds of event that the client enabled with SETEVENTS, shared with the monitor. This is `None` until SETEVENTS
+/// is first sent, in which case no event is skipped because of its kind.
+#[derive(Debug, Clone, Default)]
+pub struct EnabledEvents(Arc<RwLock<Option<Vec<String>>>>);
+
+impl EnabledEvents {
+    pub fn get(&self) -> Option<Vec<String>> {
+        self.0.read().expect("EnabledEvents lock poisoned").clone()
+    }
+
+    pub fn set(&self, events: Option<Vec<String>>) {
+        *self.0.write().expect("EnabledEvents lock poisoned") = events;
+    }
+
+    fn is_enabled(&self, keyword: &str) -> bool {
+        match self.0.read().expect("EnabledEvents lock poisoned").as_ref() {
+            Some(events) => events.iter().any(|event| event.eq_ignore_ascii_case(keyword)),
+            None => true,
         }
     }
 }
 
+/// Returns true if the line is an asynchronous event that there are no subscribers to receive, or whose kind was not
+/// enabled by the client. Tor may emit frequent events (e.g. BW every second) that another controller enabled, so
+/// these are dropped without being parsed. Only the event keyword is read to decide this.
+fn is_unobserved_event(line: &str, event_tx: &broadcast::Sender<TorControlEvent>, enabled: &EnabledEvents) -> bool {
+    if !line.starts_with("650") {
+        return false;
+    }
+    if event_tx.receiver_count() == 0 {
+        return true;
+    }
+    let keyword = line
+        .get(4..)
+        .and_then(|rest| rest.split(' ').next())
+        .unwrap_or_default();
+    !enabled.is_enabled(keyword)
+}
+
 fn log_server_response_error<E: fmt::Debug>(err: E) {
     error!(
         target: LOG_TARGET,
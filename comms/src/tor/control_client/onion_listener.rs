@@ -0,0 +1,217 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use super::{
+    client::TorControlPortClient,
+    commands::AddOnionFlag,
+    error::TorClientError,
+    types::OnionAddress,
+    LOG_TARGET,
+};
+use log::*;
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{net::TcpListener, runtime::Handle};
+
+/// A local `TcpListener` that is exposed as an ephemeral onion service. The onion service is deleted (DEL_ONION) when
+/// this is dropped, or when [close](Self::close) is called.
+pub struct OnionListener {
+    listener: TcpListener,
+    onion_address: OnionAddress,
+    onion_port: u16,
+    client: Option<TorControlPortClient>,
+}
+
+impl OnionListener {
+    /// Creates an onion service that forwards `onion_port` to the address `listener` is bound to. If `ready_timeout`
+    /// is given, this first waits up to that long for tor to be able to build circuits, without which the service
+    /// cannot be reached. The client is owned by the returned listener so that the service can be deleted on drop.
+    pub async fn new(
+        mut client: TorControlPortClient,
+        listener: TcpListener,
+        onion_port: u16,
+        ready_timeout: Option<Duration>,
+    ) -> Result<Self, TorClientError>
+    {
+        let target = forwarding_target(listener.local_addr()?);
+        if let Some(timeout) = ready_timeout {
+            client.wait_for_circuit_established(timeout).await?;
+        }
+        let response = client
+            .add_onion_v3(vec![AddOnionFlag::DiscardPK], (onion_port, target), None)
+            .await?;
+        let onion_address = response.service_id.parse()?;
+        debug!(
+            target: LOG_TARGET,
+            "Exposed listener at {} as onion service {}:{}", target, onion_address, onion_port
+        );
+
+        Ok(Self {
+            listener,
+            onion_address,
+            onion_port,
+            client: Some(client),
+        })
+    }
+
+    /// Binds a listener to `127.0.0.1:local_port` (an OS-assigned port if `local_port` is 0) and exposes it as an onion
+    /// service. See [new](Self::new).
+    pub async fn bind(
+        client: TorControlPortClient,
+        local_port: u16,
+        onion_port: u16,
+        ready_timeout: Option<Duration>,
+    ) -> Result<Self, TorClientError>
+    {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, local_port)).await?;
+        Self::new(client, listener, onion_port, ready_timeout).await
+    }
+
+    pub fn onion_address(&self) -> &OnionAddress {
+        &self.onion_address
+    }
+
+    pub fn onion_port(&self) -> u16 {
+        self.onion_port
+    }
+
+    /// The listener that connections to the onion service are forwarded to
+    pub fn listener(&mut self) -> &mut TcpListener {
+        &mut self.listener
+    }
+
+    /// Deletes the onion service and returns the client
+    pub async fn close(mut self) -> Result<TorControlPortClient, TorClientError> {
+        let mut client = self.client.take().expect("client is only taken on close or drop");
+        client.del_onion(self.onion_address.service_id()).await?;
+        Ok(client)
+    }
+}
+
+impl Drop for OnionListener {
+    fn drop(&mut self) {
+        if let Some(mut client) = self.client.take() {
+            // Tor deletes the service when the control connection closes regardless, so this is best-effort
+            match Handle::try_current() {
+                Ok(handle) => {
+                    let service_id = self.onion_address.service_id().to_string();
+                    handle.spawn(async move {
+                        if let Err(err) = client.del_onion(&service_id).await {
+                            warn!(
+                                target: LOG_TARGET,
+                                "Failed to delete onion service {}: {}", service_id, err
+                            );
+                        }
+                    });
+                },
+                Err(_) => {
+                    warn!(
+                        target: LOG_TARGET,
+                        "Onion listener dropped outside of a tokio runtime, onion service {} will be deleted when the \
+                         control connection closes",
+                        self.onion_address
+                    );
+                },
+            }
+        }
+    }
+}
+
+/// Tor cannot forward to an unspecified address, so listeners bound to all interfaces are reached through loopback
+fn forwarding_target(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if v4.ip().is_unspecified() => (Ipv4Addr::LOCALHOST, v4.port()).into(),
+        SocketAddr::V6(v6) if v6.ip().is_unspecified() => (Ipv6Addr::LOCALHOST, v6.port()).into(),
+        addr => addr,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        runtime,
+        tor::control_client::{test_server, test_server::canned_responses},
+    };
+    use tokio::{net::TcpStream, sync::broadcast, time};
+
+    #[runtime::test]
+    async fn lifecycle() {
+        let (_, mock_state, socket) = test_server::spawn().await;
+        let (event_tx, _) = broadcast::channel(1);
+        let client = TorControlPortClient::new(socket, event_tx);
+
+        mock_state.push_response(canned_responses::ADD_ONION_DISCARDPK_OK).await;
+        mock_state.set_canned_response(canned_responses::OK).await;
+        let mut listener = OnionListener::bind(client, 0, 80, None).await.unwrap();
+        let local_addr = listener.listener().local_addr().unwrap();
+        assert_eq!(
+            listener.onion_address().service_id(),
+            "qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid"
+        );
+        assert_eq!(listener.onion_port(), 80);
+        assert_eq!(mock_state.take_requests().await, [format!(
+            "ADD_ONION NEW:ED25519-V3 Flags=DiscardPK Port=80,{}",
+            local_addr
+        )]);
+
+        // Connections to the target are accepted by the listener
+        let (_stream, accepted) =
+            futures::future::join(TcpStream::connect(local_addr), listener.listener().accept()).await;
+        accepted.unwrap();
+
+        drop(listener);
+        // DEL_ONION is sent by a spawned task
+        let mut requests = Vec::new();
+        for _ in 0..50 {
+            requests = mock_state.take_requests().await;
+            if !requests.is_empty() {
+                break;
+            }
+            time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(requests, [
+            "DEL_ONION qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid"
+        ]);
+    }
+
+    #[runtime::test]
+    async fn close() {
+        let (_, mock_state, socket) = test_server::spawn().await;
+        let (event_tx, _) = broadcast::channel(1);
+        let client = TorControlPortClient::new(socket, event_tx);
+
+        mock_state.push_response(canned_responses::ADD_ONION_DISCARDPK_OK).await;
+        mock_state.set_canned_response(canned_responses::OK).await;
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let listener = OnionListener::new(client, listener, 9090, None).await.unwrap();
+        let client = listener.close().await.unwrap();
+        assert!(client.is_connected());
+        assert_eq!(mock_state.take_requests().await, [
+            format!("ADD_ONION NEW:ED25519-V3 Flags=DiscardPK Port=9090,127.0.0.1:{}", port),
+            "DEL_ONION qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid".to_string(),
+        ]);
+    }
+}
@@ -27,7 +27,7 @@ use nom::{
     combinator::map_res,
     error::ErrorKind,
 };
-use std::{borrow::Cow, fmt};
+use std::{borrow::Cow, fmt, str::FromStr};
 
 type NomErr<'a> = nom::Err<(&'a str, ErrorKind)>;
 
@@ -40,6 +40,14 @@ impl From<NomErr<'_>> for ParseError {
     }
 }
 
+impl ParseError {
+    /// Adds the verb of the command whose reply failed to parse to the error message e.g. `while parsing ADD_ONION
+    /// reply: ...`
+    pub fn with_command(self, verb: &str) -> Self {
+        ParseError(format!("while parsing {} reply: {}", verb, self.0))
+    }
+}
+
 impl std::error::Error for ParseError {}
 
 impl fmt::Display for ParseError {
@@ -83,6 +91,115 @@ pub fn key_value(line: &str) -> Result<(Cow<'_, str>, Vec<Cow<'_, str>>), ParseE
     Ok((identifier.trim().into(), parts))
 }
 
+/// Parses a quoted string (including the surrounding quotes) from the start of `s`, unescaping any escaped characters.
+/// Returns the unquoted value and the remaining input.
+pub fn quoted_string(s: &str) -> Result<(Cow<'_, str>, &str), ParseError> {
+    let (rest, _) = chr('"')(s)?;
+    let mut unescaped: Option<String> = None;
+    let mut chars = rest.char_indices();
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '"' => {
+                let value = match unescaped {
+                    Some(value) => Cow::Owned(value),
+                    None => Cow::Borrowed(&rest[..i]),
+                };
+                return Ok((value, &rest[i + 1..]));
+            },
+            '\\' => {
+                let (_, escaped) = chars
+                    .next()
+                    .ok_or_else(|| ParseError("Unterminated escape sequence in quoted string".to_string()))?;
+                let value = unescaped.get_or_insert_with(|| rest[..i].to_string());
+                value.push(match escaped {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    ch => ch,
+                });
+            },
+            ch => {
+                if let Some(value) = unescaped.as_mut() {
+                    value.push(ch);
+                }
+            },
+        }
+    }
+
+    Err(ParseError(format!("Unterminated quoted string '{}'", s)))
+}
+
+/// Returns `value` as a quoted string argument, escaping any quotes, backslashes and control characters. This is the
+/// inverse of [quoted_string], and should be used by every command that sends a quoted argument.
+pub fn quote_arg(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for ch in value.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Parses space separated `KEY=VALUE` arguments, where each value is either a quoted string or runs until the next
+/// space, e.g. `METHODS=COOKIE,SAFECOOKIE COOKIEFILE="/var/lib/tor/control_auth_cookie"`.
+pub fn key_value_args(s: &str) -> Result<Vec<(&str, Cow<'_, str>)>, ParseError> {
+    let mut args = Vec::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let (r, key) = take_while1(|ch| ch != '=' && ch != ' ')(rest)?;
+        let (r, _) = chr('=')(r)?;
+        let (value, r) = if r.starts_with('"') {
+            quoted_string(r)?
+        } else {
+            let end = r.find(' ').unwrap_or_else(|| r.len());
+            (Cow::Borrowed(&r[..end]), &r[end..])
+        };
+        args.push((key, value));
+        rest = r.trim_start();
+    }
+
+    Ok(args)
+}
+
+/// Parses the decimal number `value` of `name`, returning an error naming the value if it is not a valid number
+pub fn number<T: FromStr>(name: &str, value: &str) -> Result<T, ParseError> {
+    value
+        .parse()
+        .map_err(|_| ParseError(format!("Invalid {} value '{}'", name, value)))
+}
+
+pub type KeyValueArgs<'a> = Vec<(&'a str, Cow<'a, str>)>;
+
+/// Splits the arguments of an event into the positional arguments and the `KEY=VALUE` arguments that follow them e.g.
+/// `12 BUILT $AAAA~relay1,$BBBB~relay2 PURPOSE=GENERAL`. Relay names such as `$AAAA=relay1` are positional arguments,
+/// as keys are always upper case keywords.
+pub fn event_args(s: &str) -> Result<(Vec<&str>, KeyValueArgs<'_>), ParseError> {
+    let mut positional = Vec::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let end = rest.find(' ').unwrap_or_else(|| rest.len());
+        let token = &rest[..end];
+        let is_keyword_arg = token
+            .find('=')
+            .map(|i| i > 0 && token[..i].chars().all(|ch| ch.is_ascii_uppercase() || ch == '_'))
+            .unwrap_or(false);
+        if is_keyword_arg {
+            break;
+        }
+        positional.push(token);
+        rest = rest[end..].trim_start();
+    }
+    Ok((positional, key_value_args(rest)?))
+}
+
 #[cfg(test)]
 mod test {
     #[test]
@@ -91,4 +208,166 @@ mod test {
         assert_eq!(key, "greeting");
         assert_eq!(values, &["hello", "world 🌎"]);
     }
+
+    #[test]
+    fn number() {
+        assert_eq!(super::number::<u64>("traffic/read", "1234").unwrap(), 1234);
+        let err = super::number::<u64>("traffic/read", "-1").unwrap_err();
+        assert_eq!(err.0, "Invalid traffic/read value '-1'");
+    }
+
+    #[test]
+    fn event_args() {
+        let (positional, args) =
+            super::event_args(r#"12 BUILT $AAAA=relay1,$BBBB~relay2 PURPOSE=GENERAL SOCKS_USERNAME="a b""#).unwrap();
+        assert_eq!(positional, ["12", "BUILT", "$AAAA=relay1,$BBBB~relay2"]);
+        assert_eq!(args.len(), 2);
+        assert_eq!(args[0], ("PURPOSE", "GENERAL".into()));
+        assert_eq!(args[1], ("SOCKS_USERNAME", "a b".into()));
+
+        let (positional, args) = super::event_args("3 LAUNCHED").unwrap();
+        assert_eq!(positional, ["3", "LAUNCHED"]);
+        assert!(args.is_empty());
+    }
+
+    #[test]
+    fn parse_error_with_command() {
+        let err = super::ParseError("bad line".to_string()).with_command("HSPOST");
+        assert_eq!(err.0, "while parsing HSPOST reply: bad line");
+    }
+
+    #[test]
+    fn quoted_string() {
+        let (value, rest) = super::quoted_string(r#""hello world" rest"#).unwrap();
+        assert_eq!(value, "hello world");
+        assert_eq!(rest, " rest");
+
+        let (value, rest) = super::quoted_string(r#""C:\\Tor\"data\"""#).unwrap();
+        assert_eq!(value, r#"C:\Tor"data""#);
+        assert!(rest.is_empty());
+
+        assert!(super::quoted_string(r#""unterminated"#).is_err());
+        assert!(super::quoted_string("unquoted").is_err());
+    }
+
+    #[test]
+    fn quote_arg() {
+        assert_eq!(super::quote_arg(""), r#""""#);
+        assert_eq!(super::quote_arg("hello world"), r#""hello world""#);
+        assert_eq!(super::quote_arg(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(super::quote_arg(r"C:\Tor\"), r#""C:\\Tor\\""#);
+        assert_eq!(super::quote_arg("line 1\nline 2\r\t"), r#""line 1\nline 2\r\t""#);
+
+        let value = "\"\\ \n\" 🌎";
+        let quoted = super::quote_arg(value);
+        let (unquoted, rest) = super::quoted_string(&quoted).unwrap();
+        assert_eq!(unquoted, value);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn key_value_args() {
+        let args = super::key_value_args(r#"METHODS=COOKIE,SAFECOOKIE COOKIEFILE="/var/lib/tor/control auth cookie""#)
+            .unwrap();
+        assert_eq!(args, vec![
+            ("METHODS", "COOKIE,SAFECOOKIE".into()),
+            ("COOKIEFILE", "/var/lib/tor/control auth cookie".into())
+        ]);
+
+        assert!(super::key_value_args("").unwrap().is_empty());
+        assert!(super::key_value_args("NOVALUE").is_err());
+    }
+
+    mod prop {
+        use super::super::*;
+        use proptest::prelude::*;
+
+        /// A reply line as (code, separator, value). Each part shrinks independently towards `200 ` and an empty value.
+        fn reply_line() -> impl Strategy<Value = (u16, char, String)> {
+            (200u16..700, prop_oneof![Just(' '), Just('-'), Just('+')], "[^\r\n]*")
+        }
+
+        fn key() -> impl Strategy<Value = String> {
+            "[A-Za-z0-9/_-]{1,24}"
+        }
+
+        /// A value that is not split or dropped by `key_value` i.e. not blank and without quotes or newlines
+        fn plain_value() -> impl Strategy<Value = String> {
+            "[^\"\r\n]*[^\"\r\n\\s][^\"\r\n]*"
+        }
+
+        proptest! {
+            #[test]
+            fn response_line_never_panics(line in any::<String>()) {
+                let _ = response_line(&line);
+            }
+
+            #[test]
+            fn key_value_never_panics(line in any::<String>()) {
+                let _ = key_value(&line);
+            }
+
+            #[test]
+            fn quoted_string_never_panics(s in any::<String>()) {
+                let _ = quoted_string(&s);
+                let _ = quoted_string(&format!("\"{}", s));
+            }
+
+            #[test]
+            fn key_value_args_never_panics(s in any::<String>()) {
+                let _ = key_value_args(&s);
+            }
+
+            #[test]
+            fn response_line_round_trip((code, sep, value) in reply_line()) {
+                let line = response_line(&format!("{}{}{}", code, sep, value)).unwrap();
+                prop_assert_eq!(line.code, code);
+                prop_assert_eq!(line.has_more, sep != ' ');
+                prop_assert_eq!(line.is_multiline, sep == '+');
+                prop_assert_eq!(line.value, value);
+            }
+
+            #[test]
+            fn quoted_string_round_trip(value in any::<String>(), rest in any::<String>()) {
+                let quoted = format!("{}{}", quote_arg(&value), rest);
+                let (parsed, parsed_rest) = quoted_string(&quoted).unwrap();
+                prop_assert_eq!(parsed, value);
+                prop_assert_eq!(parsed_rest, rest.as_str());
+            }
+
+            #[test]
+            fn key_value_round_trip(key in key(), values in prop::collection::vec(plain_value(), 1..5)) {
+                let quoted = values.iter().map(|v| format!("\"{}\"", v)).collect::<Vec<_>>().join(" ");
+                let line = format!("{}={}", key, quoted);
+                let (parsed_key, parsed_values) = key_value(&line).unwrap();
+                prop_assert_eq!(parsed_key, key.as_str());
+                prop_assert_eq!(parsed_values, values);
+            }
+
+            #[test]
+            fn key_value_multiline_body(key in key(), lines in prop::collection::vec(plain_value(), 1..10)) {
+                // The value of a multi-line (`250+`) reply starts on the line after the key
+                let line = format!("{}=\n{}", key, lines.join("\n"));
+                let (parsed_key, parsed_values) = key_value(&line).unwrap();
+                prop_assert_eq!(parsed_key, key.as_str());
+                prop_assert_eq!(parsed_values, lines);
+            }
+
+            #[test]
+            fn key_value_args_round_trip(
+                args in prop::collection::vec((key(), prop_oneof![
+                    "[A-Za-z0-9,/_.:-]*".prop_map(|v| (v.clone(), v)),
+                    any::<String>().prop_map(|v| (quote_arg(&v), v)),
+                ]), 0..5)
+            ) {
+                let line = args.iter().map(|(k, (raw, _))| format!("{}={}", k, raw)).collect::<Vec<_>>().join(" ");
+                let parsed = key_value_args(&line).unwrap();
+                prop_assert_eq!(parsed.len(), args.len());
+                for ((key, value), (expected_key, (_, expected_value))) in parsed.iter().zip(&args) {
+                    prop_assert_eq!(*key, expected_key.as_str());
+                    prop_assert_eq!(value, expected_value);
+                }
+            }
+        }
+    }
 }
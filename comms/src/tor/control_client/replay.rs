@@ -0,0 +1,173 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Replays tor control port transcripts against the client.
+//!
+//! A transcript is a text file of the lines exchanged with tor, in order. Lines sent by the client are prefixed with
+//! `C: `, lines sent by tor are prefixed with `S: ` and lines starting with `#` are comments. The replay server checks
+//! that each line received from the client matches the next `C: ` line and then sends the `S: ` lines that follow it.
+//!
+//! The transcripts in `transcripts/` are written by hand from the reply formats in the control spec, which their
+//! `_handwritten` suffix records. They are not replies of any particular version of tor, so they do not cover quirks
+//! such as the `(git-...)` suffix of the version of a tor built from git. To record a transcript from tor instead, run
+//! tor with `ControlPort 9051` and proxy a second port to it with `socat -v TCP-LISTEN:9052,reuseaddr
+//! TCP:127.0.0.1:9051`. Connect a controller to port 9052 and run the commands of interest. socat prints the client
+//! lines in `>` blocks and the tor lines in `<` blocks. Prefix them with `C: ` and `S: ` respectively, replace secrets
+//! such as passwords, cookies and private keys with dummy values of the same format, and note the version of tor in the
+//! header.
+
+use super::TorControlPortClient;
+use crate::{memsocket::MemorySocket, runtime, runtime::task};
+use futures::{io::BufReader, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, StreamExt};
+use std::collections::VecDeque;
+use tokio::sync::broadcast;
+
+struct Exchange {
+    request: String,
+    replies: Vec<String>,
+}
+
+fn parse_transcript(transcript: &str) -> VecDeque<Exchange> {
+    let mut exchanges = VecDeque::new();
+    for line in transcript.lines() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(request) = line.strip_prefix("C: ") {
+            exchanges.push_back(Exchange {
+                request: request.to_string(),
+                replies: Vec::new(),
+            });
+        } else if let Some(reply) = line.strip_prefix("S: ") {
+            exchanges
+                .back_mut()
+                .expect("transcript must start with a client line")
+                .replies
+                .push(reply.to_string());
+        } else {
+            panic!("Invalid transcript line '{}'", line);
+        }
+    }
+    exchanges
+}
+
+/// A running replay of a transcript
+pub struct Replay {
+    handle: task::JoinHandle<Result<(), String>>,
+}
+
+impl Replay {
+    /// Closes the client and asserts that the client sent every request in the transcript, in order.
+    pub async fn finish(self, client: TorControlPortClient) {
+        drop(client);
+        if let Err(err) = self.handle.await.unwrap() {
+            panic!("Replay failed: {}", err);
+        }
+    }
+}
+
+/// Returns a client connected to a server that replays the given transcript
+pub fn replay(transcript: &str) -> (TorControlPortClient, Replay) {
+    let mut exchanges = parse_transcript(transcript);
+    let (client_socket, server_socket) = MemorySocket::new_pair();
+    let handle = runtime::current().spawn(async move {
+        let (reader, mut writer) = server_socket.split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next().await {
+            let line = line.map_err(|err| err.to_string())?;
+            let exchange = exchanges
+                .pop_front()
+                .ok_or_else(|| format!("Unexpected request '{}' after the end of the transcript", line))?;
+            if line != exchange.request {
+                return Err(format!("Expected request '{}' but got '{}'", exchange.request, line));
+            }
+            for reply in exchange.replies {
+                writer
+                    .write_all(format!("{}\r\n", reply).as_bytes())
+                    .await
+                    .map_err(|err| err.to_string())?;
+            }
+        }
+
+        match exchanges.pop_front() {
+            Some(exchange) => Err(format!("Request '{}' was never sent", exchange.request)),
+            None => Ok(()),
+        }
+    });
+
+    let (event_tx, _) = broadcast::channel(1);
+    (TorControlPortClient::new(client_socket, event_tx), Replay { handle })
+}
+
+mod test {
+    use super::*;
+    use crate::tor::{PrivateKey, TorVersion};
+    use tari_test_utils::unpack_enum;
+
+    #[runtime::test]
+    async fn add_onion() {
+        let (mut client, replay) = replay(include_str!("transcripts/add_onion_handwritten.txt"));
+
+        let response = client.add_onion_v3(vec![], 80, None).await.unwrap();
+        assert_eq!(
+            response.service_id,
+            "qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid"
+        );
+        unpack_enum!(Some(key) = response.private_key.as_ref());
+        unpack_enum!(PrivateKey::Ed25519V3(_key) = key);
+        client.del_onion(&response.service_id).await.unwrap();
+
+        replay.finish(client).await;
+    }
+
+    #[runtime::test]
+    async fn get_info_version() {
+        let (mut client, replay) = replay(include_str!("transcripts/getinfo_version_handwritten.txt"));
+
+        let version = client.get_info("version").await.unwrap();
+        assert_eq!(version, vec!["0.4.5.7"]);
+        let version = version[0].parse::<TorVersion>().unwrap();
+        assert_eq!(version, TorVersion::new(0, 4, 5, 7));
+
+        replay.finish(client).await;
+    }
+
+    #[runtime::test]
+    async fn protocol_info() {
+        let (mut client, replay) = replay(include_str!("transcripts/protocolinfo_handwritten.txt"));
+
+        let info = client.protocol_info().await.unwrap();
+        assert_eq!(info.auth_methods(), &["COOKIE", "SAFECOOKIE"]);
+        assert_eq!(info.cookie_file(), Some("/run/tor/control.authcookie"));
+        assert_eq!(info.tor_version(), TorVersion::new(0, 4, 5, 7));
+
+        replay.finish(client).await;
+    }
+
+    #[runtime::test]
+    #[should_panic]
+    async fn unexpected_request() {
+        let (mut client, replay) = replay(include_str!("transcripts/protocolinfo_handwritten.txt"));
+        let _ = client.get_info("version").await;
+        replay.finish(client).await;
+    }
+}
@@ -23,6 +23,17 @@
 const OK_CODE: u16 = 250;
 pub const EVENT_CODE: u16 = 650;
 
+/// The position of a line within a reply, given by the separator after the status code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseLineKind {
+    /// A `250-` line, which is followed by more lines of the same reply
+    MidReply,
+    /// A `250+` line, whose value includes the data body that followed it. More lines of the same reply follow.
+    Data,
+    /// A `250 ` line, which ends the reply
+    EndReply,
+}
+
 /// Represents a single response line from the server.
 #[derive(Debug)]
 pub struct ResponseLine {
@@ -33,6 +44,16 @@ pub struct ResponseLine {
 }
 
 impl ResponseLine {
+    /// The status code of this line. This is parsed once when the line is read.
+    pub fn code(&self) -> u16 {
+        self.code
+    }
+
+    /// The text following the status code and separator.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
     pub fn is_ok(&self) -> bool {
         self.code == OK_CODE
     }
@@ -41,6 +62,41 @@ impl ResponseLine {
         self.has_more
     }
 
+    /// Returns whether this line continues a reply, carries a data body or ends the reply
+    pub fn kind(&self) -> ResponseLineKind {
+        match (self.has_more, self.is_multiline) {
+            (_, true) => ResponseLineKind::Data,
+            (true, false) => ResponseLineKind::MidReply,
+            (false, false) => ResponseLineKind::EndReply,
+        }
+    }
+
+    /// Returns true if this is a `250-` line
+    pub fn is_mid_reply(&self) -> bool {
+        self.kind() == ResponseLineKind::MidReply
+    }
+
+    /// Returns true if this is a `250+` line
+    pub fn is_data(&self) -> bool {
+        self.is_multiline
+    }
+
+    /// Returns true if this is the last line of its reply
+    pub fn is_end_reply(&self) -> bool {
+        !self.has_more
+    }
+
+    /// Returns true if this line is part of an asynchronous (6xx) reply i.e. an event, rather than a reply to a
+    /// command
+    pub fn is_async(&self) -> bool {
+        self.code / 100 == 6
+    }
+
+    /// Returns true if this line is part of a reply to a command
+    pub fn is_sync(&self) -> bool {
+        !self.is_async()
+    }
+
     pub fn is_event(&self) -> bool {
         self.code == EVENT_CODE
     }
@@ -66,3 +122,48 @@ impl ResponseLine {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tor::control_client::parsers;
+
+    #[test]
+    fn code_matches_prefix() {
+        let lines = [
+            "250 OK",
+            "250-ServiceID=qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid",
+            "250+onions/detached=",
+            "552 Unrecognized key \"dummy\"",
+            "650 BW 1024 2048",
+        ];
+        for line in &lines {
+            let response = parsers::response_line(line).unwrap();
+            assert_eq!(response.code(), line[..3].parse::<u16>().unwrap());
+            assert_eq!(response.value(), &line[4..]);
+            assert_eq!(response.err().is_some(), !line.starts_with("250"));
+        }
+    }
+
+    #[test]
+    fn line_kinds() {
+        let cases = [
+            ("250-ServiceID=abcdef", ResponseLineKind::MidReply, false),
+            ("250+onions/detached=", ResponseLineKind::Data, false),
+            ("250 OK", ResponseLineKind::EndReply, false),
+            ("552 Unrecognized key \"dummy\"", ResponseLineKind::EndReply, false),
+            ("650-CIRC 1 LAUNCHED", ResponseLineKind::MidReply, true),
+            ("650+NS", ResponseLineKind::Data, true),
+            ("650 BW 1024 2048", ResponseLineKind::EndReply, true),
+        ];
+        for (line, kind, is_async) in &cases {
+            let response = parsers::response_line(line).unwrap();
+            assert_eq!(response.kind(), *kind, "{}", line);
+            assert_eq!(response.is_mid_reply(), *kind == ResponseLineKind::MidReply);
+            assert_eq!(response.is_data(), *kind == ResponseLineKind::Data);
+            assert_eq!(response.is_end_reply(), *kind == ResponseLineKind::EndReply);
+            assert_eq!(response.is_async(), *is_async, "{}", line);
+            assert_eq!(response.is_sync(), !*is_async);
+        }
+    }
+}
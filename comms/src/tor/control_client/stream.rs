@@ -0,0 +1,223 @@
+// Copyright 2021, The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Types that describe streams in `STREAM` events
+
+use super::{
+    commands::InfoListIter,
+    parsers::{self, ParseError},
+};
+use std::collections::HashMap;
+
+tor_keyword_enum!(
+    /// The `REASON` and `REMOTE_REASON` fields of a `STREAM` event for a stream that failed, was closed or was detached
+    /// from its circuit
+    StreamReason {
+        /// The reason is not one of the others
+        Misc => "MISC",
+        /// The exit relay could not resolve the target address
+        ResolveFailed => "RESOLVEFAILED",
+        /// The target refused the connection
+        ConnectRefused => "CONNECTREFUSED",
+        /// The exit policy of the exit relay does not allow connections to the target
+        ExitPolicy => "EXITPOLICY",
+        /// The circuit the stream was attached to was destroyed
+        Destroy => "DESTROY",
+        /// The stream was closed normally
+        Done => "DONE",
+        /// The connection to the target timed out
+        Timeout => "TIMEOUT",
+        /// There is no route to the target
+        NoRoute => "NOROUTE",
+        /// The exit relay is hibernating
+        Hibernating => "HIBERNATING",
+        /// An internal error occurred on the exit relay
+        Internal => "INTERNAL",
+        /// The exit relay ran out of resources
+        ResourceLimit => "RESOURCELIMIT",
+        /// The connection to the target was reset
+        ConnReset => "CONNRESET",
+        /// A relay violated the tor protocol
+        TorProtocol => "TORPROTOCOL",
+        /// A directory request was made to a relay that is not a directory
+        NotDirectory => "NOTDIRECTORY",
+        /// The stream was closed by the remote end, see `REMOTE_REASON`
+        End => "END",
+        /// The client refused to connect to a private address
+        PrivateAddr => "PRIVATE_ADDR",
+    }
+);
+
+/// The status of a stream in a `STREAM` event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamStatus {
+    New,
+    NewResolve,
+    Remap,
+    SentConnect,
+    SentResolve,
+    Succeeded,
+    Failed {
+        reason: Option<StreamReason>,
+        remote_reason: Option<StreamReason>,
+    },
+    Closed {
+        reason: Option<StreamReason>,
+        remote_reason: Option<StreamReason>,
+    },
+    Detached {
+        reason: Option<StreamReason>,
+        remote_reason: Option<StreamReason>,
+    },
+    Other(String),
+}
+
+impl StreamStatus {
+    /// Returns true if the stream has failed or been closed. A detached stream is not closed, as tor may attach it to
+    /// another circuit.
+    pub fn is_closed(&self) -> bool {
+        matches!(self, StreamStatus::Failed { .. } | StreamStatus::Closed { .. })
+    }
+}
+
+/// A `STREAM` event, emitted when the status of a stream changes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamEvent {
+    pub stream_id: String,
+    pub status: StreamStatus,
+    /// The circuit the stream is attached to, or `0` if it is not attached
+    pub circuit_id: String,
+    /// The `address:port` the stream is connecting to
+    pub target: String,
+    /// The `KEY=VALUE` fields that are not parsed into the fields above e.g. `SOURCE_ADDR` and `PURPOSE`, and those
+    /// added by later versions of tor
+    pub extra: HashMap<String, String>,
+}
+
+/// An entry of `GETINFO stream-status`, which has the same format as the arguments of a `STREAM` event
+pub type StreamStatusEntry = StreamEvent;
+
+impl StreamEvent {
+    /// Parses the arguments of a `STREAM` event i.e. the event line without the leading `STREAM`, or a line of
+    /// `GETINFO stream-status`
+    pub fn parse(args: &str) -> Result<Self, ParseError> {
+        let (positional, args) = parsers::event_args(args)?;
+        let (stream_id, status, circuit_id, target) = match positional.as_slice() {
+            [stream_id, status, circuit_id, target, ..] => (*stream_id, *status, *circuit_id, *target),
+            _ => {
+                return Err(ParseError(format!(
+                    "STREAM event has {} of 4 required arguments",
+                    positional.len()
+                )))
+            },
+        };
+
+        let mut reason = None;
+        let mut remote_reason = None;
+        let mut extra = HashMap::new();
+        for (key, value) in args {
+            match key {
+                "REASON" => reason = Some(value.parse()?),
+                "REMOTE_REASON" => remote_reason = Some(value.parse()?),
+                _ => {
+                    extra.insert(key.to_string(), value.into_owned());
+                },
+            }
+        }
+
+        let status = match status {
+            "NEW" => StreamStatus::New,
+            "NEWRESOLVE" => StreamStatus::NewResolve,
+            "REMAP" => StreamStatus::Remap,
+            "SENTCONNECT" => StreamStatus::SentConnect,
+            "SENTRESOLVE" => StreamStatus::SentResolve,
+            "SUCCEEDED" => StreamStatus::Succeeded,
+            "FAILED" => StreamStatus::Failed { reason, remote_reason },
+            "CLOSED" => StreamStatus::Closed { reason, remote_reason },
+            "DETACHED" => StreamStatus::Detached { reason, remote_reason },
+            s => StreamStatus::Other(s.to_string()),
+        };
+
+        Ok(Self {
+            stream_id: stream_id.to_string(),
+            status,
+            circuit_id: circuit_id.to_string(),
+            target: target.to_string(),
+            extra,
+        })
+    }
+}
+
+/// The entries of a `GETINFO stream-status` reply, which are parsed one line at a time in the same way as
+/// [CircuitStatusIter](super::CircuitStatusIter)
+pub type StreamStatusIter = InfoListIter<StreamStatusEntry>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stream_reason_parse() {
+        let cases = [
+            ("TIMEOUT", StreamReason::Timeout),
+            ("CONNRESET", StreamReason::ConnReset),
+            ("RESOLVEFAILED", StreamReason::ResolveFailed),
+            ("END", StreamReason::End),
+            ("PRIVATE_ADDR", StreamReason::PrivateAddr),
+            ("SOMETHING_NEW", StreamReason::Other("SOMETHING_NEW".to_string())),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(s.parse::<StreamReason>().unwrap(), *expected);
+            assert_eq!(expected.to_string(), *s);
+        }
+    }
+
+    #[test]
+    fn stream_event_parse() {
+        let event = StreamEvent::parse("21 FAILED 5 example.com:443 REASON=END REMOTE_REASON=RESOLVEFAILED").unwrap();
+        assert_eq!(event.stream_id, "21");
+        assert_eq!(event.circuit_id, "5");
+        assert_eq!(event.target, "example.com:443");
+        assert_eq!(event.status, StreamStatus::Failed {
+            reason: Some(StreamReason::End),
+            remote_reason: Some(StreamReason::ResolveFailed),
+        });
+
+        let event = StreamEvent::parse(
+            "22 CLOSED 5 10.0.0.1:80 REASON=DONE SOURCE_ADDR=127.0.0.1:51234 PURPOSE=USER SOME_NEW_FIELD=x",
+        )
+        .unwrap();
+        assert_eq!(event.status, StreamStatus::Closed {
+            reason: Some(StreamReason::Done),
+            remote_reason: None,
+        });
+        assert_eq!(event.extra.len(), 3);
+        assert_eq!(event.extra["SOURCE_ADDR"], "127.0.0.1:51234");
+        assert_eq!(event.extra["PURPOSE"], "USER");
+        assert_eq!(event.extra["SOME_NEW_FIELD"], "x");
+
+        let event = StreamEvent::parse("23 SUCCEEDED 5 10.0.0.1:80").unwrap();
+        assert_eq!(event.status, StreamStatus::Succeeded);
+
+        assert!(StreamEvent::parse("24 NEW 0").is_err());
+    }
+}
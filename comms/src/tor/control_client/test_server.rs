@@ -28,7 +28,8 @@ use crate::{
     test_utils::transport::build_connected_sockets,
 };
 use futures::{lock::Mutex, stream, SinkExt, StreamExt};
-use std::sync::Arc;
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+use tokio::time;
 use tokio_util::codec::{Framed, LinesCodec};
 
 pub async fn spawn() -> (Multiaddr, State, MemorySocket) {
@@ -45,6 +46,8 @@ pub async fn spawn() -> (Multiaddr, State, MemorySocket) {
 pub struct State {
     request_lines: Arc<Mutex<Vec<String>>>,
     canned_response: Arc<Mutex<Vec<String>>>,
+    queued_responses: Arc<Mutex<VecDeque<Vec<String>>>>,
+    response_delay: Arc<Mutex<Duration>>,
 }
 
 impl State {
@@ -52,6 +55,8 @@ impl State {
         Self {
             request_lines: Arc::new(Mutex::new(Vec::new())),
             canned_response: Arc::new(Mutex::new(all_to_owned(canned_responses::OK))),
+            queued_responses: Arc::new(Mutex::new(VecDeque::new())),
+            response_delay: Arc::new(Mutex::new(Duration::from_secs(0))),
         }
     }
 
@@ -59,6 +64,17 @@ impl State {
         *self.canned_response.lock().await = all_to_owned(lines);
     }
 
+    /// Queue a response for a single request. Queued responses are sent in order before falling back to the canned
+    /// response.
+    pub async fn push_response<'a, T: AsRef<[&'a str]>>(&self, lines: T) {
+        self.queued_responses.lock().await.push_back(all_to_owned(lines));
+    }
+
+    /// Delay every following response by `delay`, as a slow tor would
+    pub async fn set_response_delay(&self, delay: Duration) {
+        *self.response_delay.lock().await = delay;
+    }
+
     pub async fn take_requests(&self) -> Vec<String> {
         self.request_lines.lock().await.drain(..).collect()
     }
@@ -86,7 +102,15 @@ impl TorControlPortTestServer {
         let state = self.state;
         while let Some(msg) = framed.next().await {
             state.request_lines.lock().await.push(msg.unwrap());
-            let mut responses = stream::iter(state.canned_response.lock().await.clone()).map(Ok);
+            let response = match state.queued_responses.lock().await.pop_front() {
+                Some(response) => response,
+                None => state.canned_response.lock().await.clone(),
+            };
+            let delay = *state.response_delay.lock().await;
+            if delay > Duration::from_secs(0) {
+                time::delay_for(delay).await;
+            }
+            let mut responses = stream::iter(response).map(Ok);
             framed.send_all(&mut responses).await.unwrap();
         }
     }
@@ -117,6 +141,20 @@ pub mod canned_responses {
         "250 OK",
     ];
 
+    pub const GET_INFO_MICRODESCRIPTOR_OK: &[&str] = &[
+        "250+md/id/9695DFC35FFEB861329B9F1AB04C46397020CE31=",
+        "onion-key",
+        "-----BEGIN RSA PUBLIC KEY-----",
+        "MIGJAoGBAKdd0Ih5tyLcu0ELDq9UzYmXDTnHsqXqx1H6aHsOmU2F5RIh46Mb3d8n",
+        "-----END RSA PUBLIC KEY-----",
+        "ntor-onion-key 2RnXzssXqOBS0MRaqv0n+UQ9dC4GYlTlA8cLdmyEe1Q=",
+        "family $0011BD2485AD45D984EC4159C88FC066E5E3300E",
+        "p accept 80,443",
+        "id ed25519 8RH34kO07AT9WF9Wz5bAkRufgg1AW9Dq9rEv3S1TMv4",
+        ".",
+        "250 OK",
+    ];
+
     pub const ADD_ONION_OK: &[&str] = &[
         "250-ServiceID=qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid",
         "250-PrivateKey=ED25519-V3:\
@@ -129,5 +167,12 @@ pub mod canned_responses {
         "250 OK",
     ];
 
+    pub const PROTOCOLINFO_OK: &[&str] = &[
+        "250-PROTOCOLINFO 1",
+        "250-AUTH METHODS=COOKIE,SAFECOOKIE COOKIEFILE=\"/home/user/.tor/control_auth_cookie\"",
+        "250-VERSION Tor=\"0.4.5.6\"",
+        "250 OK",
+    ];
+
     pub const ERR_552: &[&str] = &["552 Unrecognised configuration key \"dummy\""];
 }
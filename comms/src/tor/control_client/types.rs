@@ -20,26 +20,81 @@
 // WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
 // USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+use super::{
+    error::{OnionAddressError, TorClientError},
+    parsers::{self, ParseError},
+};
+use curve25519_dalek::{constants::ED25519_BASEPOINT_TABLE, scalar::Scalar};
+use data_encoding::{BASE32_NOPAD, BASE64};
 use serde_derive::{Deserialize, Serialize};
-use std::{fmt, net::SocketAddr};
+use sha3::{Digest, Sha3_256};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::{self, FromStr},
+};
+
+/// Placeholder rendered in place of secret material (private keys, passwords, cookies) when displaying commands.
+pub(crate) const REDACTED: &str = "[redacted]";
+
+/// Replaces `secret` in `s` with [REDACTED] where it appears as a quoted string, a space separated argument or the
+/// value of a `KEY=VALUE` argument, for text such as a server reply that may echo part of a command that contained the
+/// secret. Other occurrences are left as is, so that a short secret does not mangle the rest of the text.
+pub(crate) fn redact_secret(s: String, secret: &str) -> String {
+    if secret.is_empty() {
+        return s;
+    }
+    let quoted = parsers::quote_arg(secret);
+    let s = s.replace(&quoted, &format!("\"{}\"", REDACTED));
+    if !s.split(' ').any(|arg| is_secret_arg(arg, secret)) {
+        return s;
+    }
+    s.split(' ')
+        .map(|arg| match arg.find('=') {
+            _ if arg == secret => REDACTED.into(),
+            Some(i) if is_secret_arg(arg, secret) => format!("{}{}", &arg[..=i], REDACTED),
+            _ => arg.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn is_secret_arg(arg: &str, secret: &str) -> bool {
+    arg == secret || arg.find('=').map_or(false, |i| &arg[i + 1..] == secret)
+}
 
 #[derive(Clone, Copy, Debug)]
 pub enum KeyType {
-    /// The server should generate a key of algorithm KeyBlob
+    /// The server should generate a key of algorithm KeyBlob. Use `KeyBlob::Ed25519V3` (or `KeyBlob::Best`, which tor
+    /// resolves to ED25519-V3) to create a v3 onion service.
     New,
     /// The server should use the 1024-bit RSA key provided in as KeyBlob (v2).
+    #[deprecated(note = "v2 onion services are not supported from tor 0.4.6, use KeyType::Ed25519V3 instead")]
     Rsa1024,
     /// The server should use the ED25519-V3 key provided in as KeyBlob (v3).
     Ed25519V3,
 }
 
-impl KeyType {
-    pub fn as_tor_repr(self) -> &'static str {
-        match self {
-            KeyType::New => "NEW",
-            KeyType::Rsa1024 => "RSA1024",
-            KeyType::Ed25519V3 => "ED25519-V3",
-        }
+/// The key type of an ADD_ONION command: either a [KeyType] or a key type that is not known to this client.
+#[derive(Clone, Copy, Debug)]
+pub enum AnyKeyType<'a> {
+    Known(KeyType),
+    /// A key type that is not known to this client, such as one added in a later version of tor. The key type is sent
+    /// verbatim, followed by the key provided in KeyBlob.
+    Other(&'a str),
+}
+
+impl AnyKeyType<'_> {
+    /// Returns true for the `NEW` key type
+    pub fn is_new(self) -> bool {
+        matches!(self, AnyKeyType::Known(KeyType::New))
+    }
+}
+
+impl From<KeyType> for AnyKeyType<'_> {
+    fn from(key_type: KeyType) -> Self {
+        AnyKeyType::Known(key_type)
     }
 }
 
@@ -47,32 +102,22 @@ pub enum KeyBlob<'a> {
     /// The server should generate a key using the "best" supported algorithm (KeyType == "NEW").
     Best,
     /// The server should generate a 1024 bit RSA key (KeyType == "NEW") (v2).
+    #[deprecated(note = "v2 onion services are not supported from tor 0.4.6, use KeyBlob::Ed25519V3 instead")]
     Rsa1024,
     /// The server should generate an ed25519 private key (KeyType == "NEW") (v3).
     Ed25519V3,
-    /// A serialized private key (without whitespace)
+    /// A serialized private key (without whitespace). The key is borrowed, so it is never copied when the command is
+    /// serialized.
     String(&'a str),
 }
 
 impl KeyBlob<'_> {
-    pub fn as_tor_repr(&self) -> &str {
+    /// Returns the tor representation of this key blob, with any serialized private key masked. This should be used
+    /// whenever a command is rendered for display/logging.
+    pub fn as_redacted_tor_repr(&self) -> &str {
         match self {
-            KeyBlob::Best => "BEST",
-            KeyBlob::Rsa1024 => "RSA1024",
-            KeyBlob::Ed25519V3 => "ED25519-V3",
-            KeyBlob::String(priv_key) => priv_key,
-        }
-    }
-}
-
-impl fmt::Display for KeyBlob<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use KeyBlob::*;
-        match self {
-            Best => write!(f, "Best"),
-            Rsa1024 => write!(f, "Rsa1024"),
-            Ed25519V3 => write!(f, "Ed25519V3"),
-            String(_) => write!(f, "[redacted]"),
+            KeyBlob::String(_) => REDACTED,
+            _ => self.as_tor_repr(),
         }
     }
 }
@@ -83,6 +128,121 @@ pub enum PrivateKey {
     Rsa1024(String),
     /// The server should use the ed25519 v3 key provided in as KeyBlob (v3).
     Ed25519V3(String),
+    /// A key of a type that is not known to this client (see [AnyKeyType::Other]).
+    Other { key_type: String, key: String },
+}
+
+/// The deprecated v2 (RSA1024) variants are only named in this module, so that the rest of the crate uses them
+/// through these methods and does not need to allow their use.
+#[allow(deprecated)]
+mod v2 {
+    use super::*;
+
+    impl KeyType {
+        pub fn as_tor_repr(self) -> &'static str {
+            match self {
+                KeyType::New => "NEW",
+                KeyType::Rsa1024 => "RSA1024",
+                KeyType::Ed25519V3 => "ED25519-V3",
+            }
+        }
+    }
+
+    impl<'a> AnyKeyType<'a> {
+        pub fn as_tor_repr(self) -> &'a str {
+            match self {
+                AnyKeyType::Known(key_type) => key_type.as_tor_repr(),
+                AnyKeyType::Other(key_type) => key_type,
+            }
+        }
+
+        /// Returns true for the v2 (RSA1024) key type
+        pub(crate) fn is_v2(self) -> bool {
+            matches!(self, AnyKeyType::Known(KeyType::Rsa1024))
+        }
+    }
+
+    impl KeyBlob<'_> {
+        /// The key blob that has tor generate a new v2 (RSA1024) key
+        pub(crate) const NEW_V2: KeyBlob<'static> = KeyBlob::Rsa1024;
+
+        pub fn as_tor_repr(&self) -> &str {
+            match self {
+                KeyBlob::Best => "BEST",
+                KeyBlob::Rsa1024 => "RSA1024",
+                KeyBlob::Ed25519V3 => "ED25519-V3",
+                KeyBlob::String(priv_key) => priv_key,
+            }
+        }
+
+        /// Returns true if tor should generate a new v2 (RSA1024) key
+        pub(crate) fn is_v2(&self) -> bool {
+            matches!(self, KeyBlob::Rsa1024)
+        }
+    }
+
+    impl fmt::Display for KeyBlob<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            use KeyBlob::*;
+            match self {
+                Best => write!(f, "Best"),
+                Rsa1024 => write!(f, "Rsa1024"),
+                Ed25519V3 => write!(f, "Ed25519V3"),
+                String(_) => write!(f, "{}", REDACTED),
+            }
+        }
+    }
+
+    impl PrivateKey {
+        /// Returns the key type and key blob that add an onion service using this key i.e. `RSA1024:<blob>` or
+        /// `ED25519-V3:<blob>`. This allows keys that were returned by tor and persisted to be used again.
+        pub fn as_key_type_and_blob(&self) -> (AnyKeyType<'_>, KeyBlob<'_>) {
+            match self {
+                PrivateKey::Rsa1024(key) => (KeyType::Rsa1024.into(), KeyBlob::String(key)),
+                PrivateKey::Ed25519V3(key) => (KeyType::Ed25519V3.into(), KeyBlob::String(key)),
+                PrivateKey::Other { key_type, key } => (AnyKeyType::Other(key_type), KeyBlob::String(key)),
+            }
+        }
+    }
+}
+
+impl PrivateKey {
+    /// Returns the `ED25519-V3` key for the given expanded ed25519 secret key i.e. the clamped scalar followed by the
+    /// nonce prefix, which is the form that tor uses for both the key blob and its `hs_ed25519_secret_key` file.
+    pub fn from_ed25519_secret(secret: &[u8; 64]) -> Self {
+        PrivateKey::Ed25519V3(BASE64.encode(secret))
+    }
+
+    /// Returns the address of the onion service that tor will create with this key, without having to add it first.
+    /// Only `ED25519-V3` keys are supported, any other key type returns [OnionAddressError::UnsupportedKeyType].
+    pub fn onion_address(&self) -> Result<OnionAddress, OnionAddressError> {
+        use clear_on_drop::clear::Clear;
+        let key = match self {
+            PrivateKey::Ed25519V3(key) => key,
+            _ => {
+                let (key_type, _) = self.as_key_type_and_blob();
+                return Err(OnionAddressError::UnsupportedKeyType(
+                    key_type.as_tor_repr().to_string(),
+                ));
+            },
+        };
+        let mut secret = BASE64
+            .decode(key.as_bytes())
+            .map_err(|_| OnionAddressError::InvalidPrivateKey)?;
+        if secret.len() != 64 {
+            Clear::clear(&mut secret);
+            return Err(OnionAddressError::InvalidPrivateKey);
+        }
+
+        let mut scalar_bytes = [0u8; 32];
+        scalar_bytes.copy_from_slice(&secret[..32]);
+        Clear::clear(&mut secret);
+        // The scalar is already clamped, so it is used as is rather than reduced
+        let scalar = Scalar::from_bits(scalar_bytes);
+        Clear::clear(&mut scalar_bytes);
+        let pubkey = (&scalar * &ED25519_BASEPOINT_TABLE).compress().to_bytes();
+        Ok(OnionAddress::from_public_key(&pubkey))
+    }
 }
 
 impl Drop for PrivateKey {
@@ -95,6 +255,401 @@ impl Drop for PrivateKey {
             PrivateKey::Ed25519V3(ref mut key) => {
                 Clear::clear(key);
             },
+            PrivateKey::Other { ref mut key, .. } => {
+                Clear::clear(key);
+            },
+        }
+    }
+}
+
+/// The version byte of v3 onion addresses
+const ONION_V3_VERSION: u8 = 0x03;
+const ONION_V3_CHECKSUM_PREFIX: &[u8] = b".onion checksum";
+
+/// Returns the checksum of the v3 onion address for the given ed25519 public key i.e. the first 2 bytes of
+/// `SHA3_256(".onion checksum" | pubkey | version)` (rend-spec-v3 section 6).
+pub fn onion_v3_checksum(pubkey: &[u8; 32]) -> [u8; 2] {
+    let hash = Sha3_256::new()
+        .chain(ONION_V3_CHECKSUM_PREFIX)
+        .chain(pubkey)
+        .chain(&[ONION_V3_VERSION])
+        .finalize();
+    [hash[0], hash[1]]
+}
+
+/// Returns the v3 onion address (including the `.onion` suffix) of the onion service with the given ed25519 public
+/// key. The ServiceID returned by tor is this address without the suffix.
+pub fn encode_onion_v3_address(pubkey: &[u8; 32]) -> String {
+    let mut bytes = [0u8; 35];
+    bytes[..32].copy_from_slice(pubkey);
+    bytes[32..34].copy_from_slice(&onion_v3_checksum(pubkey));
+    bytes[34] = ONION_V3_VERSION;
+    let mut address = BASE32_NOPAD.encode(&bytes).to_ascii_lowercase();
+    address.push_str(ONION_SUFFIX);
+    address
+}
+
+const ONION_SUFFIX: &str = ".onion";
+const ONION_V2_LEN: usize = 16;
+const ONION_V3_LEN: usize = 56;
+
+/// A v2 or v3 onion address, stored as the ServiceID i.e. without the `.onion` suffix.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OnionAddress(String);
+
+impl OnionAddress {
+    /// Returns the v3 onion address of the onion service with the given ed25519 public key
+    pub fn from_public_key(pubkey: &[u8; 32]) -> Self {
+        let mut address = encode_onion_v3_address(pubkey);
+        address.truncate(ONION_V3_LEN);
+        Self(address)
+    }
+
+    /// The address without the `.onion` suffix, as used by ADD_ONION and DEL_ONION
+    pub fn service_id(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_v2(&self) -> bool {
+        self.0.len() == ONION_V2_LEN
+    }
+
+    /// Decodes the ed25519 public key of a v3 onion address, checking its version and checksum. v2 addresses are a
+    /// truncated hash of the service's RSA key, so an error is returned for them.
+    pub fn public_key(&self) -> Result<[u8; 32], OnionAddressError> {
+        if self.is_v2() {
+            return Err(OnionAddressError::V2NoPublicKey);
+        }
+
+        let bytes = BASE32_NOPAD
+            .decode(self.0.to_ascii_uppercase().as_bytes())
+            .map_err(|_| OnionAddressError::InvalidEncoding)?;
+        // The length was checked when parsing, so this is always 35 bytes
+        let (pubkey_bytes, rest) = bytes.split_at(32);
+        let mut pubkey = [0u8; 32];
+        pubkey.copy_from_slice(pubkey_bytes);
+
+        if rest[2] != ONION_V3_VERSION {
+            return Err(OnionAddressError::UnsupportedVersion(rest[2]));
+        }
+        if rest[..2] != onion_v3_checksum(&pubkey) {
+            return Err(OnionAddressError::InvalidChecksum);
+        }
+        Ok(pubkey)
+    }
+}
+
+/// Parses a ServiceID or onion address, with or without the `.onion` suffix
+impl FromStr for OnionAddress {
+    type Err = OnionAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let service_id = s.strip_suffix(ONION_SUFFIX).unwrap_or(s).to_ascii_lowercase();
+        if service_id.len() != ONION_V2_LEN && service_id.len() != ONION_V3_LEN {
+            return Err(OnionAddressError::InvalidLength(service_id.len()));
+        }
+        if !service_id.bytes().all(|b| matches!(b, b'a'..=b'z' | b'2'..=b'7')) {
+            return Err(OnionAddressError::InvalidEncoding);
+        }
+        Ok(Self(service_id))
+    }
+}
+
+impl fmt::Display for OnionAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.0, ONION_SUFFIX)
+    }
+}
+
+/// The onion services that tor has, from `GETINFO onions/current onions/detached`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OnionInventory {
+    /// The services owned by this control connection, which are removed when it closes
+    pub current: Vec<OnionAddress>,
+    /// The detached services, which are not owned by any control connection
+    pub detached: Vec<OnionAddress>,
+}
+
+impl OnionInventory {
+    pub fn is_current(&self, address: &OnionAddress) -> bool {
+        self.current.contains(address)
+    }
+
+    pub fn is_detached(&self, address: &OnionAddress) -> bool {
+        self.detached.contains(address)
+    }
+
+    /// Returns true if tor has the service, in which case adding it again fails
+    pub fn contains(&self, address: &OnionAddress) -> bool {
+        self.is_current(address) || self.is_detached(address)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.current.is_empty() && self.detached.is_empty()
+    }
+
+    /// Adds the services in the value of the `onions/current` or `onions/detached` key, which is a service ID per
+    /// line. Depending on the version of tor the IDs may have the `.onion` suffix. Other keys are ignored.
+    pub(crate) fn add_services(&mut self, key: &str, value: &str) -> Result<(), OnionAddressError> {
+        let list = match key {
+            "onions/current" => &mut self.current,
+            "onions/detached" => &mut self.detached,
+            _ => return Ok(()),
+        };
+        for line in value.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let address = line.parse()?;
+            if !list.contains(&address) {
+                list.push(address);
+            }
+        }
+        Ok(())
+    }
+}
+
+const CLIENT_NAME_MAX_LEN: usize = 16;
+
+/// The name of an onion service client, used by ADD_ONION client authorization and ONION_CLIENT_AUTH_ADD. Tor only
+/// accepts names of up to 16 characters from `[A-Za-z0-9+-_]`, so names are checked when they are created rather than
+/// being rejected by tor. Surrounding whitespace is removed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClientName(String);
+
+impl ClientName {
+    pub fn new(name: &str) -> Result<Self, TorClientError> {
+        let name = name.trim();
+        let invalid = |reason| TorClientError::InvalidClientName {
+            name: name.to_string(),
+            reason,
+        };
+        if name.is_empty() {
+            return Err(invalid("the name is empty"));
+        }
+        if name.len() > CLIENT_NAME_MAX_LEN {
+            return Err(invalid("the name is longer than 16 characters"));
+        }
+        if !name
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '+' | '-' | '_'))
+        {
+            return Err(invalid(
+                "only the characters A-Z, a-z, 0-9, '+', '-' and '_' are allowed",
+            ));
+        }
+        Ok(Self(name.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for ClientName {
+    type Err = TorClientError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+impl fmt::Display for ClientName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Tor's best guess at its external IP address, from `GETINFO address`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExternalAddress {
+    Known(IpAddr),
+    /// Tor has not (yet) determined its address. This is normal for a client that is not a relay.
+    Unknown,
+}
+
+tor_keyword_enum!(
+    /// Whether the running version of tor is recommended by the directory authorities, from
+    /// `GETINFO status/version/current`
+    VersionStatus {
+        /// The version is recommended
+        Recommended => "recommended",
+        /// The version is newer than every recommended version
+        New => "new",
+        /// The version is newer than every recommended version in its series
+        NewInSeries => "new in series",
+        /// The version is older than every recommended version
+        Old => "old",
+        /// The version is no longer recommended, but newer versions in its series are
+        Unrecommended => "unrecommended",
+        /// The version is older than every recommended version and its series is no longer supported
+        Obsolete => "obsolete",
+        /// Tor has not determined the status yet, or does not support the query
+        Unknown => "unknown",
+    }
+);
+
+impl VersionStatus {
+    /// Returns true if operators should be told to upgrade tor
+    pub fn is_outdated(&self) -> bool {
+        matches!(
+            self,
+            VersionStatus::Old | VersionStatus::Unrecommended | VersionStatus::Obsolete
+        )
+    }
+}
+
+/// The number of bytes an onion service has read and written, from `GETINFO hs/service/traffic/read/id/<ServiceID>
+/// hs/service/traffic/written/id/<ServiceID>`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficCounters {
+    pub read: u64,
+    pub written: u64,
+}
+
+/// An address mapping, from `GETINFO address-mappings/all`. Mappings are created by MAPADDRESS, the torrc `MapAddress`
+/// option and DNS resolves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressMapping {
+    pub address: String,
+    pub new_address: String,
+    /// When the mapping expires, as given by tor (`YYYY-MM-DD HH:MM:SS` in local time), or None if it never does
+    pub expiry: Option<String>,
+}
+
+impl AddressMapping {
+    /// Parses the lines of an `address-mappings/*` reply. Empty lines are skipped.
+    pub fn parse_all(body: &str) -> Result<Vec<Self>, ParseError> {
+        body.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+impl FromStr for AddressMapping {
+    type Err = ParseError;
+
+    /// Parses a single `<address> <new address> <expiry>` line, where the expiry is a quoted time or `NEVER`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError(format!("Invalid address mapping '{}'", s));
+        let mut parts = s.trim().splitn(3, ' ');
+        let address = parts.next().filter(|part| !part.is_empty()).ok_or_else(invalid)?;
+        let new_address = parts.next().filter(|part| !part.is_empty()).ok_or_else(invalid)?;
+        let expiry = match parts.next().ok_or_else(invalid)? {
+            "NEVER" => None,
+            expiry => {
+                let (expiry, rest) = parsers::quoted_string(expiry).map_err(|_| invalid())?;
+                if !rest.is_empty() {
+                    return Err(invalid());
+                }
+                Some(expiry.into_owned())
+            },
+        };
+        Ok(Self {
+            address: address.to_string(),
+            new_address: new_address.to_string(),
+            expiry,
+        })
+    }
+}
+
+/// Returns true if `host` is a syntactically valid DNS name i.e. dot-separated labels of up to 63 alphanumeric or `-`
+/// characters that do not start or end with `-`. Whitespace or any other characters would corrupt the command line.
+fn is_plausible_hostname(host: &str) -> bool {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    !host.is_empty() &&
+        host.len() <= 253 &&
+        host.split('.').all(|label| {
+            !label.is_empty() &&
+                label.len() <= 63 &&
+                !label.starts_with('-') &&
+                !label.ends_with('-') &&
+                label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// The maximum length of a DNS name, including a trailing `.`
+const MAX_HOSTNAME_LEN: usize = 254;
+
+/// A DNS name that an onion port can be forwarded to. The name is stored inline so that [OnionTarget] and
+/// [PortMapping] are `Copy`.
+#[derive(Clone, Copy)]
+pub struct Hostname {
+    len: u8,
+    buf: [u8; MAX_HOSTNAME_LEN],
+}
+
+impl Hostname {
+    /// Returns the hostname, or [TorClientError::AddOnionInvalidTarget] if `host` is not a syntactically valid DNS
+    /// name.
+    pub fn new(host: &str) -> Result<Self, TorClientError> {
+        if !is_plausible_hostname(host) {
+            return Err(TorClientError::AddOnionInvalidTarget(host.to_string()));
+        }
+        let mut buf = [0u8; MAX_HOSTNAME_LEN];
+        buf[..host.len()].copy_from_slice(host.as_bytes());
+        Ok(Self {
+            len: host.len() as u8,
+            buf,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        str::from_utf8(&self.buf[..usize::from(self.len)]).expect("hostname is checked to be ASCII")
+    }
+}
+
+impl PartialEq for Hostname {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for Hostname {}
+
+impl fmt::Debug for Hostname {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Hostname").field(&self.as_str()).finish()
+    }
+}
+
+impl fmt::Display for Hostname {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The target that an onion port is forwarded to.
+// Boxing the hostname would make the target smaller, but it would no longer be Copy
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnionTarget {
+    /// Forward to the given socket address. IPv6 addresses are rendered in brackets e.g. `[::1]:8080`.
+    Socket(SocketAddr),
+    /// Forward to the given hostname and port e.g. `backend.internal:8080`. The host is passed to tor verbatim: it is
+    /// not resolved by this client, so it must be reachable from the tor process.
+    HostPort(Hostname, u16),
+}
+
+impl OnionTarget {
+    /// Returns the socket address of this target, or None if the target is a hostname.
+    pub fn socket_addr(&self) -> Option<&SocketAddr> {
+        match self {
+            OnionTarget::Socket(addr) => Some(addr),
+            OnionTarget::HostPort(_, _) => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for OnionTarget {
+    fn from(addr: SocketAddr) -> Self {
+        OnionTarget::Socket(addr)
+    }
+}
+
+impl fmt::Display for OnionTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OnionTarget::Socket(addr) => write!(f, "{}", addr),
+            OnionTarget::HostPort(host, port) => write!(f, "{}:{}", host, port),
         }
     }
 }
@@ -102,29 +657,48 @@ impl Drop for PrivateKey {
 /// Represents a mapping between an onion port and a proxied address (usually 127.0.0.1:xxxx).
 /// If the proxied_address is not specified, the default `127.0.0.1:[onion_port]` will be used.
 #[derive(Debug, Clone, Copy)]
-pub struct PortMapping(u16, SocketAddr);
+pub struct PortMapping(u16, OnionTarget);
 
 impl PortMapping {
     /// Returns a new `PortMapping` with the proxied address set to 127.0.0.1:{onion_port}
     pub fn from_port(onion_port: u16) -> Self {
-        Self(onion_port, ([127, 0, 0, 1], onion_port).into())
+        Self::new(onion_port, ([127, 0, 0, 1], onion_port).into())
     }
 
     /// Returns a new `PortMapping` with the specified proxied address
     pub fn new(onion_port: u16, proxied_address: SocketAddr) -> Self {
-        Self(onion_port, proxied_address)
+        Self(onion_port, OnionTarget::Socket(proxied_address))
+    }
+
+    /// Returns a new `PortMapping` that forwards the onion port to `host:port` (see [OnionTarget::HostPort]), or
+    /// [TorClientError::AddOnionInvalidTarget] if `host` is not a syntactically valid DNS name.
+    pub fn new_host(onion_port: u16, host: &str, port: u16) -> Result<Self, TorClientError> {
+        Ok(Self(onion_port, OnionTarget::HostPort(Hostname::new(host)?, port)))
     }
 
     pub fn onion_port(&self) -> u16 {
         self.0
     }
 
+    /// Returns the socket address that the onion port is forwarded to.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the onion port is forwarded to a hostname, which is only the case for a mapping created with
+    /// [new_host](Self::new_host). Use [target](Self::target) for a mapping that may have a hostname target.
     pub fn proxied_address(&self) -> &SocketAddr {
+        self.1
+            .socket_addr()
+            .expect("PortMapping::proxied_address called on a mapping with a hostname target")
+    }
+
+    /// Returns the address or hostname that the onion port is forwarded to
+    pub fn target(&self) -> &OnionTarget {
         &self.1
     }
 
     pub fn set_proxied_addr(&mut self, proxied_addr: SocketAddr) {
-        self.1 = proxied_addr
+        self.1 = OnionTarget::Socket(proxied_addr)
     }
 }
 
@@ -134,9 +708,16 @@ impl From<u16> for PortMapping {
     }
 }
 
+/// Maps the port of the given address to the same onion port i.e. `[proxied_address.port()] -> [proxied_address]`
+impl From<SocketAddr> for PortMapping {
+    fn from(proxied_address: SocketAddr) -> Self {
+        Self::new(proxied_address.port(), proxied_address)
+    }
+}
+
 impl<T: Into<u16>, U: Into<SocketAddr>> From<(T, U)> for PortMapping {
     fn from((port, addr): (T, U)) -> Self {
-        Self(port.into(), addr.into())
+        Self::new(port.into(), addr.into())
     }
 }
 
@@ -145,3 +726,423 @@ impl fmt::Display for PortMapping {
         write!(f, "PortMapping [{} -> {}]", self.0, self.1)
     }
 }
+
+/// An address that tor is listening on, as reported by `GETINFO net/listeners/*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenerAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenerAddress {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(ListenerAddress::Unix(path.into())),
+            None => s
+                .parse()
+                .map(ListenerAddress::Tcp)
+                .map_err(|_| ParseError(format!("Invalid listener address '{}'", s))),
+        }
+    }
+}
+
+impl fmt::Display for ListenerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenerAddress::Tcp(addr) => write!(f, "{}", addr),
+            ListenerAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// The value of a port option such as `SocksPort` or `ControlPort`, as set by SETCONF.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortSpec {
+    /// Let tor choose an unused port. The chosen port is reported by `GETINFO net/listeners/*`.
+    Auto,
+    Tcp(SocketAddr),
+    /// Listen on a unix socket at the given path
+    Unix(PathBuf),
+}
+
+impl From<SocketAddr> for PortSpec {
+    fn from(addr: SocketAddr) -> Self {
+        PortSpec::Tcp(addr)
+    }
+}
+
+impl From<ListenerAddress> for PortSpec {
+    fn from(addr: ListenerAddress) -> Self {
+        match addr {
+            ListenerAddress::Tcp(addr) => PortSpec::Tcp(addr),
+            ListenerAddress::Unix(path) => PortSpec::Unix(path),
+        }
+    }
+}
+
+/// Renders the option value as it appears in the torrc. A unix path that contains whitespace, quotes or backslashes
+/// is quoted (`unix:"/path with spaces"`) so that tor reads it as a single path.
+impl fmt::Display for PortSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortSpec::Auto => f.write_str("auto"),
+            PortSpec::Tcp(addr) => write!(f, "{}", addr),
+            PortSpec::Unix(path) => {
+                let path = path.to_string_lossy();
+                if path.contains(|ch: char| ch.is_whitespace() || ch == '"' || ch == '\\') {
+                    write!(f, "unix:{}", parsers::quote_arg(&path))
+                } else {
+                    write!(f, "unix:{}", path)
+                }
+            },
+        }
+    }
+}
+
+/// The version of a tor daemon e.g. `0.4.5.6`. Any status tag (e.g. `-alpha`) is ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TorVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub micro: u16,
+    pub patch: u16,
+}
+
+impl TorVersion {
+    pub const fn new(major: u16, minor: u16, micro: u16, patch: u16) -> Self {
+        Self {
+            major,
+            minor,
+            micro,
+            patch,
+        }
+    }
+}
+
+impl FromStr for TorVersion {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError(format!("Invalid tor version '{}'", s));
+        let version = s.split(|ch| ch == '-' || ch == ' ').next().ok_or_else(invalid)?;
+        let mut parts = version
+            .split('.')
+            .map(|part| part.parse::<u16>().map_err(|_| invalid()));
+        let mut next_part = || parts.next().ok_or_else(invalid).and_then(|r| r);
+        let major = next_part()?;
+        let minor = next_part()?;
+        let micro = next_part()?;
+        // The patch level is optional
+        let patch = next_part().unwrap_or(0);
+        Ok(Self::new(major, minor, micro, patch))
+    }
+}
+
+impl fmt::Display for TorVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.major, self.minor, self.micro, self.patch)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use data_encoding::HEXLOWER;
+
+    fn from_hex(hex: &str) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&HEXLOWER.decode(hex.as_bytes()).unwrap());
+        bytes
+    }
+
+    fn from_hex64(hex: &str) -> [u8; 64] {
+        let mut bytes = [0u8; 64];
+        bytes.copy_from_slice(&HEXLOWER.decode(hex.as_bytes()).unwrap());
+        bytes
+    }
+
+    #[test]
+    fn redact_secret() {
+        let redact = |s: &str, secret| super::redact_secret(s.to_string(), secret);
+        assert_eq!(
+            redact(r#"Authentication failed: "a" is not the password"#, "a"),
+            r#"Authentication failed: "[redacted]" is not the password"#
+        );
+        assert_eq!(
+            redact("Bad cookie 0AB3 given, PASSWORD=0AB3", "0AB3"),
+            "Bad cookie [redacted] given, PASSWORD=[redacted]"
+        );
+        assert_eq!(
+            redact(r#"Bad password "p\"w" was given"#, r#"p"w"#),
+            r#"Bad password "[redacted]" was given"#
+        );
+        // Occurrences inside other words are not the secret
+        assert_eq!(
+            redact("Authentication failed: password incorrect", "a"),
+            "Authentication failed: password incorrect"
+        );
+        assert_eq!(redact("nothing to hide", ""), "nothing to hide");
+    }
+
+    #[test]
+    fn listener_address_parse_mixed_list() {
+        let (_, values) =
+            parsers::key_value(r#"net/listeners/socks="127.0.0.1:9050" "unix:/run/tor/socks" "[::1]:9150""#).unwrap();
+        let addrs = values
+            .iter()
+            .map(|v| v.parse::<ListenerAddress>())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(addrs, vec![
+            ListenerAddress::Tcp(([127, 0, 0, 1], 9050).into()),
+            ListenerAddress::Unix("/run/tor/socks".into()),
+            ListenerAddress::Tcp("[::1]:9150".parse().unwrap()),
+        ]);
+        assert_eq!(addrs[1].to_string(), "unix:/run/tor/socks");
+
+        assert!("not-an-address".parse::<ListenerAddress>().is_err());
+    }
+
+    #[test]
+    fn key_blob_borrowed_repr() {
+        let key = String::from("this-is-a-key");
+        let blob = KeyBlob::String(&key);
+        // The representation is the caller's buffer rather than a copy of it
+        assert_eq!(blob.as_tor_repr().as_ptr(), key.as_ptr());
+        assert_eq!(blob.as_tor_repr(), key);
+        assert_eq!(blob.as_redacted_tor_repr(), REDACTED);
+    }
+
+    #[test]
+    fn onion_v3_address() {
+        // The example address from rend-spec-v3
+        let pubkey = from_hex("79bcc625184b05194975c28b66b66b0469f7f6556fb1ac3189a79b40dda32f1f");
+        assert_eq!(onion_v3_checksum(&pubkey), [0x21, 0x47]);
+        assert_eq!(
+            encode_onion_v3_address(&pubkey),
+            "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion"
+        );
+
+        let pubkey = from_hex("820c13065ca13663868f93a663ff62631498a1ba03e2bc5e516c6af60999c6cd");
+        assert_eq!(onion_v3_checksum(&pubkey), [0xcb, 0x05]);
+        assert_eq!(
+            encode_onion_v3_address(&pubkey),
+            "qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid.onion"
+        );
+
+        assert_eq!(
+            encode_onion_v3_address(&[0; 32]),
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaam2dqd.onion"
+        );
+    }
+
+    #[test]
+    fn onion_address_public_key() {
+        let vectors = [
+            (
+                "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion",
+                "79bcc625184b05194975c28b66b66b0469f7f6556fb1ac3189a79b40dda32f1f",
+            ),
+            (
+                "qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4wbid",
+                "820c13065ca13663868f93a663ff62631498a1ba03e2bc5e516c6af60999c6cd",
+            ),
+        ];
+        for (address, pubkey) in vectors.iter() {
+            let address = address.parse::<OnionAddress>().unwrap();
+            let pubkey = from_hex(pubkey);
+            assert_eq!(address.public_key().unwrap(), pubkey);
+            assert_eq!(OnionAddress::from_public_key(&pubkey), address);
+            assert_eq!(address.to_string(), encode_onion_v3_address(&pubkey));
+        }
+    }
+
+    #[test]
+    fn private_key_from_ed25519_secret() {
+        // The expanded key of the RFC 8032 test 1 secret key
+        let secret = from_hex64(
+            "307c83864f2833cb427a2ef1c00a013cfdff2768d980c0a3a520f006904de94f9b4f0afe280b746a778684e75442502057b7473a03f08\
+             f96f5a38e9287e01f8f",
+        );
+        let private_key = PrivateKey::from_ed25519_secret(&secret);
+        let (key_type, key_blob) = private_key.as_key_type_and_blob();
+        assert_eq!(key_type.as_tor_repr(), "ED25519-V3");
+        assert_eq!(
+            key_blob.as_tor_repr(),
+            "MHyDhk8oM8tCei7xwAoBPP3/J2jZgMCjpSDwBpBN6U+bTwr+KAt0aneGhOdUQlAgV7dHOgPwj5b1o46Sh+Afjw=="
+        );
+
+        let pubkey = from_hex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+        let address = private_key.onion_address().unwrap();
+        assert_eq!(address, OnionAddress::from_public_key(&pubkey));
+        assert_eq!(address.public_key().unwrap(), pubkey);
+
+        // A key returned by tor is handled in the same way
+        let from_tor = PrivateKey::Ed25519V3(key_blob.as_tor_repr().to_string());
+        assert_eq!(from_tor.onion_address().unwrap(), address);
+
+        let err = PrivateKey::Ed25519V3("bm90LWEta2V5".to_string())
+            .onion_address()
+            .unwrap_err();
+        assert_eq!(err, OnionAddressError::InvalidPrivateKey);
+        let err = PrivateKey::Rsa1024("this-is-a-key".to_string())
+            .onion_address()
+            .unwrap_err();
+        assert_eq!(err, OnionAddressError::UnsupportedKeyType("RSA1024".to_string()));
+    }
+
+    #[test]
+    fn onion_inventory() {
+        let current = OnionAddress::from_public_key(&[1u8; 32]);
+        let detached = OnionAddress::from_public_key(&[2u8; 32]);
+        let other = OnionAddress::from_public_key(&[3u8; 32]);
+
+        let mut inventory = OnionInventory::default();
+        assert!(inventory.is_empty());
+        inventory
+            .add_services("onions/current", &format!("{}\n", current.service_id()))
+            .unwrap();
+        // Some versions of tor include the suffix, and the IDs are case-insensitive
+        inventory
+            .add_services(
+                "onions/detached",
+                &format!("{}\n\n{}", detached, detached.service_id().to_ascii_uppercase()),
+            )
+            .unwrap();
+        inventory.add_services("onions/other", "not-an-onion").unwrap();
+        inventory.add_services("onions/current", "").unwrap();
+
+        assert_eq!(inventory.current, [current.clone()]);
+        assert_eq!(inventory.detached, [detached.clone()]);
+        assert!(inventory.is_current(&current));
+        assert!(!inventory.is_detached(&current));
+        assert!(inventory.is_detached(&detached));
+        assert!(inventory.contains(&detached));
+        assert!(!inventory.contains(&other));
+
+        let err = inventory.add_services("onions/detached", "abc").unwrap_err();
+        assert_eq!(err, OnionAddressError::InvalidLength(3));
+    }
+
+    #[test]
+    fn onion_address_invalid() {
+        let v2 = "62q4tswkxp74dtn7.onion".parse::<OnionAddress>().unwrap();
+        assert!(v2.is_v2());
+        assert_eq!(v2.public_key().unwrap_err(), OnionAddressError::V2NoPublicKey);
+
+        // Last character of the checksum changed
+        let address = "qigbgbs4ue3ghbupsotgh73cmmkjrin2aprlyxsrnrvpmcmzy3g4xbid"
+            .parse::<OnionAddress>()
+            .unwrap();
+        assert_eq!(address.public_key().unwrap_err(), OnionAddressError::InvalidChecksum);
+
+        // Version 4
+        let mut bytes = vec![0u8; 35];
+        bytes[34] = 4;
+        let address = BASE32_NOPAD
+            .encode(&bytes)
+            .to_ascii_lowercase()
+            .parse::<OnionAddress>()
+            .unwrap();
+        assert_eq!(
+            address.public_key().unwrap_err(),
+            OnionAddressError::UnsupportedVersion(4)
+        );
+
+        assert_eq!(
+            "abc.onion".parse::<OnionAddress>().unwrap_err(),
+            OnionAddressError::InvalidLength(3)
+        );
+        assert_eq!(
+            "62q4tswkxp74dtn1".parse::<OnionAddress>().unwrap_err(),
+            OnionAddressError::InvalidEncoding
+        );
+    }
+
+    #[test]
+    fn tor_version_parse() {
+        assert_eq!("0.4.5.6".parse::<TorVersion>().unwrap(), TorVersion::new(0, 4, 5, 6));
+        assert_eq!(
+            "0.4.6.1-alpha".parse::<TorVersion>().unwrap(),
+            TorVersion::new(0, 4, 6, 1)
+        );
+        assert_eq!(
+            "0.3.5.12 (git-b5eb246563e8a2e9)".parse::<TorVersion>().unwrap(),
+            TorVersion::new(0, 3, 5, 12)
+        );
+        assert_eq!("0.4.5".parse::<TorVersion>().unwrap(), TorVersion::new(0, 4, 5, 0));
+        assert!("0.4".parse::<TorVersion>().is_err());
+        assert!("tor".parse::<TorVersion>().is_err());
+
+        assert!(TorVersion::new(0, 4, 6, 1) > TorVersion::new(0, 4, 5, 10));
+        assert_eq!(TorVersion::new(0, 4, 5, 6).to_string(), "0.4.5.6");
+    }
+
+    #[test]
+    fn client_name() {
+        assert_eq!(ClientName::new("alice_laptop-2+").unwrap().as_str(), "alice_laptop-2+");
+        assert_eq!(" bob\n".parse::<ClientName>().unwrap().as_str(), "bob");
+        assert_eq!(ClientName::new(&"a".repeat(16)).unwrap().as_str().len(), 16);
+
+        let err = ClientName::new(&"a".repeat(17)).unwrap_err();
+        assert!(matches!(err, TorClientError::InvalidClientName { .. }));
+        assert!(err.to_string().contains("longer than 16"));
+
+        for name in &["alice laptop", "alice:laptop", "ålice", ""] {
+            let err = ClientName::new(name).unwrap_err();
+            assert!(matches!(err, TorClientError::InvalidClientName { .. }), "{}", name);
+        }
+    }
+
+    #[test]
+    fn address_mappings() {
+        let body = "\nexample.com 93.184.216.34 \"2021-03-04 10:38:12\"\nfoo.test bar.onion NEVER\n";
+        let mappings = AddressMapping::parse_all(body).unwrap();
+        assert_eq!(mappings, vec![
+            AddressMapping {
+                address: "example.com".to_string(),
+                new_address: "93.184.216.34".to_string(),
+                expiry: Some("2021-03-04 10:38:12".to_string()),
+            },
+            AddressMapping {
+                address: "foo.test".to_string(),
+                new_address: "bar.onion".to_string(),
+                expiry: None,
+            },
+        ]);
+
+        for line in &[
+            "example.com",
+            "example.com 93.184.216.34",
+            "a b 2021-03-04",
+            "a b \"2021\" extra",
+        ] {
+            assert!(line.parse::<AddressMapping>().is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn port_spec_display() {
+        assert_eq!(PortSpec::Auto.to_string(), "auto");
+        assert_eq!(
+            PortSpec::from("127.0.0.1:9050".parse::<SocketAddr>().unwrap()).to_string(),
+            "127.0.0.1:9050"
+        );
+        assert_eq!(PortSpec::Tcp("[::1]:9050".parse().unwrap()).to_string(), "[::1]:9050");
+        assert_eq!(
+            PortSpec::Unix("/run/tor/socks".into()).to_string(),
+            "unix:/run/tor/socks"
+        );
+        assert_eq!(
+            PortSpec::Unix("/home/user/tor data/socks".into()).to_string(),
+            r#"unix:"/home/user/tor data/socks""#
+        );
+        assert_eq!(PortSpec::Unix(r#"/tmp/a"b"#.into()).to_string(), r#"unix:"/tmp/a\"b""#);
+        assert_eq!(
+            PortSpec::from(ListenerAddress::Unix("/run/tor/control".into())),
+            PortSpec::Unix("/run/tor/control".into())
+        );
+    }
+}
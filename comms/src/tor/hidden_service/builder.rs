@@ -24,10 +24,11 @@ use super::controller::HiddenServiceControllerError;
 use crate::{
     multiaddr::Multiaddr,
     socks,
-    tor::{hidden_service::controller::HiddenServiceController, Authentication, PortMapping, TorIdentity},
+    tor::{hidden_service::controller::HiddenServiceController, Authentication, Clock, PortMapping, TorIdentity},
 };
 use bitflags::bitflags;
 use log::*;
+use std::{path::PathBuf, sync::Arc};
 use tari_shutdown::{OptionalShutdownSignal, ShutdownSignal};
 use thiserror::Error;
 
@@ -39,6 +40,8 @@ pub enum HiddenServiceBuilderError {
     ProxiedPortMappingNotProvided,
     #[error("The control server address was not provided. Use `with_control_server_address` to set it.")]
     TorControlServerAddressNotProvided,
+    #[error("The proxied port mapping forwards to '{0}', but a socket address is required to bind the listener")]
+    ProxiedTargetNotSocket(String),
     #[error("HiddenServiceControllerError: {0}")]
     HiddenServiceControllerError(#[from] HiddenServiceControllerError),
 }
@@ -50,6 +53,27 @@ bitflags! {
         const NONE = 0x0;
         /// Detach the service from the control server connection. This keeps the hidden service active even if comms is shutdown.
         const DETACH = 0x1;
+        /// Check that the proxied address is accepting connections before adding the hidden service, so that an onion
+        /// address is never published for a backend that is not running.
+        const CHECK_BACKEND = 0x2;
+    }
+}
+
+/// How tor keeps a hidden service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServicePersistence {
+    /// The service is added with ADD_ONION and is removed when tor restarts (or, unless it is detached, when the
+    /// control port connection closes).
+    Ephemeral,
+    /// The service is configured with `HiddenServiceDir` and `HiddenServicePort` and saved to the torrc, so that tor
+    /// keeps publishing it across restarts. Tor keeps the service key in the given directory, which must be on the
+    /// host running tor and readable by this process. Any `TorIdentity` is ignored in this mode.
+    OnDisk(PathBuf),
+}
+
+impl Default for ServicePersistence {
+    fn default() -> Self {
+        ServicePersistence::Ephemeral
     }
 }
 
@@ -63,7 +87,9 @@ pub struct HiddenServiceBuilder {
     control_server_auth: Authentication,
     socks_auth: socks::Authentication,
     hs_flags: HsFlags,
+    persistence: ServicePersistence,
     shutdown_signal: OptionalShutdownSignal,
+    clock: Option<Arc<dyn Clock>>,
 }
 
 impl HiddenServiceBuilder {
@@ -91,6 +117,9 @@ impl HiddenServiceBuilder {
     #[doc("Configuration flags for the hidden service")]
     setter!(with_hs_flags, hs_flags, HsFlags);
 
+    #[doc("Whether the hidden service is ephemeral (the default) or configured on disk. See `ServicePersistence`.")]
+    setter!(with_persistence, persistence, ServicePersistence);
+
     /// The address of the SOCKS5 server. If an address is None, the hidden service builder will use the SOCKS
     /// listener address as given by the tor control port.
     pub fn with_shutdown_signal(mut self, shutdown_signal: ShutdownSignal) -> Self {
@@ -105,6 +134,13 @@ impl HiddenServiceBuilder {
         self
     }
 
+    /// The clock used for the delay between attempts to reconnect to the Tor Control Port and by the control port
+    /// client. Defaults to real time.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
     /// Set the PortMapping to use when creating this hidden service. A PortMapping maps a Tor port to a proxied address
     /// (usually local). An error will result if this is not provided.
     pub fn with_port_mapping<P: Into<PortMapping>>(mut self, port_mapping: P) -> Self {
@@ -119,6 +155,12 @@ impl HiddenServiceBuilder {
         let proxied_port_mapping = self
             .port_mapping
             .ok_or(HiddenServiceBuilderError::ProxiedPortMappingNotProvided)?;
+        // The controller's proxied address is bound to receive traffic for the service, so it must be a socket address
+        if proxied_port_mapping.target().socket_addr().is_none() {
+            return Err(HiddenServiceBuilderError::ProxiedTargetNotSocket(
+                proxied_port_mapping.target().to_string(),
+            ));
+        }
         let control_server_addr = self
             .control_server_addr
             .ok_or(HiddenServiceBuilderError::TorControlServerAddressNotProvided)?;
@@ -130,7 +172,7 @@ impl HiddenServiceBuilder {
             proxied_port_mapping
         );
 
-        let controller = HiddenServiceController::new(
+        let mut controller = HiddenServiceController::new(
             control_server_addr,
             self.control_server_auth,
             proxied_port_mapping,
@@ -138,8 +180,12 @@ impl HiddenServiceBuilder {
             self.socks_auth,
             self.identity,
             self.hs_flags,
+            self.persistence,
             self.shutdown_signal,
         );
+        if let Some(clock) = self.clock {
+            controller = controller.with_clock(clock);
+        }
 
         Ok(controller)
     }
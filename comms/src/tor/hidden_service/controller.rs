@@ -27,12 +27,21 @@ use crate::{
     tor::{
         control_client::{
             commands::{AddOnionFlag, AddOnionResponse},
+            ConnectionStateTracker,
             TorControlEvent,
         },
         Authentication,
+        Clock,
+        ConnectionState,
         HiddenService,
         HsFlags,
+        ListenerAddress,
+        OnionAddress,
+        OnionTarget,
         PortMapping,
+        PrivateKey,
+        ServicePersistence,
+        SystemClock,
         TorClientError,
         TorControlPortClient,
         TorIdentity,
@@ -40,14 +49,31 @@ use crate::{
     transports::{SocksConfig, SocksTransport},
     utils::multiaddr::{multiaddr_to_socketaddr, socketaddr_to_multiaddr},
 };
+use data_encoding::BASE64;
 use futures::{future, future::Either, pin_mut, StreamExt};
 use log::*;
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    fs,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 use tari_shutdown::OptionalShutdownSignal;
 use thiserror::Error;
-use tokio::{sync::broadcast, time};
+use tokio::{
+    net::TcpStream,
+    sync::{broadcast, watch},
+    time,
+};
 
 const LOG_TARGET: &str = "comms::tor::hidden_service_controller";
+const BACKEND_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+/// The delay between attempts to reconnect to the tor control port
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// The header of the `hs_ed25519_secret_key` file that tor writes to a hidden service directory, which is followed by
+/// the 64 byte expanded secret key
+const HS_SECRET_KEY_HEADER: &[u8] = b"== ed25519v1-secret: type0 ==\0\0\0";
 
 #[derive(Debug, Error)]
 pub enum HiddenServiceControllerError {
@@ -63,6 +89,33 @@ pub enum HiddenServiceControllerError {
     InvalidDetachedServiceId,
     #[error("The shutdown signal interrupted the HiddenServiceController")]
     ShutdownSignalInterrupt,
+    #[error("The hidden service backend at '{target}' is not reachable: {source}")]
+    BackendUnreachable {
+        // Boxed as a hostname target is stored inline
+        target: Box<OnionTarget>,
+        source: io::Error,
+    },
+    #[error("Invalid hidden service directory '{path}': {reason}")]
+    InvalidHiddenServiceDir { path: PathBuf, reason: String },
+}
+
+impl HiddenServiceControllerError {
+    /// Returns true if the error was caused by the connection to the control port, so that it may not recur on a new
+    /// connection
+    fn is_transient(&self) -> bool {
+        match self {
+            HiddenServiceControllerError::NotConnected | HiddenServiceControllerError::TorControlPortOffline => true,
+            HiddenServiceControllerError::TorClientError(err) => matches!(
+                err,
+                TorClientError::Io(_) |
+                    TorClientError::ConnectionFailed { .. } |
+                    TorClientError::UnexpectedEof |
+                    TorClientError::CommandSenderDisconnected |
+                    TorClientError::ClientClosed
+            ),
+            _ => false,
+        }
+    }
 }
 
 pub struct HiddenServiceController {
@@ -74,8 +127,11 @@ pub struct HiddenServiceController {
     socks_auth: socks::Authentication,
     identity: Option<TorIdentity>,
     hs_flags: HsFlags,
+    persistence: ServicePersistence,
     is_authenticated: bool,
     shutdown_signal: OptionalShutdownSignal,
+    clock: Arc<dyn Clock>,
+    connection_state: ConnectionStateTracker,
 }
 
 impl HiddenServiceController {
@@ -88,6 +144,7 @@ impl HiddenServiceController {
         socks_auth: socks::Authentication,
         identity: Option<TorIdentity>,
         hs_flags: HsFlags,
+        persistence: ServicePersistence,
         shutdown_signal: OptionalShutdownSignal,
     ) -> Self
     {
@@ -99,14 +156,35 @@ impl HiddenServiceController {
             proxied_port_mapping,
             socks_auth,
             hs_flags,
+            persistence,
             identity,
             is_authenticated: false,
             shutdown_signal,
+            clock: Arc::new(SystemClock),
+            connection_state: ConnectionStateTracker::new(ConnectionState::Closed),
         }
     }
 
+    pub(super) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Returns the current state of the control port connection, which is `Closed` until the controller connects
+    pub fn connection_state(&self) -> ConnectionState {
+        self.connection_state.get()
+    }
+
+    /// Returns a receiver that is notified when the state of the control port connection changes. Unlike the state of
+    /// a [TorControlPortClient], this continues to be updated when the controller reconnects. The state is
+    /// `Reconnecting` until the connection is authenticated and the hidden service has been added again.
+    pub fn watch_connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.connection_state.subscribe()
+    }
+
     /// The address to which all tor traffic is proxied. A TCP socket should be bound to this address to receive traffic
-    /// for this hidden service.
+    /// for this hidden service. This is always a socket address, as the builder rejects port mappings with a hostname
+    /// target.
     pub fn proxied_address(&self) -> Multiaddr {
         socketaddr_to_multiaddr(self.proxied_port_mapping.proxied_address())
     }
@@ -188,38 +266,96 @@ impl HiddenServiceController {
         shutdown_signal: &mut OptionalShutdownSignal,
     ) -> Result<(), HiddenServiceControllerError>
     {
-        let mut signal = Some(shutdown_signal);
-        loop {
-            warn!(
-                target: LOG_TARGET,
-                "Attempting to reestablish control port connection at '{}'", self.control_server_addr
-            );
-            let connect_fut = TorControlPortClient::connect(self.control_server_addr.clone(), event_tx.clone());
-            pin_mut!(connect_fut);
-            let either = future::select(connect_fut, signal.take().expect("signal was None")).await;
-            match either {
-                Either::Left((Ok(client), _)) => {
-                    self.client = Some(client);
-                    self.authenticate().await?;
-                    self.set_events().await?;
-                    let _ = self.create_hidden_service_from_identity().await;
-                    break Ok(());
-                },
-                Either::Left((Err(err), shutdown_signal)) => {
-                    signal = Some(shutdown_signal);
-                    warn!(
-                        target: LOG_TARGET,
-                        "Failed to reestablish connection with tor control server because '{:?}'", err
-                    );
-                    warn!(target: LOG_TARGET, "Will attempt again in 5 seconds...");
-                    time::delay_for(Duration::from_secs(5)).await;
-                },
+        // The span covers every attempt, including the commands sent once connected
+        #[cfg(feature = "tracing")]
+        let span = tracing::span!(
+            tracing::Level::INFO,
+            "tor_reconnect",
+            control_server_addr = %self.control_server_addr,
+            attempts = tracing::field::Empty
+        );
+        let reestablish = async {
+            let mut signal = Some(shutdown_signal);
+            #[cfg(feature = "tracing")]
+            let mut attempts = 0u32;
+            loop {
+                #[cfg(feature = "tracing")]
+                {
+                    attempts += 1;
+                    span.record("attempts", &attempts);
+                }
+                warn!(
+                    target: LOG_TARGET,
+                    "Attempting to reestablish control port connection at '{}'", self.control_server_addr
+                );
+                self.connection_state.set(ConnectionState::Reconnecting);
+                let connect_fut = TorControlPortClient::builder()
+                    .with_clock(self.clock.clone())
+                    .with_connection_state(self.connection_state.clone())
+                    .connect(self.control_server_addr.clone(), event_tx.clone());
+                pin_mut!(connect_fut);
+                let either = future::select(connect_fut, signal.take().expect("signal was None")).await;
+                let (err, shutdown_signal) = match either {
+                    Either::Left((Ok(client), shutdown_signal)) => {
+                        self.client = Some(client);
+                        // Losing the connection again fails this attempt like a failure to connect does
+                        match self.restore_hidden_service().await {
+                            Ok(()) => {
+                                self.connection_state.set(ConnectionState::Ready);
+                                let _ = event_tx.send(TorControlEvent::TorControlReconnected);
+                                break Ok(());
+                            },
+                            Err(err) => {
+                                // Dropping the client closes the connection without a disconnected event
+                                self.client = None;
+                                (err, shutdown_signal)
+                            },
+                        }
+                    },
+                    Either::Left((Err(err), shutdown_signal)) => (err.into(), shutdown_signal),
+                    Either::Right(_) => {
+                        self.connection_state.set(ConnectionState::Closed);
+                        break Err(HiddenServiceControllerError::ShutdownSignalInterrupt);
+                    },
+                };
 
-                Either::Right(_) => {
-                    break Err(HiddenServiceControllerError::ShutdownSignalInterrupt);
-                },
+                // Errors such as a rejected AUTHENTICATE or ADD_ONION would fail every attempt
+                if !err.is_transient() {
+                    self.connection_state.set(ConnectionState::Closed);
+                    break Err(err);
+                }
+
+                warn!(
+                    target: LOG_TARGET,
+                    "Failed to reestablish connection with tor control server because '{:?}'", err
+                );
+                warn!(
+                    target: LOG_TARGET,
+                    "Will attempt again in {} seconds...",
+                    RECONNECT_DELAY.as_secs()
+                );
+                // Shutting down also interrupts the delay
+                let delay = self.clock.delay_for(RECONNECT_DELAY);
+                match future::select(delay, shutdown_signal).await {
+                    Either::Left((_, shutdown_signal)) => signal = Some(shutdown_signal),
+                    Either::Right(_) => {
+                        self.connection_state.set(ConnectionState::Closed);
+                        break Err(HiddenServiceControllerError::ShutdownSignalInterrupt);
+                    },
+                }
             }
-        }
+        };
+        #[cfg(feature = "tracing")]
+        let reestablish = tracing::Instrument::instrument(reestablish, span.clone());
+        reestablish.await
+    }
+
+    /// Authenticates a new connection and adds the hidden service again
+    async fn restore_hidden_service(&mut self) -> Result<(), HiddenServiceControllerError> {
+        self.authenticate().await?;
+        self.set_events().await?;
+        self.create_hidden_service_from_identity().await?;
+        Ok(())
     }
 
     fn client_mut(&mut self) -> Result<&mut TorControlPortClient, HiddenServiceControllerError> {
@@ -235,7 +371,10 @@ impl HiddenServiceController {
         }
 
         let (event_tx, _) = broadcast::channel(20);
-        let client = TorControlPortClient::connect(self.control_server_addr.clone(), event_tx)
+        let client = TorControlPortClient::builder()
+            .with_clock(self.clock.clone())
+            .with_connection_state(self.connection_state.clone())
+            .connect(self.control_server_addr.clone(), event_tx)
             .await
             .map_err(|err| {
                 error!(target: LOG_TARGET, "Tor client error: {:?}", err);
@@ -268,17 +407,13 @@ impl HiddenServiceController {
             },
             None => {
                 // Get configured SOCK5 address from Tor
-                let socks_addrs = self.client_mut()?.get_info("net/listeners/socks").await?;
-
-                let addr = socks_addrs
-                    .iter()
-                    .map(|addr| addr.parse::<SocketAddr>())
-                    .filter_map(Result::ok)
-                    .map(|addr| socketaddr_to_multiaddr(&addr))
-                    .next()
-                    .ok_or_else(|| HiddenServiceControllerError::FailedToParseSocksAddress)?;
-
-                Ok(addr)
+                match self.client_mut()?.socks_proxy_addr().await {
+                    Ok(ListenerAddress::Tcp(addr)) => Ok(socketaddr_to_multiaddr(&addr)),
+                    Ok(ListenerAddress::Unix(_)) | Err(TorClientError::NoSocksListener) => {
+                        Err(HiddenServiceControllerError::FailedToParseSocksAddress)
+                    },
+                    Err(err) => Err(err.into()),
+                }
             },
         }
     }
@@ -287,7 +422,41 @@ impl HiddenServiceController {
         let socks_addr = self.get_socks_address().await?;
         debug!(target: LOG_TARGET, "Tor SOCKS address is '{}'", socks_addr);
 
-        // Initialize a onion hidden service - either from the given private key or by creating a new one
+        if self.hs_flags.contains(HsFlags::CHECK_BACKEND) {
+            check_backend(self.proxied_port_mapping.target()).await?;
+        }
+
+        // Initialize a onion hidden service - either in the hidden service directory, from the given private key or
+        // by creating a new one
+        if let ServicePersistence::OnDisk(dir) = &self.persistence {
+            let dir = dir.clone();
+            let port_mapping = self.proxied_port_mapping;
+            let identity = configure_on_disk_service(self.client_mut()?, &dir, &port_mapping).await?;
+            self.identity = Some(identity);
+        } else {
+            self.add_ephemeral_service().await?;
+        }
+
+        let identity = self.identity.as_ref().map(Clone::clone).expect("already checked");
+        debug!(
+            target: LOG_TARGET,
+            "Added hidden service with service id '{}' on port '{}'", identity.service_id, identity.onion_port
+        );
+
+        let proxied_addr = self.proxied_address();
+        let event_tx = self.client_mut()?.event_sender().clone();
+
+        Ok(HiddenService {
+            socks_addr,
+            socks_auth: self.socks_auth.clone(),
+            identity,
+            proxied_addr,
+            shutdown_signal: self.shutdown_signal.clone(),
+            event_tx,
+        })
+    }
+
+    async fn add_ephemeral_service(&mut self) -> Result<(), HiddenServiceControllerError> {
         match self.identity.take() {
             Some(identity) => {
                 let resp = self.create_or_reuse_onion(&identity).await?;
@@ -312,21 +481,7 @@ impl HiddenServiceController {
             },
         };
 
-        let identity = self.identity.as_ref().map(Clone::clone).expect("already checked");
-        debug!(
-            target: LOG_TARGET,
-            "Added hidden service with service id '{}' on port '{}'", identity.service_id, identity.onion_port
-        );
-
-        let proxied_addr = socketaddr_to_multiaddr(self.proxied_port_mapping.proxied_address());
-
-        Ok(HiddenService {
-            socks_addr,
-            socks_auth: self.socks_auth.clone(),
-            identity,
-            proxied_addr,
-            shutdown_signal: self.shutdown_signal.clone(),
-        })
+        Ok(())
     }
 
     pub fn set_proxied_addr(&mut self, addr: Multiaddr) {
@@ -335,6 +490,20 @@ impl HiddenServiceController {
         )
     }
 
+    /// Returns the address of the detached onion service created with `private_key`, or None if tor has no such
+    /// service. The address is derived from the key, so this works if only the key of a service was persisted. An
+    /// [UnsupportedKeyType](crate::tor::OnionAddressError::UnsupportedKeyType) error is returned for keys that the
+    /// address cannot be derived from, i.e. any key that isn't an `ED25519-V3` key.
+    pub async fn find_detached_service(
+        &mut self,
+        private_key: &PrivateKey,
+    ) -> Result<Option<OnionAddress>, HiddenServiceControllerError>
+    {
+        let address = private_key.onion_address().map_err(TorClientError::from)?;
+        let inventory = self.client_mut()?.get_onion_inventory().await?;
+        Ok(Some(address).filter(|address| inventory.is_detached(address)))
+    }
+
     async fn create_or_reuse_onion(
         &mut self,
         identity: &TorIdentity,
@@ -356,25 +525,32 @@ impl HiddenServiceController {
 
             match result {
                 Ok(resp) => break Ok(resp),
-                Err(TorClientError::OnionAddressCollision) => {
+                Err(TorClientError::OnionServiceExists { .. }) => {
                     debug!(target: LOG_TARGET, "Onion address is already registered.");
 
-                    let detached = client.get_info("onions/detached").await?;
+                    let inventory = client.get_onion_inventory().await?;
                     debug!(
                         target: LOG_TARGET,
-                        "Checking that the active detached service IDs '{}' to expected service id '{}'",
-                        detached.join(", "),
+                        "Checking that the active detached services '{:?}' include the expected service id '{}'",
+                        inventory.detached,
                         identity.service_id
                     );
 
-                    if detached.iter().all(|svc_id| **svc_id != identity.service_id) {
+                    // Fall back to the address of the key if the service id was not kept
+                    let address = identity
+                        .service_id
+                        .parse::<OnionAddress>()
+                        .or_else(|_| identity.private_key.onion_address())
+                        .map_err(|_| HiddenServiceControllerError::InvalidDetachedServiceId)?;
+                    if !inventory.is_detached(&address) {
                         return Err(HiddenServiceControllerError::InvalidDetachedServiceId);
                     }
                     debug!(
                         target: LOG_TARGET,
-                        "Deleting duplicate onion service `{}` and then recreating it.", identity.service_id
+                        "Deleting duplicate onion service `{}` and then recreating it.",
+                        address.service_id()
                     );
-                    client.del_onion(&identity.service_id).await?;
+                    client.del_onion(address.service_id()).await?;
                     continue;
                 },
                 Err(err) => break Err(err.into()),
@@ -382,3 +558,537 @@ impl HiddenServiceController {
         }
     }
 }
+
+/// Configures a hidden service in `dir` with SETCONF and saves the configuration, so that tor keeps the service across
+/// restarts. The identity of the service is read from the files tor writes to `dir`.
+///
+/// SETCONF replaces every hidden service option, so the services that are already configured are set again, in
+/// order, followed by this one. A service that is already configured in `dir` is replaced.
+async fn configure_on_disk_service(
+    client: &mut TorControlPortClient,
+    dir: &Path,
+    port_mapping: &PortMapping,
+) -> Result<TorIdentity, HiddenServiceControllerError>
+{
+    let invalid_dir = |reason: String| HiddenServiceControllerError::InvalidHiddenServiceDir {
+        path: dir.to_path_buf(),
+        reason,
+    };
+    let dir_str = dir
+        .to_str()
+        .ok_or_else(|| invalid_dir("path is not valid UTF-8".to_string()))?;
+    let port = format!("{} {}", port_mapping.onion_port(), port_mapping.target());
+
+    let existing = client.get_conf_entries("HiddenServiceOptions").await?;
+    let mut options = Vec::with_capacity(existing.len() + 2);
+    let mut is_replaced = false;
+    // Options without a value are only returned if no hidden services are configured
+    for (key, value) in existing
+        .iter()
+        .filter_map(|(key, value)| Some((key, value.as_deref()?)))
+    {
+        // Each service starts with its HiddenServiceDir, followed by its other options
+        if key.eq_ignore_ascii_case("HiddenServiceDir") {
+            is_replaced = Path::new(value) == dir;
+        }
+        if !is_replaced {
+            options.push((key.as_str(), Some(value)));
+        }
+    }
+    options.push(("HiddenServiceDir", Some(dir_str)));
+    options.push(("HiddenServicePort", Some(&port)));
+    client.set_conf(&options).await?;
+    client.save_conf().await?;
+
+    let hs_dir = dir.to_path_buf();
+    let (hostname, secret_key) = task::spawn_blocking(move || -> io::Result<_> {
+        let hostname = fs::read_to_string(hs_dir.join("hostname"))?;
+        let secret_key = fs::read(hs_dir.join("hs_ed25519_secret_key"))?;
+        Ok((hostname, secret_key))
+    })
+    .await
+    .map_err(|err| invalid_dir(err.to_string()))?
+    .map_err(|err| invalid_dir(err.to_string()))?;
+    let onion_addr = hostname
+        .trim()
+        .parse::<OnionAddress>()
+        .map_err(|err| invalid_dir(err.to_string()))?;
+    if secret_key.len() != HS_SECRET_KEY_HEADER.len() + 64 || !secret_key.starts_with(HS_SECRET_KEY_HEADER) {
+        return Err(invalid_dir(
+            "hs_ed25519_secret_key is not a v3 secret key file".to_string(),
+        ));
+    }
+
+    Ok(TorIdentity {
+        private_key: PrivateKey::Ed25519V3(BASE64.encode(&secret_key[HS_SECRET_KEY_HEADER.len()..])),
+        service_id: onion_addr.service_id().to_string(),
+        onion_port: port_mapping.onion_port(),
+    })
+}
+
+/// Checks that the backend of a hidden service is accepting TCP connections. The connection is closed immediately, so
+/// the backend will see a connection that sends no data.
+async fn check_backend(target: &OnionTarget) -> Result<(), HiddenServiceControllerError> {
+    let connect = match target {
+        OnionTarget::Socket(addr) => Either::Left(TcpStream::connect(*addr)),
+        OnionTarget::HostPort(host, port) => Either::Right(TcpStream::connect((host.as_str(), *port))),
+    };
+    let result = time::timeout(BACKEND_CHECK_TIMEOUT, connect)
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+    match result {
+        Ok(_) => {
+            debug!(target: LOG_TARGET, "Hidden service backend '{}' is reachable", target);
+            Ok(())
+        },
+        Err(source) => Err(HiddenServiceControllerError::BackendUnreachable {
+            target: Box::new(*target),
+            source,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        runtime,
+        tor::{
+            control_client::{test_server, MockClock},
+            encode_onion_v3_address,
+            HiddenServiceBuilder,
+            HiddenServiceBuilderError,
+            OnionAddressError,
+        },
+    };
+    use futures::{channel::oneshot, SinkExt};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+    use tari_shutdown::Shutdown;
+    use tari_test_utils::unpack_enum;
+    use tokio::net::TcpListener;
+    use tokio_util::codec::{Framed, LinesCodec};
+
+    #[runtime::test]
+    async fn check_backend_listening() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        check_backend(&OnionTarget::Socket(addr)).await.unwrap();
+
+        drop(listener);
+        let err = check_backend(&OnionTarget::Socket(addr)).await.unwrap_err();
+        unpack_enum!(HiddenServiceControllerError::BackendUnreachable { target, source } = err);
+        assert_eq!(*target, OnionTarget::Socket(addr));
+        assert_eq!(source.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[runtime::test]
+    async fn builder_rejects_hostname_target() {
+        let err = HiddenServiceBuilder::new()
+            .with_port_mapping(PortMapping::new_host(80, "backend.internal", 8080).unwrap())
+            .build()
+            .await
+            .err()
+            .unwrap();
+        unpack_enum!(HiddenServiceBuilderError::ProxiedTargetNotSocket(target) = err);
+        assert_eq!(target, "backend.internal:8080");
+    }
+
+    #[runtime::test]
+    async fn configure_on_disk_service_ok() {
+        let (_, mock_state, socket) = test_server::spawn().await;
+        let (event_tx, _) = broadcast::channel(1);
+        let mut client = TorControlPortClient::new(socket, event_tx);
+
+        // Write the files that tor creates in the hidden service directory
+        let dir = tempfile::tempdir().unwrap();
+        let hostname = encode_onion_v3_address(&[1u8; 32]);
+        fs::write(dir.path().join("hostname"), format!("{}\n", hostname)).unwrap();
+        let mut secret_key = HS_SECRET_KEY_HEADER.to_vec();
+        secret_key.extend_from_slice(&[2u8; 64]);
+        fs::write(dir.path().join("hs_ed25519_secret_key"), &secret_key).unwrap();
+
+        let port_mapping = PortMapping::new(80, ([127, 0, 0, 1], 8080).into());
+        mock_state.push_response(&["250 HiddenServiceOptions"]).await;
+        let identity = configure_on_disk_service(&mut client, dir.path(), &port_mapping)
+            .await
+            .unwrap();
+        assert_eq!(mock_state.take_requests().await, [
+            "GETCONF HiddenServiceOptions".to_string(),
+            format!(
+                r#"SETCONF HiddenServiceDir="{}" HiddenServicePort="80 127.0.0.1:8080""#,
+                dir.path().display()
+            ),
+            "SAVECONF".to_string(),
+        ]);
+        assert_eq!(format!("{}.onion", identity.service_id), hostname);
+        assert_eq!(identity.onion_port, 80);
+        assert_eq!(identity.private_key, PrivateKey::Ed25519V3(BASE64.encode(&[2u8; 64])));
+
+        fs::write(dir.path().join("hs_ed25519_secret_key"), b"bogus").unwrap();
+        let err = configure_on_disk_service(&mut client, dir.path(), &port_mapping)
+            .await
+            .unwrap_err();
+        unpack_enum!(HiddenServiceControllerError::InvalidHiddenServiceDir { path, reason } = err);
+        assert_eq!(path, dir.path());
+        assert!(reason.contains("hs_ed25519_secret_key"));
+    }
+
+    #[runtime::test]
+    async fn configure_on_disk_service_keeps_other_services() {
+        let (_, mock_state, socket) = test_server::spawn().await;
+        let (event_tx, _) = broadcast::channel(1);
+        let mut client = TorControlPortClient::new(socket, event_tx);
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("hostname"),
+            format!("{}\n", encode_onion_v3_address(&[1u8; 32])),
+        )
+        .unwrap();
+        let mut secret_key = HS_SECRET_KEY_HEADER.to_vec();
+        secret_key.extend_from_slice(&[2u8; 64]);
+        fs::write(dir.path().join("hs_ed25519_secret_key"), &secret_key).unwrap();
+
+        // Another service is configured, as is this one from a previous run with a different port
+        let this_dir = format!("250-HiddenServiceDir={}", dir.path().display());
+        mock_state
+            .push_response(&[
+                "250-HiddenServiceDir=/var/lib/tor/other",
+                "250-HiddenServicePort=22 127.0.0.1:22",
+                "250-HiddenServiceVersion=3",
+                &this_dir,
+                "250 HiddenServicePort=80 127.0.0.1:1234",
+            ])
+            .await;
+        let port_mapping = PortMapping::new(80, ([127, 0, 0, 1], 8080).into());
+        configure_on_disk_service(&mut client, dir.path(), &port_mapping)
+            .await
+            .unwrap();
+        assert_eq!(mock_state.take_requests().await, [
+            "GETCONF HiddenServiceOptions".to_string(),
+            format!(
+                r#"SETCONF HiddenServiceDir="/var/lib/tor/other" HiddenServicePort="22 127.0.0.1:22" HiddenServiceVersion="3" HiddenServiceDir="{}" HiddenServicePort="80 127.0.0.1:8080""#,
+                dir.path().display()
+            ),
+            "SAVECONF".to_string(),
+        ]);
+    }
+
+    const SECRET_KEY: [u8; 64] = [2u8; 64];
+
+    /// Replies to every command like tor would for the hidden service with [SECRET_KEY], calling `on_line` with each
+    /// command line before replying
+    async fn serve_control_port<F: FnMut(&str)>(socket: TcpStream, mut on_line: F) {
+        let service_id = PrivateKey::from_ed25519_secret(&SECRET_KEY)
+            .onion_address()
+            .unwrap()
+            .service_id()
+            .to_string();
+        let mut lines = Framed::new(socket, LinesCodec::new());
+        while let Some(Ok(line)) = lines.next().await {
+            on_line(&line);
+            if line.starts_with("ADD_ONION") {
+                lines.send(format!("250-ServiceID={}", service_id)).await.unwrap();
+                if line.starts_with("ADD_ONION NEW:") {
+                    let private_key = format!("250-PrivateKey=ED25519-V3:{}", BASE64.encode(&SECRET_KEY));
+                    lines.send(private_key).await.unwrap();
+                }
+            }
+            lines.send("250 OK".to_string()).await.unwrap();
+        }
+    }
+
+    #[runtime::test]
+    async fn reestablish_after_disconnect() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let num_add_onion = Arc::new(AtomicUsize::new(0));
+        let (disconnect_tx, disconnect_rx) = oneshot::channel::<()>();
+        task::spawn({
+            let num_add_onion = num_add_onion.clone();
+            let count_add_onion = move |line: &str| {
+                if line.starts_with("ADD_ONION") {
+                    num_add_onion.fetch_add(1, Ordering::SeqCst);
+                }
+            };
+            async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                // Dropping the connection once told to disconnects the controller
+                future::select(
+                    Box::pin(serve_control_port(socket, count_add_onion.clone())),
+                    disconnect_rx,
+                )
+                .await;
+                let (socket, _) = listener.accept().await.unwrap();
+                serve_control_port(socket, count_add_onion).await;
+            }
+        });
+
+        let controller = HiddenServiceController::new(
+            socketaddr_to_multiaddr(&addr),
+            Authentication::None,
+            PortMapping::from_port(80),
+            Some("/ip4/127.0.0.1/tcp/9050".parse().unwrap()),
+            socks::Authentication::None,
+            None,
+            HsFlags::NONE,
+            ServicePersistence::Ephemeral,
+            OptionalShutdownSignal::none(),
+        );
+        let hidden_service = controller.create_hidden_service().await.unwrap();
+        let mut events = hidden_service.get_event_stream();
+        assert_eq!(num_add_onion.load(Ordering::SeqCst), 1);
+
+        disconnect_tx.send(()).unwrap();
+        unpack_enum!(TorControlEvent::TorControlDisconnected = events.recv().await.unwrap());
+        unpack_enum!(TorControlEvent::TorControlReconnected = events.recv().await.unwrap());
+        // The event is only sent once the service has been added again
+        assert_eq!(num_add_onion.load(Ordering::SeqCst), 2);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[runtime::test]
+    async fn reestablish_stops_on_rejected_add_onion() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let num_add_onion = Arc::new(AtomicUsize::new(0));
+        let (disconnect_tx, disconnect_rx) = oneshot::channel::<()>();
+        task::spawn({
+            let num_add_onion = num_add_onion.clone();
+            let count_add_onion = move |line: &str| {
+                if line.starts_with("ADD_ONION") {
+                    num_add_onion.fetch_add(1, Ordering::SeqCst);
+                }
+            };
+            async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                future::select(
+                    Box::pin(serve_control_port(socket, count_add_onion.clone())),
+                    disconnect_rx,
+                )
+                .await;
+
+                // The attempt to reconnect fails to add the service again
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut lines = Framed::new(socket, LinesCodec::new());
+                while let Some(Ok(line)) = lines.next().await {
+                    let reply = if line.starts_with("ADD_ONION") {
+                        count_add_onion(&line);
+                        "512 Invalid argument"
+                    } else {
+                        "250 OK"
+                    };
+                    lines.send(reply.to_string()).await.unwrap();
+                }
+            }
+        });
+
+        let controller = HiddenServiceController::new(
+            socketaddr_to_multiaddr(&addr),
+            Authentication::None,
+            PortMapping::from_port(80),
+            Some("/ip4/127.0.0.1/tcp/9050".parse().unwrap()),
+            socks::Authentication::None,
+            None,
+            HsFlags::NONE,
+            ServicePersistence::Ephemeral,
+            OptionalShutdownSignal::none(),
+        )
+        .with_clock(Arc::new(MockClock::new()));
+        let mut states = controller.watch_connection_state();
+        let hidden_service = controller.create_hidden_service().await.unwrap();
+        let mut events = hidden_service.get_event_stream();
+
+        disconnect_tx.send(()).unwrap();
+        unpack_enum!(TorControlEvent::TorControlDisconnected = events.recv().await.unwrap());
+        // The state stays `Reconnecting` between attempts, so it is only `Closed` after the rejected ADD_ONION if the
+        // controller has given up
+        loop {
+            let state = states.recv().await.unwrap();
+            if state == ConnectionState::Closed && num_add_onion.load(Ordering::SeqCst) == 2 {
+                break;
+            }
+        }
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn is_transient() {
+        let err = HiddenServiceControllerError::from(TorClientError::UnexpectedEof);
+        assert!(err.is_transient());
+        assert!(HiddenServiceControllerError::TorControlPortOffline.is_transient());
+        let err = HiddenServiceControllerError::from(TorClientError::TorCommandFailed(
+            "515 Authentication failed".to_string(),
+        ));
+        assert!(!err.is_transient());
+        assert!(!HiddenServiceControllerError::InvalidDetachedServiceId.is_transient());
+    }
+
+    #[runtime::test]
+    async fn find_detached_service() {
+        let (_, mock_state, socket) = test_server::spawn().await;
+        let (event_tx, _) = broadcast::channel(1);
+        let mut controller = HiddenServiceController::new(
+            "/ip4/127.0.0.1/tcp/9051".parse().unwrap(),
+            Authentication::None,
+            PortMapping::from_port(80),
+            None,
+            socks::Authentication::None,
+            None,
+            HsFlags::DETACH,
+            ServicePersistence::Ephemeral,
+            OptionalShutdownSignal::none(),
+        );
+        controller.client = Some(TorControlPortClient::new(socket, event_tx));
+
+        let private_key = PrivateKey::from_ed25519_secret(&[2u8; 64]);
+        let address = private_key.onion_address().unwrap();
+        let other = OnionAddress::from_public_key(&[1u8; 32]);
+        let detached_lines = [other.service_id().to_string(), address.service_id().to_string()];
+        mock_state
+            .push_response(&[
+                "250-onions/current=",
+                "250+onions/detached=",
+                &detached_lines[0],
+                &detached_lines[1],
+                ".",
+                "250 OK",
+            ])
+            .await;
+        let found = controller.find_detached_service(&private_key).await.unwrap();
+        assert_eq!(found, Some(address));
+
+        mock_state
+            .push_response(&["250-onions/current=", "250-onions/detached=", "250 OK"])
+            .await;
+        let found = controller.find_detached_service(&private_key).await.unwrap();
+        assert_eq!(found, None);
+
+        let err = controller
+            .find_detached_service(&PrivateKey::Rsa1024("dummy-key".to_string()))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            HiddenServiceControllerError::TorClientError(
+                TorClientError::OnionAddressError(OnionAddressError::UnsupportedKeyType(_)),
+            )
+        ));
+    }
+
+    #[runtime::test]
+    async fn reestablish_delay_uses_clock() {
+        // Nothing listens on the address once the listener is dropped, so every attempt to reconnect fails
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let clock = MockClock::manual();
+        let mut controller = HiddenServiceController::new(
+            socketaddr_to_multiaddr(&addr),
+            Authentication::None,
+            PortMapping::from_port(80),
+            None,
+            socks::Authentication::None,
+            None,
+            HsFlags::NONE,
+            ServicePersistence::Ephemeral,
+            OptionalShutdownSignal::none(),
+        )
+        .with_clock(Arc::new(clock.clone()));
+
+        let mut shutdown = Shutdown::new();
+        let mut signal = OptionalShutdownSignal::none();
+        signal.set(shutdown.to_signal());
+        let (event_tx, _) = broadcast::channel(1);
+        let handle = task::spawn(async move { controller.reestablish_hidden_service(event_tx, &mut signal).await });
+
+        // Each attempt waits for the delay on the mock clock, so 3 attempts take no real time. Shutting down interrupts
+        // the fourth delay.
+        for _ in 0..3 {
+            clock.wait_for_delay().await;
+            clock.advance(RECONNECT_DELAY);
+        }
+        clock.wait_for_delay().await;
+        shutdown.trigger().unwrap();
+        let err = handle.await.unwrap().unwrap_err();
+        unpack_enum!(HiddenServiceControllerError::ShutdownSignalInterrupt = err);
+        assert_eq!(clock.elapsed(), RECONNECT_DELAY * 3);
+    }
+
+    #[runtime::test]
+    async fn connection_state_across_reconnection() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (disconnect_tx, disconnect_rx) = futures::channel::oneshot::channel::<()>();
+        task::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut lines = Framed::new(socket, LinesCodec::new());
+            lines.next().await.unwrap().unwrap();
+            lines.send("250 OK".to_string()).await.unwrap();
+            // Close the connection, and stop listening, once told to
+            let _ = disconnect_rx.await;
+        });
+
+        let clock = MockClock::manual();
+        let mut controller = HiddenServiceController::new(
+            socketaddr_to_multiaddr(&addr),
+            Authentication::None,
+            PortMapping::from_port(80),
+            Some("/ip4/127.0.0.1/tcp/9050".parse().unwrap()),
+            socks::Authentication::None,
+            None,
+            HsFlags::NONE,
+            ServicePersistence::Ephemeral,
+            OptionalShutdownSignal::none(),
+        )
+        .with_clock(Arc::new(clock.clone()));
+        let mut states = controller.watch_connection_state();
+        assert_eq!(states.recv().await, Some(ConnectionState::Closed));
+
+        controller.connect().await.unwrap();
+        assert_eq!(controller.connection_state(), ConnectionState::Authenticating);
+        controller.authenticate().await.unwrap();
+        assert_eq!(controller.connection_state(), ConnectionState::Ready);
+
+        // The receiver only sees the latest state
+        assert_eq!(states.recv().await, Some(ConnectionState::Ready));
+
+        disconnect_tx.send(()).unwrap();
+        assert_eq!(states.recv().await, Some(ConnectionState::Closed));
+
+        let event_tx = controller.client.as_ref().unwrap().event_sender().clone();
+        let connection_state = controller.connection_state.clone();
+        let handle = task::spawn(async move {
+            controller
+                .reestablish_hidden_service(event_tx, &mut OptionalShutdownSignal::none())
+                .await
+                .map(|_| controller)
+        });
+        // Nothing is listening, so the state stays `Reconnecting` while each attempt fails
+        clock.wait_for_delay().await;
+        assert_eq!(states.recv().await, Some(ConnectionState::Reconnecting));
+
+        // The state stays `Reconnecting` while the connection is authenticated and the hidden service is added again
+        let mut listener = TcpListener::bind(addr).await.unwrap();
+        let states_seen = Arc::new(Mutex::new(Vec::new()));
+        task::spawn({
+            let states_seen = states_seen.clone();
+            async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                // Each command is sent once the reply to the previous command was received
+                serve_control_port(socket, move |_| {
+                    states_seen.lock().unwrap().push(connection_state.get());
+                })
+                .await;
+            }
+        });
+        clock.advance(RECONNECT_DELAY);
+        let controller = handle.await.unwrap().unwrap();
+        let states_seen = states_seen.lock().unwrap();
+        // AUTHENTICATE, SETEVENTS and ADD_ONION
+        assert_eq!(*states_seen, vec![ConnectionState::Reconnecting; 3]);
+        assert_eq!(controller.connection_state(), ConnectionState::Ready);
+        assert_eq!(states.recv().await, Some(ConnectionState::Ready));
+    }
+}
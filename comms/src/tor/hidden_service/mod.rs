@@ -21,19 +21,20 @@
 // USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
 mod builder;
-pub use builder::{HiddenServiceBuilder, HiddenServiceBuilderError, HsFlags};
+pub use builder::{HiddenServiceBuilder, HiddenServiceBuilderError, HsFlags, ServicePersistence};
 
 mod controller;
 use crate::{
     multiaddr::Multiaddr,
     socks,
-    tor::{PrivateKey, TorClientError},
+    tor::{PrivateKey, TorClientError, TorControlEvent},
     transports::{SocksConfig, SocksTransport},
 };
 pub use controller::{HiddenServiceController, HiddenServiceControllerError};
 use serde_derive::{Deserialize, Serialize};
 use std::fmt;
 use tari_shutdown::OptionalShutdownSignal;
+use tokio::sync::broadcast;
 
 /// Handle for a Tor Hidden Service. This handle keeps the session to the Tor control port alive.
 /// Once this is dropped, the hidden service will cease to be accessible.
@@ -49,6 +50,8 @@ pub struct HiddenService {
     pub(super) proxied_addr: Multiaddr,
     /// Shutdown signal for hidden service
     pub(super) shutdown_signal: OptionalShutdownSignal,
+    /// The sender of the control port events, which is kept across reconnections
+    pub(super) event_tx: broadcast::Sender<TorControlEvent>,
 }
 
 impl HiddenService {
@@ -76,6 +79,12 @@ impl HiddenService {
     pub fn tor_identity(&self) -> &TorIdentity {
         &self.identity
     }
+
+    /// Subscribes to the events of the control port connection. `TorControlEvent::TorControlReconnected` is received
+    /// each time the connection is reestablished, once the hidden service has been added again.
+    pub fn get_event_stream(&self) -> broadcast::Receiver<TorControlEvent> {
+        self.event_tx.subscribe()
+    }
 }
 
 fn multiaddr_from_service_id_and_port(service_id: &str, onion_port: u16) -> Result<Multiaddr, TorClientError> {
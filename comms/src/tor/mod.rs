@@ -31,14 +31,62 @@
 //! `tari_comms` to function over Tor.
 
 mod control_client;
+#[cfg(feature = "benches")]
+#[doc(hidden)]
+pub use control_client::bench_parsers as parsers;
 pub use control_client::{
+    commands,
+    encode_onion_v3_address,
+    onion_v3_checksum,
+    AddressMapping,
+    AnyKeyType,
     Authentication,
+    BuildFlag,
+    BuildFlags,
+    CircEvent,
+    CircStatus,
+    CircuitPurpose,
+    CircuitReason,
+    CircuitStatus,
+    CircuitStatusIter,
+    ClientName,
+    Clock,
+    ConnectionFailureKind,
+    ConnectionState,
+    ExternalAddress,
+    GuardEvent,
+    GuardStatus,
+    GuardType,
+    Heartbeat,
+    Hostname,
     KeyBlob,
     KeyType,
+    ListenerAddress,
+    OnionAddress,
+    OnionAddressError,
+    OnionInventory,
+    OnionListener,
+    OnionTarget,
     PortMapping,
+    PortSpec,
     PrivateKey,
+    RelaySpec,
+    ResponseDecoder,
+    ResponseLine,
+    ResponseLineKind,
+    StreamEvent,
+    StreamReason,
+    StreamStatus,
+    StreamStatusEntry,
+    StreamStatusIter,
+    SystemClock,
     TorClientError,
+    TorControlEvent,
     TorControlPortClient,
+    TorControlPortClientBuilder,
+    TorVersion,
+    TrafficCounters,
+    VersionStatus,
 };
 
 mod hidden_service;
@@ -49,5 +97,6 @@ pub use hidden_service::{
     HiddenServiceController,
     HiddenServiceControllerError,
     HsFlags,
+    ServicePersistence,
     TorIdentity,
 };
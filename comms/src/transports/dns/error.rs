@@ -49,3 +49,9 @@ pub enum DnsResolverError {
     #[error("Failed to join on blocking task: {0}")]
     BlockingJoinError(#[from] JoinError),
 }
+
+/// A [DnsResolverError] from resolving the address given to a transport. The resolver error is kept as the source, so
+/// that callers can tell that resolution failed.
+#[derive(Debug, Error)]
+#[error("Address resolution failed: {0}")]
+pub struct AddressResolutionError(#[source] pub DnsResolverError);
@@ -23,7 +23,7 @@
 mod common;
 
 mod error;
-pub use error::DnsResolverError;
+pub use error::{AddressResolutionError, DnsResolverError};
 
 mod system;
 pub use system::SystemDnsResolver;
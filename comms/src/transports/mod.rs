@@ -28,6 +28,7 @@ use futures::{Future, Stream};
 use multiaddr::Multiaddr;
 
 mod dns;
+pub(crate) use dns::AddressResolutionError;
 
 mod memory;
 pub use memory::MemoryTransport;
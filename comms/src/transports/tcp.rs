@@ -20,7 +20,10 @@
 // WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
 // USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use super::{dns::DnsResolver, Transport};
+use super::{
+    dns::{AddressResolutionError, DnsResolver},
+    Transport,
+};
 use crate::{
     transports::dns::{DnsResolverRef, SystemDnsResolver},
     utils::multiaddr::socketaddr_to_multiaddr,
@@ -147,7 +150,7 @@ impl Transport for TcpTransport {
         Ok(config
             .dns_resolver
             .resolve(addr)
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("Address resolution failed: {}", err)))
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, AddressResolutionError(err)))
             .and_then(|socket_addr| TcpOutbound::new(Box::pin(TcpStream::connect(socket_addr)), config)))
     }
 }